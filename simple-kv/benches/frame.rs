@@ -0,0 +1,76 @@
+use bytes::BytesMut;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use prost::Message;
+use simple_kv::{CommandResponse, CompressionCodec, FrameCoder, FrameCompression, Kvpair, Value};
+
+/// 模拟 HGETALL 的 response：一些 JSON 格式的 value
+fn response(size: usize) -> CommandResponse {
+    let mut pairs = Vec::new();
+    let mut len = 0;
+    while len < size {
+        let i = pairs.len();
+        let value = format!(
+            r#"{{"id":{},"name":"user{}","email":"user{}@example.com","city":"city{}","score":{}}}"#,
+            i,
+            i,
+            i,
+            i % 17,
+            i * 7919 % 1000
+        );
+        len += value.len();
+        pairs.push(Kvpair::new(format!("key{}", i), Value::from(value)));
+    }
+    pairs.into()
+}
+
+fn compression(codec: CompressionCodec) -> FrameCompression {
+    FrameCompression {
+        codec,
+        ..Default::default()
+    }
+}
+
+fn frame_codecs(c: &mut Criterion) {
+    let codecs = [
+        CompressionCodec::None,
+        CompressionCodec::Gzip,
+        CompressionCodec::Lz4,
+        CompressionCodec::Zstd,
+    ];
+
+    for size in [4 * 1024, 64 * 1024] {
+        let res = response(size);
+
+        let mut group = c.benchmark_group(format!("encode_frame_{}k", size / 1024));
+        group.throughput(Throughput::Bytes(res.encoded_len() as u64));
+        for codec in codecs {
+            let compression = compression(codec);
+            group.bench_with_input(BenchmarkId::from_parameter(codec.name()), &res, |b, res| {
+                let mut buf = BytesMut::new();
+                b.iter(|| {
+                    buf.clear();
+                    res.encode_frame_with(&mut buf, &compression).unwrap();
+                })
+            });
+        }
+        group.finish();
+
+        let mut group = c.benchmark_group(format!("decode_frame_{}k", size / 1024));
+        group.throughput(Throughput::Bytes(res.encoded_len() as u64));
+        for codec in codecs {
+            let mut frame = BytesMut::new();
+            res.encode_frame_with(&mut frame, &compression(codec))
+                .unwrap();
+            // 压缩之后 frame 的大小放在 benchmark 的名字里
+            group.bench_with_input(
+                BenchmarkId::new(codec.name(), frame.len()),
+                &frame,
+                |b, frame| b.iter(|| CommandResponse::decode_frame(&mut frame.clone()).unwrap()),
+            );
+        }
+        group.finish();
+    }
+}
+
+criterion_group!(benches, frame_codecs);
+criterion_main!(benches);
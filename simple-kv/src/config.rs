@@ -1,6 +1,6 @@
 use crate::KvError;
 use serde::{Deserialize, Serialize};
-use std::fs;
+use std::{collections::HashMap, fs};
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub struct ServerConfig {
@@ -8,6 +8,38 @@ pub struct ServerConfig {
     pub storage: StorageConfig,
     pub tls: ServerTlsConfig,
     pub log: LogConfig,
+    #[serde(default)]
+    pub reaper: ReaperConfig,
+    #[serde(default)]
+    pub mvcc: MvccConfig,
+    #[serde(default)]
+    pub indexes: Vec<IndexConfig>,
+    #[serde(default)]
+    pub quotas: Vec<QuotaConfig>,
+    /// 配置之后，value 在写入存储之前会被加密
+    #[serde(default)]
+    pub encryption: Option<EncryptionConfig>,
+    /// 写入存储之前压缩 value，默认不压缩
+    ///
+    /// 压缩和加密、配额一样是包在所有存储后端外面的一层，对每种 StorageConfig 都适用，
+    /// 所以放在和 storage 并列的 [compression] 中；StorageConfig 是按 type 区分后端的 enum，
+    /// 放进去的话每个后端都要重复一份
+    #[serde(default)]
+    pub compression: CompressionConfig,
+    /// Backup/Restore 命令读写的文件都在这个目录下
+    #[serde(default = "default_backup_dir")]
+    pub backup_dir: String,
+    /// 分块上传的 value 最大的大小（字节），0 表示没有限制
+    #[serde(default = "default_max_value_size")]
+    pub max_value_size: usize,
+}
+
+fn default_backup_dir() -> String {
+    "/tmp/kv-backup".into()
+}
+
+fn default_max_value_size() -> usize {
+    256 * 1024 * 1024
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
@@ -21,6 +53,16 @@ pub struct GeneralConfig {
     pub addr: String,
     #[serde(default)]
     pub network: NetworkType,
+    /// 发送 frame 时使用的压缩方式，默认和之前一样使用 gzip
+    #[serde(default)]
+    pub compression: FrameCompression,
+    /// 能接收的最大 frame（字节），0 表示没有限制，更大的 value 需要分块传输
+    #[serde(default = "default_max_frame_size")]
+    pub max_frame_size: usize,
+}
+
+fn default_max_frame_size() -> usize {
+    16 * 1024 * 1024
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
@@ -57,6 +99,243 @@ pub enum RotationConfig {
 pub enum StorageConfig {
     MemTable,
     SledDb(String),
+    BoundedMemTable {
+        /// 数据最多占用的内存（字节），超过之后按 eviction 淘汰 key
+        max_bytes: usize,
+        #[serde(default)]
+        eviction: EvictionPolicy,
+    },
+    MemTableWal {
+        /// 存放预写日志和快照的目录
+        dir: String,
+        #[serde(default)]
+        fsync_policy: FsyncPolicy,
+        /// 预写日志超过这个大小（字节）之后，生成快照并清空日志
+        #[serde(default = "default_compact_threshold")]
+        compact_threshold: u64,
+    },
+    Lsm {
+        /// 存放预写日志、MANIFEST 和 SSTable 的目录
+        dir: String,
+        #[serde(default)]
+        fsync_policy: FsyncPolicy,
+        /// memtable 超过这个大小（字节）之后写成 SSTable
+        #[serde(default = "default_memtable_size")]
+        memtable_size: usize,
+    },
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum EvictionPolicy {
+    /// 淘汰最久没有访问的 key
+    Lru,
+    /// 淘汰访问次数最少的 key
+    Lfu,
+    /// 随机淘汰 key
+    Random,
+    /// 只淘汰设置了过期时间的 key，最先过期的最先淘汰
+    VolatileTtl,
+}
+
+impl Default for EvictionPolicy {
+    fn default() -> Self {
+        EvictionPolicy::Lru
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FsyncPolicy {
+    /// 每次写入日志之后都调用 fsync，机器宕机也不会丢数据
+    Always,
+    /// 只把日志写入操作系统，由操作系统决定何时落盘，进程崩溃不会丢数据
+    Never,
+}
+
+impl Default for FsyncPolicy {
+    fn default() -> Self {
+        FsyncPolicy::Always
+    }
+}
+
+fn default_compact_threshold() -> u64 {
+    64 * 1024 * 1024
+}
+
+fn default_memtable_size() -> usize {
+    4 * 1024 * 1024
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ReaperConfig {
+    /// 后台清理过期 key 的间隔，单位是毫秒
+    pub interval: u64,
+}
+
+impl Default for ReaperConfig {
+    fn default() -> Self {
+        Self { interval: 1000 }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct MvccConfig {
+    /// 旧版本保留的时间，单位是毫秒，超过之后由后台任务清理；
+    /// 0（默认）表示关闭多版本，不能读取旧版本，也不能创建快照
+    #[serde(default)]
+    pub retention: u64,
+    /// 旧版本最多占用的内存（字节），超过之后提前清理最旧的版本，0 表示没有限制
+    #[serde(default = "default_mvcc_max_bytes")]
+    pub max_bytes: u64,
+}
+
+fn default_mvcc_max_bytes() -> u64 {
+    64 * 1024 * 1024
+}
+
+impl Default for MvccConfig {
+    fn default() -> Self {
+        Self {
+            retention: 0,
+            max_bytes: default_mvcc_max_bytes(),
+        }
+    }
+}
+
+/// table 上的二级索引
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct IndexConfig {
+    pub table: String,
+    /// 索引的名字，Hfind 通过它指定使用哪个索引
+    pub name: String,
+    /// 字符串 value 中 JSON 字段的 path（比如 address.city），为空时索引整个 value
+    #[serde(default)]
+    pub path: Option<String>,
+}
+
+/// table 的配额，0 表示没有限制
+///
+/// 大小按客户端写入的数据计算（压缩、加密之前），所以可能比 TableInfo 返回的 bytes 大
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct QuotaConfig {
+    pub table: String,
+    /// 最多可以有多少个 key
+    #[serde(default)]
+    pub max_keys: usize,
+    /// key 和 value 最多占用的字节数
+    #[serde(default)]
+    pub max_bytes: usize,
+    /// 单个 value 最多占用的字节数
+    #[serde(default)]
+    pub max_value_size: usize,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct EncryptionConfig {
+    /// 密钥文件，格式见 Keyring，可以用 kv-encrypt gen-key 生成
+    pub key_file: String,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct CompressionConfig {
+    /// 没有单独配置的 table 使用的压缩策略
+    #[serde(default)]
+    pub default: CompressionPolicy,
+    /// 每个 table 单独的压缩策略
+    #[serde(default)]
+    pub tables: HashMap<String, CompressionPolicy>,
+}
+
+impl CompressionConfig {
+    /// 获取 table 使用的压缩策略
+    pub fn policy(&self, table: &str) -> &CompressionPolicy {
+        self.tables.get(table).unwrap_or(&self.default)
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct CompressionPolicy {
+    #[serde(default)]
+    pub codec: CompressionCodec,
+    /// value 编码之后不小于这个大小（字节）才压缩，太小的 value 压缩不划算
+    #[serde(default = "default_compression_threshold")]
+    pub threshold: usize,
+}
+
+impl Default for CompressionPolicy {
+    fn default() -> Self {
+        Self {
+            codec: CompressionCodec::default(),
+            threshold: default_compression_threshold(),
+        }
+    }
+}
+
+fn default_compression_threshold() -> usize {
+    256
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct FrameCompression {
+    /// 对方不支持这个压缩算法时不压缩
+    #[serde(default = "default_frame_codec")]
+    pub codec: CompressionCodec,
+    /// payload 超过这个大小（字节）才压缩
+    #[serde(default = "default_frame_threshold")]
+    pub threshold: usize,
+    /// 压缩级别，0 表示使用压缩算法缺省的级别，lz4 没有压缩级别
+    #[serde(default)]
+    pub level: i32,
+}
+
+impl Default for FrameCompression {
+    fn default() -> Self {
+        Self {
+            codec: default_frame_codec(),
+            threshold: default_frame_threshold(),
+            level: 0,
+        }
+    }
+}
+
+fn default_frame_codec() -> CompressionCodec {
+    CompressionCodec::Gzip
+}
+
+fn default_frame_threshold() -> usize {
+    1436
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CompressionCodec {
+    /// 不压缩
+    None,
+    /// gzip，压缩率高，速度比较慢
+    Gzip,
+    /// lz4，压缩率低一些，速度快很多
+    Lz4,
+    /// zstd，压缩率接近 gzip，速度快很多
+    Zstd,
+}
+
+impl CompressionCodec {
+    /// 压缩算法的名字，和配置文件中的写法一致
+    pub fn name(&self) -> &'static str {
+        match self {
+            CompressionCodec::None => "none",
+            CompressionCodec::Gzip => "gzip",
+            CompressionCodec::Lz4 => "lz4",
+            CompressionCodec::Zstd => "zstd",
+        }
+    }
+}
+
+impl Default for CompressionCodec {
+    fn default() -> Self {
+        CompressionCodec::None
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
@@ -98,6 +377,160 @@ mod tests {
         let result: Result<ServerConfig, toml::de::Error> =
             toml::from_str(include_str!("../fixtures/server.conf"));
         assert!(result.is_ok());
+        // 没有配置时默认关闭多版本
+        let mvcc = result.unwrap().mvcc;
+        assert_eq!(mvcc, MvccConfig::default());
+        assert_eq!(mvcc.retention, 0);
+    }
+
+    #[test]
+    fn memtable_wal_config_should_be_loaded() {
+        let config = r#"
+            type = 'MemTableWal'
+
+            [args]
+            dir = '/tmp/kv_wal'
+        "#;
+        let result: StorageConfig = toml::from_str(config).unwrap();
+        assert_eq!(
+            result,
+            StorageConfig::MemTableWal {
+                dir: "/tmp/kv_wal".into(),
+                fsync_policy: FsyncPolicy::Always,
+                compact_threshold: default_compact_threshold(),
+            }
+        );
+    }
+
+    #[test]
+    fn bounded_memtable_config_should_be_loaded() {
+        let config = r#"
+            type = 'BoundedMemTable'
+
+            [args]
+            max_bytes = 1048576
+            eviction = 'volatile_ttl'
+        "#;
+        let result: StorageConfig = toml::from_str(config).unwrap();
+        assert_eq!(
+            result,
+            StorageConfig::BoundedMemTable {
+                max_bytes: 1048576,
+                eviction: EvictionPolicy::VolatileTtl,
+            }
+        );
+    }
+
+    #[test]
+    fn lsm_config_should_be_loaded() {
+        let config = r#"
+            type = 'Lsm'
+
+            [args]
+            dir = '/tmp/kv_lsm'
+            fsync_policy = 'never'
+        "#;
+        let result: StorageConfig = toml::from_str(config).unwrap();
+        assert_eq!(
+            result,
+            StorageConfig::Lsm {
+                dir: "/tmp/kv_lsm".into(),
+                fsync_policy: FsyncPolicy::Never,
+                memtable_size: default_memtable_size(),
+            }
+        );
+    }
+
+    #[test]
+    fn index_config_should_be_loaded() {
+        let config = r#"
+            [[indexes]]
+            table = 'users'
+            name = 'city'
+            path = 'address.city'
+
+            [[indexes]]
+            table = 'tags'
+            name = 'tag'
+        "#;
+        #[derive(Deserialize)]
+        struct Config {
+            indexes: Vec<IndexConfig>,
+        }
+        let result: Config = toml::from_str(config).unwrap();
+        assert_eq!(result.indexes[0].path.as_deref(), Some("address.city"));
+        assert_eq!(result.indexes[1].path, None);
+    }
+
+    #[test]
+    fn quota_config_should_be_loaded() {
+        let config = r#"
+            [[quotas]]
+            table = 'tenant1'
+            max_keys = 1000
+            max_value_size = 4096
+        "#;
+        #[derive(Deserialize)]
+        struct Config {
+            quotas: Vec<QuotaConfig>,
+        }
+        let result: Config = toml::from_str(config).unwrap();
+        assert_eq!(
+            result.quotas[0],
+            QuotaConfig {
+                table: "tenant1".into(),
+                max_keys: 1000,
+                max_bytes: 0,
+                max_value_size: 4096,
+            }
+        );
+    }
+
+    #[test]
+    fn compression_config_should_be_loaded() {
+        let config = r#"
+            [compression.default]
+            codec = 'lz4'
+
+            [compression.tables.logs]
+            codec = 'gzip'
+            threshold = 64
+        "#;
+        #[derive(Deserialize)]
+        struct Config {
+            compression: CompressionConfig,
+        }
+        let result: Config = toml::from_str(config).unwrap();
+        let policy = result.compression.policy("users");
+        assert_eq!(policy.codec, CompressionCodec::Lz4);
+        assert_eq!(policy.threshold, default_compression_threshold());
+        let policy = result.compression.policy("logs");
+        assert_eq!(policy.codec, CompressionCodec::Gzip);
+        assert_eq!(policy.threshold, 64);
+    }
+
+    #[test]
+    fn frame_compression_config_should_be_loaded() {
+        let result: ClientConfig = toml::from_str(include_str!("../fixtures/client.conf")).unwrap();
+        assert_eq!(result.general.compression, FrameCompression::default());
+
+        let config = r#"
+            addr = '127.0.0.1:9527'
+
+            [compression]
+            codec = 'zstd'
+            level = 1
+        "#;
+        let result: GeneralConfig = toml::from_str(config).unwrap();
+        assert_eq!(result.max_frame_size, default_max_frame_size());
+        assert_eq!(
+            result.compression,
+            FrameCompression {
+                codec: CompressionCodec::Zstd,
+                threshold: default_frame_threshold(),
+                level: 1,
+            }
+        );
     }
 
     #[test]
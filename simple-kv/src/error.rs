@@ -6,8 +6,24 @@ pub enum KvError {
     NotFound(String),
     #[error("Frame is larger than max size")]
     FrameError,
+    #[error("Frame of {0} bytes exceeds the limit of {1} bytes")]
+    FrameTooLarge(usize, usize),
+    #[error("Handshake failed: {0}")]
+    HandshakeError(String),
     #[error("Command is invalid: `{0}`")]
     InvalidCommand(String),
+    #[error("Precondition failed: {0}")]
+    PreconditionFailed(String),
+    #[error("Out of memory: {0}")]
+    OutOfMemory(String),
+    #[error("Encryption error: {0}")]
+    EncryptionError(String),
+    #[error("Quota exceeded: {0}")]
+    QuotaExceeded(String),
+    #[error("Version {0} is not available")]
+    VersionNotAvailable(u64),
+    #[error("Failed to import line {0}: {1}")]
+    ImportError(usize, String),
     #[error("Cannot convert value {0} to {1}")]
     ConvertError(String, &'static str),
     #[error("Cannot process command {0} with table: {1}, key: {2}. Error: {}")]
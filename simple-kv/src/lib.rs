@@ -5,7 +5,7 @@ mod pb;
 mod service;
 mod storage;
 
-use std::{net::SocketAddr, str::FromStr};
+use std::{net::SocketAddr, str::FromStr, time::Duration};
 
 pub use config::*;
 pub use error::KvError;
@@ -19,12 +19,60 @@ use s2n_quic::{client::Connect, Client, Server};
 use tokio::net::{TcpListener, TcpStream};
 use tokio_rustls::client;
 use tokio_util::compat::FuturesAsyncReadCompatExt;
-use tracing::{info, instrument, span};
+use tracing::{info, instrument, span, warn};
 
 /// 通过配置创建 KV 服务器
 #[instrument(skip_all)]
 pub async fn start_server_with_config(config: &ServerConfig) -> Result<()> {
+    match &config.storage {
+        StorageConfig::MemTable => serve(MemTable::new(), config).await,
+        StorageConfig::SledDb(path) => serve(SledDb::new(path)?, config).await,
+        StorageConfig::BoundedMemTable {
+            max_bytes,
+            eviction,
+        } => serve(MemTable::with_limit(*max_bytes, *eviction), config).await,
+        StorageConfig::MemTableWal {
+            dir,
+            fsync_policy,
+            compact_threshold,
+        } => {
+            let store = WalMemTable::open(dir, *fsync_policy, *compact_threshold)?;
+            serve(store, config).await
+        }
+        StorageConfig::Lsm {
+            dir,
+            fsync_policy,
+            memtable_size,
+        } => {
+            let store = LsmDb::open(dir, *fsync_policy, *memtable_size)?;
+            serve(store, config).await
+        }
+    }
+}
+
+/// 配置了加密时先用 EncryptedStorage 包装 store，再用 CompressedStorage 包装，这样 value
+/// 先压缩再加密；最外层用 QuotaStorage 检查 table 的配额，限制的是压缩之前的 value 大小，
+/// 不会被容易压缩的大 value 绕过。然后启动服务器
+async fn serve<Store: Storage>(store: Store, config: &ServerConfig) -> Result<()> {
+    let compressor = Compressor::new(config.compression.clone());
+    match &config.encryption {
+        Some(encryption) => {
+            let keyring = Keyring::load(&encryption.key_file)?;
+            let store = CompressedStorage::new(EncryptedStorage::new(store, keyring), compressor);
+            start_server(QuotaStorage::new(store, &config.quotas), config).await
+        }
+        None => {
+            let store = CompressedStorage::new(store, compressor);
+            start_server(QuotaStorage::new(store, &config.quotas), config).await
+        }
+    }
+}
+
+/// 根据配置的网络类型启动服务器
+async fn start_server<Store: Storage>(store: Store, config: &ServerConfig) -> Result<()> {
     let addr = &config.general.addr;
+    let general = config.general.clone();
+    let service = start_service(store, config)?;
     match config.general.network {
         NetworkType::Tcp => {
             let acceptor = TlsServerAcceptor::new(
@@ -32,29 +80,37 @@ pub async fn start_server_with_config(config: &ServerConfig) -> Result<()> {
                 &config.tls.key,
                 config.tls.ca.as_deref(),
             )?;
-
-            match &config.storage {
-                StorageConfig::MemTable => {
-                    start_tls_server(addr, MemTable::new(), acceptor).await?
-                }
-                StorageConfig::SledDb(path) => {
-                    start_tls_server(addr, SledDb::new(path), acceptor).await?
-                }
-            };
-        }
-        NetworkType::Quic => {
-            match &config.storage {
-                StorageConfig::MemTable => {
-                    start_quic_server(addr, MemTable::new(), &config.tls).await?
-                }
-                StorageConfig::SledDb(path) => {
-                    start_quic_server(addr, SledDb::new(path), &config.tls).await?
-                }
-            };
+            start_tls_server(addr, service, acceptor, general).await
         }
+        NetworkType::Quic => start_quic_server(addr, service, &config.tls, general).await,
     }
+}
 
-    Ok(())
+/// 创建 Service，并启动清理过期 key 的后台任务
+fn start_service<Store: Storage>(
+    store: Store,
+    config: &ServerConfig,
+) -> Result<Service<BlockingStorage<MvccStorage<IndexedStorage<Store>>>>> {
+    let store = IndexedStorage::new(store);
+    for index in &config.indexes {
+        store.create_index(index)?;
+    }
+    // 保留时间为 0 时不需要多版本，写操作也就不用竞争 MvccStorage 的锁
+    let store = match config.mvcc.retention {
+        0 => MvccStorage::disabled(store),
+        retention => MvccStorage::new(store, Duration::from_millis(retention))
+            .with_max_bytes(config.mvcc.max_bytes),
+    };
+    let mut store = BlockingStorage::new(store)
+        .with_backup_dir(&config.backup_dir)
+        .with_max_value_size(config.max_value_size);
+    // Backup 读到的是解密之后的 value，开启了加密时备份文件也要加密
+    if let Some(encryption) = &config.encryption {
+        store = store.with_backup_codec(Keyring::load(&encryption.key_file)?);
+    }
+    let service: Service<_> = ServiceInner::new(store).into();
+    service.start_reaper(Duration::from_millis(config.reaper.interval));
+    Ok(service)
 }
 
 /// 通过配置创建 KV 客户端
@@ -94,12 +150,12 @@ pub async fn start_quic_client_with_config(config: &ClientConfig) -> Result<Quic
     Ok(QuicCtrl::new(conn))
 }
 
-async fn start_quic_server<Store: Storage>(
+async fn start_quic_server<Store: AsyncStorage>(
     addr: &str,
-    store: Store,
+    service: Service<Store>,
     tls_config: &ServerTlsConfig,
+    general: GeneralConfig,
 ) -> Result<()> {
-    let service: Service<Store> = ServiceInner::new(store).into();
     let mut listener = Server::builder()
         .with_tls((tls_config.cert.as_str(), tls_config.key.as_str()))?
         .with_io(addr)?
@@ -115,6 +171,7 @@ async fn start_quic_server<Store: Storage>(
         if let Some(mut conn) = listener.accept().await {
             info!("Client {} connected", conn.remote_addr()?);
             let svc = service.clone();
+            let general = general.clone();
 
             tokio::spawn(async move {
                 while let Ok(Some(stream)) = conn.accept_bidirectional_stream().await {
@@ -123,9 +180,13 @@ async fn start_quic_server<Store: Storage>(
                         stream.connection().remote_addr()?
                     );
                     let svc1 = svc.clone();
+                    let general = general.clone();
                     tokio::spawn(async move {
-                        let stream = ProstServerStream::new(stream, svc1.clone());
-                        stream.process().await.unwrap();
+                        let stream =
+                            ProstServerStream::new(stream, svc1.clone()).with_config(&general);
+                        if let Err(e) = stream.process().await {
+                            warn!("Failed to process stream: {e:?}");
+                        }
                     });
                 }
                 Ok::<(), anyhow::Error>(())
@@ -134,12 +195,12 @@ async fn start_quic_server<Store: Storage>(
     }
 }
 
-async fn start_tls_server<Store: Storage>(
+async fn start_tls_server<Store: AsyncStorage>(
     addr: &str,
-    store: Store,
+    service: Service<Store>,
     acceptor: TlsServerAcceptor,
+    general: GeneralConfig,
 ) -> Result<()> {
-    let service: Service<Store> = ServiceInner::new(store).into();
     let listener = TcpListener::bind(addr).await?;
     info!("Start listening on {}", addr);
     loop {
@@ -150,13 +211,24 @@ async fn start_tls_server<Store: Storage>(
         info!("Client {:?} connected", addr);
 
         let svc = service.clone();
+        let general = general.clone();
         tokio::spawn(async move {
-            let stream = tls.accept(stream).await.unwrap();
+            let stream = match tls.accept(stream).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!("TLS handshake with {addr:?} failed: {e:?}");
+                    return;
+                }
+            };
             YamuxCtrl::new_server(stream, None, move |stream| {
                 let svc1 = svc.clone();
+                let general = general.clone();
                 async move {
-                    let stream = ProstServerStream::new(stream.compat(), svc1.clone());
-                    stream.process().await.unwrap();
+                    let stream =
+                        ProstServerStream::new(stream.compat(), svc1.clone()).with_config(&general);
+                    if let Err(e) = stream.process().await {
+                        warn!("Failed to process stream: {e:?}");
+                    }
                     Ok(())
                 }
             });
@@ -1,6 +1,9 @@
 use std::io::{Read, Write};
 
-use crate::{CommandRequest, CommandResponse, KvError};
+use crate::{
+    BackupRecord, CommandRequest, CommandResponse, CompressionCodec, FrameCompression, KvError,
+    WalRecord,
+};
 use bytes::{Buf, BufMut, BytesMut};
 use flate2::{read::GzDecoder, write::GzEncoder, Compression};
 use prost::Message;
@@ -9,99 +12,186 @@ use tracing::debug;
 
 /// 长度整个占用 4 个字节
 pub const LEN_LEN: usize = 4;
-/// 长度占 31 bit，所以最大的 frame 是 2G
-const MAX_FRAME: usize = 2 * 1024 * 1024 * 1024;
-/// 如果 payload 超过了 1436 字节，就做压缩
-const COMPRESSION_LIMIT: usize = 1436;
-/// 代表压缩的 bit（整个长度 4 字节的最高位）
-const COMPRESSION_BIT: usize = 1 << 31;
+/// 长度占 30 bit，所以最大的 frame 接近 1G
+pub(crate) const MAX_FRAME: usize = (1 << CODEC_SHIFT) - 1;
+/// 长度 4 字节的最高 2 位代表 payload 的压缩算法
+const CODEC_SHIFT: usize = 30;
+/// gzip 沿用最高位，这样以前只支持 gzip 时写入的 frame（WAL、备份）仍然可以读取
+const GZIP_BITS: usize = 0b10;
+const LZ4_BITS: usize = 0b01;
+const ZSTD_BITS: usize = 0b11;
 
 /// 处理 Frame 的 encode/decode
 pub trait FrameCoder
 where
     Self: Message + Sized + Default,
 {
-    /// 把一个 Message encode 成一个 frame
+    /// 把一个 Message encode 成一个 frame，payload 超过 1436 字节时使用 gzip 压缩
     fn encode_frame(&self, buf: &mut BytesMut) -> Result<(), KvError> {
+        self.encode_frame_with(buf, &FrameCompression::default())
+    }
+
+    /// 按指定的压缩方式把一个 Message encode 成一个 frame
+    fn encode_frame_with(
+        &self,
+        buf: &mut BytesMut,
+        compression: &FrameCompression,
+    ) -> Result<(), KvError> {
         let size = self.encoded_len();
 
         if size > MAX_FRAME {
             return Err(KvError::FrameError);
         }
 
-        // 我们先写入长度，如果需要压缩，再重写压缩后的长度
-        buf.put_u32(size as _);
-
-        if size > COMPRESSION_LIMIT {
-            let mut buf1 = Vec::with_capacity(size);
-            self.encode(&mut buf1)?;
-
-            // BytesMut 支持逻辑上的 split（之后还能 unsplit）
-            // 所以我们先把长度这 4 字节拿走，清除
-            let payload = buf.split_off(LEN_LEN);
-            buf.clear();
-
-            // 处理 gzip 压缩，具体可以参考 flate2 文档
-            let mut encoder = GzEncoder::new(payload.writer(), Compression::default());
-            encoder.write_all(&buf1[..])?;
-
-            // 压缩完成后，从 gzip encoder 中把 BytesMut 再拿回来
-            let payload = encoder.finish()?.into_inner();
-            debug!("Encode a frame: size {}({})", size, payload.len());
-
-            // 写入压缩后的长度
-            buf.put_u32((payload.len() | COMPRESSION_BIT) as _);
+        if compression.codec == CompressionCodec::None || size <= compression.threshold {
+            buf.reserve(LEN_LEN + size);
+            buf.put_u32(size as _);
+            self.encode(buf)?;
+            return Ok(());
+        }
 
-            // 把 BytesMut 再合并回来
-            buf.unsplit(payload);
+        let mut data = Vec::with_capacity(size);
+        self.encode(&mut data)?;
+        let payload = compress(compression, &data)?;
+        debug!("Encode a frame: size {}({})", size, payload.len());
 
-            Ok(())
+        // 压缩之后没有变小，就不压缩了
+        if payload.len() >= size {
+            buf.reserve(LEN_LEN + size);
+            buf.put_u32(size as _);
+            buf.put_slice(&data);
         } else {
-            self.encode(buf)?;
-            Ok(())
+            buf.reserve(LEN_LEN + payload.len());
+            buf.put_u32(encode_header(payload.len(), compression.codec) as _);
+            buf.put_slice(&payload);
         }
+        Ok(())
     }
 
     /// 把一个完整的 frame decode 成一个 Message
     fn decode_frame(buf: &mut BytesMut) -> Result<Self, KvError> {
-        // 先取 4 字节，从中拿出长度和 compression bit
+        Self::decode_frame_with(buf, MAX_FRAME)
+    }
+
+    /// 把一个完整的 frame decode 成一个 Message，解压之后超过 max_frame_size 时返回错误
+    fn decode_frame_with(buf: &mut BytesMut, max_frame_size: usize) -> Result<Self, KvError> {
+        // 先取 4 字节，从中拿出长度和压缩算法
         let header = buf.get_u32() as usize;
-        let (len, compressed) = decode_header(header);
-        debug!("Got a frame: msg len {}, compressed {}", len, compressed);
+        let (len, codec) = decode_header(header);
+        debug!("Got a frame: msg len {}, codec {}", len, codec.name());
 
-        if compressed {
+        if codec == CompressionCodec::None {
+            let msg = Self::decode(&buf[..len])?;
+            buf.advance(len);
+            Ok(msg)
+        } else {
             // 解压缩
-            let mut decoder = GzDecoder::new(&buf[..len]);
-            let mut buf1 = Vec::with_capacity(len * 2);
-            decoder.read_to_end(&mut buf1)?;
+            let data = decompress(codec, &buf[..len], max_frame_size)?;
             buf.advance(len);
 
             // decode 成相应的消息
-            Ok(Self::decode(&buf1[..buf1.len()])?)
-        } else {
-            let msg = Self::decode(&buf[..len])?;
-            buf.advance(len);
-            Ok(msg)
+            Ok(Self::decode(&data[..])?)
         }
     }
 }
 
 impl FrameCoder for CommandRequest {}
 impl FrameCoder for CommandResponse {}
+impl FrameCoder for WalRecord {}
+impl FrameCoder for BackupRecord {}
+
+/// 从长度 4 字节中拿出 payload 的长度和压缩算法
+pub fn decode_header(header: usize) -> (usize, CompressionCodec) {
+    let len = header & MAX_FRAME;
+    let codec = match header >> CODEC_SHIFT {
+        GZIP_BITS => CompressionCodec::Gzip,
+        LZ4_BITS => CompressionCodec::Lz4,
+        ZSTD_BITS => CompressionCodec::Zstd,
+        _ => CompressionCodec::None,
+    };
+    (len, codec)
+}
+
+fn encode_header(len: usize, codec: CompressionCodec) -> usize {
+    let bits = match codec {
+        CompressionCodec::None => 0,
+        CompressionCodec::Gzip => GZIP_BITS,
+        CompressionCodec::Lz4 => LZ4_BITS,
+        CompressionCodec::Zstd => ZSTD_BITS,
+    };
+    len | bits << CODEC_SHIFT
+}
+
+fn compress(compression: &FrameCompression, data: &[u8]) -> Result<Vec<u8>, KvError> {
+    let level = compression.level;
+    match compression.codec {
+        CompressionCodec::None => Ok(data.to_vec()),
+        CompressionCodec::Gzip => {
+            let level = match level {
+                0 => Compression::default(),
+                level => Compression::new(level.clamp(1, 9) as _),
+            };
+            let mut encoder = GzEncoder::new(Vec::with_capacity(data.len()), level);
+            encoder.write_all(data)?;
+            Ok(encoder.finish()?)
+        }
+        CompressionCodec::Lz4 => Ok(lz4_flex::compress_prepend_size(data)),
+        CompressionCodec::Zstd => Ok(zstd::bulk::compress(data, level)?),
+    }
+}
+
+/// 解压缩 payload，解压之后超过 limit 字节时返回错误，
+/// 很小的 frame 也可能解压出巨大的数据，不能无限制地解压
+fn decompress(codec: CompressionCodec, data: &[u8], limit: usize) -> Result<Vec<u8>, KvError> {
+    match codec {
+        CompressionCodec::None => Ok(data.to_vec()),
+        CompressionCodec::Gzip => read_limited(GzDecoder::new(data), data.len() * 2, limit),
+        CompressionCodec::Lz4 => {
+            // lz4 在最前面的 4 字节（小端）中记录了解压之后的大小，按这个大小分配内存
+            if let Some(size) = data.get(..4) {
+                let size = u32::from_le_bytes(size.try_into().unwrap()) as usize;
+                if size > limit {
+                    return Err(KvError::FrameTooLarge(size, limit));
+                }
+            }
+            lz4_flex::decompress_size_prepended(data)
+                .map_err(|e| KvError::Internal(format!("Failed to decompress frame: {}", e)))
+        }
+        CompressionCodec::Zstd => read_limited(zstd::Decoder::new(data)?, data.len() * 2, limit),
+    }
+}
+
+/// 从 reader 中读取所有数据，超过 limit 字节时返回错误
+fn read_limited(reader: impl Read, capacity: usize, limit: usize) -> Result<Vec<u8>, KvError> {
+    let mut buf = Vec::with_capacity(capacity.min(limit));
+    reader.take(limit as u64 + 1).read_to_end(&mut buf)?;
+    if buf.len() > limit {
+        return Err(KvError::FrameTooLarge(buf.len(), limit));
+    }
+    Ok(buf)
+}
 
-pub fn decode_header(header: usize) -> (usize, bool) {
-    let len = header & !COMPRESSION_BIT;
-    let compressed = header & COMPRESSION_BIT == COMPRESSION_BIT;
-    (len, compressed)
+/// 从长度 4 字节中拿出 payload 的长度，超过 max_frame_size 时返回错误
+pub(crate) fn payload_len(header: usize, max_frame_size: usize) -> Result<usize, KvError> {
+    let (len, _codec) = decode_header(header);
+    if len > max_frame_size {
+        return Err(KvError::FrameTooLarge(len, max_frame_size));
+    }
+    Ok(len)
 }
 
-/// 从 stream 中读取一个完整的 frame
-pub async fn read_frame<S>(stream: &mut S, buf: &mut BytesMut) -> Result<(), KvError>
+/// 从 stream 中读取一个完整的 frame，payload 超过 max_frame_size 时返回错误
+pub async fn read_frame<S>(
+    stream: &mut S,
+    buf: &mut BytesMut,
+    max_frame_size: usize,
+) -> Result<(), KvError>
 where
     S: AsyncRead + Unpin + Send,
 {
     let header = stream.read_u32().await? as usize;
-    let (len, _compressed) = decode_header(header);
+    // 在分配内存之前检查长度，恶意的长度不会让我们分配巨大的内存
+    let len = payload_len(header, max_frame_size)?;
     // 如果没有这么大的内存，就分配至少一个 frame 的内存，保证它可用
     buf.reserve(LEN_LEN + len);
     buf.put_u32(header as _);
@@ -152,7 +242,7 @@ mod tests {
     fn command_response_compressed_encode_decode_should_work() {
         let mut buf = BytesMut::new();
 
-        let value: Value = Bytes::from(vec![0u8; COMPRESSION_LIMIT + 1]).into();
+        let value: Value = Bytes::from(vec![0u8; default_threshold() + 1]).into();
         let res: CommandResponse = value.into();
         res.encode_frame(&mut buf).unwrap();
 
@@ -163,6 +253,93 @@ mod tests {
         assert_eq!(res, res1);
     }
 
+    #[test]
+    fn frame_codecs_should_work() {
+        let value: Value = "hello world ".repeat(200).into();
+        let res: CommandResponse = value.into();
+        for codec in [
+            CompressionCodec::None,
+            CompressionCodec::Gzip,
+            CompressionCodec::Lz4,
+            CompressionCodec::Zstd,
+        ] {
+            let compression = FrameCompression {
+                codec,
+                level: 3,
+                ..Default::default()
+            };
+            let mut buf = BytesMut::new();
+            res.encode_frame_with(&mut buf, &compression).unwrap();
+            let (len, codec1) =
+                decode_header(u32::from_be_bytes(buf[..4].try_into().unwrap()) as _);
+            assert_eq!(codec1, codec);
+            assert_eq!(len, buf.len() - LEN_LEN);
+
+            let res1 = CommandResponse::decode_frame(&mut buf).unwrap();
+            assert_eq!(res, res1);
+        }
+
+        // 没有超过 threshold，或者压缩之后没有变小，都不压缩
+        let compression = FrameCompression {
+            codec: CompressionCodec::Zstd,
+            threshold: 1 << 20,
+            ..Default::default()
+        };
+        let mut buf = BytesMut::new();
+        res.encode_frame_with(&mut buf, &compression).unwrap();
+        assert_eq!(buf.len(), LEN_LEN + res.encoded_len());
+
+        let value: Value =
+            Bytes::from((0..2000).map(|_| rand::random::<u8>()).collect::<Vec<_>>()).into();
+        let res: CommandResponse = value.into();
+        let mut buf = BytesMut::new();
+        res.encode_frame_with(
+            &mut buf,
+            &FrameCompression {
+                codec: CompressionCodec::Lz4,
+                threshold: 0,
+                level: 0,
+            },
+        )
+        .unwrap();
+        assert!(!is_compressed(&buf));
+        assert_eq!(CommandResponse::decode_frame(&mut buf).unwrap(), res);
+    }
+
+    #[test]
+    fn decode_frame_should_not_decompress_beyond_max_frame() {
+        // lz4 的 payload 声称解压之后有 4G
+        let mut payload = u32::MAX.to_le_bytes().to_vec();
+        payload.extend_from_slice(b"small");
+        let mut buf = BytesMut::new();
+        buf.put_u32(encode_header(payload.len(), CompressionCodec::Lz4) as _);
+        buf.put_slice(&payload);
+        let err = CommandResponse::decode_frame(&mut buf.clone()).unwrap_err();
+        assert!(matches!(err, KvError::FrameTooLarge(_, MAX_FRAME)));
+        // 按配置的限制检查
+        let mut payload = 2048u32.to_le_bytes().to_vec();
+        payload.extend_from_slice(b"small");
+        let mut buf = BytesMut::new();
+        buf.put_u32(encode_header(payload.len(), CompressionCodec::Lz4) as _);
+        buf.put_slice(&payload);
+        let err = CommandResponse::decode_frame_with(&mut buf, 1024).unwrap_err();
+        assert!(matches!(err, KvError::FrameTooLarge(2048, 1024)));
+
+        // 解压之后超过限制的 gzip 和 zstd 在读到限制时停下
+        let data = vec![0u8; 4096];
+        for codec in [CompressionCodec::Gzip, CompressionCodec::Zstd] {
+            let compression = FrameCompression {
+                codec,
+                threshold: 0,
+                level: 0,
+            };
+            let payload = compress(&compression, &data).unwrap();
+            let err = decompress(codec, &payload, 1024).unwrap_err();
+            assert!(matches!(err, KvError::FrameTooLarge(1025, 1024)));
+            assert_eq!(decompress(codec, &payload, 4096).unwrap(), data);
+        }
+    }
+
     #[tokio::test]
     async fn read_frame_should_work() {
         let mut buf = BytesMut::new();
@@ -171,10 +348,23 @@ mod tests {
         let mut stream = DummyStream { buf };
 
         let mut data = BytesMut::new();
-        read_frame(&mut stream, &mut data).await.unwrap();
+        read_frame(&mut stream, &mut data, MAX_FRAME).await.unwrap();
 
         let cmd1 = CommandRequest::decode_frame(&mut data).unwrap();
         assert_eq!(cmd, cmd1);
+
+        // 超过限制的 frame 在分配内存之前就返回错误
+        let mut buf = BytesMut::new();
+        buf.put_u32(MAX_FRAME as _);
+        let mut stream = DummyStream { buf };
+        let mut data = BytesMut::new();
+        let err = read_frame(&mut stream, &mut data, 1024).await.unwrap_err();
+        assert!(matches!(err, KvError::FrameTooLarge(MAX_FRAME, 1024)));
+        assert_eq!(data.capacity(), 0);
+    }
+
+    fn default_threshold() -> usize {
+        FrameCompression::default().threshold
     }
 
     fn is_compressed(data: &[u8]) -> bool {
@@ -0,0 +1,178 @@
+use futures::{SinkExt, StreamExt};
+use http::StatusCode;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use super::frame::MAX_FRAME;
+use crate::{
+    command_request::RequestData, CommandRequest, CommandResponse, CompressionCodec,
+    FrameCompression, Hello, KvError, ProstStream,
+};
+
+/// 协议版本，不兼容的修改时增加
+pub const PROTOCOL_VERSION: u32 = 1;
+/// 支持的 frame 压缩算法
+const COMPRESSIONS: &[CompressionCodec] = &[
+    CompressionCodec::Gzip,
+    CompressionCodec::Lz4,
+    CompressionCodec::Zstd,
+];
+
+impl Hello {
+    /// 按本地支持的协议版本和能力创建 Hello
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+            compressions: COMPRESSIONS.iter().map(|c| c.name().into()).collect(),
+            max_frame_size: MAX_FRAME as u32,
+            name: name.into(),
+        }
+    }
+
+    /// 设置能接收的最大 frame，0 表示没有限制
+    pub fn with_max_frame_size(mut self, size: usize) -> Self {
+        self.max_frame_size = size.min(MAX_FRAME) as _;
+        self
+    }
+
+    /// 检查对方的 Hello 是否和本地兼容
+    pub fn check(&self, peer: &Hello) -> Result<(), KvError> {
+        if peer.protocol_version != self.protocol_version {
+            return Err(KvError::HandshakeError(format!(
+                "{} speaks protocol version {}, but {} is required",
+                peer.name, peer.protocol_version, self.protocol_version
+            )));
+        }
+        // 握手之前的 frame 使用 gzip 压缩
+        if !peer.supports(CompressionCodec::Gzip) {
+            return Err(KvError::HandshakeError(format!(
+                "{} does not support compression gzip",
+                peer.name
+            )));
+        }
+        Ok(())
+    }
+
+    /// 对方是否能解压这个压缩算法
+    pub fn supports(&self, codec: CompressionCodec) -> bool {
+        codec == CompressionCodec::None || self.compressions.iter().any(|c| c == codec.name())
+    }
+
+    /// 双方都能接收的最大 frame，0 表示没有限制
+    pub fn max_frame_size(&self, peer: &Hello) -> u32 {
+        match (self.max_frame_size, peer.max_frame_size) {
+            (0, size) | (size, 0) => size,
+            (a, b) => a.min(b),
+        }
+    }
+}
+
+impl FrameCompression {
+    /// 握手之后发送 frame 使用的压缩方式，对方不支持配置的压缩算法时不压缩
+    pub fn negotiate(&self, peer: &Hello) -> FrameCompression {
+        let mut compression = self.clone();
+        if !peer.supports(compression.codec) {
+            compression.codec = CompressionCodec::None;
+        }
+        compression
+    }
+}
+
+/// 服务器端：读取客户端的 Hello，检查之后返回服务器的 Hello，成功时返回客户端的 Hello
+pub(super) async fn accept_hello<S>(
+    stream: &mut ProstStream<S, CommandRequest, CommandResponse>,
+    local: Hello,
+) -> Result<Hello, KvError>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let result = match stream.next().await {
+        Some(Ok(CommandRequest {
+            request_data: Some(RequestData::Hello(peer)),
+            ..
+        })) => local.check(&peer).map(|_| peer),
+        Some(Ok(_)) => Err(KvError::HandshakeError(
+            "The first request must be Hello".into(),
+        )),
+        Some(Err(e)) => Err(e),
+        None => Err(KvError::HandshakeError("Stream closed before Hello".into())),
+    };
+    let mut res = match &result {
+        Ok(_) => CommandResponse::ok(),
+        // message 中只放原因，客户端收到之后会包装成 HandshakeError
+        Err(e) => CommandResponse {
+            status: StatusCode::UPGRADE_REQUIRED.as_u16() as _,
+            message: match e {
+                KvError::HandshakeError(reason) => reason.clone(),
+                e => e.to_string(),
+            },
+            ..Default::default()
+        },
+    };
+    res.hello = Some(local);
+    stream.send(&res).await?;
+    result
+}
+
+/// 客户端：发送 Hello，检查服务器返回的 Hello，成功时返回服务器的 Hello
+pub(super) async fn send_hello<S>(
+    stream: &mut ProstStream<S, CommandResponse, CommandRequest>,
+    local: Hello,
+) -> Result<Hello, KvError>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    stream
+        .send(&CommandRequest::new_hello(local.clone()))
+        .await?;
+    let res = match stream.next().await {
+        Some(res) => res?,
+        None => return Err(KvError::HandshakeError("Stream closed before Hello".into())),
+    };
+    if res.status != 200 {
+        return Err(KvError::HandshakeError(res.message));
+    }
+    let peer = res
+        .hello
+        .ok_or_else(|| KvError::HandshakeError("Server did not send Hello".into()))?;
+    local.check(&peer)?;
+    Ok(peer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hello_should_reject_incompatible_peers() {
+        let local = Hello::new("server");
+        assert!(local.check(&Hello::new("client")).is_ok());
+
+        let mut peer = Hello::new("client");
+        peer.protocol_version = PROTOCOL_VERSION + 1;
+        let err = local.check(&peer).unwrap_err();
+        assert!(err.to_string().contains("protocol version"));
+
+        let mut peer = Hello::new("client");
+        peer.compressions.clear();
+        let err = local.check(&peer).unwrap_err();
+        assert!(err.to_string().contains("compression gzip"));
+
+        // 只支持 gzip 的对方不会收到 zstd 压缩的 frame
+        let mut peer = Hello::new("client");
+        peer.compressions = vec!["gzip".into()];
+        assert!(local.check(&peer).is_ok());
+        let compression = FrameCompression {
+            codec: CompressionCodec::Zstd,
+            ..Default::default()
+        };
+        assert_eq!(compression.negotiate(&peer).codec, CompressionCodec::None);
+        let peer = Hello::new("client");
+        assert_eq!(compression.negotiate(&peer), compression);
+
+        let mut peer = Hello::new("client");
+        peer.max_frame_size = 1024;
+        assert_eq!(local.max_frame_size(&peer), 1024);
+        peer.max_frame_size = 0;
+        assert_eq!(local.max_frame_size(&peer), MAX_FRAME as u32);
+    }
+}
@@ -1,59 +1,238 @@
 mod frame;
+mod handshake;
 mod multiplex;
+mod pipeline;
 mod stream;
 mod stream_result;
 mod tls;
 
-pub use frame::{read_frame, FrameCoder};
+pub use frame::{decode_header, read_frame, FrameCoder, LEN_LEN};
+pub(crate) use frame::{payload_len, MAX_FRAME};
+pub use handshake::PROTOCOL_VERSION;
 pub use multiplex::{AppStream, QuicCtrl, YamuxCtrl};
+pub use pipeline::PipelineClient;
 pub use stream::ProstStream;
 pub use stream_result::StreamResult;
 pub use tls::{TlsClientConnector, TlsServerAcceptor};
 
-use crate::{CommandRequest, CommandResponse, KvError, Service, Storage};
+use crate::{
+    command_request::RequestData, AsyncStorage, Chunk, CommandRequest, CommandResponse,
+    FrameCompression, GeneralConfig, Hello, KvError, Service, Value,
+};
+use bytes::Bytes;
 use futures::{SinkExt, StreamExt};
-use tokio::io::{AsyncRead, AsyncWrite};
+use handshake::{accept_hello, send_hello};
+use prost::Message;
+use std::sync::Arc;
+use stream::frame_limit;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::{mpsc, OwnedSemaphorePermit, Semaphore},
+};
 use tracing::{info, warn};
 
+/// 一个 stream 上等待发送的并发请求的 response 的个数
+const PIPELINE_CAPACITY: usize = 128;
+/// 一个 stream 上同时在执行的请求最多的个数，达到之后暂停读取新的请求
+const MAX_IN_FLIGHT: usize = 128;
+/// 握手时服务器使用的名字
+const SERVER_NAME: &str = "simple-kv server";
+/// 握手时客户端缺省使用的名字
+const CLIENT_NAME: &str = "simple-kv client";
+
 /// 处理服务器端的某个 accept 下来的 socket 的读写
 pub struct ProstServerStream<S, Store> {
     inner: ProstStream<S, CommandRequest, CommandResponse>,
     service: Service<Store>,
+    compression: FrameCompression,
+    max_frame_size: usize,
 }
 
-/// 处理客户端 socket 的读写
+/// 处理客户端 socket 的读写，第一次发送请求之前会先和服务器握手
 pub struct ProstClientStream<S> {
     inner: ProstStream<S, CommandResponse, CommandRequest>,
+    name: String,
+    compression: FrameCompression,
+    max_frame_size: usize,
+    // 握手之后服务器的 Hello
+    peer: Option<Hello>,
 }
 
 impl<S, Store> ProstServerStream<S, Store>
 where
     S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
-    Store: Storage,
+    Store: AsyncStorage,
 {
     pub fn new(stream: S, service: Service<Store>) -> Self {
         Self {
             inner: ProstStream::new(stream),
             service,
+            compression: FrameCompression::default(),
+            max_frame_size: 0,
         }
     }
 
-    pub async fn process(mut self) -> Result<(), KvError> {
-        let stream = &mut self.inner;
-        while let Some(Ok(cmd)) = stream.next().await {
-            info!("Got a new command: {:?}", cmd);
-            let mut res = self.service.execute(cmd);
-            while let Some(data) = res.next().await {
-                if let Err(e) = stream.send(&data).await {
-                    warn!("Failed to send response: {e:?}");
+    /// 按配置设置握手之后发送 response 时的压缩方式，以及能接收的最大 frame
+    pub fn with_config(mut self, config: &GeneralConfig) -> Self {
+        self.compression = config.compression.clone();
+        self.max_frame_size = config.max_frame_size;
+        self.inner.set_max_frame_size(config.max_frame_size);
+        self
+    }
+
+    /// 处理 stream 上的所有请求：第一个请求必须是 Hello，握手失败时关闭 stream；
+    /// 之后 id 为 0 的请求按顺序执行，带 id 的请求并发执行，
+    /// 它们的 response 带上请求的 id，执行完一个就发送一个；
+    /// 同时在执行的请求达到 MAX_IN_FLIGHT 之后，等有请求返回了第一个 response 再读取新的请求；
+    /// 读取请求失败时（比如 frame 太大）返回一个错误的 response，然后关闭 stream
+    pub async fn process(self) -> Result<(), KvError> {
+        let Self {
+            inner: mut stream,
+            service,
+            compression,
+            max_frame_size,
+        } = self;
+        let local = Hello::new(SERVER_NAME).with_max_frame_size(max_frame_size);
+        let peer = match accept_hello(&mut stream, local).await {
+            Ok(peer) => peer,
+            Err(e) => {
+                warn!("Failed to handshake: {e:?}");
+                return Err(e);
+            }
+        };
+        info!("Client {} connected", peer.name);
+        stream.set_compression(compression.negotiate(&peer));
+        stream.set_peer_max_frame_size(peer.max_frame_size as _);
+        let (tx, mut rx) = mpsc::channel(PIPELINE_CAPACITY);
+        // id 为 0 的请求交给同一个任务按顺序执行，response 和带 id 的请求一样通过 tx 发送，
+        // 这样订阅这种一直在返回 response 的请求不会挡住其它请求的 response；
+        // 每个请求都带着一个 permit，所以 ordered_tx 中的请求不会超过 MAX_IN_FLIGHT 个
+        let (ordered_tx, ordered_rx) = mpsc::channel(MAX_IN_FLIGHT);
+        let worker = tokio::spawn(execute_ordered(service.clone(), ordered_rx, tx.clone()));
+        let permits = Arc::new(Semaphore::new(MAX_IN_FLIGHT));
+        // 拿到 permit 之后才读取下一个请求
+        let mut permit = None;
+        // 客户端不再发送请求之后丢弃 tx，这样所有的请求执行完之后 rx 就结束了
+        let mut senders = Some((tx, ordered_tx));
+        loop {
+            tokio::select! {
+                p = permits.clone().acquire_owned(), if senders.is_some() && permit.is_none() => {
+                    // permits 不会被 close，这里不会失败
+                    permit = p.ok();
+                }
+                cmd = stream.next(), if senders.is_some() && permit.is_some() => match cmd {
+                    Some(Ok(mut cmd)) => {
+                        info!("Got a new command: {:?}", cmd);
+                        // 分块下载的每一块都要能被客户端接收
+                        if let Some(RequestData::HgetChunked(param)) = &mut cmd.request_data {
+                            param.clamp_chunk_size(frame_limit(peer.max_frame_size as _));
+                        }
+                        let (tx, ordered_tx) = senders.as_ref().unwrap();
+                        let permit = permit.take().unwrap();
+                        if cmd.id == 0 {
+                            // 最多只有 MAX_IN_FLIGHT 个 permit，ordered_tx 不会满，
+                            // worker 在 ordered_tx 被丢弃之前也不会退出，这里不会失败
+                            let _ = ordered_tx.try_send((cmd, permit));
+                        } else {
+                            let service = service.clone();
+                            tokio::spawn(execute_tagged(service, cmd, permit, tx.clone()));
+                        }
+                    }
+                    Some(Err(e)) => {
+                        // 读取失败之后不知道下一个 frame 从哪里开始，告诉客户端原因之后关闭连接
+                        warn!("Failed to read command: {e:?}");
+                        if let Err(e) = stream.send(&CommandResponse::from(e)).await {
+                            warn!("Failed to send response: {e:?}");
+                        }
+                        break;
+                    }
+                    None => senders = None,
+                },
+                Some(data) = rx.recv() => {
+                    if let Err(e) = send_response(&mut stream, &data).await {
+                        warn!("Failed to send response: {e:?}");
+                        break;
+                    }
                 }
+                else => break,
             }
         }
+        worker.abort();
         // info!("Client {:?} disconnected", self.addr);
         Ok(())
     }
 }
 
+/// 发送 response，超过客户端能接收的大小时改为发送错误，客户端可以改用分块下载
+async fn send_response<S>(
+    stream: &mut ProstStream<S, CommandRequest, CommandResponse>,
+    res: &CommandResponse,
+) -> Result<(), KvError>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    match stream.send(res).await {
+        Err(e @ KvError::FrameTooLarge(..)) => {
+            let mut err = CommandResponse::from(e);
+            err.id = res.id;
+            stream.send(&err).await
+        }
+        result => result,
+    }
+}
+
+/// 按顺序执行 id 为 0 的请求，把 response 交给 tx 发送；
+/// 发出第一个 response 之后释放 permit 并执行下一个请求，订阅之后的消息在后台转发
+async fn execute_ordered<Store: AsyncStorage>(
+    service: Service<Store>,
+    mut cmds: mpsc::Receiver<(CommandRequest, OwnedSemaphorePermit)>,
+    tx: mpsc::Sender<CommandResponse>,
+) {
+    while let Some((cmd, permit)) = cmds.recv().await {
+        let mut res = service.execute(cmd).await;
+        let data = match res.next().await {
+            Some(data) => data,
+            None => continue,
+        };
+        let sent = tx.send(data.as_ref().clone()).await;
+        drop(permit);
+        // 连接已经断开
+        if sent.is_err() {
+            break;
+        }
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            while let Some(data) = res.next().await {
+                if tx.send(data.as_ref().clone()).await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+}
+
+/// 执行带 id 的请求，把带上 id 的 response 交给 tx 发送；
+/// 和 id 为 0 的请求一样，发出第一个 response 之后释放 permit
+async fn execute_tagged<Store: AsyncStorage>(
+    service: Service<Store>,
+    cmd: CommandRequest,
+    permit: OwnedSemaphorePermit,
+    tx: mpsc::Sender<CommandResponse>,
+) {
+    let id = cmd.id;
+    let mut res = service.execute(cmd).await;
+    let mut permit = Some(permit);
+    while let Some(data) = res.next().await {
+        let mut data = data.as_ref().clone();
+        data.id = id;
+        // 连接已经断开
+        if tx.send(data).await.is_err() {
+            break;
+        }
+        permit.take();
+    }
+}
+
 impl<S> ProstClientStream<S>
 where
     S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
@@ -61,13 +240,45 @@ where
     pub fn new(stream: S) -> Self {
         Self {
             inner: ProstStream::new(stream),
+            name: CLIENT_NAME.into(),
+            compression: FrameCompression::default(),
+            max_frame_size: 0,
+            peer: None,
+        }
+    }
+
+    /// 按配置设置握手之后发送请求时的压缩方式，以及能接收的最大 frame
+    pub fn with_config(mut self, config: &GeneralConfig) -> Self {
+        self.compression = config.compression.clone();
+        self.max_frame_size = config.max_frame_size;
+        self.inner.set_max_frame_size(config.max_frame_size);
+        self
+    }
+
+    /// 设置握手时使用的名字
+    pub fn with_name(mut self, name: impl Into<String>) -> Self {
+        self.name = name.into();
+        self
+    }
+
+    /// 和服务器握手，返回服务器的 Hello；已经握手过时直接返回
+    pub async fn handshake(&mut self) -> Result<&Hello, KvError> {
+        if self.peer.is_none() {
+            let local = Hello::new(self.name.clone()).with_max_frame_size(self.max_frame_size);
+            let peer = send_hello(&mut self.inner, local).await?;
+            self.inner
+                .set_compression(self.compression.negotiate(&peer));
+            self.inner.set_peer_max_frame_size(peer.max_frame_size as _);
+            self.peer = Some(peer);
         }
+        Ok(self.peer.as_ref().unwrap())
     }
 
     pub async fn execute_unary(
         &mut self,
         cmd: &CommandRequest,
     ) -> Result<CommandResponse, KvError> {
+        self.handshake().await?;
         let stream = &mut self.inner;
         stream.send(cmd).await?;
 
@@ -77,7 +288,85 @@ where
         }
     }
 
-    pub async fn execute_streaming(self, cmd: &CommandRequest) -> Result<StreamResult, KvError> {
+    /// 分块上传一个 value，每块不超过 chunk_size 字节，返回最后一块的 response，
+    /// 其中是 key 之前的 value；某一块失败时返回这一块的 response
+    pub async fn upload(
+        &mut self,
+        table: &str,
+        key: &str,
+        value: &Value,
+        chunk_size: usize,
+    ) -> Result<CommandResponse, KvError> {
+        let data = Bytes::from(value.encode_to_vec());
+        let total = data.len();
+        let chunk_size = chunk_size.max(1);
+        let mut offset = 0;
+        loop {
+            let end = total.min(offset + chunk_size);
+            let chunk = Chunk {
+                offset: offset as _,
+                total: total as _,
+                data: data.slice(offset..end),
+            };
+            let res = self
+                .execute_unary(&CommandRequest::new_hset_chunk(table, key, chunk))
+                .await?;
+            if res.status != 200 || end == total {
+                return Ok(res);
+            }
+            offset = end;
+        }
+    }
+
+    /// 分块下载一个 value，每块不超过 chunk_size 字节（0 表示使用服务器缺省的大小）。
+    /// 成功时返回的 response 和 HGET 一样，values 中是 value
+    pub async fn download(
+        &mut self,
+        table: &str,
+        key: &str,
+        chunk_size: u32,
+    ) -> Result<CommandResponse, KvError> {
+        self.handshake().await?;
+        let stream = &mut self.inner;
+        stream
+            .send(&CommandRequest::new_hget_chunked(table, key, chunk_size))
+            .await?;
+
+        let mut data = Vec::new();
+        loop {
+            let res = match stream.next().await {
+                Some(res) => res?,
+                None => return Err(KvError::Internal("Didn't get any response".into())),
+            };
+            let chunk = match res.chunk {
+                Some(chunk) if res.status == 200 => chunk,
+                _ => return Ok(res),
+            };
+            if chunk.offset != data.len() as u64 {
+                return Err(KvError::Internal(format!(
+                    "Expected chunk at offset {}, got {}",
+                    data.len(),
+                    chunk.offset
+                )));
+            }
+            data.extend_from_slice(&chunk.data);
+            if data.len() as u64 >= chunk.total {
+                return Ok(Value::try_from(&data[..])?.into());
+            }
+        }
+    }
+
+    /// 握手之后转换成可以同时发送多个请求的 PipelineClient
+    pub async fn pipeline(mut self) -> Result<PipelineClient, KvError> {
+        self.handshake().await?;
+        Ok(PipelineClient::new(self.inner))
+    }
+
+    pub async fn execute_streaming(
+        mut self,
+        cmd: &CommandRequest,
+    ) -> Result<StreamResult, KvError> {
+        self.handshake().await?;
         let mut stream = self.inner;
 
         stream.send(cmd).await?;
@@ -144,7 +433,7 @@ mod tests {
     use std::net::SocketAddr;
 
     use super::*;
-    use crate::{assert_res_ok, MemTable, ServiceInner, Value};
+    use crate::{assert_res_ok, BlockingStorage, MemTable, ServiceInner, Value};
     use anyhow::Result;
     use bytes::Bytes;
     use tokio::net::{TcpListener, TcpStream};
@@ -201,15 +490,171 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn server_should_reject_clients_without_hello() -> anyhow::Result<()> {
+        let addr = start_server().await?;
+
+        let stream = TcpStream::connect(addr).await?;
+        let mut stream = ProstStream::<_, CommandResponse, CommandRequest>::new(stream);
+        stream.send(&CommandRequest::new_hget("t1", "k1")).await?;
+        let res = stream.next().await.unwrap()?;
+        assert_eq!(res.status, 426);
+        assert_eq!(res.message, "The first request must be Hello");
+        // 握手失败之后服务器关闭 stream
+        assert!(!matches!(stream.next().await, Some(Ok(_))));
+
+        // 协议版本不兼容的客户端会被拒绝
+        let stream = TcpStream::connect(addr).await?;
+        let mut stream = ProstStream::<_, CommandResponse, CommandRequest>::new(stream);
+        let mut hello = Hello::new("old client");
+        hello.protocol_version = 0;
+        stream.send(&CommandRequest::new_hello(hello)).await?;
+        let res = stream.next().await.unwrap()?;
+        assert_eq!(res.status, 426);
+        assert_eq!(res.hello.unwrap().protocol_version, PROTOCOL_VERSION);
+
+        let stream = TcpStream::connect(addr).await?;
+        let mut client = ProstClientStream::new(stream).with_name("test");
+        assert_eq!(client.handshake().await?.name, SERVER_NAME);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn large_values_should_be_transferred_in_chunks() -> Result<()> {
+        let addr = start_server_with_max_frame_size(64 * 1024).await?;
+        let stream = TcpStream::connect(addr).await?;
+        let mut client = ProstClientStream::new(stream);
+        assert_eq!(client.handshake().await?.max_frame_size, 64 * 1024);
+
+        // 不容易压缩的 1M 数据，超过了服务器的限制
+        let data: Bytes = (0..1024 * 1024).map(|_| rand::random::<u8>()).collect();
+        let value: Value = data.into();
+        let cmd = CommandRequest::new_hset("t1", "k1", value.clone());
+        let values = std::slice::from_ref(&value);
+        let err = client.execute_unary(&cmd).await.unwrap_err();
+        assert!(matches!(err, KvError::FrameTooLarge(_, 65536)));
+
+        let res = client.upload("t1", "k1", &value, 60 * 1024).await?;
+        assert_res_ok(&res, &[Value::default()], &[]);
+
+        // 客户端可以接收大的 frame，但最好还是分块下载
+        let res = client
+            .execute_unary(&CommandRequest::new_hget("t1", "k1"))
+            .await?;
+        assert_res_ok(&res, values, &[]);
+        let res = client.download("t1", "k1", 16 * 1024).await?;
+        assert_res_ok(&res, values, &[]);
+        let res = client.download("t1", "k2", 0).await?;
+        assert_eq!(res.status, 404);
+
+        // 客户端接收不了的 response 返回 413
+        let stream = TcpStream::connect(addr).await?;
+        let config = GeneralConfig {
+            addr: addr.to_string(),
+            network: Default::default(),
+            compression: Default::default(),
+            max_frame_size: 64 * 1024,
+        };
+        let mut client = ProstClientStream::new(stream).with_config(&config);
+        let res = client
+            .execute_unary(&CommandRequest::new_hget("t1", "k1"))
+            .await?;
+        assert_eq!(res.status, 413);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn streaming_commands_should_not_block_tagged_commands() -> Result<()> {
+        let addr = start_server().await?;
+        let stream = TcpStream::connect(addr).await?;
+        let mut stream = ProstStream::<_, CommandResponse, CommandRequest>::new(stream);
+        send_hello(&mut stream, Hello::new("test")).await?;
+
+        // 订阅会一直返回 response，不能挡住之后的请求
+        stream.send(&CommandRequest::new_subscribe("lobby")).await?;
+        let mut cmd = CommandRequest::new_hget("t1", "k1");
+        cmd.id = 7;
+        stream.send(&cmd).await?;
+
+        // 两个请求并发执行，response 的顺序不确定
+        let wait = std::time::Duration::from_secs(1);
+        let mut responses = Vec::new();
+        for _ in 0..2 {
+            responses.push(tokio::time::timeout(wait, stream.next()).await?.unwrap()?);
+        }
+        responses.sort_by_key(|res| res.id);
+        assert_eq!(responses[0].status, 200);
+        assert_eq!(responses[1].id, 7);
+        assert_eq!(responses[1].status, 404);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn requests_over_in_flight_limit_should_wait() -> Result<()> {
+        let addr = start_server().await?;
+        let stream = TcpStream::connect(addr).await?;
+        let mut stream = ProstStream::<_, CommandResponse, CommandRequest>::new(stream);
+        send_hello(&mut stream, Hello::new("test")).await?;
+
+        // 超过 MAX_IN_FLIGHT 的请求要等前面的请求返回之后才会被读取，但不会丢失
+        let count = MAX_IN_FLIGHT * 3;
+        for i in 0..count {
+            let mut cmd = CommandRequest::new_hincrby("t1", "k1", 1);
+            cmd.id = (i % 2) as u64;
+            stream.send(&cmd).await?;
+        }
+        let wait = std::time::Duration::from_secs(5);
+        for _ in 0..count {
+            let res = tokio::time::timeout(wait, stream.next()).await?.unwrap()?;
+            assert_eq!(res.status, 200);
+        }
+        let res = tokio::time::timeout(wait, async {
+            stream.send(&CommandRequest::new_hget("t1", "k1")).await?;
+            stream.next().await.unwrap()
+        })
+        .await??;
+        assert_res_ok(&res, &[(count as i64).into()], &[]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn server_should_report_read_errors_before_closing() -> Result<()> {
+        let addr = start_server_with_max_frame_size(1024).await?;
+        let stream = TcpStream::connect(addr).await?;
+        let mut stream = ProstStream::<_, CommandResponse, CommandRequest>::new(stream);
+        send_hello(&mut stream, Hello::new("test")).await?;
+
+        // 不容易压缩的 4K 数据，超过了服务器的限制
+        let data: Bytes = (0..4096).map(|_| rand::random::<u8>()).collect();
+        let cmd = CommandRequest::new_hset("t1", "k1", data.into());
+        stream.send(&cmd).await?;
+        let res = stream.next().await.unwrap()?;
+        assert_eq!(res.status, 413);
+        // 之后服务器关闭 stream
+        assert!(!matches!(stream.next().await, Some(Ok(_))));
+        Ok(())
+    }
+
     async fn start_server() -> Result<SocketAddr> {
+        start_server_with_max_frame_size(0).await
+    }
+
+    async fn start_server_with_max_frame_size(max_frame_size: usize) -> Result<SocketAddr> {
         let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
         let addr = listener.local_addr().unwrap();
+        let config = GeneralConfig {
+            addr: addr.to_string(),
+            network: Default::default(),
+            compression: Default::default(),
+            max_frame_size,
+        };
+        // 同一个服务器的所有连接共享存储
+        let service: Service = ServiceInner::new(BlockingStorage::new(MemTable::new())).into();
 
         tokio::spawn(async move {
             loop {
                 let (stream, _) = listener.accept().await.unwrap();
-                let service: Service = ServiceInner::new(MemTable::new()).into();
-                let server = ProstServerStream::new(stream, service);
+                let server = ProstServerStream::new(stream, service.clone()).with_config(&config);
                 tokio::spawn(server.process());
             }
         });
@@ -93,8 +93,8 @@ mod tests {
         assert_res_ok,
         network::tls::tls_utils::{tls_acceptor, tls_connector},
         utils::DummyStream,
-        CommandRequest, KvError, MemTable, ProstServerStream, Service, ServiceInner, Storage,
-        TlsServerAcceptor,
+        BlockingStorage, CommandRequest, KvError, MemTable, ProstServerStream, Service,
+        ServiceInner, Storage, TlsServerAcceptor,
     };
     use anyhow::Result;
     use tokio::net::{TcpListener, TcpStream};
@@ -109,11 +109,11 @@ mod tests {
     ) -> Result<SocketAddr, KvError>
     where
         Store: Storage,
-        Service: From<ServiceInner<Store>>,
+        Service: From<ServiceInner<BlockingStorage<Store>>>,
     {
         let listener = TcpListener::bind(addr).await.unwrap();
         let addr = listener.local_addr().unwrap();
-        let service: Service = ServiceInner::new(store).into();
+        let service: Service = ServiceInner::new(BlockingStorage::new(store)).into();
 
         tokio::spawn(async move {
             loop {
@@ -138,7 +138,7 @@ mod tests {
     ) -> Result<SocketAddr, KvError>
     where
         Store: Storage,
-        Service: From<ServiceInner<Store>>,
+        Service: From<ServiceInner<BlockingStorage<Store>>>,
     {
         let f = |stream, service: Service| {
             YamuxCtrl::new_server(stream, None, move |s| {
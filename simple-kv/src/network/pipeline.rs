@@ -0,0 +1,127 @@
+use futures::{SinkExt, StreamExt};
+use std::collections::HashMap;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::{mpsc, oneshot},
+};
+use tracing::warn;
+
+use crate::{CommandRequest, CommandResponse, KvError, ProstStream};
+
+type Reply = oneshot::Sender<Result<CommandResponse, KvError>>;
+
+/// 在一个 stream 上同时发送多个请求的客户端
+///
+/// 每个请求带上一个 id，服务器并发执行它们，后台任务按 id 把 response 交给对应的请求。
+/// 并发的请求之间没有顺序保证，需要顺序执行的命令要等前一个返回之后再发送。
+/// 只用于一个请求对应一个 response 的命令，SUBSCRIBE 这样的命令请用 execute_streaming
+#[derive(Clone)]
+pub struct PipelineClient {
+    requests: mpsc::Sender<(CommandRequest, Reply)>,
+}
+
+impl PipelineClient {
+    pub fn new<S>(stream: ProstStream<S, CommandResponse, CommandRequest>) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel(super::PIPELINE_CAPACITY);
+        tokio::spawn(run(stream, rx));
+        Self { requests: tx }
+    }
+
+    /// 发送请求并等待它的 response，可以在多个任务中同时调用
+    pub async fn execute(&self, cmd: CommandRequest) -> Result<CommandResponse, KvError> {
+        let (tx, rx) = oneshot::channel();
+        self.requests
+            .send((cmd, tx))
+            .await
+            .map_err(|_| KvError::Internal("Connection is closed".into()))?;
+        rx.await
+            .map_err(|_| KvError::Internal("Didn't get any response".into()))?
+    }
+}
+
+/// 后台任务：给请求分配 id 并发送，收到 response 之后按 id 交给对应的请求
+async fn run<S>(
+    mut stream: ProstStream<S, CommandResponse, CommandRequest>,
+    mut requests: mpsc::Receiver<(CommandRequest, Reply)>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let mut pending: HashMap<u64, Reply> = HashMap::new();
+    let mut next_id = 1;
+    // 所有的 PipelineClient 都被丢弃之后，等已经发出的请求都返回了再结束
+    let mut open = true;
+    while open || !pending.is_empty() {
+        tokio::select! {
+            req = requests.recv(), if open => match req {
+                Some((mut cmd, reply)) => {
+                    cmd.id = next_id;
+                    next_id += 1;
+                    match stream.send(&cmd).await {
+                        Ok(()) => {
+                            pending.insert(cmd.id, reply);
+                        }
+                        Err(e) => {
+                            let _ = reply.send(Err(e));
+                        }
+                    }
+                }
+                None => open = false,
+            },
+            res = stream.next() => match res {
+                Some(Ok(res)) => match pending.remove(&res.id) {
+                    Some(reply) => {
+                        let _ = reply.send(Ok(res));
+                    }
+                    None => warn!("Got a response for unknown request {}", res.id),
+                },
+                // 连接断开，还在等待的请求都会收到错误
+                _ => break,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        assert_res_ok, BlockingStorage, MemTable, ProstClientStream, ProstServerStream, Service,
+        ServiceInner,
+    };
+    use anyhow::Result;
+    use tokio::net::{TcpListener, TcpStream};
+
+    #[tokio::test]
+    async fn pipeline_client_should_match_responses_by_id() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let service: Service = ServiceInner::new(BlockingStorage::new(MemTable::new())).into();
+            ProstServerStream::new(stream, service).process().await
+        });
+
+        let stream = ProstClientStream::new(TcpStream::connect(addr).await?);
+        let client = stream.pipeline().await?;
+        let tasks: Vec<_> = (0..50)
+            .map(|i| {
+                let client = client.clone();
+                tokio::spawn(async move {
+                    let key = format!("k{}", i);
+                    let cmd = CommandRequest::new_hset("t1", &key, (i as i64).into());
+                    client.execute(cmd).await.unwrap();
+                    client.execute(CommandRequest::new_hget("t1", key)).await
+                })
+            })
+            .collect();
+        for (i, task) in tasks.into_iter().enumerate() {
+            let res = task.await??;
+            assert_ne!(res.id, 0);
+            assert_res_ok(&res, &[(i as i64).into()], &[]);
+        }
+        Ok(())
+    }
+}
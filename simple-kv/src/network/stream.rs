@@ -1,13 +1,17 @@
 use bytes::BytesMut;
-use futures::{ready, FutureExt, Sink, Stream};
+use futures::{ready, Sink, Stream};
 use std::{
+    convert::TryInto,
+    io::ErrorKind,
     marker::PhantomData,
     pin::Pin,
     task::{Context, Poll},
 };
 use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::io::poll_read_buf;
 
-use crate::{read_frame, FrameCoder, KvError};
+use super::frame::{payload_len, MAX_FRAME};
+use crate::{FrameCoder, FrameCompression, KvError, LEN_LEN};
 
 /// 处理 KV server prost frame 的 stream
 pub struct ProstStream<S, In, Out> {
@@ -19,6 +23,12 @@ pub struct ProstStream<S, In, Out> {
     written: usize,
     // 读缓存
     rbuf: BytesMut,
+    // 发送 frame 时的压缩方式，接收时按 frame 头部的压缩算法解压
+    compression: FrameCompression,
+    // 能接收的最大 frame
+    max_frame_size: usize,
+    // 对方能接收的最大 frame
+    peer_max_frame_size: usize,
 
     // 类型占位符
     _in: PhantomData<In>,
@@ -34,22 +44,41 @@ where
     /// 当调用 next() 时，得到 Result<In, KvError>
     type Item = Result<In, KvError>;
 
-    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        // 上一次调用结束后 rbuf 应该为空
-        assert!(self.rbuf.is_empty());
-
-        // 从 rbuf 中分离出 rest（摆脱对 self 的引用）
-        let mut rest = self.rbuf.split_off(0);
-
-        // 使用 read_frame 来获取数据
-        let fut = read_frame(&mut self.stream, &mut rest);
-        ready!(Box::pin(fut).poll_unpin(cx))?;
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
 
-        // 拿到一个 frame 的数据，把 buffer 合并回去
-        self.rbuf.unsplit(rest);
+        // 没有读完的 frame 留在 rbuf 中，下次调用时接着读
+        loop {
+            if this.rbuf.len() >= LEN_LEN {
+                let header = u32::from_be_bytes(this.rbuf[..LEN_LEN].try_into().unwrap());
+                let len = LEN_LEN + payload_len(header as usize, this.max_frame_size)?;
+                if this.rbuf.len() >= len {
+                    // 拿到一个 frame 的数据，调用 decode_frame_with 获取解包后的数据
+                    let mut frame = this.rbuf.split_to(len);
+                    return Poll::Ready(Some(In::decode_frame_with(
+                        &mut frame,
+                        this.max_frame_size,
+                    )));
+                }
+                // 检查过长度之后才分配整个 frame 的内存
+                this.rbuf.reserve(len - this.rbuf.len());
+            }
 
-        // 调用 decode_frame 获取解包后的数据
-        Poll::Ready(Some(In::decode_frame(&mut self.rbuf)))
+            if ready!(poll_read_buf(
+                Pin::new(&mut this.stream),
+                cx,
+                &mut this.rbuf
+            ))? == 0
+            {
+                // 在两个 frame 之间关闭的 stream 正常结束
+                if this.rbuf.is_empty() {
+                    return Poll::Ready(None);
+                }
+                return Poll::Ready(Some(Err(
+                    std::io::Error::from(ErrorKind::UnexpectedEof).into()
+                )));
+            }
+        }
     }
 }
 
@@ -69,9 +98,12 @@ where
 
     fn start_send(self: Pin<&mut Self>, item: &Out) -> Result<(), Self::Error> {
         let this = self.get_mut();
-        item.encode_frame(&mut this.wbuf)?;
-
-        Ok(())
+        // 对方接收不了的 frame 不发送；对方解压之后的大小也不能超过限制
+        let len = item.encoded_len();
+        if len > this.peer_max_frame_size {
+            return Err(KvError::FrameTooLarge(len, this.peer_max_frame_size));
+        }
+        item.encode_frame_with(&mut this.wbuf, &this.compression)
     }
 
     fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
@@ -117,17 +149,44 @@ where
             written: 0,
             wbuf: BytesMut::new(),
             rbuf: BytesMut::new(),
+            compression: FrameCompression::default(),
+            max_frame_size: MAX_FRAME,
+            peer_max_frame_size: MAX_FRAME,
             _in: PhantomData::default(),
             _out: PhantomData::default(),
         }
     }
+
+    /// 设置发送 frame 时的压缩方式
+    pub fn set_compression(&mut self, compression: FrameCompression) {
+        self.compression = compression;
+    }
+
+    /// 设置能接收的最大 frame，收到更大的 frame 时返回错误，0 表示没有限制
+    pub fn set_max_frame_size(&mut self, size: usize) {
+        self.max_frame_size = frame_limit(size);
+    }
+
+    /// 设置对方能接收的最大 frame，发送更大的 frame 时返回错误，0 表示没有限制
+    pub fn set_peer_max_frame_size(&mut self, size: usize) {
+        self.peer_max_frame_size = frame_limit(size);
+    }
+}
+
+/// 把配置的 frame 大小转换成实际的限制，0 表示没有限制
+pub(super) fn frame_limit(size: usize) -> usize {
+    match size {
+        0 => MAX_FRAME,
+        size => size.min(MAX_FRAME),
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{utils::DummyStream, CommandRequest};
+    use crate::{utils::DummyStream, CommandRequest, Value};
     use anyhow::Result;
+    use bytes::Bytes;
     use futures::prelude::*;
 
     #[tokio::test]
@@ -150,4 +209,65 @@ mod tests {
         }
         Ok(())
     }
+
+    #[tokio::test]
+    async fn prost_stream_should_read_frames_in_pieces() -> Result<()> {
+        let (client, server) = tokio::io::duplex(64);
+        let mut client = ProstStream::<_, CommandRequest, CommandRequest>::new(client);
+        let mut server = ProstStream::<_, CommandRequest, CommandRequest>::new(server);
+
+        // frame 比 duplex 的缓冲大得多，需要分很多次读完
+        let value: Value = Bytes::from(vec![1u8; 100_000]).into();
+        let cmds = vec![
+            CommandRequest::new_hset("t1", "k1", value),
+            CommandRequest::new_hget("t1", "k1"),
+        ];
+        let sent = cmds.clone();
+        tokio::spawn(async move {
+            for cmd in &sent {
+                client.send(cmd).await.unwrap();
+            }
+        });
+        for cmd in cmds {
+            assert_eq!(server.next().await.unwrap()?, cmd);
+        }
+        // 对方关闭之后 stream 结束
+        assert!(server.next().await.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn prost_stream_should_enforce_max_frame_size() -> Result<()> {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let mut client = ProstStream::<_, CommandRequest, CommandRequest>::new(client);
+        let mut server = ProstStream::<_, CommandRequest, CommandRequest>::new(server);
+        server.set_max_frame_size(1024);
+
+        let value: Value =
+            Bytes::from((0..2000).map(|_| rand::random::<u8>()).collect::<Vec<_>>()).into();
+        let cmd = CommandRequest::new_hset("t1", "k1", value);
+        client.send(&cmd).await?;
+        let err = server.next().await.unwrap().unwrap_err();
+        assert!(matches!(err, KvError::FrameTooLarge(_, 1024)));
+
+        // 压缩之后很小、解压之后超过限制的 frame 也会被拒绝
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let mut client = ProstStream::<_, CommandRequest, CommandRequest>::new(client);
+        let mut server = ProstStream::<_, CommandRequest, CommandRequest>::new(server);
+        server.set_max_frame_size(1024);
+        client.set_compression(FrameCompression::default());
+        let value: Value = Bytes::from(vec![0u8; 64 * 1024]).into();
+        client
+            .send(&CommandRequest::new_hset("t1", "k1", value))
+            .await?;
+        let err = server.next().await.unwrap().unwrap_err();
+        assert!(matches!(err, KvError::FrameTooLarge(1025, 1024)));
+
+        // 发送超过对方限制的 frame 会失败，之后的 frame 不受影响
+        client.set_peer_max_frame_size(1024);
+        let err = client.send(&cmd).await.unwrap_err();
+        assert!(matches!(err, KvError::FrameTooLarge(_, 1024)));
+        assert!(client.wbuf.is_empty());
+        Ok(())
+    }
 }
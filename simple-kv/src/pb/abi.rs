@@ -1,9 +1,14 @@
 /// 来自客户端的命令请求
 #[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
 pub struct CommandRequest {
+    /// 请求的 id，服务器在这个请求的所有 response 中带上相同的 id；
+    /// 为 0 时按收到的顺序依次执行，不为 0 时和其它请求并发执行，客户端按 id 匹配 response。
+    /// 用一个比较大的编号，把小的编号留给 request_data 中的命令
+    #[prost(uint64, tag = "100")]
+    pub id: u64,
     #[prost(
         oneof = "command_request::RequestData",
-        tags = "1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12"
+        tags = "1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32, 33, 34, 35, 36, 37, 38, 39, 40, 41, 42, 43"
     )]
     pub request_data: ::core::option::Option<command_request::RequestData>,
 }
@@ -35,6 +40,68 @@ pub mod command_request {
         Unsubscribe(super::Unsubscribe),
         #[prost(message, tag = "12")]
         Publish(super::Publish),
+        #[prost(message, tag = "13")]
+        Hexpire(super::Hexpire),
+        #[prost(message, tag = "14")]
+        Httl(super::Httl),
+        #[prost(message, tag = "15")]
+        Hscan(super::Hscan),
+        #[prost(message, tag = "16")]
+        Transaction(super::Transaction),
+        #[prost(message, tag = "17")]
+        Hcas(super::Hcas),
+        #[prost(message, tag = "18")]
+        Hsetnx(super::Hsetnx),
+        #[prost(message, tag = "19")]
+        Hsetxx(super::Hsetxx),
+        #[prost(message, tag = "20")]
+        Hincrby(super::Hincrby),
+        #[prost(message, tag = "21")]
+        Hincrbyfloat(super::Hincrbyfloat),
+        #[prost(message, tag = "22")]
+        ListTables(super::ListTables),
+        #[prost(message, tag = "23")]
+        TableInfo(super::TableInfo),
+        #[prost(message, tag = "24")]
+        DropTable(super::DropTable),
+        #[prost(message, tag = "25")]
+        RenameTable(super::RenameTable),
+        #[prost(message, tag = "26")]
+        Stats(super::Stats),
+        #[prost(message, tag = "27")]
+        Backup(super::Backup),
+        #[prost(message, tag = "28")]
+        Restore(super::Restore),
+        #[prost(message, tag = "29")]
+        Lpush(super::Lpush),
+        #[prost(message, tag = "30")]
+        Rpush(super::Rpush),
+        #[prost(message, tag = "31")]
+        Lrange(super::Lrange),
+        #[prost(message, tag = "32")]
+        Sadd(super::Sadd),
+        #[prost(message, tag = "33")]
+        Smembers(super::Smembers),
+        #[prost(message, tag = "34")]
+        Zadd(super::Zadd),
+        #[prost(message, tag = "35")]
+        Zrange(super::Zrange),
+        #[prost(message, tag = "36")]
+        Snapshot(super::Snapshot),
+        #[prost(message, tag = "37")]
+        ReleaseSnapshot(super::ReleaseSnapshot),
+        #[prost(message, tag = "38")]
+        Hfind(super::Hfind),
+        #[prost(message, tag = "39")]
+        TableQuota(super::TableQuota),
+        #[prost(message, tag = "40")]
+        Batch(super::Batch),
+        #[prost(message, tag = "41")]
+        Hello(super::Hello),
+        #[prost(message, tag = "42")]
+        HsetChunk(super::HsetChunk),
+        #[prost(message, tag = "43")]
+        HgetChunked(super::HgetChunked),
     }
 }
 /// 服务器的响应
@@ -52,20 +119,80 @@ pub struct CommandResponse {
     /// 成功返回的 kv pairs
     #[prost(message, repeated, tag = "4")]
     pub pairs: ::prost::alloc::vec::Vec<Kvpair>,
+    /// 扫描时用于获取下一批数据的 cursor，为空表示没有更多数据
+    #[prost(string, tag = "5")]
+    pub cursor: ::prost::alloc::string::String,
+    /// TRANSACTION 和 BATCH 中每个命令各自的 response，顺序和请求中的命令一致。
+    /// BATCH 的 status 总是 200，某个命令失败时看 responses 中对应的 status 和 message
+    #[prost(message, repeated, tag = "6")]
+    pub responses: ::prost::alloc::vec::Vec<CommandResponse>,
+    /// 修改了数据的命令产生的版本号，按这个版本读取可以看到命令做的修改；
+    /// 没有修改数据的命令为 0
+    #[prost(uint64, tag = "7")]
+    pub version: u64,
+    /// 对应的请求的 id
+    #[prost(uint64, tag = "8")]
+    pub id: u64,
+    /// 握手时服务器返回自己的 Hello
+    #[prost(message, optional, tag = "9")]
+    pub hello: ::core::option::Option<Hello>,
+    /// 分块下载时 value 的一块
+    #[prost(message, optional, tag = "10")]
+    pub chunk: ::core::option::Option<Chunk>,
+}
+/// 打开 stream 之后客户端发送的第一个请求，服务器在 response 的 hello 中返回自己的 Hello。
+/// 双方检查对方的协议版本和能力，不兼容时返回错误，之后的请求都不会被处理
+#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+pub struct Hello {
+    /// 协议版本，不兼容的修改会增加版本号
+    #[prost(uint32, tag = "1")]
+    pub protocol_version: u32,
+    /// 支持的 frame 压缩算法
+    #[prost(string, repeated, tag = "2")]
+    pub compressions: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+    /// 能接收的最大 frame（字节），0 表示没有限制
+    #[prost(uint32, tag = "3")]
+    pub max_frame_size: u32,
+    /// 客户端或者服务器的名字，用于日志
+    #[prost(string, tag = "4")]
+    pub name: ::prost::alloc::string::String,
 }
 /// 从 table 中获取一个 key，返回 value
+/// version 不为 0 时读取这个版本（或者快照）时的 value，为 0 时读取最新的 value
 #[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
 pub struct Hget {
     #[prost(string, tag = "1")]
     pub table: ::prost::alloc::string::String,
     #[prost(string, tag = "2")]
     pub key: ::prost::alloc::string::String,
+    #[prost(uint64, tag = "3")]
+    pub version: u64,
 }
-/// 从 table 中获取所有的 Kvpair
+/// 从 table 中获取所有的 Kvpair，version 的含义和 Hget 一样
 #[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
 pub struct Hgetall {
     #[prost(string, tag = "1")]
     pub table: ::prost::alloc::string::String,
+    #[prost(uint64, tag = "2")]
+    pub version: u64,
+}
+/// 按 key 的顺序扫描 table，返回 [start, end) 范围内以 prefix 开头的 kvpair
+/// end 为空表示扫描到 table 的末尾，limit 为 0 时使用缺省值
+/// 如果还有更多的数据，返回的 cursor 不为空，下次带上它继续扫描
+#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+pub struct Hscan {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub start: ::prost::alloc::string::String,
+    #[prost(string, tag = "3")]
+    pub end: ::prost::alloc::string::String,
+    #[prost(string, tag = "4")]
+    pub prefix: ::prost::alloc::string::String,
+    #[prost(uint32, tag = "5")]
+    pub limit: u32,
+    #[prost(string, tag = "6")]
+    pub cursor: ::prost::alloc::string::String,
 }
 /// 从 table 中获取一组 key，返回它们的 value
 #[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
@@ -74,11 +201,13 @@ pub struct Hmget {
     pub table: ::prost::alloc::string::String,
     #[prost(string, repeated, tag = "2")]
     pub keys: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+    #[prost(uint64, tag = "3")]
+    pub version: u64,
 }
 /// 返回的值
 #[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
 pub struct Value {
-    #[prost(oneof = "value::Value", tags = "1, 2, 3, 4, 5")]
+    #[prost(oneof = "value::Value", tags = "1, 2, 3, 4, 5, 6, 7, 8")]
     pub value: ::core::option::Option<value::Value>,
 }
 /// Nested message and enum types in `Value`.
@@ -95,8 +224,40 @@ pub mod value {
         Float(f64),
         #[prost(bool, tag = "5")]
         Bool(bool),
+        #[prost(message, tag = "6")]
+        List(super::ValueList),
+        #[prost(message, tag = "7")]
+        Set(super::ValueSet),
+        #[prost(message, tag = "8")]
+        SortedSet(super::SortedSet),
     }
 }
+/// 列表，元素按插入的顺序排列
+#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+pub struct ValueList {
+    #[prost(message, repeated, tag = "1")]
+    pub values: ::prost::alloc::vec::Vec<Value>,
+}
+/// 集合，成员不重复并且按顺序排列，这样同样的集合编码之后是一样的
+#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+pub struct ValueSet {
+    #[prost(string, repeated, tag = "1")]
+    pub members: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+}
+/// 有序集合中的一个成员
+#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+pub struct ScoredMember {
+    #[prost(string, tag = "1")]
+    pub member: ::prost::alloc::string::String,
+    #[prost(double, tag = "2")]
+    pub score: f64,
+}
+/// 有序集合，成员不重复，按 score 排序，score 相同时按成员排序
+#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+pub struct SortedSet {
+    #[prost(message, repeated, tag = "1")]
+    pub members: ::prost::alloc::vec::Vec<ScoredMember>,
+}
 /// 返回的 kvpair
 #[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
 pub struct Kvpair {
@@ -123,6 +284,55 @@ pub struct Hmset {
     #[prost(message, repeated, tag = "2")]
     pub pairs: ::prost::alloc::vec::Vec<Kvpair>,
 }
+/// 当 key 当前的值等于 expected 时才把它设置成 value，
+/// expected 为空表示 key 必须不存在；条件不满足时返回 412
+#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+pub struct Hcas {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(message, optional, tag = "3")]
+    pub expected: ::core::option::Option<Value>,
+    #[prost(message, optional, tag = "4")]
+    pub value: ::core::option::Option<Value>,
+}
+/// key 不存在时才设置 kvpair，条件不满足时返回 412
+#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+pub struct Hsetnx {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(message, optional, tag = "2")]
+    pub pair: ::core::option::Option<Kvpair>,
+}
+/// key 存在时才设置 kvpair，返回它之前的值；条件不满足时返回 412
+#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+pub struct Hsetxx {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(message, optional, tag = "2")]
+    pub pair: ::core::option::Option<Kvpair>,
+}
+/// 把 key 对应的整数加上 delta，返回新的值；key 不存在时当作 0
+#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+pub struct Hincrby {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(int64, tag = "3")]
+    pub delta: i64,
+}
+/// 把 key 对应的浮点数加上 delta，返回新的值；key 不存在时当作 0
+#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+pub struct Hincrbyfloat {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(double, tag = "3")]
+    pub delta: f64,
+}
 /// 从 table 中删除一个 key，返回它之前的值
 #[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
 pub struct Hdel {
@@ -155,6 +365,219 @@ pub struct Hmexist {
     #[prost(string, repeated, tag = "2")]
     pub keys: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
 }
+/// 给 table 中的 key 设置存活时间（秒），ttl 为 0 表示去掉过期时间
+/// 返回 key 是否存在
+#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+pub struct Hexpire {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(uint64, tag = "3")]
+    pub ttl: u64,
+}
+/// 查看 key 剩余的存活时间（秒），没有设置过期时间的 key 返回 -1
+#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+pub struct Httl {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub key: ::prost::alloc::string::String,
+}
+/// 在一个事务中按顺序执行一组命令，要么全部生效，要么都不生效
+/// 目前支持 HGET/HMGET/HSET/HMSET/HDEL/HMDEL/HEXIST/HMEXIST 以及列表、集合和有序集合的命令
+#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+pub struct Transaction {
+    #[prost(message, repeated, tag = "1")]
+    pub commands: ::prost::alloc::vec::Vec<CommandRequest>,
+}
+/// 按顺序执行一组命令，命令可以访问任意的 table，但不在一个事务中，某个命令失败不影响其它命令。
+/// 返回的 responses 中是每个命令各自的 response（包括各自的 status），
+/// 不支持 SUBSCRIBE/UNSUBSCRIBE/PUBLISH
+#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+pub struct Batch {
+    #[prost(message, repeated, tag = "1")]
+    pub commands: ::prost::alloc::vec::Vec<CommandRequest>,
+}
+/// 分块上传一个 value，超过 frame 大小限制的 value 可以这样写入。
+/// 所有块按 offset 从 0 开始依次发送，最后一块写入之后 value 才可见，返回 key 之前的 value；
+/// offset 为 0 时重新开始上传，之前没有完成的上传会被丢弃
+#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+pub struct HsetChunk {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(message, optional, tag = "3")]
+    pub chunk: ::core::option::Option<Chunk>,
+}
+/// 分块下载一个 value，服务器依次返回多个 response，每个 response 的 chunk 中带一块，
+/// 收到 offset + data 的长度等于 total 的块就结束了；key 不存在时返回 404
+#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+pub struct HgetChunked {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub key: ::prost::alloc::string::String,
+    /// 每块的大小（字节），0 表示使用服务器缺省的大小
+    #[prost(uint32, tag = "3")]
+    pub chunk_size: u32,
+}
+/// value（protobuf 编码之后）的一块
+#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+pub struct Chunk {
+    /// 这一块在整个 value 中的偏移
+    #[prost(uint64, tag = "1")]
+    pub offset: u64,
+    /// 整个 value 的大小
+    #[prost(uint64, tag = "2")]
+    pub total: u64,
+    #[prost(bytes = "bytes", tag = "3")]
+    pub data: ::prost::bytes::Bytes,
+}
+/// 列出所有的 table，返回 table 名
+#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+pub struct ListTables {}
+/// 查看 table 的信息，返回 key 的个数（keys）、大约占用的字节数（bytes），
+/// 以及开启压缩时写入的 value 压缩前后的字节数（uncompressed_bytes、compressed_bytes）
+#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+pub struct TableInfo {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+}
+/// 查看 table 的配额和用量，返回 key 的个数（keys）、占用的字节数（bytes），
+/// 以及配额 max_keys、max_bytes 和 max_value_size（0 表示没有限制）
+#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+pub struct TableQuota {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+}
+/// 删除 table 以及其中所有的 key，返回 table 之前是否存在
+#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+pub struct DropTable {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+}
+/// 把 table 重命名为 to，to 不能已经存在
+#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+pub struct RenameTable {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub to: ::prost::alloc::string::String,
+}
+/// 查看存储的统计信息，返回数据占用的字节数（used_bytes）、
+/// 内存上限（max_bytes，0 表示没有限制）、被淘汰的 key 的个数（evictions），
+/// 以及开启压缩时写入的 value 压缩前后的字节数（uncompressed_bytes、compressed_bytes）
+#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+pub struct Stats {}
+/// 把所有 table 备份到服务器上的 path 文件中，返回备份的 kv pair 的个数；
+/// path 是服务器配置的 backup_dir 下的相对路径，不能是绝对路径，也不能包含 ".."
+#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+pub struct Backup {
+    #[prost(string, tag = "1")]
+    pub path: ::prost::alloc::string::String,
+}
+/// 从服务器上的 path 文件中恢复数据，已有的 key 会被覆盖，返回恢复的 kv pair 的个数；
+/// path 的限制和 Backup 一样
+#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+pub struct Restore {
+    #[prost(string, tag = "1")]
+    pub path: ::prost::alloc::string::String,
+}
+/// 把一组 value 依次插入到列表的头部，返回列表的长度；key 不存在时当作空的列表
+#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+pub struct Lpush {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(message, repeated, tag = "3")]
+    pub values: ::prost::alloc::vec::Vec<Value>,
+}
+/// 把一组 value 依次追加到列表的尾部，返回列表的长度；key 不存在时当作空的列表
+#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+pub struct Rpush {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(message, repeated, tag = "3")]
+    pub values: ::prost::alloc::vec::Vec<Value>,
+}
+/// 返回列表中 [start, stop] 范围内的元素，负数表示从尾部开始计算（-1 是最后一个元素）
+#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+pub struct Lrange {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(int64, tag = "3")]
+    pub start: i64,
+    #[prost(int64, tag = "4")]
+    pub stop: i64,
+}
+/// 把一组成员加入集合，返回新加入的成员个数；key 不存在时当作空的集合
+#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+pub struct Sadd {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(string, repeated, tag = "3")]
+    pub members: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+}
+/// 按顺序返回集合中所有的成员
+#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+pub struct Smembers {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub key: ::prost::alloc::string::String,
+}
+/// 把一组成员加入有序集合，已经存在的成员会更新 score，返回新加入的成员个数
+/// score 不能是 NaN 或者 Infinity
+#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+pub struct Zadd {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(message, repeated, tag = "3")]
+    pub members: ::prost::alloc::vec::Vec<ScoredMember>,
+}
+/// 按 score 的顺序返回有序集合中 [start, stop] 范围内的成员，
+/// 结果放在 pairs 中，key 是成员，value 是 score；负数表示从尾部开始计算
+#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+pub struct Zrange {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(int64, tag = "3")]
+    pub start: i64,
+    #[prost(int64, tag = "4")]
+    pub stop: i64,
+}
+/// 创建一个快照，返回快照的版本号；释放之前可以一直按这个版本读取数据
+#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+pub struct Snapshot {}
+/// 释放快照，返回快照是否存在
+#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+pub struct ReleaseSnapshot {
+    #[prost(uint64, tag = "1")]
+    pub version: u64,
+}
+/// 通过 table 上名为 index 的二级索引，找到索引值等于 value 的所有 kv pair，按 key 的顺序返回
+#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+pub struct Hfind {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub index: ::prost::alloc::string::String,
+    #[prost(message, optional, tag = "3")]
+    pub value: ::core::option::Option<Value>,
+}
 /// subscribe 到某个主题，任何发布到这个主题的数据都会被收到
 /// 成功后，第一个返回的 CommandResponse，我们返回一个唯一的 subscription id
 #[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
@@ -178,3 +601,34 @@ pub struct Publish {
     #[prost(message, repeated, tag = "2")]
     pub data: ::prost::alloc::vec::Vec<Value>,
 }
+/// MemTable 预写日志（WAL）和快照中的一条记录，保存 key 修改之后的完整状态
+#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+pub struct WalEntry {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub key: ::prost::alloc::string::String,
+    /// value 为空表示 key 已经被删除
+    #[prost(message, optional, tag = "3")]
+    pub value: ::core::option::Option<Value>,
+    /// 过期的时间点（毫秒），0 表示没有过期时间
+    #[prost(uint64, tag = "4")]
+    pub deadline: u64,
+}
+/// 一次写入产生的所有记录，重放时要么全部生效，要么全部丢弃
+/// 重放时先删除 dropped_tables 中的 table，再应用 entries
+#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+pub struct WalRecord {
+    #[prost(message, repeated, tag = "1")]
+    pub entries: ::prost::alloc::vec::Vec<WalEntry>,
+    #[prost(string, repeated, tag = "2")]
+    pub dropped_tables: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+}
+/// 备份文件由一个个 frame 组成，每个 frame 是一个 table 中的一批 kv pair
+#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+pub struct BackupRecord {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(message, repeated, tag = "2")]
+    pub pairs: ::prost::alloc::vec::Vec<Kvpair>,
+}
@@ -11,28 +11,68 @@ use crate::KvError;
 
 impl CommandRequest {
     pub fn new_hget(table: impl Into<String>, key: impl Into<String>) -> Self {
+        Self::new_hget_at(table, key, 0)
+    }
+
+    /// 读取 key 在 version 时的 value，version 为 0 时读取最新的 value
+    pub fn new_hget_at(table: impl Into<String>, key: impl Into<String>, version: u64) -> Self {
         Self {
             request_data: Some(RequestData::Hget(Hget {
                 table: table.into(),
                 key: key.into(),
+                version,
             })),
+            ..Default::default()
         }
     }
 
     pub fn new_hgetall(table: impl Into<String>) -> Self {
+        Self::new_hgetall_at(table, 0)
+    }
+
+    pub fn new_hgetall_at(table: impl Into<String>, version: u64) -> Self {
         Self {
             request_data: Some(RequestData::Hgetall(Hgetall {
                 table: table.into(),
+                version,
             })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hscan(
+        table: impl Into<String>,
+        start: impl Into<String>,
+        end: impl Into<String>,
+        prefix: impl Into<String>,
+        limit: u32,
+        cursor: impl Into<String>,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Hscan(Hscan {
+                table: table.into(),
+                start: start.into(),
+                end: end.into(),
+                prefix: prefix.into(),
+                limit,
+                cursor: cursor.into(),
+            })),
+            ..Default::default()
         }
     }
 
     pub fn new_hmget(table: impl Into<String>, keys: Vec<String>) -> Self {
+        Self::new_hmget_at(table, keys, 0)
+    }
+
+    pub fn new_hmget_at(table: impl Into<String>, keys: Vec<String>, version: u64) -> Self {
         Self {
             request_data: Some(RequestData::Hmget(Hmget {
                 table: table.into(),
                 keys,
+                version,
             })),
+            ..Default::default()
         }
     }
 
@@ -42,6 +82,7 @@ impl CommandRequest {
                 table: table.into(),
                 pair: Some(Kvpair::new(key, value)),
             })),
+            ..Default::default()
         }
     }
 
@@ -51,6 +92,131 @@ impl CommandRequest {
                 table: table.into(),
                 pairs,
             })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hcas(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        expected: Option<Value>,
+        value: Value,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Hcas(Hcas {
+                table: table.into(),
+                key: key.into(),
+                expected,
+                value: Some(value),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hsetnx(table: impl Into<String>, key: impl Into<String>, value: Value) -> Self {
+        Self {
+            request_data: Some(RequestData::Hsetnx(Hsetnx {
+                table: table.into(),
+                pair: Some(Kvpair::new(key, value)),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hsetxx(table: impl Into<String>, key: impl Into<String>, value: Value) -> Self {
+        Self {
+            request_data: Some(RequestData::Hsetxx(Hsetxx {
+                table: table.into(),
+                pair: Some(Kvpair::new(key, value)),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hincrby(table: impl Into<String>, key: impl Into<String>, delta: i64) -> Self {
+        Self {
+            request_data: Some(RequestData::Hincrby(Hincrby {
+                table: table.into(),
+                key: key.into(),
+                delta,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hincrbyfloat(table: impl Into<String>, key: impl Into<String>, delta: f64) -> Self {
+        Self {
+            request_data: Some(RequestData::Hincrbyfloat(Hincrbyfloat {
+                table: table.into(),
+                key: key.into(),
+                delta,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_list_tables() -> Self {
+        Self {
+            request_data: Some(RequestData::ListTables(ListTables {})),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_table_info(table: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::TableInfo(TableInfo {
+                table: table.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_table_quota(table: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::TableQuota(TableQuota {
+                table: table.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_drop_table(table: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::DropTable(DropTable {
+                table: table.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_rename_table(table: impl Into<String>, to: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::RenameTable(RenameTable {
+                table: table.into(),
+                to: to.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_stats() -> Self {
+        Self {
+            request_data: Some(RequestData::Stats(Stats {})),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_backup(path: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Backup(Backup { path: path.into() })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_restore(path: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Restore(Restore { path: path.into() })),
+            ..Default::default()
         }
     }
 
@@ -60,6 +226,7 @@ impl CommandRequest {
                 table: table.into(),
                 key: key.into(),
             })),
+            ..Default::default()
         }
     }
 
@@ -69,6 +236,7 @@ impl CommandRequest {
                 table: table.into(),
                 keys,
             })),
+            ..Default::default()
         }
     }
 
@@ -78,6 +246,7 @@ impl CommandRequest {
                 table: table.into(),
                 key: key.into(),
             })),
+            ..Default::default()
         }
     }
 
@@ -87,12 +256,203 @@ impl CommandRequest {
                 table: table.into(),
                 keys,
             })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hexpire(table: impl Into<String>, key: impl Into<String>, ttl: u64) -> Self {
+        Self {
+            request_data: Some(RequestData::Hexpire(Hexpire {
+                table: table.into(),
+                key: key.into(),
+                ttl,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_httl(table: impl Into<String>, key: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Httl(Httl {
+                table: table.into(),
+                key: key.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_lpush(table: impl Into<String>, key: impl Into<String>, values: Vec<Value>) -> Self {
+        Self {
+            request_data: Some(RequestData::Lpush(Lpush {
+                table: table.into(),
+                key: key.into(),
+                values,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_rpush(table: impl Into<String>, key: impl Into<String>, values: Vec<Value>) -> Self {
+        Self {
+            request_data: Some(RequestData::Rpush(Rpush {
+                table: table.into(),
+                key: key.into(),
+                values,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_lrange(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        start: i64,
+        stop: i64,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Lrange(Lrange {
+                table: table.into(),
+                key: key.into(),
+                start,
+                stop,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_sadd(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        members: Vec<String>,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Sadd(Sadd {
+                table: table.into(),
+                key: key.into(),
+                members,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_smembers(table: impl Into<String>, key: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Smembers(Smembers {
+                table: table.into(),
+                key: key.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_zadd(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        members: Vec<ScoredMember>,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Zadd(Zadd {
+                table: table.into(),
+                key: key.into(),
+                members,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_zrange(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        start: i64,
+        stop: i64,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Zrange(Zrange {
+                table: table.into(),
+                key: key.into(),
+                start,
+                stop,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hfind(table: impl Into<String>, index: impl Into<String>, value: Value) -> Self {
+        Self {
+            request_data: Some(RequestData::Hfind(Hfind {
+                table: table.into(),
+                index: index.into(),
+                value: Some(value),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_snapshot() -> Self {
+        Self {
+            request_data: Some(RequestData::Snapshot(Snapshot {})),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_release_snapshot(version: u64) -> Self {
+        Self {
+            request_data: Some(RequestData::ReleaseSnapshot(ReleaseSnapshot { version })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_transaction(commands: Vec<CommandRequest>) -> Self {
+        Self {
+            request_data: Some(RequestData::Transaction(Transaction { commands })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_batch(commands: Vec<CommandRequest>) -> Self {
+        Self {
+            request_data: Some(RequestData::Batch(Batch { commands })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hello(hello: Hello) -> Self {
+        Self {
+            request_data: Some(RequestData::Hello(hello)),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hset_chunk(table: impl Into<String>, key: impl Into<String>, chunk: Chunk) -> Self {
+        Self {
+            request_data: Some(RequestData::HsetChunk(HsetChunk {
+                table: table.into(),
+                key: key.into(),
+                chunk: Some(chunk),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hget_chunked(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        chunk_size: u32,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::HgetChunked(HgetChunked {
+                table: table.into(),
+                key: key.into(),
+                chunk_size,
+            })),
+            ..Default::default()
         }
     }
 
     pub fn new_subscribe(name: impl Into<String>) -> Self {
         Self {
             request_data: Some(RequestData::Subscribe(Subscribe { topic: name.into() })),
+            ..Default::default()
         }
     }
 
@@ -102,6 +462,7 @@ impl CommandRequest {
                 topic: name.into(),
                 id,
             })),
+            ..Default::default()
         }
     }
 
@@ -111,6 +472,7 @@ impl CommandRequest {
                 topic: name.into(),
                 data,
             })),
+            ..Default::default()
         }
     }
 
@@ -159,6 +521,43 @@ impl Kvpair {
     }
 }
 
+impl ValueSet {
+    /// 加入一个成员，返回它之前是否不存在
+    pub fn insert(&mut self, member: String) -> bool {
+        match self.members.binary_search(&member) {
+            Ok(_) => false,
+            Err(i) => {
+                self.members.insert(i, member);
+                true
+            }
+        }
+    }
+}
+
+impl ScoredMember {
+    pub fn new(member: impl Into<String>, score: f64) -> Self {
+        Self {
+            member: member.into(),
+            score,
+        }
+    }
+}
+
+impl SortedSet {
+    /// 加入一个成员，已经存在的成员会更新 score，返回它之前是否不存在
+    pub fn insert(&mut self, member: ScoredMember) -> bool {
+        let old = self.members.iter().position(|m| m.member == member.member);
+        if let Some(i) = old {
+            self.members.remove(i);
+        }
+        let i = self
+            .members
+            .partition_point(|m| (m.score, &m.member) < (member.score, &member.member));
+        self.members.insert(i, member);
+        old.is_none()
+    }
+}
+
 /// 从 String 转换成 Value
 impl From<String> for Value {
     fn from(s: String) -> Self {
@@ -200,6 +599,30 @@ impl From<Bytes> for Value {
     }
 }
 
+impl From<ValueList> for Value {
+    fn from(list: ValueList) -> Self {
+        Self {
+            value: Some(value::Value::List(list)),
+        }
+    }
+}
+
+impl From<ValueSet> for Value {
+    fn from(set: ValueSet) -> Self {
+        Self {
+            value: Some(value::Value::Set(set)),
+        }
+    }
+}
+
+impl From<SortedSet> for Value {
+    fn from(set: SortedSet) -> Self {
+        Self {
+            value: Some(value::Value::SortedSet(set)),
+        }
+    }
+}
+
 /// 从 Value 转换成 CommandResponse
 impl From<Value> for CommandResponse {
     fn from(v: Value) -> Self {
@@ -228,13 +651,25 @@ impl From<KvError> for CommandResponse {
         let mut result = Self {
             status: StatusCode::INTERNAL_SERVER_ERROR.as_u16() as _,
             message: e.to_string(),
-            values: vec![],
-            pairs: vec![],
+            ..Default::default()
         };
 
         match e {
             KvError::NotFound(_) => result.status = StatusCode::NOT_FOUND.as_u16() as _,
             KvError::InvalidCommand(_) => result.status = StatusCode::BAD_REQUEST.as_u16() as _,
+            KvError::PreconditionFailed(_) => {
+                result.status = StatusCode::PRECONDITION_FAILED.as_u16() as _
+            }
+            KvError::OutOfMemory(_) => {
+                result.status = StatusCode::INSUFFICIENT_STORAGE.as_u16() as _
+            }
+            KvError::QuotaExceeded(_) | KvError::FrameTooLarge(..) => {
+                result.status = StatusCode::PAYLOAD_TOO_LARGE.as_u16() as _
+            }
+            KvError::VersionNotAvailable(_) => result.status = StatusCode::GONE.as_u16() as _,
+            KvError::HandshakeError(_) => {
+                result.status = StatusCode::UPGRADE_REQUIRED.as_u16() as _
+            }
             _ => {}
         }
 
@@ -242,6 +677,17 @@ impl From<KvError> for CommandResponse {
     }
 }
 
+/// 从一组 CommandResponse 转换成 CommandResponse
+impl From<Vec<CommandResponse>> for CommandResponse {
+    fn from(v: Vec<CommandResponse>) -> Self {
+        Self {
+            status: StatusCode::OK.as_u16() as _,
+            responses: v,
+            ..Default::default()
+        }
+    }
+}
+
 impl From<Vec<Value>> for CommandResponse {
     fn from(v: Vec<Value>) -> Self {
         Self {
@@ -301,6 +747,17 @@ impl TryFrom<Value> for f64 {
     }
 }
 
+impl TryFrom<&Value> for f64 {
+    type Error = KvError;
+
+    fn try_from(v: &Value) -> Result<Self, Self::Error> {
+        match v.value {
+            Some(value::Value::Float(f)) => Ok(f),
+            _ => Err(KvError::ConvertError(v.format(), "Float")),
+        }
+    }
+}
+
 impl TryFrom<Value> for Bytes {
     type Error = KvError;
 
@@ -323,6 +780,39 @@ impl TryFrom<Value> for bool {
     }
 }
 
+impl TryFrom<Value> for ValueList {
+    type Error = KvError;
+
+    fn try_from(v: Value) -> Result<Self, Self::Error> {
+        match v.value {
+            Some(value::Value::List(list)) => Ok(list),
+            _ => Err(KvError::ConvertError(v.format(), "List")),
+        }
+    }
+}
+
+impl TryFrom<Value> for ValueSet {
+    type Error = KvError;
+
+    fn try_from(v: Value) -> Result<Self, Self::Error> {
+        match v.value {
+            Some(value::Value::Set(set)) => Ok(set),
+            _ => Err(KvError::ConvertError(v.format(), "Set")),
+        }
+    }
+}
+
+impl TryFrom<Value> for SortedSet {
+    type Error = KvError;
+
+    fn try_from(v: Value) -> Result<Self, Self::Error> {
+        match v.value {
+            Some(value::Value::SortedSet(set)) => Ok(set),
+            _ => Err(KvError::ConvertError(v.format(), "SortedSet")),
+        }
+    }
+}
+
 impl TryFrom<Value> for Vec<u8> {
     type Error = KvError;
     fn try_from(v: Value) -> Result<Self, Self::Error> {
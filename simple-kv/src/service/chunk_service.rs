@@ -0,0 +1,189 @@
+use crate::*;
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::stream;
+use prost::Message;
+use std::sync::Arc;
+
+/// 分块下载时缺省的块大小
+const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+/// 分块下载的 response 中除了块的数据之外最多占用的字节数
+const CHUNK_RESPONSE_OVERHEAD: usize = 128;
+
+#[async_trait]
+impl CommandService for HsetChunk {
+    async fn execute(self, store: &impl AsyncStorage) -> CommandResponse {
+        match self.chunk {
+            Some(chunk) => match store.set_chunk(&self.table, &self.key, chunk).await {
+                Ok(Some(v)) => v.into(),
+                Ok(None) => Value::default().into(),
+                Err(e) => e.into(),
+            },
+            None => KvError::InvalidCommand(format!("{:?}", self)).into(),
+        }
+    }
+}
+
+impl HgetChunked {
+    /// 限制块的大小，让每个 response 都不超过对方能接收的 max_frame_size
+    pub fn clamp_chunk_size(&mut self, max_frame_size: usize) {
+        let size = match self.chunk_size {
+            0 => DEFAULT_CHUNK_SIZE,
+            size => size as usize,
+        };
+        let max = max_frame_size
+            .saturating_sub(CHUNK_RESPONSE_OVERHEAD)
+            .max(1);
+        self.chunk_size = size.min(max).min(u32::MAX as usize) as u32;
+    }
+
+    /// 读出 value，按 chunk_size 拆成多个 response 返回，key 不存在时只返回一个 404
+    pub async fn execute(self, store: &impl AsyncStorage) -> StreamingResponse {
+        let value = match store.get(&self.table, &self.key).await {
+            Ok(Some(v)) => v,
+            Ok(None) => {
+                let res = KvError::NotFound(format!("table {}, key {}", self.table, self.key));
+                return Box::pin(stream::once(async { Arc::new(res.into()) }));
+            }
+            Err(e) => return Box::pin(stream::once(async { Arc::new(e.into()) })),
+        };
+
+        let data = Bytes::from(value.encode_to_vec());
+        let chunk_size = match self.chunk_size {
+            0 => DEFAULT_CHUNK_SIZE,
+            size => size as usize,
+        };
+        // 空的 value 也要返回一块，客户端才知道结束了
+        let count = data.len().div_ceil(chunk_size).max(1);
+        let responses = (0..count).map(move |i| {
+            let offset = i * chunk_size;
+            let end = data.len().min(offset + chunk_size);
+            Arc::new(CommandResponse {
+                status: 200,
+                chunk: Some(Chunk {
+                    offset: offset as _,
+                    total: data.len() as _,
+                    data: data.slice(offset..end),
+                }),
+                ..Default::default()
+            })
+        });
+        Box::pin(stream::iter(responses))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+
+    #[tokio::test]
+    async fn chunked_upload_and_download_should_work() {
+        let store = BlockingStorage::new(MemTable::new());
+        let value: Value = "hello world ".repeat(1000).into();
+        let data = Bytes::from(value.encode_to_vec());
+        let total = data.len();
+
+        let mut offset = 0;
+        while offset < total {
+            let end = total.min(offset + 5000);
+            let chunk = Chunk {
+                offset: offset as _,
+                total: total as _,
+                data: data.slice(offset..end),
+            };
+            let res = dispatch(CommandRequest::new_hset_chunk("t1", "k1", chunk), &store).await;
+            assert_res_ok(&res, &[Value::default()], &[]);
+            offset = end;
+        }
+        let res = dispatch(CommandRequest::new_hget("t1", "k1"), &store).await;
+        assert_res_ok(&res, std::slice::from_ref(&value), &[]);
+
+        // 不按顺序的块返回错误
+        let chunk = Chunk {
+            offset: 5000,
+            total: total as _,
+            data: data.slice(5000..10000),
+        };
+        let res = dispatch(CommandRequest::new_hset_chunk("t1", "k1", chunk), &store).await;
+        assert_res_error(&res, 400, "Expected chunk at offset 0, got 5000");
+
+        let param = HgetChunked {
+            table: "t1".into(),
+            key: "k1".into(),
+            chunk_size: 4096,
+        };
+        let responses: Vec<_> = param.execute(&store).await.collect().await;
+        assert_eq!(responses.len(), total.div_ceil(4096));
+        let mut buf = Vec::new();
+        for res in responses {
+            let chunk = res.chunk.clone().unwrap();
+            assert_eq!(chunk.offset as usize, buf.len());
+            assert_eq!(chunk.total as usize, total);
+            buf.extend_from_slice(&chunk.data);
+        }
+        assert_eq!(Value::try_from(&buf[..]).unwrap(), value);
+
+        let param = HgetChunked {
+            table: "t1".into(),
+            key: "k2".into(),
+            chunk_size: 0,
+        };
+        let responses: Vec<_> = param.execute(&store).await.collect().await;
+        assert_eq!(responses.len(), 1);
+        assert_res_error(&responses[0], 404, "Not found: table t1, key k2");
+    }
+
+    #[tokio::test]
+    async fn chunked_upload_should_be_limited_and_hidden() {
+        let store = BlockingStorage::new(MemTable::new()).with_max_value_size(1024);
+        let chunk = |offset: u64, total: u64| Chunk {
+            offset,
+            total,
+            data: Bytes::from(vec![1u8; 100]),
+        };
+        let res = dispatch(
+            CommandRequest::new_hset_chunk("t1", "k1", chunk(0, 2048)),
+            &store,
+        )
+        .await;
+        assert_res_error(&res, 413, "Quota exceeded");
+
+        // 上传中暂存的块不出现在 ListTables 中
+        let res = dispatch(
+            CommandRequest::new_hset_chunk("t1", "k1", chunk(0, 1000)),
+            &store,
+        )
+        .await;
+        assert_res_ok(&res, &[Value::default()], &[]);
+        let res = dispatch(CommandRequest::new_list_tables(), &store).await;
+        assert_res_ok(&res, &[], &[]);
+    }
+
+    #[test]
+    fn chunk_size_should_fit_in_peer_frame() {
+        let mut param = HgetChunked {
+            table: "t1".into(),
+            key: "k1".into(),
+            chunk_size: 0,
+        };
+        param.clamp_chunk_size(1024 * 1024);
+        assert_eq!(param.chunk_size as usize, DEFAULT_CHUNK_SIZE);
+        param.clamp_chunk_size(4096);
+        assert_eq!(param.chunk_size as usize, 4096 - CHUNK_RESPONSE_OVERHEAD);
+
+        // 所有字段都取最大值时，response 也不会超过 frame 的限制
+        let res = CommandResponse {
+            status: u32::MAX,
+            id: u64::MAX,
+            version: u64::MAX,
+            chunk: Some(Chunk {
+                offset: u64::MAX,
+                total: u64::MAX,
+                data: Bytes::from(vec![0u8; param.chunk_size as usize]),
+            }),
+            ..Default::default()
+        };
+        assert!(res.encoded_len() <= 4096);
+    }
+}
@@ -0,0 +1,289 @@
+use crate::*;
+use async_trait::async_trait;
+use std::ops::Range;
+
+// 修改集合类型的命令需要先读出整个 value，修改之后再写回去，
+// 所以放在事务中执行，这样并发的修改不会互相覆盖
+
+#[async_trait]
+impl CommandService for Lpush {
+    async fn execute(self, store: &impl AsyncStorage) -> CommandResponse {
+        execute_in_txn(self, store).await
+    }
+}
+
+#[async_trait]
+impl CommandService for Rpush {
+    async fn execute(self, store: &impl AsyncStorage) -> CommandResponse {
+        execute_in_txn(self, store).await
+    }
+}
+
+#[async_trait]
+impl CommandService for Sadd {
+    async fn execute(self, store: &impl AsyncStorage) -> CommandResponse {
+        execute_in_txn(self, store).await
+    }
+}
+
+#[async_trait]
+impl CommandService for Zadd {
+    async fn execute(self, store: &impl AsyncStorage) -> CommandResponse {
+        execute_in_txn(self, store).await
+    }
+}
+
+#[async_trait]
+impl CommandService for Lrange {
+    async fn execute(self, store: &impl AsyncStorage) -> CommandResponse {
+        let result = store.get(&self.table, &self.key).await;
+        result
+            .and_then(|v| self.range(v))
+            .unwrap_or_else(Into::into)
+    }
+}
+
+#[async_trait]
+impl CommandService for Smembers {
+    async fn execute(self, store: &impl AsyncStorage) -> CommandResponse {
+        let result = store.get(&self.table, &self.key).await;
+        result.and_then(members).unwrap_or_else(Into::into)
+    }
+}
+
+#[async_trait]
+impl CommandService for Zrange {
+    async fn execute(self, store: &impl AsyncStorage) -> CommandResponse {
+        let result = store.get(&self.table, &self.key).await;
+        result
+            .and_then(|v| self.range(v))
+            .unwrap_or_else(Into::into)
+    }
+}
+
+impl TxnService for Lpush {
+    fn execute_txn(self, txn: &dyn TxnStorage) -> Result<CommandResponse, KvError> {
+        let mut list: ValueList = load(txn.get(&self.table, &self.key)?)?;
+        // 依次插入到头部，所以最后一个 value 在最前面
+        list.values.splice(0..0, self.values.into_iter().rev());
+        let len = list.values.len() as i64;
+        txn.set(&self.table, self.key, list.into())?;
+        Ok(Value::from(len).into())
+    }
+}
+
+impl TxnService for Rpush {
+    fn execute_txn(self, txn: &dyn TxnStorage) -> Result<CommandResponse, KvError> {
+        let mut list: ValueList = load(txn.get(&self.table, &self.key)?)?;
+        list.values.extend(self.values);
+        let len = list.values.len() as i64;
+        txn.set(&self.table, self.key, list.into())?;
+        Ok(Value::from(len).into())
+    }
+}
+
+impl TxnService for Lrange {
+    fn execute_txn(self, txn: &dyn TxnStorage) -> Result<CommandResponse, KvError> {
+        let value = txn.get(&self.table, &self.key)?;
+        self.range(value)
+    }
+}
+
+impl TxnService for Sadd {
+    fn execute_txn(self, txn: &dyn TxnStorage) -> Result<CommandResponse, KvError> {
+        let mut set: ValueSet = load(txn.get(&self.table, &self.key)?)?;
+        let added = self.members.into_iter().filter(|m| set.insert(m.clone()));
+        let added = added.count() as i64;
+        txn.set(&self.table, self.key, set.into())?;
+        Ok(Value::from(added).into())
+    }
+}
+
+impl TxnService for Smembers {
+    fn execute_txn(self, txn: &dyn TxnStorage) -> Result<CommandResponse, KvError> {
+        members(txn.get(&self.table, &self.key)?)
+    }
+}
+
+impl TxnService for Zadd {
+    fn execute_txn(self, txn: &dyn TxnStorage) -> Result<CommandResponse, KvError> {
+        if let Some(m) = self.members.iter().find(|m| !m.score.is_finite()) {
+            return Err(KvError::InvalidCommand(format!(
+                "Invalid score {} for member {}",
+                m.score, m.member
+            )));
+        }
+        let mut set: SortedSet = load(txn.get(&self.table, &self.key)?)?;
+        let added = self.members.into_iter().filter(|m| set.insert(m.clone()));
+        let added = added.count() as i64;
+        txn.set(&self.table, self.key, set.into())?;
+        Ok(Value::from(added).into())
+    }
+}
+
+impl TxnService for Zrange {
+    fn execute_txn(self, txn: &dyn TxnStorage) -> Result<CommandResponse, KvError> {
+        let value = txn.get(&self.table, &self.key)?;
+        self.range(value)
+    }
+}
+
+impl Lrange {
+    fn range(&self, value: Option<Value>) -> Result<CommandResponse, KvError> {
+        let mut list: ValueList = load(value)?;
+        let range = index_range(list.values.len(), self.start, self.stop);
+        Ok(list.values.drain(range).collect::<Vec<_>>().into())
+    }
+}
+
+impl Zrange {
+    fn range(&self, value: Option<Value>) -> Result<CommandResponse, KvError> {
+        let mut set: SortedSet = load(value)?;
+        let range = index_range(set.members.len(), self.start, self.stop);
+        let pairs: Vec<_> = set
+            .members
+            .drain(range)
+            .map(|m| Kvpair::new(m.member, m.score.into()))
+            .collect();
+        Ok(pairs.into())
+    }
+}
+
+fn members(value: Option<Value>) -> Result<CommandResponse, KvError> {
+    let set: ValueSet = load(value)?;
+    let values: Vec<Value> = set.members.into_iter().map(Value::from).collect();
+    Ok(values.into())
+}
+
+/// key 不存在时返回空的集合，key 的类型不对时返回 ConvertError
+fn load<T>(value: Option<Value>) -> Result<T, KvError>
+where
+    T: TryFrom<Value, Error = KvError> + Default,
+{
+    value
+        .map(T::try_from)
+        .transpose()
+        .map(Option::unwrap_or_default)
+}
+
+/// 把 [start, stop] 转换成下标的范围，负数表示从尾部开始计算，超出的部分会被忽略
+fn index_range(len: usize, start: i64, stop: i64) -> Range<usize> {
+    let len = len as i64;
+    let index = |i: i64| match i < 0 {
+        true => (len + i).max(0),
+        false => i,
+    };
+    let start = index(start).min(len);
+    let stop = index(stop).saturating_add(1).clamp(start, len);
+    start as usize..stop as usize
+}
+
+async fn execute_in_txn<T>(cmd: T, store: &impl AsyncStorage) -> CommandResponse
+where
+    T: TxnService + Clone + Send + 'static,
+{
+    let result = store.transaction(move |txn| cmd.clone().execute_txn(txn));
+    result.await.unwrap_or_else(Into::into)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn lpush_rpush_and_lrange_should_work() {
+        let store = BlockingStorage::new(MemTable::new());
+        let cmd = CommandRequest::new_lpush("t1", "l1", vec!["a".into(), "b".into()]);
+        let res = dispatch(cmd, &store).await;
+        assert_res_ok(&res, &[2.into()], &[]);
+        let cmd = CommandRequest::new_rpush("t1", "l1", vec![1.into(), 2.into()]);
+        let res = dispatch(cmd, &store).await;
+        assert_res_ok(&res, &[4.into()], &[]);
+
+        let res = dispatch(CommandRequest::new_lrange("t1", "l1", 0, -1), &store).await;
+        let values = &["b".into(), "a".into(), 1.into(), 2.into()];
+        assert_res_ok(&res, values, &[]);
+        let res = dispatch(CommandRequest::new_lrange("t1", "l1", 1, 2), &store).await;
+        assert_res_ok(&res, &["a".into(), 1.into()], &[]);
+        let res = dispatch(CommandRequest::new_lrange("t1", "l1", -2, i64::MAX), &store).await;
+        assert_res_ok(&res, &[1.into(), 2.into()], &[]);
+        let res = dispatch(CommandRequest::new_lrange("t1", "l1", 3, 1), &store).await;
+        assert_res_ok(&res, &[], &[]);
+
+        // 不存在的 key 当作空的列表
+        let res = dispatch(CommandRequest::new_lrange("t1", "l2", 0, -1), &store).await;
+        assert_res_ok(&res, &[], &[]);
+    }
+
+    #[tokio::test]
+    async fn sadd_and_smembers_should_work() {
+        let store = BlockingStorage::new(MemTable::new());
+        let members = vec!["b".into(), "a".into(), "b".into()];
+        let res = dispatch(CommandRequest::new_sadd("t1", "s1", members), &store).await;
+        assert_res_ok(&res, &[2.into()], &[]);
+        let members = vec!["c".into(), "a".into()];
+        let res = dispatch(CommandRequest::new_sadd("t1", "s1", members), &store).await;
+        assert_res_ok(&res, &[1.into()], &[]);
+
+        let res = dispatch(CommandRequest::new_smembers("t1", "s1"), &store).await;
+        assert_res_ok(&res, &["a".into(), "b".into(), "c".into()], &[]);
+    }
+
+    #[tokio::test]
+    async fn zadd_and_zrange_should_work() {
+        let store = BlockingStorage::new(MemTable::new());
+        let members = vec![
+            ScoredMember::new("u1", 3.0),
+            ScoredMember::new("u2", 1.0),
+            ScoredMember::new("u3", 2.0),
+        ];
+        let res = dispatch(CommandRequest::new_zadd("t1", "z1", members), &store).await;
+        assert_res_ok(&res, &[3.into()], &[]);
+
+        // 已经存在的成员会更新 score
+        let members = vec![ScoredMember::new("u1", 0.5)];
+        let res = dispatch(CommandRequest::new_zadd("t1", "z1", members), &store).await;
+        assert_res_ok(&res, &[0.into()], &[]);
+
+        let res = dispatch(CommandRequest::new_zrange("t1", "z1", 0, 1), &store).await;
+        assert_eq!(
+            res.pairs,
+            vec![Kvpair::new("u1", 0.5.into()), Kvpair::new("u2", 1.0.into())]
+        );
+
+        let members = vec![ScoredMember::new("u4", f64::NAN)];
+        let res = dispatch(CommandRequest::new_zadd("t1", "z1", members), &store).await;
+        assert_res_error(&res, 400, "Invalid score");
+    }
+
+    #[tokio::test]
+    async fn collection_commands_with_wrong_type_should_fail() {
+        let store = BlockingStorage::new(MemTable::new());
+        dispatch(CommandRequest::new_hset("t1", "k1", "v1".into()), &store).await;
+
+        let cmd = CommandRequest::new_lpush("t1", "k1", vec!["a".into()]);
+        let res = dispatch(cmd, &store).await;
+        assert_res_error(&res, 500, "Cannot convert value");
+        let res = dispatch(CommandRequest::new_smembers("t1", "k1"), &store).await;
+        assert_res_error(&res, 500, "Cannot convert value");
+
+        // 失败的命令不会修改 value
+        let res = dispatch(CommandRequest::new_hget("t1", "k1"), &store).await;
+        assert_res_ok(&res, &["v1".into()], &[]);
+    }
+
+    #[tokio::test]
+    async fn collection_commands_should_work_in_transaction() {
+        let store = BlockingStorage::new(SledDb::new(tempfile::tempdir().unwrap()).unwrap());
+        let cmd = CommandRequest::new_transaction(vec![
+            CommandRequest::new_rpush("t1", "l1", vec!["a".into()]),
+            CommandRequest::new_sadd("t1", "s1", vec!["a".into()]),
+            CommandRequest::new_lrange("t1", "l1", 0, -1),
+        ]);
+        let res = dispatch(cmd, &store).await;
+        assert_res_ok(&res.responses[2], &["a".into()], &[]);
+
+        let res = dispatch(CommandRequest::new_smembers("t1", "s1"), &store).await;
+        assert_res_ok(&res, &["a".into()], &[]);
+    }
+}
@@ -1,8 +1,18 @@
-use crate::*;
+use crate::{command_request::RequestData, *};
+use async_trait::async_trait;
+use std::time::Duration;
 
+/// Hscan 没有指定 limit 时，每次最多返回的 kv pair 个数
+const DEFAULT_SCAN_LIMIT: usize = 100;
+
+#[async_trait]
 impl CommandService for Hget {
-    fn execute(self, store: &impl Storage) -> CommandResponse {
-        match store.get(&self.table, &self.key) {
+    async fn execute(self, store: &impl AsyncStorage) -> CommandResponse {
+        let result = match self.version {
+            0 => store.get(&self.table, &self.key).await,
+            version => store.get_at(&self.table, &self.key, version).await,
+        };
+        match result {
             Ok(Some(v)) => v.into(),
             Ok(None) => KvError::NotFound(format!("table {}, key {}", self.table, self.key)).into(),
             Err(e) => e.into(),
@@ -10,32 +20,96 @@ impl CommandService for Hget {
     }
 }
 
+#[async_trait]
 impl CommandService for Hmget {
-    fn execute(self, store: &impl Storage) -> CommandResponse {
-        self.keys
-            .iter()
-            .map(|key| match store.get(&self.table, key) {
+    async fn execute(self, store: &impl AsyncStorage) -> CommandResponse {
+        let mut values = Vec::with_capacity(self.keys.len());
+        for key in self.keys.iter() {
+            let result = match self.version {
+                0 => store.get(&self.table, key).await,
+                version => store.get_at(&self.table, key, version).await,
+            };
+            values.push(match result {
                 Ok(Some(v)) => v,
+                // 版本不存在时每个 key 都读不到，直接返回错误
+                Err(e @ KvError::VersionNotAvailable(_)) => return e.into(),
                 _ => Value::default(),
-            })
-            .collect::<Vec<_>>()
-            .into()
+            });
+        }
+        values.into()
     }
 }
 
+#[async_trait]
 impl CommandService for Hgetall {
-    fn execute(self, store: &impl Storage) -> CommandResponse {
-        match store.get_all(&self.table) {
+    async fn execute(self, store: &impl AsyncStorage) -> CommandResponse {
+        let result = match self.version {
+            0 => store.get_all(&self.table).await,
+            version => store.get_all_at(&self.table, version).await,
+        };
+        match result {
+            Ok(v) => v.into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+#[async_trait]
+impl CommandService for Hfind {
+    async fn execute(self, store: &impl AsyncStorage) -> CommandResponse {
+        let value = match self.value {
+            Some(v) => v,
+            None => return KvError::InvalidCommand(format!("{:?}", self)).into(),
+        };
+        match store.find(&self.table, &self.index, value).await {
             Ok(v) => v.into(),
             Err(e) => e.into(),
         }
     }
 }
 
+#[async_trait]
+impl CommandService for Hscan {
+    async fn execute(self, store: &impl AsyncStorage) -> CommandResponse {
+        let limit = match self.limit {
+            0 => DEFAULT_SCAN_LIMIT,
+            n => n as usize,
+        };
+        // 以 prefix 开头的 key 是连续的，所以从 start、prefix 和 cursor 中最大的那个开始扫描
+        let start = [&self.start, &self.prefix, &self.cursor]
+            .into_iter()
+            .max()
+            .unwrap();
+        let end = (!self.end.is_empty()).then_some(self.end.as_str());
+
+        // 多取一个，用来判断是否还有更多的数据，并作为下一次扫描的 cursor
+        match store.get_range(&self.table, start, end, limit + 1).await {
+            Ok(pairs) => {
+                let mut pairs: Vec<_> = pairs
+                    .into_iter()
+                    .take_while(|pair| pair.key.starts_with(&self.prefix))
+                    .collect();
+                let cursor = match pairs.len() > limit {
+                    true => pairs.pop().map(|pair| pair.key).unwrap_or_default(),
+                    false => String::new(),
+                };
+                let mut res: CommandResponse = pairs.into();
+                res.cursor = cursor;
+                res
+            }
+            Err(e) => e.into(),
+        }
+    }
+}
+
+#[async_trait]
 impl CommandService for Hset {
-    fn execute(self, store: &impl Storage) -> CommandResponse {
+    async fn execute(self, store: &impl AsyncStorage) -> CommandResponse {
         match self.pair {
-            Some(v) => match store.set(&self.table, v.key, v.value.unwrap_or_default()) {
+            Some(v) => match store
+                .set(&self.table, v.key, v.value.unwrap_or_default())
+                .await
+            {
                 Ok(Some(v)) => v.into(),
                 Ok(None) => Value::default().into(),
                 Err(e) => e.into(),
@@ -45,27 +119,245 @@ impl CommandService for Hset {
     }
 }
 
+#[async_trait]
 impl CommandService for Hmset {
-    fn execute(self, store: &impl Storage) -> CommandResponse {
-        let pairs = self.pairs;
-        let table = self.table;
-        pairs
-            .into_iter()
-            .map(|pair| {
-                let result = store.set(&table, pair.key, pair.value.unwrap_or_default());
-                match result {
-                    Ok(Some(v)) => v,
-                    _ => Value::default(),
-                }
-            })
-            .collect::<Vec<_>>()
-            .into()
+    async fn execute(self, store: &impl AsyncStorage) -> CommandResponse {
+        let mut values = Vec::with_capacity(self.pairs.len());
+        for pair in self.pairs {
+            let result = store
+                .set(&self.table, pair.key, pair.value.unwrap_or_default())
+                .await;
+            // 遇到错误（比如超出配额）就停下来返回，前面的 key 已经写入了
+            match result {
+                Ok(v) => values.push(v.unwrap_or_default()),
+                Err(e) => return e.into(),
+            }
+        }
+        values.into()
+    }
+}
+
+#[async_trait]
+impl CommandService for Hcas {
+    async fn execute(self, store: &impl AsyncStorage) -> CommandResponse {
+        let value = match self.value {
+            Some(v) => v,
+            None => return KvError::InvalidCommand(format!("{:?}", self)).into(),
+        };
+        match store
+            .compare_and_swap(&self.table, self.key.clone(), self.expected, value)
+            .await
+        {
+            Ok(true) => Value::from(true).into(),
+            Ok(false) => KvError::PreconditionFailed(format!(
+                "table {}, key {} does not match the expected value",
+                self.table, self.key
+            ))
+            .into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+#[async_trait]
+impl CommandService for Hsetnx {
+    async fn execute(self, store: &impl AsyncStorage) -> CommandResponse {
+        let pair = match self.pair {
+            Some(v) => v,
+            None => return KvError::InvalidCommand(format!("{:?}", self)).into(),
+        };
+        let key = pair.key.clone();
+        match store
+            .compare_and_swap(&self.table, pair.key, None, pair.value.unwrap_or_default())
+            .await
+        {
+            Ok(true) => Value::from(true).into(),
+            Ok(false) => KvError::PreconditionFailed(format!(
+                "table {}, key {} already exists",
+                self.table, key
+            ))
+            .into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+#[async_trait]
+impl CommandService for Hsetxx {
+    async fn execute(self, store: &impl AsyncStorage) -> CommandResponse {
+        let pair = match self.pair {
+            Some(v) => v,
+            None => return KvError::InvalidCommand(format!("{:?}", self)).into(),
+        };
+        let key = pair.key.clone();
+        match store
+            .set_if_present(&self.table, pair.key, pair.value.unwrap_or_default())
+            .await
+        {
+            Ok(Some(v)) => v.into(),
+            Ok(None) => KvError::PreconditionFailed(format!(
+                "table {}, key {} does not exist",
+                self.table, key
+            ))
+            .into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+#[async_trait]
+impl CommandService for Hincrby {
+    async fn execute(self, store: &impl AsyncStorage) -> CommandResponse {
+        match store.incr(&self.table, &self.key, self.delta).await {
+            Ok(v) => Value::from(v).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+#[async_trait]
+impl CommandService for Hincrbyfloat {
+    async fn execute(self, store: &impl AsyncStorage) -> CommandResponse {
+        match store.incr_float(&self.table, &self.key, self.delta).await {
+            Ok(v) => Value::from(v).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+#[async_trait]
+impl CommandService for ListTables {
+    async fn execute(self, store: &impl AsyncStorage) -> CommandResponse {
+        match store.list_tables().await {
+            Ok(v) => v.into_iter().map(Value::from).collect::<Vec<_>>().into(),
+            Err(e) => e.into(),
+        }
     }
 }
 
+#[async_trait]
+impl CommandService for TableInfo {
+    async fn execute(self, store: &impl AsyncStorage) -> CommandResponse {
+        match store.table_info(&self.table).await {
+            Ok(Some(stats)) => vec![
+                Kvpair::new("keys", (stats.keys as i64).into()),
+                Kvpair::new("bytes", (stats.bytes as i64).into()),
+                Kvpair::new(
+                    "uncompressed_bytes",
+                    (stats.uncompressed_bytes as i64).into(),
+                ),
+                Kvpair::new("compressed_bytes", (stats.compressed_bytes as i64).into()),
+            ]
+            .into(),
+            Ok(None) => KvError::NotFound(format!("table {}", self.table)).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+#[async_trait]
+impl CommandService for TableQuota {
+    async fn execute(self, store: &impl AsyncStorage) -> CommandResponse {
+        match store.quota(&self.table).await {
+            Ok(Some(quota)) => vec![
+                Kvpair::new("keys", (quota.keys as i64).into()),
+                Kvpair::new("bytes", (quota.bytes as i64).into()),
+                Kvpair::new("max_keys", (quota.max_keys as i64).into()),
+                Kvpair::new("max_bytes", (quota.max_bytes as i64).into()),
+                Kvpair::new("max_value_size", (quota.max_value_size as i64).into()),
+            ]
+            .into(),
+            Ok(None) => KvError::NotFound(format!("quota of table {}", self.table)).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+#[async_trait]
+impl CommandService for Stats {
+    async fn execute(self, store: &impl AsyncStorage) -> CommandResponse {
+        match store.stats().await {
+            Ok(stats) => vec![
+                Kvpair::new("used_bytes", (stats.used_bytes as i64).into()),
+                Kvpair::new("max_bytes", (stats.max_bytes as i64).into()),
+                Kvpair::new("evictions", (stats.evictions as i64).into()),
+                Kvpair::new(
+                    "uncompressed_bytes",
+                    (stats.uncompressed_bytes as i64).into(),
+                ),
+                Kvpair::new("compressed_bytes", (stats.compressed_bytes as i64).into()),
+            ]
+            .into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+#[async_trait]
+impl CommandService for Snapshot {
+    async fn execute(self, store: &impl AsyncStorage) -> CommandResponse {
+        match store.snapshot().await {
+            Ok(version) => Value::from(version as i64).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+#[async_trait]
+impl CommandService for ReleaseSnapshot {
+    async fn execute(self, store: &impl AsyncStorage) -> CommandResponse {
+        match store.release_snapshot(self.version).await {
+            Ok(v) => Value::from(v).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+#[async_trait]
+impl CommandService for Backup {
+    async fn execute(self, store: &impl AsyncStorage) -> CommandResponse {
+        match store.backup(&self.path).await {
+            Ok(n) => Value::from(n as i64).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+#[async_trait]
+impl CommandService for Restore {
+    async fn execute(self, store: &impl AsyncStorage) -> CommandResponse {
+        match store.restore(&self.path).await {
+            Ok(n) => Value::from(n as i64).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+#[async_trait]
+impl CommandService for DropTable {
+    async fn execute(self, store: &impl AsyncStorage) -> CommandResponse {
+        match store.drop_table(&self.table).await {
+            Ok(v) => Value::from(v).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+#[async_trait]
+impl CommandService for RenameTable {
+    async fn execute(self, store: &impl AsyncStorage) -> CommandResponse {
+        match store.rename_table(&self.table, &self.to).await {
+            Ok(true) => Value::from(true).into(),
+            Ok(false) => KvError::NotFound(format!("table {}", self.table)).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+#[async_trait]
 impl CommandService for Hdel {
-    fn execute(self, store: &impl Storage) -> CommandResponse {
-        match store.del(&self.table, &self.key) {
+    async fn execute(self, store: &impl AsyncStorage) -> CommandResponse {
+        match store.del(&self.table, &self.key).await {
             Ok(Some(v)) => v.into(),
             Ok(None) => Value::default().into(),
             Err(e) => e.into(),
@@ -73,38 +365,103 @@ impl CommandService for Hdel {
     }
 }
 
+#[async_trait]
 impl CommandService for Hmdel {
-    fn execute(self, store: &impl Storage) -> CommandResponse {
-        self.keys
-            .iter()
-            .map(|key| match store.del(&self.table, key) {
+    async fn execute(self, store: &impl AsyncStorage) -> CommandResponse {
+        let mut values = Vec::with_capacity(self.keys.len());
+        for key in self.keys.iter() {
+            values.push(match store.del(&self.table, key).await {
                 Ok(Some(v)) => v,
                 _ => Value::default(),
-            })
-            .collect::<Vec<_>>()
-            .into()
+            });
+        }
+        values.into()
     }
 }
 
+#[async_trait]
 impl CommandService for Hexist {
-    fn execute(self, store: &impl Storage) -> CommandResponse {
-        match store.contains(&self.table, &self.key) {
+    async fn execute(self, store: &impl AsyncStorage) -> CommandResponse {
+        match store.contains(&self.table, &self.key).await {
             Ok(v) => Value::from(v).into(),
             Err(e) => e.into(),
         }
     }
 }
 
+#[async_trait]
 impl CommandService for Hmexist {
-    fn execute(self, store: &impl Storage) -> CommandResponse {
-        self.keys
-            .iter()
-            .map(|key| match store.contains(&self.table, key) {
+    async fn execute(self, store: &impl AsyncStorage) -> CommandResponse {
+        let mut values = Vec::with_capacity(self.keys.len());
+        for key in self.keys.iter() {
+            values.push(match store.contains(&self.table, key).await {
                 Ok(v) => v.into(),
                 _ => Value::default(),
-            })
-            .collect::<Vec<Value>>()
-            .into()
+            });
+        }
+        values.into()
+    }
+}
+
+#[async_trait]
+impl CommandService for Hexpire {
+    async fn execute(self, store: &impl AsyncStorage) -> CommandResponse {
+        let result = match self.ttl {
+            // ttl 为 0 表示去掉 key 的过期时间
+            0 => match store.contains(&self.table, &self.key).await {
+                Ok(true) => store.persist(&self.table, &self.key).await.map(|_| true),
+                other => other,
+            },
+            ttl => {
+                store
+                    .expire(&self.table, &self.key, Duration::from_secs(ttl))
+                    .await
+            }
+        };
+        match result {
+            Ok(v) => Value::from(v).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+#[async_trait]
+impl CommandService for Httl {
+    async fn execute(self, store: &impl AsyncStorage) -> CommandResponse {
+        match store.ttl(&self.table, &self.key).await {
+            Ok(Some(ttl)) => Value::from(ttl.as_secs() as i64).into(),
+            Ok(None) => match store.contains(&self.table, &self.key).await {
+                Ok(true) => Value::from(-1).into(),
+                Ok(false) => {
+                    KvError::NotFound(format!("table {}, key {}", self.table, self.key)).into()
+                }
+                Err(e) => e.into(),
+            },
+            Err(e) => e.into(),
+        }
+    }
+}
+
+#[async_trait]
+impl CommandService for Batch {
+    async fn execute(self, store: &impl AsyncStorage) -> CommandResponse {
+        let mut responses = Vec::with_capacity(self.commands.len());
+        for cmd in self.commands {
+            // 流式的命令需要 dispatch_stream 处理，在 batch 中不支持
+            let res = match cmd.request_data {
+                Some(
+                    RequestData::Subscribe(_)
+                    | RequestData::Unsubscribe(_)
+                    | RequestData::Publish(_),
+                ) => {
+                    KvError::InvalidCommand(format!("Command is not supported in batch: {:?}", cmd))
+                        .into()
+                }
+                _ => dispatch(cmd, store).await,
+            };
+            responses.push(res);
+        }
+        responses.into()
     }
 }
 
@@ -112,52 +469,54 @@ impl CommandService for Hmexist {
 mod tests {
     use super::*;
 
-    #[test]
-    fn hget_should_work() {
-        let store = MemTable::new();
+    #[tokio::test]
+    async fn hget_should_work() {
+        let store = BlockingStorage::new(MemTable::new());
         let cmd = CommandRequest::new_hset("score", "u1", 10.into());
-        dispatch(cmd, &store);
+        dispatch(cmd, &store).await;
         let cmd = CommandRequest::new_hget("score", "u1");
-        let res = dispatch(cmd, &store);
+        let res = dispatch(cmd, &store).await;
         assert_res_ok(&res, &[10.into()], &[]);
     }
 
-    #[test]
-    fn hget_with_non_exist_key_should_return_404() {
-        let store = MemTable::new();
+    #[tokio::test]
+    async fn hget_with_non_exist_key_should_return_404() {
+        let store = BlockingStorage::new(MemTable::new());
         let cmd = CommandRequest::new_hget("score", "u1");
-        let res = dispatch(cmd, &store);
+        let res = dispatch(cmd, &store).await;
         assert_res_error(&res, 404, "Not found");
     }
 
-    #[test]
-    fn hmget_should_work() {
-        let store = MemTable::new();
+    #[tokio::test]
+    async fn hmget_should_work() {
+        let store = BlockingStorage::new(MemTable::new());
 
         set_key_pairs(
             "user",
             vec![("u1", "Tyr"), ("u2", "Lindsey"), ("u3", "Rosie")],
             &store,
-        );
+        )
+        .await;
 
         let cmd = CommandRequest::new_hmget("user", vec!["u1".into(), "u4".into(), "u3".into()]);
-        let res = dispatch(cmd, &store);
+        let res = dispatch(cmd, &store).await;
         let values = &["Tyr".into(), Value::default(), "Rosie".into()];
         assert_res_ok(&res, values, &[]);
     }
 
-    #[test]
-    fn hgetall_should_work() {
-        let store = MemTable::new();
+    #[tokio::test]
+    async fn hgetall_should_work() {
+        let store = BlockingStorage::new(MemTable::new());
 
         set_key_pairs(
             "score",
             vec![("u1", 10), ("u2", 8), ("u3", 11), ("u1", 6)],
             &store,
-        );
+        )
+        .await;
 
         let cmd = CommandRequest::new_hgetall("score");
-        let res = dispatch(cmd, &store);
+        let res = dispatch(cmd, &store).await;
         let pairs = &[
             Kvpair::new("u1", 6.into()),
             Kvpair::new("u2", 8.into()),
@@ -166,82 +525,433 @@ mod tests {
         assert_res_ok(&res, &[], pairs);
     }
 
-    #[test]
-    fn hset_should_work() {
-        let store = MemTable::new();
+    #[tokio::test]
+    async fn hscan_should_work() {
+        let store = BlockingStorage::new(MemTable::new());
+
+        set_key_pairs(
+            "user",
+            vec![("a1", 1), ("b1", 2), ("b2", 3), ("b3", 4), ("c1", 5)],
+            &store,
+        )
+        .await;
+
+        // 按 prefix 分页扫描，cursor 为下一页的起点
+        let cmd = CommandRequest::new_hscan("user", "", "", "b", 2, "");
+        let res = dispatch(cmd, &store).await;
+        let pairs = &[Kvpair::new("b1", 2.into()), Kvpair::new("b2", 3.into())];
+        assert_res_ok(&res, &[], pairs);
+        assert_eq!(res.cursor, "b3");
+
+        let cmd = CommandRequest::new_hscan("user", "", "", "b", 2, res.cursor);
+        let res = dispatch(cmd, &store).await;
+        assert_res_ok(&res, &[], &[Kvpair::new("b3", 4.into())]);
+        assert_eq!(res.cursor, "");
+
+        // 按 [start, end) 范围扫描
+        let cmd = CommandRequest::new_hscan("user", "b2", "c1", "", 0, "");
+        let res = dispatch(cmd, &store).await;
+        let pairs = &[Kvpair::new("b2", 3.into()), Kvpair::new("b3", 4.into())];
+        assert_res_ok(&res, &[], pairs);
+        assert_eq!(res.cursor, "");
+    }
+
+    #[tokio::test]
+    async fn hfind_should_work() {
+        let store = IndexedStorage::new(MemTable::new());
+        let index = IndexConfig {
+            table: "user".into(),
+            name: "age".into(),
+            path: None,
+        };
+        store.create_index(&index).unwrap();
+        let store = BlockingStorage::new(store);
+        set_key_pairs("user", vec![("u1", 20), ("u2", 30), ("u3", 20)], &store).await;
+
+        let res = dispatch(CommandRequest::new_hfind("user", "age", 20.into()), &store).await;
+        let pairs = &[Kvpair::new("u1", 20.into()), Kvpair::new("u3", 20.into())];
+        assert_res_ok(&res, &[], pairs);
+
+        let res = dispatch(CommandRequest::new_hfind("user", "name", 20.into()), &store).await;
+        assert_res_error(&res, 404, "Not found");
+    }
+
+    #[tokio::test]
+    async fn hset_should_work() {
+        let store = BlockingStorage::new(MemTable::new());
         let cmd = CommandRequest::new_hset("t1", "hello", "world".into());
-        let res = dispatch(cmd.clone(), &store);
+        let res = dispatch(cmd.clone(), &store).await;
         assert_res_ok(&res, &[Value::default()], &[]);
 
-        let res = dispatch(cmd, &store);
+        let res = dispatch(cmd, &store).await;
         assert_res_ok(&res, &["world".into()], &[]);
     }
 
-    #[test]
-    fn hmset_should_work() {
-        let store = MemTable::new();
-        set_key_pairs("t1", vec![("u1", "world")], &store);
+    #[tokio::test]
+    async fn hmset_should_work() {
+        let store = BlockingStorage::new(MemTable::new());
+        set_key_pairs("t1", vec![("u1", "world")], &store).await;
         let pairs = vec![
             Kvpair::new("u1", 10.1.into()),
             Kvpair::new("u2", 8.1.into()),
         ];
         let cmd = CommandRequest::new_hmset("t1", pairs);
-        let res = dispatch(cmd, &store);
+        let res = dispatch(cmd, &store).await;
         assert_res_ok(&res, &["world".into(), Value::default()], &[]);
     }
 
-    #[test]
-    fn hdel_should_work() {
-        let store = MemTable::new();
-        set_key_pairs("t1", vec![("u1", "v1")], &store);
+    #[tokio::test]
+    async fn hcas_should_work() {
+        let store = BlockingStorage::new(MemTable::new());
+        set_key_pairs("t1", vec![("u1", "v1")], &store).await;
+        let cmd = CommandRequest::new_hcas("t1", "u1", Some("v1".into()), "v2".into());
+        let res = dispatch(cmd, &store).await;
+        assert_res_ok(&res, &[true.into()], &[]);
+
+        let cmd = CommandRequest::new_hcas("t1", "u1", Some("v1".into()), "v3".into());
+        let res = dispatch(cmd, &store).await;
+        assert_res_error(&res, 412, "Precondition failed");
+
+        let cmd = CommandRequest::new_hcas("t1", "u2", None, "v1".into());
+        let res = dispatch(cmd, &store).await;
+        assert_res_ok(&res, &[true.into()], &[]);
+
+        let cmd = CommandRequest::new_hget("t1", "u1");
+        let res = dispatch(cmd, &store).await;
+        assert_res_ok(&res, &["v2".into()], &[]);
+    }
+
+    #[tokio::test]
+    async fn hsetnx_should_work() {
+        let store = BlockingStorage::new(MemTable::new());
+        let cmd = CommandRequest::new_hsetnx("t1", "u1", "v1".into());
+        let res = dispatch(cmd, &store).await;
+        assert_res_ok(&res, &[true.into()], &[]);
+
+        let cmd = CommandRequest::new_hsetnx("t1", "u1", "v2".into());
+        let res = dispatch(cmd, &store).await;
+        assert_res_error(&res, 412, "already exists");
+    }
+
+    #[tokio::test]
+    async fn hsetxx_should_work() {
+        let store = BlockingStorage::new(MemTable::new());
+        let cmd = CommandRequest::new_hsetxx("t1", "u1", "v1".into());
+        let res = dispatch(cmd, &store).await;
+        assert_res_error(&res, 412, "does not exist");
+
+        set_key_pairs("t1", vec![("u1", "v1")], &store).await;
+        let cmd = CommandRequest::new_hsetxx("t1", "u1", "v2".into());
+        let res = dispatch(cmd, &store).await;
+        assert_res_ok(&res, &["v1".into()], &[]);
+    }
+
+    #[tokio::test]
+    async fn hincrby_should_work() {
+        let store = BlockingStorage::new(MemTable::new());
+        let cmd = CommandRequest::new_hincrby("score", "u1", 10);
+        let res = dispatch(cmd, &store).await;
+        assert_res_ok(&res, &[10.into()], &[]);
+
+        let cmd = CommandRequest::new_hincrby("score", "u1", -3);
+        let res = dispatch(cmd, &store).await;
+        assert_res_ok(&res, &[7.into()], &[]);
+    }
+
+    #[tokio::test]
+    async fn hincrbyfloat_should_work() {
+        let store = BlockingStorage::new(MemTable::new());
+        let cmd = CommandRequest::new_hincrbyfloat("score", "u1", 1.5);
+        let res = dispatch(cmd, &store).await;
+        assert_res_ok(&res, &[1.5.into()], &[]);
+
+        let cmd = CommandRequest::new_hincrbyfloat("score", "u1", 2.0);
+        let res = dispatch(cmd, &store).await;
+        assert_res_ok(&res, &[3.5.into()], &[]);
+    }
+
+    #[tokio::test]
+    async fn hincrby_with_non_integer_value_should_fail() {
+        let store = BlockingStorage::new(MemTable::new());
+        set_key_pairs("score", vec![("u1", "ten")], &store).await;
+        let cmd = CommandRequest::new_hincrby("score", "u1", 1);
+        let res = dispatch(cmd, &store).await;
+        assert_res_error(&res, 500, "Cannot convert value");
+    }
+
+    #[tokio::test]
+    async fn list_tables_should_work() {
+        let store = BlockingStorage::new(MemTable::new());
+        set_key_pairs("t2", vec![("k1", "v1")], &store).await;
+        set_key_pairs("t1", vec![("k1", "v1")], &store).await;
+        let cmd = CommandRequest::new_list_tables();
+        let res = dispatch(cmd, &store).await;
+        assert_res_ok(&res, &["t1".into(), "t2".into()], &[]);
+    }
+
+    #[tokio::test]
+    async fn table_info_should_work() {
+        let store = BlockingStorage::new(MemTable::new());
+        set_key_pairs("t1", vec![("k1", "v1"), ("k2", "v2")], &store).await;
+        let cmd = CommandRequest::new_table_info("t1");
+        let res = dispatch(cmd, &store).await;
+        assert_eq!(res.status, 200);
+        assert_eq!(res.pairs[0], Kvpair::new("keys", 2.into()));
+        assert_eq!(res.pairs[1].key, "bytes");
+
+        let cmd = CommandRequest::new_table_info("t2");
+        let res = dispatch(cmd, &store).await;
+        assert_res_error(&res, 404, "Not found");
+    }
+
+    #[tokio::test]
+    async fn table_quota_should_work() {
+        let quota = QuotaConfig {
+            table: "t1".into(),
+            max_keys: 1,
+            ..Default::default()
+        };
+        let store = BlockingStorage::new(QuotaStorage::new(MemTable::new(), &[quota]));
+        set_key_pairs("t1", vec![("k1", "v1")], &store).await;
+        let cmd = CommandRequest::new_hset("t1", "k2", "v2".into());
+        let res = dispatch(cmd, &store).await;
+        assert_res_error(&res, 413, "Quota exceeded");
+        let cmd = CommandRequest::new_hmset(
+            "t1",
+            vec![
+                Kvpair::new("k1", "v3".into()),
+                Kvpair::new("k3", "v3".into()),
+            ],
+        );
+        let res = dispatch(cmd, &store).await;
+        assert_res_error(&res, 413, "Quota exceeded");
+
+        let res = dispatch(CommandRequest::new_table_quota("t1"), &store).await;
+        assert_eq!(res.status, 200);
+        assert_eq!(res.pairs[0], Kvpair::new("keys", 1.into()));
+        assert_eq!(res.pairs[2], Kvpair::new("max_keys", 1.into()));
+        assert_eq!(res.pairs[3], Kvpair::new("max_bytes", 0.into()));
+
+        let res = dispatch(CommandRequest::new_table_quota("t2"), &store).await;
+        assert_res_error(&res, 404, "Not found");
+    }
+
+    #[tokio::test]
+    async fn batch_should_work() {
+        let store = BlockingStorage::new(MemTable::new());
+        let cmd = CommandRequest::new_batch(vec![
+            CommandRequest::new_hset("t1", "k1", "v1".into()),
+            CommandRequest::new_hget("t2", "k1"),
+            CommandRequest::new_hincrby("t2", "k2", 2),
+            CommandRequest::new_subscribe("chat"),
+            CommandRequest::new_hget("t1", "k1"),
+        ]);
+        let res = dispatch(cmd, &store).await;
+        assert_eq!(res.status, 200);
+        assert_res_ok(&res.responses[0], &[Value::default()], &[]);
+        // 失败的命令不影响后面的命令
+        assert_res_error(&res.responses[1], 404, "Not found");
+        assert_res_ok(&res.responses[2], &[2.into()], &[]);
+        assert_res_error(&res.responses[3], 400, "not supported in batch");
+        assert_res_ok(&res.responses[4], &["v1".into()], &[]);
+    }
+
+    #[tokio::test]
+    async fn batch_response_with_errors_should_survive_encoding() {
+        use prost::Message;
+
+        let store = BlockingStorage::new(MemTable::new());
+        let cmd = CommandRequest::new_batch(vec![
+            CommandRequest::new_hset("t1", "k1", "v1".into()),
+            CommandRequest::new_hget("t1", "k2"),
+            CommandRequest::new_hget("t1", "k1"),
+        ]);
+        let res = dispatch(cmd, &store).await;
+        let res = CommandResponse::decode(res.encode_to_vec().as_slice()).unwrap();
+        // 客户端从 responses 中区分每个命令是否成功
+        assert_eq!(res.status, 200);
+        assert_eq!(res.responses.len(), 3);
+        assert_res_ok(&res.responses[0], &[Value::default()], &[]);
+        assert_res_error(&res.responses[1], 404, "Not found");
+        assert_res_ok(&res.responses[2], &["v1".into()], &[]);
+    }
+
+    #[tokio::test]
+    async fn stats_should_work() {
+        let store = BlockingStorage::new(MemTable::with_limit(1024, EvictionPolicy::Lru));
+        set_key_pairs("t1", vec![("k1", "v1")], &store).await;
+        let res = dispatch(CommandRequest::new_stats(), &store).await;
+        assert_eq!(res.status, 200);
+        assert_eq!(res.pairs[0].key, "used_bytes");
+        assert_eq!(res.pairs[1], Kvpair::new("max_bytes", 1024.into()));
+        assert_eq!(res.pairs[2], Kvpair::new("evictions", 0.into()));
+    }
+
+    #[tokio::test]
+    async fn backup_and_restore_should_work() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = BlockingStorage::new(MemTable::new()).with_backup_dir(dir.path());
+        set_key_pairs("t1", vec![("k1", "v1"), ("k2", "v2")], &store).await;
+        let res = dispatch(CommandRequest::new_backup("daily/backup"), &store).await;
+        assert_res_ok(&res, &[2.into()], &[]);
+        assert!(dir.path().join("daily/backup").exists());
+
+        let store = BlockingStorage::new(MemTable::new()).with_backup_dir(dir.path());
+        let res = dispatch(CommandRequest::new_restore("daily/backup"), &store).await;
+        assert_res_ok(&res, &[2.into()], &[]);
+        let res = dispatch(CommandRequest::new_hget("t1", "k2"), &store).await;
+        assert_res_ok(&res, &["v2".into()], &[]);
+
+        let res = dispatch(CommandRequest::new_restore("non-exist"), &store).await;
+        assert_res_error(&res, 500, "I/O error");
+    }
+
+    #[tokio::test]
+    async fn backup_and_restore_should_reject_paths_outside_backup_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = BlockingStorage::new(MemTable::new()).with_backup_dir(dir.path());
+        for path in ["../x", "/etc/x"] {
+            let res = dispatch(CommandRequest::new_backup(path), &store).await;
+            assert_res_error(&res, 400, "Command is invalid");
+            let res = dispatch(CommandRequest::new_restore(path), &store).await;
+            assert_res_error(&res, 400, "Command is invalid");
+        }
+        assert!(!dir.path().parent().unwrap().join("x").exists());
+    }
+
+    #[tokio::test]
+    async fn backup_and_restore_should_require_backup_dir() {
+        let store = BlockingStorage::new(MemTable::new());
+        let res = dispatch(CommandRequest::new_backup("backup"), &store).await;
+        assert_res_error(&res, 400, "Command is invalid");
+        let res = dispatch(CommandRequest::new_restore("backup"), &store).await;
+        assert_res_error(&res, 400, "Command is invalid");
+    }
+
+    #[tokio::test]
+    async fn drop_table_should_work() {
+        let store = BlockingStorage::new(MemTable::new());
+        set_key_pairs("t1", vec![("k1", "v1")], &store).await;
+        let cmd = CommandRequest::new_drop_table("t1");
+        let res = dispatch(cmd.clone(), &store).await;
+        assert_res_ok(&res, &[true.into()], &[]);
+        let res = dispatch(cmd, &store).await;
+        assert_res_ok(&res, &[false.into()], &[]);
+    }
+
+    #[tokio::test]
+    async fn rename_table_should_work() {
+        let store = BlockingStorage::new(MemTable::new());
+        set_key_pairs("t1", vec![("k1", "v1")], &store).await;
+        set_key_pairs("t2", vec![("k1", "v1")], &store).await;
+        let cmd = CommandRequest::new_rename_table("t1", "t3");
+        let res = dispatch(cmd.clone(), &store).await;
+        assert_res_ok(&res, &[true.into()], &[]);
+
+        let res = dispatch(cmd, &store).await;
+        assert_res_error(&res, 404, "Not found");
+
+        let cmd = CommandRequest::new_rename_table("t3", "t2");
+        let res = dispatch(cmd, &store).await;
+        assert_res_error(&res, 412, "already exists");
+
+        let res = dispatch(CommandRequest::new_hget("t3", "k1"), &store).await;
+        assert_res_ok(&res, &["v1".into()], &[]);
+    }
+
+    #[tokio::test]
+    async fn hdel_should_work() {
+        let store = BlockingStorage::new(MemTable::new());
+        set_key_pairs("t1", vec![("u1", "v1")], &store).await;
         let cmd = CommandRequest::new_hdel("t1", "u2");
-        let res = dispatch(cmd, &store);
+        let res = dispatch(cmd, &store).await;
         assert_res_ok(&res, &[Value::default()], &[]);
 
         let cmd = CommandRequest::new_hdel("t1", "u1");
-        let res = dispatch(cmd, &store);
+        let res = dispatch(cmd, &store).await;
         assert_res_ok(&res, &["v1".into()], &[]);
     }
 
-    #[test]
-    fn hmdel_should_work() {
-        let store = MemTable::new();
-        set_key_pairs("t1", vec![("u1", "v1"), ("u2", "v2")], &store);
+    #[tokio::test]
+    async fn hmdel_should_work() {
+        let store = BlockingStorage::new(MemTable::new());
+        set_key_pairs("t1", vec![("u1", "v1"), ("u2", "v2")], &store).await;
 
         let cmd = CommandRequest::new_hmdel("t1", vec!["u1".into(), "u3".into()]);
-        let res = dispatch(cmd, &store);
+        let res = dispatch(cmd, &store).await;
         assert_res_ok(&res, &["v1".into(), Value::default()], &[]);
     }
 
-    #[test]
-    fn hexist_should_work() {
-        let store = MemTable::new();
-        set_key_pairs("t1", vec![("u1", "v1")], &store);
+    #[tokio::test]
+    async fn hexist_should_work() {
+        let store = BlockingStorage::new(MemTable::new());
+        set_key_pairs("t1", vec![("u1", "v1")], &store).await;
         let cmd = CommandRequest::new_hexist("t1", "u2");
-        let res = dispatch(cmd, &store);
+        let res = dispatch(cmd, &store).await;
         assert_res_ok(&res, &[false.into()], &[]);
 
         let cmd = CommandRequest::new_hexist("t1", "u1");
-        let res = dispatch(cmd, &store);
+        let res = dispatch(cmd, &store).await;
         assert_res_ok(&res, &[true.into()], &[]);
     }
 
-    #[test]
-    fn hmexist_should_work() {
-        let store = MemTable::new();
-        set_key_pairs("t1", vec![("u1", "v1"), ("u2", "v2")], &store);
+    #[tokio::test]
+    async fn hmexist_should_work() {
+        let store = BlockingStorage::new(MemTable::new());
+        set_key_pairs("t1", vec![("u1", "v1"), ("u2", "v2")], &store).await;
 
         let cmd = CommandRequest::new_hmexist("t1", vec!["u1".into(), "u3".into()]);
-        let res = dispatch(cmd, &store);
+        let res = dispatch(cmd, &store).await;
         assert_res_ok(&res, &[true.into(), false.into()], &[]);
     }
 
-    fn set_key_pairs<T: Into<Value>>(table: &str, pairs: Vec<(&str, T)>, store: &impl Storage) {
-        pairs
-            .into_iter()
-            .map(|(k, v)| CommandRequest::new_hset(table, k, v.into()))
-            .for_each(|cmd| {
-                dispatch(cmd, store);
-            });
+    #[tokio::test]
+    async fn hexpire_should_work() {
+        let store = BlockingStorage::new(MemTable::new());
+        set_key_pairs("t1", vec![("u1", "v1")], &store).await;
+        let cmd = CommandRequest::new_hexpire("t1", "u1", 100);
+        let res = dispatch(cmd, &store).await;
+        assert_res_ok(&res, &[true.into()], &[]);
+
+        let cmd = CommandRequest::new_hexpire("t1", "u2", 100);
+        let res = dispatch(cmd, &store).await;
+        assert_res_ok(&res, &[false.into()], &[]);
+
+        // ttl 为 0 会去掉过期时间
+        let cmd = CommandRequest::new_hexpire("t1", "u1", 0);
+        let res = dispatch(cmd, &store).await;
+        assert_res_ok(&res, &[true.into()], &[]);
+        let cmd = CommandRequest::new_httl("t1", "u1");
+        let res = dispatch(cmd, &store).await;
+        assert_res_ok(&res, &[(-1).into()], &[]);
+    }
+
+    #[tokio::test]
+    async fn httl_should_work() {
+        let store = BlockingStorage::new(MemTable::new());
+        set_key_pairs("t1", vec![("u1", "v1")], &store).await;
+        dispatch(CommandRequest::new_hexpire("t1", "u1", 100), &store).await;
+        let cmd = CommandRequest::new_httl("t1", "u1");
+        let res = dispatch(cmd, &store).await;
+        let ttl: i64 = (&res).try_into().unwrap();
+        assert!(ttl > 90 && ttl <= 100);
+    }
+
+    #[tokio::test]
+    async fn httl_with_non_exist_key_should_return_404() {
+        let store = BlockingStorage::new(MemTable::new());
+        let cmd = CommandRequest::new_httl("t1", "u1");
+        let res = dispatch(cmd, &store).await;
+        assert_res_error(&res, 404, "Not found");
+    }
+
+    async fn set_key_pairs<T: Into<Value>>(
+        table: &str,
+        pairs: Vec<(&str, T)>,
+        store: &impl AsyncStorage,
+    ) {
+        for (k, v) in pairs {
+            dispatch(CommandRequest::new_hset(table, k, v.into()), store).await;
+        }
     }
 }
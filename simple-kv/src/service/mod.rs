@@ -1,21 +1,29 @@
 use crate::{
-    command_request::RequestData, CommandRequest, CommandResponse, KvError, MemTable, Storage,
+    command_request::RequestData, storage::with_written_version, AsyncStorage, BlockingStorage,
+    CommandRequest, CommandResponse, KvError, MemTable,
 };
+use async_trait::async_trait;
 use futures::stream;
-use std::sync::Arc;
-use tracing::{debug, instrument};
+use std::{sync::Arc, time::Duration};
+use tokio::{task::JoinHandle, time};
+use tracing::{debug, instrument, warn};
 
+mod chunk_service;
+mod collection_service;
 mod command_service;
 mod topic;
 mod topic_service;
+mod txn_service;
 
 pub use topic::{Broadcaster, Topic};
 pub use topic_service::{StreamingResponse, TopicService};
+pub use txn_service::{dispatch_txn, TxnService};
 
 /// 对 Command 的处理的抽象
+#[async_trait]
 pub trait CommandService {
     /// 处理 Command，返回 Response
-    fn execute(self, store: &impl Storage) -> CommandResponse;
+    async fn execute(self, store: &impl AsyncStorage) -> CommandResponse;
 }
 
 /// 事件通知（不可变事件）
@@ -47,7 +55,7 @@ impl<Arg> NotifyMut<Arg> for Vec<fn(&mut Arg)> {
 }
 
 /// Service 数据结构
-pub struct Service<Store = MemTable> {
+pub struct Service<Store = BlockingStorage<MemTable>> {
     inner: Arc<ServiceInner<Store>>,
     broadcaster: Arc<Broadcaster>,
 }
@@ -70,7 +78,7 @@ pub struct ServiceInner<Store> {
     on_after_send: Vec<fn()>,
 }
 
-impl<Store: Storage> ServiceInner<Store> {
+impl<Store: AsyncStorage> ServiceInner<Store> {
     pub fn new(store: Store) -> Self {
         Self {
             store,
@@ -102,7 +110,7 @@ impl<Store: Storage> ServiceInner<Store> {
     }
 }
 
-impl<Store: Storage> From<ServiceInner<Store>> for Service<Store> {
+impl<Store: AsyncStorage> From<ServiceInner<Store>> for Service<Store> {
     fn from(inner: ServiceInner<Store>) -> Self {
         Self {
             inner: Arc::new(inner),
@@ -111,16 +119,23 @@ impl<Store: Storage> From<ServiceInner<Store>> for Service<Store> {
     }
 }
 
-impl<Store: Storage> Service<Store> {
+impl<Store: AsyncStorage> Service<Store> {
     #[instrument(name = "service_execute", skip_all)]
-    pub fn execute(&self, cmd: CommandRequest) -> StreamingResponse {
+    pub async fn execute(&self, cmd: CommandRequest) -> StreamingResponse {
         debug!("Got request: {:?}", cmd);
         self.inner.on_received.notify(&cmd);
-        let mut res = dispatch(cmd.clone(), &self.inner.store);
+        // 分块下载返回多个 response
+        if let Some(RequestData::HgetChunked(param)) = cmd.request_data {
+            return param.execute(&self.inner.store).await;
+        }
+        let (mut res, version) =
+            with_written_version(dispatch(cmd.clone(), &self.inner.store)).await;
 
         if res == CommandResponse::default() {
             dispatch_stream(cmd, Arc::clone(&self.broadcaster))
         } else {
+            // 修改了数据的命令带上它产生的版本号，客户端可以用它读取这时的数据
+            res.version = version;
             debug!("Executed response: {:?}", res);
             self.inner.on_executed.notify(&res);
             self.inner.on_before_send.notify(&mut res);
@@ -133,18 +148,78 @@ impl<Store: Storage> Service<Store> {
     }
 }
 
-/// 从 Request 中得到 Response，目前处理所有 HGET/HSET/HDEL/HEXIST
-pub fn dispatch(cmd: CommandRequest, store: &impl Storage) -> CommandResponse {
+impl<Store: AsyncStorage> Service<Store> {
+    /// 启动后台任务，每隔 interval 清理一次已经过期的 key 和超过保留时间的旧版本
+    pub fn start_reaper(&self, interval: Duration) -> JoinHandle<()> {
+        let inner = Arc::clone(&self.inner);
+        tokio::spawn(async move {
+            let mut ticker = time::interval(interval);
+            loop {
+                ticker.tick().await;
+                match inner.store.purge_expired().await {
+                    Ok(0) => {}
+                    Ok(n) => debug!("Purged {} expired keys", n),
+                    Err(e) => warn!("Failed to purge expired keys: {:?}", e),
+                }
+                match inner.store.purge_versions().await {
+                    Ok(0) => {}
+                    Ok(n) => debug!("Purged {} old versions", n),
+                    Err(e) => warn!("Failed to purge old versions: {:?}", e),
+                }
+            }
+        })
+    }
+}
+
+/// 从 Request 中得到 Response，目前处理所有 HGET/HSCAN/HFIND/HSET/HCAS/HINCRBY/HDEL/HEXIST/HEXPIRE/HTTL/TRANSACTION/BATCH/HSET_CHUNK 、table 管理命令、STATS/BACKUP/RESTORE
+/// 以及列表、集合和有序集合的命令、SNAPSHOT/RELEASE_SNAPSHOT
+pub async fn dispatch(cmd: CommandRequest, store: &impl AsyncStorage) -> CommandResponse {
     match cmd.request_data {
-        Some(RequestData::Hget(param)) => param.execute(store),
-        Some(RequestData::Hgetall(param)) => param.execute(store),
-        Some(RequestData::Hmget(param)) => param.execute(store),
-        Some(RequestData::Hset(param)) => param.execute(store),
-        Some(RequestData::Hmset(param)) => param.execute(store),
-        Some(RequestData::Hdel(param)) => param.execute(store),
-        Some(RequestData::Hmdel(param)) => param.execute(store),
-        Some(RequestData::Hexist(param)) => param.execute(store),
-        Some(RequestData::Hmexist(param)) => param.execute(store),
+        Some(RequestData::Hget(param)) => param.execute(store).await,
+        Some(RequestData::Hgetall(param)) => param.execute(store).await,
+        Some(RequestData::Hscan(param)) => param.execute(store).await,
+        Some(RequestData::Hfind(param)) => param.execute(store).await,
+        Some(RequestData::Hmget(param)) => param.execute(store).await,
+        Some(RequestData::Hset(param)) => param.execute(store).await,
+        Some(RequestData::Hmset(param)) => param.execute(store).await,
+        Some(RequestData::Hcas(param)) => param.execute(store).await,
+        Some(RequestData::Hsetnx(param)) => param.execute(store).await,
+        Some(RequestData::Hsetxx(param)) => param.execute(store).await,
+        Some(RequestData::Hincrby(param)) => param.execute(store).await,
+        Some(RequestData::Hincrbyfloat(param)) => param.execute(store).await,
+        Some(RequestData::Hdel(param)) => param.execute(store).await,
+        Some(RequestData::Hmdel(param)) => param.execute(store).await,
+        Some(RequestData::Hexist(param)) => param.execute(store).await,
+        Some(RequestData::Hmexist(param)) => param.execute(store).await,
+        Some(RequestData::Hexpire(param)) => param.execute(store).await,
+        Some(RequestData::Httl(param)) => param.execute(store).await,
+        Some(RequestData::HsetChunk(param)) => param.execute(store).await,
+        Some(RequestData::Transaction(param)) => param.execute(store).await,
+        Some(RequestData::Batch(param)) => param.execute(store).await,
+        Some(RequestData::ListTables(param)) => param.execute(store).await,
+        Some(RequestData::TableInfo(param)) => param.execute(store).await,
+        Some(RequestData::TableQuota(param)) => param.execute(store).await,
+        Some(RequestData::DropTable(param)) => param.execute(store).await,
+        Some(RequestData::RenameTable(param)) => param.execute(store).await,
+        Some(RequestData::Stats(param)) => param.execute(store).await,
+        Some(RequestData::Backup(param)) => param.execute(store).await,
+        Some(RequestData::Restore(param)) => param.execute(store).await,
+        Some(RequestData::Lpush(param)) => param.execute(store).await,
+        Some(RequestData::Rpush(param)) => param.execute(store).await,
+        Some(RequestData::Lrange(param)) => param.execute(store).await,
+        Some(RequestData::Sadd(param)) => param.execute(store).await,
+        Some(RequestData::Smembers(param)) => param.execute(store).await,
+        Some(RequestData::Zadd(param)) => param.execute(store).await,
+        Some(RequestData::Zrange(param)) => param.execute(store).await,
+        Some(RequestData::Snapshot(param)) => param.execute(store).await,
+        Some(RequestData::ReleaseSnapshot(param)) => param.execute(store).await,
+        Some(RequestData::Hello(_)) => {
+            KvError::InvalidCommand("Hello is only allowed at the start of a stream".into()).into()
+        }
+        // 分块下载由 Service::execute 处理，不能放在 batch 或者事务中
+        Some(RequestData::HgetChunked(_)) => {
+            KvError::InvalidCommand("HgetChunked must be sent on its own".into()).into()
+        }
         None => KvError::InvalidCommand("Request has no data".into()).into(),
         // 处理不了的返回一个啥都不包括的 Response，这样后续可以用 dispatch_stream 处理
         _ => CommandResponse::default(),
@@ -169,19 +244,21 @@ mod tests {
     use tracing::info;
 
     use super::*;
-    use crate::{MemTable, Value};
+    use crate::{MemTable, MvccStorage, Storage, Value};
 
     #[tokio::test]
     async fn service_should_works() {
         // 我们需要一个 service 结构至少包含 Storage
-        let service: Service = ServiceInner::new(MemTable::default()).into();
+        let service: Service = ServiceInner::new(BlockingStorage::new(MemTable::default())).into();
 
         // service 可以运行在多线程环境下，它的 clone 应该是轻量级的
         let cloned = service.clone();
 
         // 创建一个线程，在 table t1 中写入 k1, v1
         tokio::spawn(async move {
-            let mut res = cloned.execute(CommandRequest::new_hset("t1", "k1", "v1".into()));
+            let mut res = cloned
+                .execute(CommandRequest::new_hset("t1", "k1", "v1".into()))
+                .await;
             let data = res.next().await.unwrap();
             assert_res_ok(&data, &[Value::default()], &[]);
         })
@@ -189,7 +266,7 @@ mod tests {
         .unwrap();
 
         // 在当前线程下读取 table t1 的 k1，应该返回 v1
-        let mut res = service.execute(CommandRequest::new_hget("t1", "k1"));
+        let mut res = service.execute(CommandRequest::new_hget("t1", "k1")).await;
         let data = res.next().await.unwrap();
         assert_res_ok(&data, &["v1".into()], &[]);
     }
@@ -209,7 +286,7 @@ mod tests {
             info!("Data is sent");
         }
 
-        let service: Service = ServiceInner::new(MemTable::default())
+        let service: Service = ServiceInner::new(BlockingStorage::new(MemTable::default()))
             .fn_received(|_: &CommandRequest| {})
             .fn_received(b)
             .fn_executed(c)
@@ -217,12 +294,72 @@ mod tests {
             .fn_after_send(e)
             .into();
 
-        let mut res = service.execute(CommandRequest::new_hset("t1", "k1", "v1".into()));
+        let mut res = service
+            .execute(CommandRequest::new_hset("t1", "k1", "v1".into()))
+            .await;
         let data = res.next().await.unwrap();
         assert_eq!(data.status, StatusCode::CREATED.as_u16() as u32);
         assert_eq!(data.message, "");
         assert_eq!(data.values, vec![Value::default()]);
     }
+
+    #[tokio::test]
+    async fn service_should_read_versions_and_snapshots() {
+        let store = MvccStorage::new(MemTable::new(), Duration::from_secs(60));
+        let service: Service<_> = ServiceInner::new(BlockingStorage::new(store)).into();
+        let execute = |cmd| {
+            let service = service.clone();
+            async move { service.execute(cmd).await.next().await.unwrap() }
+        };
+
+        let res = execute(CommandRequest::new_hset("t1", "k1", "v1".into())).await;
+        let v1 = res.version;
+        assert!(v1 > 0);
+        let res = execute(CommandRequest::new_snapshot()).await;
+        assert_res_ok(&res, &[(v1 as i64).into()], &[]);
+        let res = execute(CommandRequest::new_hset("t1", "k1", "v2".into())).await;
+        assert_eq!(res.version, v1 + 1);
+
+        let res = execute(CommandRequest::new_hget_at("t1", "k1", v1)).await;
+        assert_res_ok(&res, &["v1".into()], &[]);
+        let res = execute(CommandRequest::new_hmget_at("t1", vec!["k1".into()], v1)).await;
+        assert_res_ok(&res, &["v1".into()], &[]);
+        let res = execute(CommandRequest::new_hgetall_at("t1", v1 + 1)).await;
+        assert_res_ok(&res, &[], &[Kvpair::new("k1", "v2".into())]);
+        let res = execute(CommandRequest::new_hget_at("t1", "k1", v1 + 2)).await;
+        assert_res_error(&res, 410, "Version");
+
+        let res = execute(CommandRequest::new_release_snapshot(v1)).await;
+        assert_res_ok(&res, &[true.into()], &[]);
+
+        // 只有修改了数据的命令才带上版本号
+        let res = execute(CommandRequest::new_hget("t1", "k1")).await;
+        assert_eq!(res.version, 0);
+        let res = execute(CommandRequest::new_hdel("t1", "k2")).await;
+        assert_eq!(res.version, 0);
+        let cmds = vec![
+            CommandRequest::new_hset("t1", "k2", "v2".into()),
+            CommandRequest::new_hset("t1", "k3", "v3".into()),
+        ];
+        let res = execute(CommandRequest::new_batch(cmds)).await;
+        assert_eq!(res.version, v1 + 3);
+        let res = execute(CommandRequest::new_hdel("t1", "k3")).await;
+        assert_eq!(res.version, v1 + 4);
+    }
+
+    #[tokio::test]
+    async fn reaper_should_purge_expired_keys() {
+        let store = MemTable::default();
+        store
+            .set_with_ttl("t1", "k1".into(), "v1".into(), Duration::ZERO)
+            .unwrap();
+        let service: Service = ServiceInner::new(BlockingStorage::new(store)).into();
+        let handle = service.start_reaper(Duration::from_millis(10));
+        time::sleep(Duration::from_millis(50)).await;
+        handle.abort();
+
+        assert_eq!(service.inner.store.purge_expired().await.unwrap(), 0);
+    }
 }
 
 #[cfg(test)]
@@ -167,7 +167,7 @@ mod tests {
 
         // 如果 subscriber 取消订阅，则收不到新数据
         let result = b.clone().unsubscribe(lobby.clone(), id1 as _).unwrap();
-        assert_eq!(result, id1 as _);
+        assert_eq!(result, id1 as u32);
 
         // publish
         let v: Value = "world".into();
@@ -0,0 +1,189 @@
+use crate::{command_request::RequestData, *};
+use async_trait::async_trait;
+
+/// 在事务中处理 Command 的抽象
+pub trait TxnService {
+    /// 在事务中处理 Command，返回 Response；返回错误时整个事务会被放弃
+    fn execute_txn(self, txn: &dyn TxnStorage) -> Result<CommandResponse, KvError>;
+}
+
+#[async_trait]
+impl CommandService for Transaction {
+    async fn execute(self, store: &impl AsyncStorage) -> CommandResponse {
+        let commands = self.commands;
+        let result = store
+            .transaction(move |txn| {
+                commands
+                    .iter()
+                    .map(|cmd| dispatch_txn(cmd.clone(), txn))
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .await;
+        match result {
+            Ok(v) => v.into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl TxnService for Hget {
+    fn execute_txn(self, txn: &dyn TxnStorage) -> Result<CommandResponse, KvError> {
+        check_latest(self.version)?;
+        Ok(match txn.get(&self.table, &self.key)? {
+            Some(v) => v.into(),
+            None => KvError::NotFound(format!("table {}, key {}", self.table, self.key)).into(),
+        })
+    }
+}
+
+impl TxnService for Hmget {
+    fn execute_txn(self, txn: &dyn TxnStorage) -> Result<CommandResponse, KvError> {
+        check_latest(self.version)?;
+        self.keys
+            .iter()
+            .map(|key| Ok(txn.get(&self.table, key)?.unwrap_or_default()))
+            .collect::<Result<Vec<Value>, KvError>>()
+            .map(|v| v.into())
+    }
+}
+
+impl TxnService for Hset {
+    fn execute_txn(self, txn: &dyn TxnStorage) -> Result<CommandResponse, KvError> {
+        match self.pair {
+            Some(v) => {
+                let old = txn.set(&self.table, v.key, v.value.unwrap_or_default())?;
+                Ok(old.unwrap_or_default().into())
+            }
+            None => Err(KvError::InvalidCommand(format!("{:?}", self))),
+        }
+    }
+}
+
+impl TxnService for Hmset {
+    fn execute_txn(self, txn: &dyn TxnStorage) -> Result<CommandResponse, KvError> {
+        let table = self.table;
+        self.pairs
+            .into_iter()
+            .map(|pair| {
+                let old = txn.set(&table, pair.key, pair.value.unwrap_or_default())?;
+                Ok(old.unwrap_or_default())
+            })
+            .collect::<Result<Vec<Value>, KvError>>()
+            .map(|v| v.into())
+    }
+}
+
+impl TxnService for Hdel {
+    fn execute_txn(self, txn: &dyn TxnStorage) -> Result<CommandResponse, KvError> {
+        Ok(txn.del(&self.table, &self.key)?.unwrap_or_default().into())
+    }
+}
+
+impl TxnService for Hmdel {
+    fn execute_txn(self, txn: &dyn TxnStorage) -> Result<CommandResponse, KvError> {
+        self.keys
+            .iter()
+            .map(|key| Ok(txn.del(&self.table, key)?.unwrap_or_default()))
+            .collect::<Result<Vec<Value>, KvError>>()
+            .map(|v| v.into())
+    }
+}
+
+impl TxnService for Hexist {
+    fn execute_txn(self, txn: &dyn TxnStorage) -> Result<CommandResponse, KvError> {
+        Ok(Value::from(txn.contains(&self.table, &self.key)?).into())
+    }
+}
+
+impl TxnService for Hmexist {
+    fn execute_txn(self, txn: &dyn TxnStorage) -> Result<CommandResponse, KvError> {
+        self.keys
+            .iter()
+            .map(|key| Ok(txn.contains(&self.table, key)?.into()))
+            .collect::<Result<Vec<Value>, KvError>>()
+            .map(|v| v.into())
+    }
+}
+
+/// 事务中只能读取最新的数据
+fn check_latest(version: u64) -> Result<(), KvError> {
+    match version {
+        0 => Ok(()),
+        _ => Err(KvError::InvalidCommand(format!(
+            "Cannot read version {} in transaction",
+            version
+        ))),
+    }
+}
+
+/// 在事务中从 Request 中得到 Response，目前处理 HGET/HSET/HDEL/HEXIST 以及它们的批量版本，
+/// 还有列表、集合和有序集合的命令
+pub fn dispatch_txn(cmd: CommandRequest, txn: &dyn TxnStorage) -> Result<CommandResponse, KvError> {
+    match cmd.request_data {
+        Some(RequestData::Hget(param)) => param.execute_txn(txn),
+        Some(RequestData::Hmget(param)) => param.execute_txn(txn),
+        Some(RequestData::Hset(param)) => param.execute_txn(txn),
+        Some(RequestData::Hmset(param)) => param.execute_txn(txn),
+        Some(RequestData::Hdel(param)) => param.execute_txn(txn),
+        Some(RequestData::Hmdel(param)) => param.execute_txn(txn),
+        Some(RequestData::Hexist(param)) => param.execute_txn(txn),
+        Some(RequestData::Hmexist(param)) => param.execute_txn(txn),
+        Some(RequestData::Lpush(param)) => param.execute_txn(txn),
+        Some(RequestData::Rpush(param)) => param.execute_txn(txn),
+        Some(RequestData::Lrange(param)) => param.execute_txn(txn),
+        Some(RequestData::Sadd(param)) => param.execute_txn(txn),
+        Some(RequestData::Smembers(param)) => param.execute_txn(txn),
+        Some(RequestData::Zadd(param)) => param.execute_txn(txn),
+        Some(RequestData::Zrange(param)) => param.execute_txn(txn),
+        None => Err(KvError::InvalidCommand("Request has no data".into())),
+        _ => Err(KvError::InvalidCommand(format!(
+            "Command is not supported in transaction: {:?}",
+            cmd
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn transaction_should_work() {
+        let store = BlockingStorage::new(MemTable::new());
+        dispatch(CommandRequest::new_hset("t1", "k1", "v1".into()), &store).await;
+
+        let cmd = CommandRequest::new_transaction(vec![
+            CommandRequest::new_hset("t1", "k1", "v2".into()),
+            CommandRequest::new_hmset("t2", vec![Kvpair::new("k2", 2.into())]),
+            CommandRequest::new_hget("t1", "k1"),
+            CommandRequest::new_hget("t1", "k3"),
+        ]);
+        let res = dispatch(cmd, &store).await;
+        assert_res_ok(&res, &[], &[]);
+        assert_eq!(res.responses.len(), 4);
+        assert_res_ok(&res.responses[0], &["v1".into()], &[]);
+        assert_res_ok(&res.responses[1], &[Value::default()], &[]);
+        assert_res_ok(&res.responses[2], &["v2".into()], &[]);
+        assert_res_error(&res.responses[3], 404, "Not found");
+
+        let res = dispatch(CommandRequest::new_hget("t2", "k2"), &store).await;
+        assert_res_ok(&res, &[2.into()], &[]);
+    }
+
+    #[tokio::test]
+    async fn transaction_with_invalid_command_should_not_apply_anything() {
+        let store = BlockingStorage::new(MemTable::new());
+        dispatch(CommandRequest::new_hset("t1", "k1", "v1".into()), &store).await;
+
+        let cmd = CommandRequest::new_transaction(vec![
+            CommandRequest::new_hset("t1", "k1", "v2".into()),
+            CommandRequest::new_hdel("t1", "k1"),
+            CommandRequest::new_subscribe("lobby"),
+        ]);
+        let res = dispatch(cmd, &store).await;
+        assert_res_error(&res, 400, "not supported in transaction");
+
+        let res = dispatch(CommandRequest::new_hget("t1", "k1"), &store).await;
+        assert_res_ok(&res, &["v1".into()], &[]);
+    }
+}
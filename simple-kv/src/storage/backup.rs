@@ -0,0 +1,269 @@
+use crate::{
+    payload_len, BackupRecord, FrameCoder, KvError, Kvpair, Storage, ValueCodec, LEN_LEN, MAX_FRAME,
+};
+use bytes::{BufMut, BytesMut};
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, ErrorKind, Read, Write},
+    path::{Component, Path, PathBuf},
+};
+use tracing::info;
+
+use super::chunk::CHUNK_TABLE;
+
+/// 每个 frame 最多包含的 kv pair 个数
+const BATCH_SIZE: usize = 1000;
+
+/// 把 store 中所有 table 的数据写入 writer，返回写入的 kv pair 个数
+/// 备份期间的写入可能只有一部分出现在备份中，过期时间不会被备份。
+/// 给出 codec 时（比如开启了加密的 Keyring），value 用 codec 编码之后再写入，
+/// 否则备份中是 store 读出的原始 value
+pub fn backup(
+    store: &impl Storage,
+    mut writer: impl Write,
+    codec: Option<&dyn ValueCodec>,
+) -> Result<usize, KvError> {
+    let mut count = 0;
+    let mut buf = BytesMut::new();
+    // 没有完成的分块上传不需要备份
+    for table in store
+        .list_tables()?
+        .into_iter()
+        .filter(|t| t != CHUNK_TABLE)
+    {
+        let mut iter = store.get_iter(&table)?.peekable();
+        while iter.peek().is_some() {
+            let record = BackupRecord {
+                table: table.clone(),
+                pairs: iter.by_ref().take(BATCH_SIZE).collect(),
+            };
+            let record = match codec {
+                Some(codec) => encode_record(codec, record)?,
+                None => record,
+            };
+            count += record.pairs.len();
+            buf.clear();
+            record.encode_frame(&mut buf)?;
+            writer.write_all(&buf)?;
+        }
+    }
+    writer.flush()?;
+    Ok(count)
+}
+
+/// 从 reader 中读取备份的数据写入 store，已有的 key 会被覆盖，返回写入的 kv pair 个数
+/// codec 需要和备份时使用的一致
+pub fn restore(
+    store: &impl Storage,
+    reader: impl Read,
+    codec: Option<&dyn ValueCodec>,
+) -> Result<usize, KvError> {
+    restore_with(store, reader, codec, MAX_FRAME)
+}
+
+/// 和 restore 一样，但是长度超过 max_len 的 frame 会返回 FrameTooLarge
+fn restore_with(
+    store: &impl Storage,
+    mut reader: impl Read,
+    codec: Option<&dyn ValueCodec>,
+    max_len: usize,
+) -> Result<usize, KvError> {
+    let mut count = 0;
+    let mut buf = BytesMut::new();
+    while let Some(record) = read_record(&mut reader, &mut buf, max_len)? {
+        for pair in record.pairs {
+            let value = pair.value.unwrap_or_default();
+            let value = match codec {
+                Some(codec) => codec.decode(&record.table, &pair.key, value)?,
+                None => value,
+            };
+            store.set(&record.table, pair.key, value)?;
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+/// 把 store 备份到 path，先写入临时文件再改名，这样 path 中不会出现写了一半的备份
+pub fn backup_to_file(
+    store: &impl Storage,
+    path: impl AsRef<Path>,
+    codec: Option<&dyn ValueCodec>,
+) -> Result<usize, KvError> {
+    let path = path.as_ref();
+    let tmp = path.with_extension("tmp");
+    let mut writer = BufWriter::new(File::create(&tmp)?);
+    let count = backup(store, &mut writer, codec)?;
+    writer
+        .into_inner()
+        .map_err(|e| e.into_error())?
+        .sync_all()?;
+    fs::rename(&tmp, path)?;
+    info!("Backed up {} pairs to {}", count, path.display());
+    Ok(count)
+}
+
+/// 从 path 中恢复数据到 store
+pub fn restore_from_file(
+    store: &impl Storage,
+    path: impl AsRef<Path>,
+    codec: Option<&dyn ValueCodec>,
+) -> Result<usize, KvError> {
+    let path = path.as_ref();
+    let file = File::open(path)?;
+    // 一个 frame 不可能比整个文件还大，这样损坏的长度不会导致分配大量的内存
+    let max_len = file.metadata()?.len().min(MAX_FRAME as u64) as usize;
+    let count = restore_with(store, BufReader::new(file), codec, max_len)?;
+    info!("Restored {} pairs from {}", count, path.display());
+    Ok(count)
+}
+
+/// 把客户端给出的 path 解析成 dir 下的文件，不允许绝对路径和 ".."，
+/// 避免通过 Backup/Restore 命令读写服务器上任意的文件
+pub(super) fn resolve_backup_path(dir: &Path, path: &str) -> Result<PathBuf, KvError> {
+    let relative = Path::new(path);
+    let valid = relative
+        .components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+    if !valid || relative.file_name().is_none() {
+        return Err(KvError::InvalidCommand(format!(
+            "backup path {:?} must be a relative file path without \"..\"",
+            path
+        )));
+    }
+    Ok(dir.join(relative))
+}
+
+/// 用 codec 编码 record 中所有的 value
+fn encode_record(codec: &dyn ValueCodec, record: BackupRecord) -> Result<BackupRecord, KvError> {
+    let pairs = record
+        .pairs
+        .into_iter()
+        .map(|pair| {
+            let value = pair.value.unwrap_or_default();
+            let value = codec.encode(&record.table, &pair.key, &value)?;
+            Ok(Kvpair::new(pair.key, value))
+        })
+        .collect::<Result<_, KvError>>()?;
+    Ok(BackupRecord {
+        table: record.table,
+        pairs,
+    })
+}
+
+/// 读取一个完整的 frame，读到文件末尾时返回 None，payload 超过 max_len 时返回错误
+fn read_record(
+    reader: &mut impl Read,
+    buf: &mut BytesMut,
+    max_len: usize,
+) -> Result<Option<BackupRecord>, KvError> {
+    let mut header = [0u8; LEN_LEN];
+    match reader.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let len = payload_len(u32::from_be_bytes(header) as usize, max_len)?;
+
+    buf.clear();
+    buf.put_slice(&header);
+    // 按实际读到的数据增长 buf，而不是按长度一次分配好
+    let read = io::copy(&mut reader.take(len as u64), &mut (&mut *buf).writer())?;
+    if read < len as u64 {
+        return Err(io::Error::from(ErrorKind::UnexpectedEof).into());
+    }
+    Ok(Some(BackupRecord::decode_frame(buf)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Keyring, MemTable, SledDb, Value};
+    use tempfile::tempdir;
+
+    #[test]
+    fn backup_and_restore_should_work() {
+        let store = MemTable::new();
+        for i in 0..BATCH_SIZE + 1 {
+            store
+                .set("t1", format!("k{}", i), (i as i64).into())
+                .unwrap();
+        }
+        store.set("t2", "k1".into(), "v1".into()).unwrap();
+
+        let mut data = Vec::new();
+        assert_eq!(backup(&store, &mut data, None).unwrap(), BATCH_SIZE + 2);
+
+        // 可以恢复到另一种 Storage 中
+        let dir = tempdir().unwrap();
+        let db = SledDb::new(dir.path()).unwrap();
+        assert_eq!(restore(&db, &data[..], None).unwrap(), BATCH_SIZE + 2);
+        assert_eq!(db.get_all("t1").unwrap().len(), BATCH_SIZE + 1);
+        assert_eq!(
+            db.get_all("t2").unwrap(),
+            vec![Kvpair::new("k1", "v1".into())]
+        );
+    }
+
+    #[test]
+    fn resolve_backup_path_should_stay_in_dir() {
+        let dir = Path::new("/var/backup");
+        assert_eq!(
+            resolve_backup_path(dir, "daily/b1").unwrap(),
+            dir.join("daily/b1")
+        );
+        for path in ["../x", "a/../../x", "/etc/x", "", "."] {
+            let result = resolve_backup_path(dir, path);
+            assert!(
+                matches!(result, Err(KvError::InvalidCommand(_))),
+                "{}",
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn restore_with_truncated_data_should_fail() {
+        let store = MemTable::new();
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        let mut data = Vec::new();
+        backup(&store, &mut data, None).unwrap();
+
+        let store = MemTable::new();
+        assert!(restore(&store, &data[..data.len() - 1], None).is_err());
+    }
+
+    #[test]
+    fn restore_with_corrupted_length_should_fail() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("backup");
+        let mut data = (MAX_FRAME as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(b"garbage");
+        fs::write(&path, data).unwrap();
+
+        let store = MemTable::new();
+        let result = restore_from_file(&store, &path, None);
+        assert!(matches!(result, Err(KvError::FrameTooLarge(len, 11)) if len == MAX_FRAME));
+    }
+
+    #[test]
+    fn backup_with_codec_should_not_store_plaintext() {
+        let store = MemTable::new();
+        store.set("t1", "k1".into(), "secret".into()).unwrap();
+        let mut keyring = Keyring::new();
+        keyring.add(1, &[7; 32]).unwrap();
+
+        let mut data = Vec::new();
+        backup(&store, &mut data, Some(&keyring)).unwrap();
+        assert!(!data.windows(6).any(|w| w == b"secret"));
+
+        let store = MemTable::new();
+        restore(&store, &data[..], Some(&keyring)).unwrap();
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("secret".into()));
+        // 没有 keyring 时恢复出来的是密文
+        let store = MemTable::new();
+        restore(&store, &data[..], None).unwrap();
+        let value: Value = store.get("t1", "k1").unwrap().unwrap();
+        assert_ne!(value, "secret".into());
+    }
+}
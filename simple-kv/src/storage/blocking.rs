@@ -0,0 +1,359 @@
+use crate::{
+    backup_to_file, restore_from_file, AsyncStorage, Chunk, KvError, Kvpair, QuotaUsage, Storage,
+    StorageStats, TableStats, TxnStorage, Value, ValueCodec,
+};
+use async_trait::async_trait;
+use std::{cell::Cell, fs, future::Future, path::PathBuf, sync::Arc, time::Duration};
+
+use super::{
+    backup::resolve_backup_path,
+    chunk::{purge_stale_chunks, CHUNK_TABLE, CHUNK_UPLOAD_TIMEOUT},
+    mvcc::take_written_version,
+};
+
+tokio::task_local! {
+    // 当前请求中的写操作产生的最大的版本号
+    static WRITTEN_VERSION: Cell<u64>;
+}
+
+/// 执行 f，同时返回 f 中通过 BlockingStorage 执行的写操作产生的最大的版本号，
+/// 没有产生新的版本（比如只读的命令）时返回 0
+pub(crate) async fn with_written_version<F: Future>(f: F) -> (F::Output, u64) {
+    WRITTEN_VERSION
+        .scope(Cell::new(0), async {
+            let output = f.await;
+            (output, WRITTEN_VERSION.with(|v| v.get()))
+        })
+        .await
+}
+
+/// 把同步的 Storage 放到 tokio 的 blocking 线程池中执行，对外提供 AsyncStorage
+/// sled 刷盘、WAL fsync 这样的慢操作就不会阻塞处理其它连接的 worker 线程
+pub struct BlockingStorage<S> {
+    store: Arc<S>,
+    // backup/restore 的 path 都是这个目录下的相对路径，没有设置时不允许 backup/restore
+    backup_dir: Option<PathBuf>,
+    // 备份文件中的 value 使用的 codec，开启了加密时应该设置成 Keyring，避免备份中出现明文
+    backup_codec: Option<Arc<dyn ValueCodec>>,
+    // 分块上传的 value 最大的大小，0 表示没有限制
+    max_value_size: usize,
+}
+
+impl<S: Storage> BlockingStorage<S> {
+    pub fn new(store: S) -> Self {
+        Self {
+            store: Arc::new(store),
+            backup_dir: None,
+            backup_codec: None,
+            max_value_size: 0,
+        }
+    }
+
+    /// 设置 backup/restore 使用的目录，不设置的话 backup/restore 会返回错误
+    pub fn with_backup_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.backup_dir = Some(dir.into());
+        self
+    }
+
+    /// 设置 backup/restore 时用来编码 value 的 codec
+    pub fn with_backup_codec(mut self, codec: impl ValueCodec) -> Self {
+        self.backup_codec = Some(Arc::new(codec));
+        self
+    }
+
+    /// 设置分块上传的 value 最大的大小，超过的上传在第一块就会被拒绝
+    pub fn with_max_value_size(mut self, size: usize) -> Self {
+        self.max_value_size = size;
+        self
+    }
+
+    /// 把 backup/restore 的 path 解析成备份目录下的文件
+    fn backup_path(&self, path: &str) -> Result<PathBuf, KvError> {
+        let dir = self
+            .backup_dir
+            .as_deref()
+            .ok_or_else(|| KvError::InvalidCommand("backup dir is not configured".into()))?;
+        resolve_backup_path(dir, path)
+    }
+
+    /// 获取内部的同步 Storage
+    pub fn inner(&self) -> &S {
+        &self.store
+    }
+
+    /// 在 blocking 线程池中执行 f
+    async fn run<T, F>(&self, f: F) -> Result<T, KvError>
+    where
+        F: FnOnce(&S) -> Result<T, KvError> + Send + 'static,
+        T: Send + 'static,
+    {
+        let store = Arc::clone(&self.store);
+        let (result, version) = tokio::task::spawn_blocking(move || {
+            // 清掉这个线程上之前的任务留下的版本号
+            take_written_version();
+            let result = f(&store);
+            (result, take_written_version())
+        })
+        .await
+        .map_err(|e| KvError::Internal(e.to_string()))?;
+        if version > 0 {
+            let _ = WRITTEN_VERSION.try_with(|v| v.set(v.get().max(version)));
+        }
+        result
+    }
+}
+
+impl<S> Clone for BlockingStorage<S> {
+    fn clone(&self) -> Self {
+        Self {
+            store: Arc::clone(&self.store),
+            backup_dir: self.backup_dir.clone(),
+            backup_codec: self.backup_codec.clone(),
+            max_value_size: self.max_value_size,
+        }
+    }
+}
+
+impl<S: Storage> From<S> for BlockingStorage<S> {
+    fn from(store: S) -> Self {
+        Self::new(store)
+    }
+}
+
+#[async_trait]
+impl<S: Storage> AsyncStorage for BlockingStorage<S> {
+    async fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        let (table, key) = (table.to_owned(), key.to_owned());
+        self.run(move |s| s.get(&table, &key)).await
+    }
+
+    async fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        let table = table.to_owned();
+        self.run(move |s| s.set(&table, key, value)).await
+    }
+
+    async fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        let (table, key) = (table.to_owned(), key.to_owned());
+        self.run(move |s| s.contains(&table, &key)).await
+    }
+
+    async fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        let (table, key) = (table.to_owned(), key.to_owned());
+        self.run(move |s| s.del(&table, &key)).await
+    }
+
+    async fn compare_and_swap(
+        &self,
+        table: &str,
+        key: String,
+        expected: Option<Value>,
+        value: Value,
+    ) -> Result<bool, KvError> {
+        let table = table.to_owned();
+        self.run(move |s| s.compare_and_swap(&table, key, expected, value))
+            .await
+    }
+
+    async fn set_if_present(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+    ) -> Result<Option<Value>, KvError> {
+        let table = table.to_owned();
+        self.run(move |s| s.set_if_present(&table, key, value))
+            .await
+    }
+
+    async fn incr(&self, table: &str, key: &str, delta: i64) -> Result<i64, KvError> {
+        let (table, key) = (table.to_owned(), key.to_owned());
+        self.run(move |s| s.incr(&table, &key, delta)).await
+    }
+
+    async fn incr_float(&self, table: &str, key: &str, delta: f64) -> Result<f64, KvError> {
+        let (table, key) = (table.to_owned(), key.to_owned());
+        self.run(move |s| s.incr_float(&table, &key, delta)).await
+    }
+
+    async fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        let table = table.to_owned();
+        self.run(move |s| s.get_all(&table)).await
+    }
+
+    async fn get_range(
+        &self,
+        table: &str,
+        start: &str,
+        end: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        let (table, start) = (table.to_owned(), start.to_owned());
+        let end = end.map(|v| v.to_owned());
+        self.run(move |s| s.get_range(&table, &start, end.as_deref(), limit))
+            .await
+    }
+
+    async fn set_with_ttl(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+        ttl: Duration,
+    ) -> Result<Option<Value>, KvError> {
+        let table = table.to_owned();
+        self.run(move |s| s.set_with_ttl(&table, key, value, ttl))
+            .await
+    }
+
+    async fn expire(&self, table: &str, key: &str, ttl: Duration) -> Result<bool, KvError> {
+        let (table, key) = (table.to_owned(), key.to_owned());
+        self.run(move |s| s.expire(&table, &key, ttl)).await
+    }
+
+    async fn ttl(&self, table: &str, key: &str) -> Result<Option<Duration>, KvError> {
+        let (table, key) = (table.to_owned(), key.to_owned());
+        self.run(move |s| s.ttl(&table, &key)).await
+    }
+
+    async fn persist(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        let (table, key) = (table.to_owned(), key.to_owned());
+        self.run(move |s| s.persist(&table, &key)).await
+    }
+
+    async fn purge_expired(&self) -> Result<usize, KvError> {
+        // 顺便清理没有完成、也很久没有收到新的块的上传
+        self.run(|s| Ok(s.purge_expired()? + purge_stale_chunks(s, CHUNK_UPLOAD_TIMEOUT)?))
+            .await
+    }
+
+    async fn list_tables(&self) -> Result<Vec<String>, KvError> {
+        let mut tables = self.run(|s| s.list_tables()).await?;
+        tables.retain(|t| t != CHUNK_TABLE);
+        Ok(tables)
+    }
+
+    async fn table_info(&self, table: &str) -> Result<Option<TableStats>, KvError> {
+        let table = table.to_owned();
+        self.run(move |s| s.table_info(&table)).await
+    }
+
+    async fn drop_table(&self, table: &str) -> Result<bool, KvError> {
+        let table = table.to_owned();
+        self.run(move |s| s.drop_table(&table)).await
+    }
+
+    async fn rename_table(&self, table: &str, to: &str) -> Result<bool, KvError> {
+        let (table, to) = (table.to_owned(), to.to_owned());
+        self.run(move |s| s.rename_table(&table, &to)).await
+    }
+
+    async fn stats(&self) -> Result<StorageStats, KvError> {
+        self.run(|s| s.stats()).await
+    }
+
+    async fn backup(&self, path: &str) -> Result<usize, KvError> {
+        let path = self.backup_path(path)?;
+        let codec = self.backup_codec.clone();
+        self.run(move |s| {
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            backup_to_file(s, path, codec.as_deref())
+        })
+        .await
+    }
+
+    async fn restore(&self, path: &str) -> Result<usize, KvError> {
+        let path = self.backup_path(path)?;
+        let codec = self.backup_codec.clone();
+        self.run(move |s| restore_from_file(s, path, codec.as_deref()))
+            .await
+    }
+
+    async fn transaction<T, F>(&self, f: F) -> Result<T, KvError>
+    where
+        F: Fn(&dyn TxnStorage) -> Result<T, KvError> + Send + 'static,
+        T: Send + 'static,
+    {
+        self.run(move |s| s.transaction(f)).await
+    }
+
+    async fn version(&self) -> Result<u64, KvError> {
+        // 读取版本号很快，不需要放到 blocking 线程池中
+        self.store.version()
+    }
+
+    async fn get_at(&self, table: &str, key: &str, version: u64) -> Result<Option<Value>, KvError> {
+        let (table, key) = (table.to_owned(), key.to_owned());
+        self.run(move |s| s.get_at(&table, &key, version)).await
+    }
+
+    async fn get_all_at(&self, table: &str, version: u64) -> Result<Vec<Kvpair>, KvError> {
+        let table = table.to_owned();
+        self.run(move |s| s.get_all_at(&table, version)).await
+    }
+
+    async fn snapshot(&self) -> Result<u64, KvError> {
+        self.run(|s| s.snapshot()).await
+    }
+
+    async fn release_snapshot(&self, version: u64) -> Result<bool, KvError> {
+        self.run(move |s| s.release_snapshot(version)).await
+    }
+
+    async fn purge_versions(&self) -> Result<usize, KvError> {
+        self.run(|s| s.purge_versions()).await
+    }
+
+    async fn find(&self, table: &str, index: &str, value: Value) -> Result<Vec<Kvpair>, KvError> {
+        let (table, index) = (table.to_owned(), index.to_owned());
+        self.run(move |s| s.find(&table, &index, &value)).await
+    }
+
+    async fn quota(&self, table: &str) -> Result<Option<QuotaUsage>, KvError> {
+        let table = table.to_owned();
+        self.run(move |s| s.quota(&table)).await
+    }
+
+    async fn set_chunk(
+        &self,
+        table: &str,
+        key: &str,
+        chunk: Chunk,
+    ) -> Result<Option<Value>, KvError> {
+        if self.max_value_size > 0 && chunk.total > self.max_value_size as u64 {
+            return Err(KvError::QuotaExceeded(format!(
+                "value of {} bytes exceeds the max value size {}",
+                chunk.total, self.max_value_size
+            )));
+        }
+        let (table, key) = (table.to_owned(), key.to_owned());
+        self.run(move |s| s.set_chunk(&table, &key, &chunk)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MemTable;
+
+    #[tokio::test]
+    async fn blocking_storage_should_work() {
+        let store = BlockingStorage::new(MemTable::new());
+        let v = store.set("t1", "k1".into(), "v1".into()).await.unwrap();
+        assert!(v.is_none());
+        let v = store.get("t1", "k1").await.unwrap();
+        assert_eq!(v, Some("v1".into()));
+
+        // 和内部的同步 Storage 看到的是同一份数据
+        let v = store.inner().get("t1", "k1").unwrap();
+        assert_eq!(v, Some("v1".into()));
+
+        let v = store
+            .transaction(|txn| txn.set("t1", "k1".into(), "v2".into()))
+            .await
+            .unwrap();
+        assert_eq!(v, Some("v1".into()));
+        assert_eq!(store.list_tables().await.unwrap(), vec!["t1".to_string()]);
+    }
+}
@@ -0,0 +1,381 @@
+use crate::{value, KvError, Kvpair, ScoredMember, SortedSet, Storage, Value, ValueList, ValueSet};
+use serde::{Deserialize, Serialize};
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    str::FromStr,
+};
+
+/// 批量导入导出 table 时使用的文件格式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataFormat {
+    /// 每行一个 JSON 对象：{"key": "k1", "type": "string", "value": "v1"}
+    /// 列表的 value 是 {"type", "value"} 的数组，集合是字符串的数组，
+    /// 有序集合是 {"member", "score"} 的数组
+    JsonLines,
+    /// 带表头的 CSV，三列分别为 key、type 和 value，集合类型的 value 和 JSON Lines 中一样
+    Csv,
+}
+
+impl FromStr for DataFormat {
+    type Err = KvError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "jsonl" | "json" => Ok(Self::JsonLines),
+            "csv" => Ok(Self::Csv),
+            _ => Err(KvError::InvalidCommand(format!(
+                "Unknown data format: {}",
+                s
+            ))),
+        }
+    }
+}
+
+/// value 的类型，这样导出再导入之后 value 的类型不会变（比如 1.0 不会变成整数 1）
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ValueType {
+    Null,
+    String,
+    Binary,
+    Integer,
+    Float,
+    Bool,
+    List,
+    Set,
+    SortedSet,
+}
+
+/// 列表中的元素
+#[derive(Debug, Deserialize)]
+struct TypedValue {
+    #[serde(rename = "type")]
+    kind: ValueType,
+    #[serde(default)]
+    value: serde_json::Value,
+}
+
+/// 有序集合中的成员
+#[derive(Debug, Deserialize)]
+struct MemberRecord {
+    member: String,
+    score: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct JsonRecord {
+    key: String,
+    #[serde(rename = "type")]
+    kind: ValueType,
+    #[serde(default)]
+    value: serde_json::Value,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CsvRecord {
+    key: String,
+    #[serde(rename = "type")]
+    kind: ValueType,
+    value: String,
+}
+
+/// 把 table 中所有的 kv pair 按 format 写入 writer，返回写入的 kv pair 个数
+/// 写入的是从 store 读出的 value，store 开启了加密时导出的文件也是明文
+pub fn export_table(
+    store: &impl Storage,
+    table: &str,
+    format: DataFormat,
+    mut writer: impl Write,
+) -> Result<usize, KvError> {
+    let mut count = 0;
+    match format {
+        DataFormat::JsonLines => {
+            for pair in store.get_iter(table)? {
+                let (kind, value) = to_json(pair.value.unwrap_or_default());
+                let record = JsonRecord {
+                    key: pair.key,
+                    kind,
+                    value,
+                };
+                serde_json::to_writer(&mut writer, &record).map_err(io::Error::from)?;
+                writer.write_all(b"\n")?;
+                count += 1;
+            }
+            writer.flush()?;
+        }
+        DataFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(writer);
+            for pair in store.get_iter(table)? {
+                let (kind, value) = to_text(pair.value.unwrap_or_default());
+                let record = CsvRecord {
+                    key: pair.key,
+                    kind,
+                    value,
+                };
+                writer.serialize(record).map_err(io::Error::from)?;
+                count += 1;
+            }
+            writer.flush()?;
+        }
+    }
+    Ok(count)
+}
+
+/// 从 reader 中按 format 读取 kv pair 写入 table，已有的 key 会被覆盖，返回写入的 kv pair 个数
+/// 所有数据都解析成功之后才会写入，任何一行有错误都不会写入任何数据
+pub fn import_table(
+    store: &impl Storage,
+    table: &str,
+    format: DataFormat,
+    reader: impl Read,
+) -> Result<usize, KvError> {
+    let pairs = match format {
+        DataFormat::JsonLines => parse_json_lines(reader)?,
+        DataFormat::Csv => parse_csv(reader)?,
+    };
+    let count = pairs.len();
+    for pair in pairs {
+        store.set(table, pair.key, pair.value.unwrap_or_default())?;
+    }
+    Ok(count)
+}
+
+fn parse_json_lines(reader: impl Read) -> Result<Vec<Kvpair>, KvError> {
+    let mut pairs = Vec::new();
+    for (i, line) in BufReader::new(reader).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record: JsonRecord =
+            serde_json::from_str(&line).map_err(|e| KvError::ImportError(i + 1, e.to_string()))?;
+        let value =
+            from_json(record.kind, record.value).map_err(|e| KvError::ImportError(i + 1, e))?;
+        pairs.push(Kvpair::new(record.key, value));
+    }
+    Ok(pairs)
+}
+
+fn parse_csv(reader: impl Read) -> Result<Vec<Kvpair>, KvError> {
+    let mut reader = csv::Reader::from_reader(reader);
+    let headers = reader.headers().map_err(csv_error)?.clone();
+    let mut pairs = Vec::new();
+    for record in reader.records() {
+        let record = record.map_err(csv_error)?;
+        let line = record.position().map_or(0, |p| p.line() as usize);
+        let record: CsvRecord = record
+            .deserialize(Some(&headers))
+            .map_err(|e| KvError::ImportError(line, e.to_string()))?;
+        let value =
+            from_text(record.kind, &record.value).map_err(|e| KvError::ImportError(line, e))?;
+        pairs.push(Kvpair::new(record.key, value));
+    }
+    Ok(pairs)
+}
+
+fn csv_error(e: csv::Error) -> KvError {
+    let line = e.position().map_or(0, |p| p.line() as usize);
+    KvError::ImportError(line, e.to_string())
+}
+
+fn to_json(value: Value) -> (ValueType, serde_json::Value) {
+    use serde_json::Value as Json;
+    match value.value {
+        None => (ValueType::Null, Json::Null),
+        Some(value::Value::String(s)) => (ValueType::String, Json::String(s)),
+        Some(value::Value::Binary(b)) => (ValueType::Binary, Json::String(base64::encode(b))),
+        Some(value::Value::Integer(i)) => (ValueType::Integer, i.into()),
+        // JSON 不支持 NaN 和 inf，这时用字符串表示
+        Some(value::Value::Float(f)) => match serde_json::Number::from_f64(f) {
+            Some(n) => (ValueType::Float, Json::Number(n)),
+            None => (ValueType::Float, Json::String(f.to_string())),
+        },
+        Some(value::Value::Bool(b)) => (ValueType::Bool, Json::Bool(b)),
+        Some(value::Value::List(list)) => {
+            let values = list.values.into_iter().map(|v| {
+                let (kind, value) = to_json(v);
+                serde_json::json!({ "type": kind, "value": value })
+            });
+            (ValueType::List, values.collect())
+        }
+        Some(value::Value::Set(set)) => (ValueType::Set, set.members.into()),
+        Some(value::Value::SortedSet(set)) => {
+            let members = set
+                .members
+                .into_iter()
+                .map(|m| serde_json::json!({ "member": m.member, "score": m.score }));
+            (ValueType::SortedSet, members.collect())
+        }
+    }
+}
+
+fn from_json(kind: ValueType, json: serde_json::Value) -> Result<Value, String> {
+    use serde_json::Value as Json;
+    let value = match (kind, json) {
+        (ValueType::Null, Json::Null) => None,
+        (ValueType::String, Json::String(s)) => Some(value::Value::String(s)),
+        (ValueType::Binary, Json::String(s)) => Some(value::Value::Binary(decode_base64(&s)?)),
+        (ValueType::Integer, Json::Number(n)) => match n.as_i64() {
+            Some(i) => Some(value::Value::Integer(i)),
+            None => return Err(format!("{} is not a valid integer", n)),
+        },
+        (ValueType::Float, Json::Number(n)) => n.as_f64().map(value::Value::Float),
+        (ValueType::Float, Json::String(s)) => Some(value::Value::Float(parse(&s)?)),
+        (ValueType::Bool, Json::Bool(b)) => Some(value::Value::Bool(b)),
+        (ValueType::List, Json::Array(items)) => {
+            let mut list = ValueList::default();
+            for item in items {
+                let item: TypedValue = serde_json::from_value(item).map_err(|e| e.to_string())?;
+                list.values.push(from_json(item.kind, item.value)?);
+            }
+            Some(value::Value::List(list))
+        }
+        (ValueType::Set, Json::Array(items)) => {
+            let mut set = ValueSet::default();
+            for item in items {
+                match item {
+                    Json::String(s) => set.insert(s),
+                    item => return Err(format!("{} is not a valid set member", item)),
+                };
+            }
+            Some(value::Value::Set(set))
+        }
+        (ValueType::SortedSet, Json::Array(items)) => {
+            let mut set = SortedSet::default();
+            for item in items {
+                let m: MemberRecord = serde_json::from_value(item).map_err(|e| e.to_string())?;
+                set.insert(ScoredMember::new(m.member, m.score));
+            }
+            Some(value::Value::SortedSet(set))
+        }
+        (kind, json) => return Err(format!("{} is not a valid {:?} value", json, kind)),
+    };
+    Ok(Value { value })
+}
+
+fn to_text(value: Value) -> (ValueType, String) {
+    match value.value {
+        None => (ValueType::Null, String::new()),
+        Some(value::Value::String(s)) => (ValueType::String, s),
+        Some(value::Value::Binary(b)) => (ValueType::Binary, base64::encode(b)),
+        Some(value::Value::Integer(i)) => (ValueType::Integer, i.to_string()),
+        // f64 的 Display 输出的是能还原出同一个 f64 的最短表示
+        Some(value::Value::Float(f)) => (ValueType::Float, f.to_string()),
+        Some(value::Value::Bool(b)) => (ValueType::Bool, b.to_string()),
+        // 集合类型用和 JSON Lines 中一样的 JSON 表示
+        value => {
+            let (kind, json) = to_json(Value { value });
+            (kind, json.to_string())
+        }
+    }
+}
+
+fn from_text(kind: ValueType, text: &str) -> Result<Value, String> {
+    let value = match kind {
+        ValueType::Null if text.is_empty() => None,
+        ValueType::Null => return Err(format!("{:?} is not a valid null value", text)),
+        ValueType::String => Some(value::Value::String(text.into())),
+        ValueType::Binary => Some(value::Value::Binary(decode_base64(text)?)),
+        ValueType::Integer => Some(value::Value::Integer(parse(text)?)),
+        ValueType::Float => Some(value::Value::Float(parse(text)?)),
+        ValueType::Bool => Some(value::Value::Bool(parse(text)?)),
+        ValueType::List | ValueType::Set | ValueType::SortedSet => {
+            let json = serde_json::from_str(text).map_err(|e| e.to_string())?;
+            return from_json(kind, json);
+        }
+    };
+    Ok(Value { value })
+}
+
+fn decode_base64(text: &str) -> Result<bytes::Bytes, String> {
+    base64::decode(text)
+        .map(Into::into)
+        .map_err(|e| format!("{:?} is not valid base64: {}", text, e))
+}
+
+fn parse<T>(text: &str) -> Result<T, String>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    text.parse()
+        .map_err(|e| format!("{:?} is not a valid value: {}", text, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MemTable;
+    use bytes::Bytes;
+
+    fn values() -> Vec<Kvpair> {
+        let list = ValueList {
+            values: vec!["a,\"b\"".into(), 1.into(), Value::default()],
+        };
+        let mut set = ValueSet::default();
+        set.insert("m1".into());
+        set.insert("m0".into());
+        let mut sorted_set = SortedSet::default();
+        sorted_set.insert(ScoredMember::new("m1", 1.5));
+        sorted_set.insert(ScoredMember::new("m0", 2.0));
+        vec![
+            Kvpair::new("k1", "hello, \"world\"\n".into()),
+            Kvpair::new("k2", Bytes::from_static(b"\x00\xff").into()),
+            Kvpair::new("k3", i64::MIN.into()),
+            Kvpair::new("k4", 1.0.into()),
+            Kvpair::new("k5", f64::INFINITY.into()),
+            Kvpair::new("k6", true.into()),
+            Kvpair::new("k7", Value::default()),
+            Kvpair::new("k8", list.into()),
+            Kvpair::new("k9", set.into()),
+            Kvpair::new("ka", sorted_set.into()),
+        ]
+    }
+
+    fn test_roundtrip(format: DataFormat) {
+        let store = MemTable::new();
+        for pair in values() {
+            store.set("t1", pair.key, pair.value.unwrap()).unwrap();
+        }
+        let mut data = Vec::new();
+        assert_eq!(export_table(&store, "t1", format, &mut data).unwrap(), 10);
+
+        let store = MemTable::new();
+        assert_eq!(import_table(&store, "t2", format, &data[..]).unwrap(), 10);
+        let mut pairs = store.get_all("t2").unwrap();
+        pairs.sort_by(|a, b| a.key.cmp(&b.key));
+        assert_eq!(pairs, values());
+    }
+
+    #[test]
+    fn json_lines_roundtrip_should_keep_value_types() {
+        test_roundtrip(DataFormat::JsonLines);
+    }
+
+    #[test]
+    fn csv_roundtrip_should_keep_value_types() {
+        test_roundtrip(DataFormat::Csv);
+    }
+
+    #[test]
+    fn import_errors_should_report_line_number() {
+        let store = MemTable::new();
+        let data = concat!(
+            "{\"key\":\"k1\",\"type\":\"integer\",\"value\":1}\n",
+            "\n",
+            "{\"key\":\"k2\",\"type\":\"integer\",\"value\":\"a\"}\n",
+        );
+        let result = import_table(&store, "t1", DataFormat::JsonLines, data.as_bytes());
+        assert!(matches!(result, Err(KvError::ImportError(3, _))));
+        // 有错误时不会写入任何数据
+        assert!(!store.contains("t1", "k1").unwrap());
+
+        let data = "key,type,value\nk1,integer,1\nk2,bool,yes\n";
+        let result = import_table(&store, "t1", DataFormat::Csv, data.as_bytes());
+        assert!(matches!(result, Err(KvError::ImportError(3, _))));
+
+        let data = "key,type,value\nk1,unknown,1\n";
+        let result = import_table(&store, "t1", DataFormat::Csv, data.as_bytes());
+        assert!(matches!(result, Err(KvError::ImportError(2, _))));
+    }
+}
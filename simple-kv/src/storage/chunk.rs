@@ -0,0 +1,234 @@
+use bytes::{BufMut, Bytes, BytesMut};
+use std::{convert::TryFrom, time::Duration};
+
+use crate::{value, Chunk, KvError, Value};
+
+use super::{now_millis, Storage, TxnStorage};
+
+/// 暂存上传中的块的 table，不会出现在 list_tables 和备份中
+///
+/// `table\0key` 中保存已经收到的字节数和最后一次收到块的时间，
+/// `table\0key\0offset` 中保存每一块的数据
+pub(super) const CHUNK_TABLE: &str = "__chunks__";
+
+/// 没有完成的上传超过这个时间没有收到新的块，暂存的块会被后台任务清理掉
+pub(super) const CHUNK_UPLOAD_TIMEOUT: Duration = Duration::from_secs(3600);
+
+/// 把 value 的一块写入暂存区，最后一块到达之后拼出整个 value 写入 table，返回之前的 value
+///
+/// 暂存的块和最终的 value 一样受 table 配额中 max_value_size 和 max_bytes 的限制
+pub(super) fn set_chunk<S>(
+    store: &S,
+    table: &str,
+    key: &str,
+    chunk: &Chunk,
+) -> Result<Option<Value>, KvError>
+where
+    S: Storage + ?Sized,
+{
+    let end = chunk.offset + chunk.data.len() as u64;
+    if end > chunk.total {
+        return Err(KvError::InvalidCommand(format!(
+            "Chunk at offset {} exceeds the total size {}",
+            chunk.offset, chunk.total
+        )));
+    }
+    check_quota(store, table, key, chunk.total)?;
+
+    let upload = format!("{}\0{}", table, key);
+    store.transaction(|txn| {
+        let received = match txn.get(CHUNK_TABLE, &upload)? {
+            Some(v) => parse_progress(v)?.0,
+            None => 0,
+        };
+        if chunk.offset == 0 {
+            // 重新开始上传，丢弃之前没有完成的块
+            take_chunks(txn, &upload, received)?;
+        } else if chunk.offset != received {
+            return Err(KvError::InvalidCommand(format!(
+                "Expected chunk at offset {}, got {}",
+                received, chunk.offset
+            )));
+        }
+
+        if end < chunk.total {
+            let data = Value::from(chunk.data.clone());
+            txn.set(CHUNK_TABLE, chunk_key(&upload, chunk.offset), data)?;
+            txn.set(CHUNK_TABLE, upload.clone(), progress(end, now_millis()))?;
+            return Ok(None);
+        }
+
+        // 最后一块到了，拼出整个 value
+        let mut data = take_chunks(txn, &upload, chunk.offset)?;
+        data.extend_from_slice(&chunk.data);
+        txn.del(CHUNK_TABLE, &upload)?;
+        txn.set(table, key.into(), Value::try_from(&data[..])?)
+    })
+}
+
+/// 删除超过 timeout 没有收到新的块的上传，返回删除的上传的个数
+pub(super) fn purge_stale_chunks<S>(store: &S, timeout: Duration) -> Result<usize, KvError>
+where
+    S: Storage + ?Sized,
+{
+    let deadline = now_millis().saturating_sub(timeout.as_millis() as u64);
+    let is_stale = |v: Value| matches!(parse_progress(v), Ok((_, updated)) if updated < deadline);
+    let stale: Vec<String> = store
+        .get_iter(CHUNK_TABLE)?
+        .filter(|pair| !is_chunk_key(&pair.key))
+        .filter(|pair| is_stale(pair.value.clone().unwrap_or_default()))
+        .map(|pair| pair.key)
+        .collect();
+
+    let mut count = 0;
+    for upload in stale {
+        let purged = store.transaction(|txn| {
+            // 在这期间可能又收到了新的块
+            let received = match txn.get(CHUNK_TABLE, &upload)? {
+                Some(v) if is_stale(v.clone()) => parse_progress(v)?.0,
+                _ => return Ok(false),
+            };
+            take_chunks(txn, &upload, received)?;
+            txn.del(CHUNK_TABLE, &upload)?;
+            Ok(true)
+        })?;
+        count += purged as usize;
+    }
+    Ok(count)
+}
+
+/// 上传开始之前按 total 检查 table 的配额，这样暂存的块不会绕过配额
+fn check_quota<S>(store: &S, table: &str, key: &str, total: u64) -> Result<(), KvError>
+where
+    S: Storage + ?Sized,
+{
+    let quota = match store.quota(table)? {
+        Some(quota) => quota,
+        None => return Ok(()),
+    };
+    let total = total as usize;
+    if quota.max_value_size > 0 && total > quota.max_value_size {
+        return Err(KvError::QuotaExceeded(format!(
+            "value of {} bytes exceeds the max value size {} of table {}",
+            total, quota.max_value_size, table
+        )));
+    }
+    if quota.max_bytes > 0 && quota.bytes + key.len() + total > quota.max_bytes {
+        return Err(KvError::QuotaExceeded(format!(
+            "table {} would use {} bytes, more than the max {} bytes",
+            table,
+            quota.bytes + key.len() + total,
+            quota.max_bytes
+        )));
+    }
+    Ok(())
+}
+
+/// 按顺序取出并删除 offset 在 end 之前的所有块，返回拼起来的数据
+fn take_chunks(txn: &dyn TxnStorage, upload: &str, end: u64) -> Result<Vec<u8>, KvError> {
+    let mut data = Vec::with_capacity(end as usize);
+    while (data.len() as u64) < end {
+        let offset = data.len() as u64;
+        let chunk = txn
+            .del(CHUNK_TABLE, &chunk_key(upload, offset))?
+            .ok_or_else(|| KvError::Internal(format!("Chunk at offset {} is missing", offset)))?;
+        let chunk = Bytes::try_from(chunk)?;
+        if chunk.is_empty() {
+            return Err(KvError::Internal(format!(
+                "Chunk at offset {} is empty",
+                offset
+            )));
+        }
+        data.extend_from_slice(&chunk);
+    }
+    Ok(data)
+}
+
+/// 上传的进度：已经收到的字节数和最后一次收到块的时间（毫秒）
+fn progress(received: u64, updated: u64) -> Value {
+    let mut buf = BytesMut::with_capacity(16);
+    buf.put_u64(received);
+    buf.put_u64(updated);
+    buf.freeze().into()
+}
+
+fn parse_progress(value: Value) -> Result<(u64, u64), KvError> {
+    match value.value {
+        // 旧版本只保存了收到的字节数
+        Some(value::Value::Integer(received)) => Ok((received as u64, 0)),
+        Some(value::Value::Binary(data)) if data.len() == 16 => {
+            let (received, updated) = data.split_at(8);
+            Ok((be_u64(received), be_u64(updated)))
+        }
+        _ => Err(KvError::Internal("Invalid chunk upload progress".into())),
+    }
+}
+
+fn be_u64(data: &[u8]) -> u64 {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(data);
+    u64::from_be_bytes(buf)
+}
+
+fn chunk_key(upload: &str, offset: u64) -> String {
+    format!("{}\0{:020}", upload, offset)
+}
+
+/// 是否是保存块数据的 key，而不是上传进度的 key
+fn is_chunk_key(key: &str) -> bool {
+    let bytes = key.as_bytes();
+    bytes.len() > 21
+        && bytes[bytes.len() - 21] == 0
+        && bytes[bytes.len() - 20..].iter().all(u8::is_ascii_digit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MemTable, QuotaConfig, QuotaStorage};
+
+    fn chunk(offset: u64, total: u64) -> Chunk {
+        Chunk {
+            offset,
+            total,
+            data: Bytes::from(vec![1u8; 100]),
+        }
+    }
+
+    #[test]
+    fn chunks_should_be_checked_against_quota() {
+        let quota = QuotaConfig {
+            table: "t1".into(),
+            max_bytes: 1000,
+            max_value_size: 500,
+            ..Default::default()
+        };
+        let store = QuotaStorage::new(MemTable::new(), &[quota]);
+        let result = store.set_chunk("t1", "k1", &chunk(0, 600));
+        assert!(matches!(result, Err(KvError::QuotaExceeded(_))));
+
+        store
+            .set("t1", "k0".into(), Bytes::from(vec![0u8; 490]).into())
+            .unwrap();
+        store
+            .set("t1", "k2".into(), Bytes::from(vec![0u8; 400]).into())
+            .unwrap();
+        let result = store.set_chunk("t1", "k1", &chunk(0, 400));
+        assert!(matches!(result, Err(KvError::QuotaExceeded(_))));
+        assert_eq!(store.get_all(CHUNK_TABLE).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn stale_uploads_should_be_purged() {
+        let store = MemTable::new();
+        set_chunk(&store, "t1", "k1", &chunk(0, 1000)).unwrap();
+        set_chunk(&store, "t1", "k1", &chunk(100, 1000)).unwrap();
+        set_chunk(&store, "t1", "k2", &chunk(0, 1000)).unwrap();
+        assert_eq!(purge_stale_chunks(&store, CHUNK_UPLOAD_TIMEOUT).unwrap(), 0);
+        assert_eq!(store.get_all(CHUNK_TABLE).unwrap().len(), 5);
+
+        std::thread::sleep(Duration::from_millis(10));
+        assert_eq!(purge_stale_chunks(&store, Duration::ZERO).unwrap(), 2);
+        assert_eq!(store.get_all(CHUNK_TABLE).unwrap(), vec![]);
+    }
+}
@@ -0,0 +1,333 @@
+use crate::{KvError, Kvpair, QuotaUsage, Storage, StorageStats, TableStats, TxnStorage, Value};
+use std::{sync::Arc, time::Duration};
+use tracing::warn;
+
+use super::{add_float, add_integer};
+
+/// 在 value 写入 Storage 之前做转换（比如加密、压缩），读出之后再转换回来
+pub trait ValueCodec: Send + Sync + 'static {
+    /// 把 value 转换成写入 Storage 的形式
+    fn encode(&self, table: &str, key: &str, value: &Value) -> Result<Value, KvError>;
+    /// 把从 Storage 中读出的 value 转换回原来的 value
+    fn decode(&self, table: &str, key: &str, value: Value) -> Result<Value, KvError>;
+    /// 统计信息是否需要遍历内部 Storage 中保存的 value，返回 false 时不会调用 update_table_stats
+    fn has_table_stats(&self) -> bool {
+        false
+    }
+    /// 根据内部 Storage 中保存的一个 value，在 table 的统计信息中加上 codec 自己的统计信息
+    fn update_table_stats(
+        &self,
+        _table: &str,
+        _stored: &Value,
+        _stats: &mut TableStats,
+    ) -> Result<(), KvError> {
+        Ok(())
+    }
+}
+
+/// 用 ValueCodec 转换所有 value 的 Storage，实现了 Storage trait
+///
+/// 只转换 value，table 和 key 保持不变，这样 key 的顺序和范围扫描都不受影响。
+/// 转换之后的 value 不一定能直接比较（比如每次加密的密文都不一样），所以 incr、
+/// compare_and_swap 这样依赖 value 内容的操作，在这里转换回来计算之后，
+/// 再用内部 Storage 的 compare_and_swap 写回去
+pub struct CodecStorage<S, C> {
+    pub(super) store: S,
+    pub(super) codec: Arc<C>,
+}
+
+impl<S: Storage, C: ValueCodec> CodecStorage<S, C> {
+    pub fn new(store: S, codec: C) -> Self {
+        Self {
+            store,
+            codec: Arc::new(codec),
+        }
+    }
+
+    /// 获取内部的 Storage
+    pub fn inner(&self) -> &S {
+        &self.store
+    }
+
+    /// 获取 codec
+    pub fn codec(&self) -> &C {
+        &self.codec
+    }
+
+    /// 遍历 table 中保存的 value，让 codec 更新 table 的统计信息
+    /// 统计的是当前保存的数据，所以覆盖、删除之后也是准确的，代价是每次都要遍历 table
+    fn update_table_stats(&self, table: &str, stats: &mut TableStats) -> Result<(), KvError> {
+        if !self.codec.has_table_stats() {
+            return Ok(());
+        }
+        for pair in self.store.get_iter(table)? {
+            let value = pair.value.unwrap_or_default();
+            self.codec.update_table_stats(table, &value, stats)?;
+        }
+        Ok(())
+    }
+
+    fn decode(
+        &self,
+        table: &str,
+        key: &str,
+        value: Option<Value>,
+    ) -> Result<Option<Value>, KvError> {
+        value.map(|v| self.codec.decode(table, key, v)).transpose()
+    }
+
+    fn decode_pairs(&self, table: &str, pairs: Vec<Kvpair>) -> Result<Vec<Kvpair>, KvError> {
+        pairs
+            .into_iter()
+            .map(|pair| {
+                let value = self.decode(table, &pair.key, pair.value)?;
+                Ok(Kvpair::new(pair.key, value.unwrap_or_default()))
+            })
+            .collect()
+    }
+
+    /// 转换回 key 当前的 value，用 f 计算出新的 value，转换之后用 compare_and_swap 写回去，
+    /// 期间 value 被别人修改了就重试。写回之后恢复 key 原来的过期时间
+    fn update(
+        &self,
+        table: &str,
+        key: &str,
+        f: impl Fn(Option<&Value>) -> Result<Value, KvError>,
+    ) -> Result<Value, KvError> {
+        loop {
+            let raw = self.store.get(table, key)?;
+            let old = self.decode(table, key, raw.clone())?;
+            let value = f(old.as_ref())?;
+            let ttl = match raw {
+                Some(_) => self.store.ttl(table, key)?,
+                None => None,
+            };
+            let encoded = self.codec.encode(table, key, &value)?;
+            if self
+                .store
+                .compare_and_swap(table, key.into(), raw, encoded)?
+            {
+                if let Some(ttl) = ttl {
+                    self.store.expire(table, key, ttl)?;
+                }
+                return Ok(value);
+            }
+        }
+    }
+}
+
+impl<S: Storage, C: ValueCodec> Storage for CodecStorage<S, C> {
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.decode(table, key, self.store.get(table, key)?)
+    }
+
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        let encoded = self.codec.encode(table, &key, &value)?;
+        let old = self.store.set(table, key.clone(), encoded)?;
+        self.decode(table, &key, old)
+    }
+
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.store.contains(table, key)
+    }
+
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.decode(table, key, self.store.del(table, key)?)
+    }
+
+    fn compare_and_swap(
+        &self,
+        table: &str,
+        key: String,
+        expected: Option<Value>,
+        value: Value,
+    ) -> Result<bool, KvError> {
+        let encoded = self.codec.encode(table, &key, &value)?;
+        let expected = match expected {
+            Some(expected) => expected,
+            None => return self.store.compare_and_swap(table, key, None, encoded),
+        };
+        // 先转换回来比较，再用读到的 value 做 compare_and_swap
+        loop {
+            let raw = self.store.get(table, &key)?;
+            if self.decode(table, &key, raw.clone())?.as_ref() != Some(&expected) {
+                return Ok(false);
+            }
+            let swapped = self
+                .store
+                .compare_and_swap(table, key.clone(), raw, encoded.clone())?;
+            if swapped {
+                return Ok(true);
+            }
+        }
+    }
+
+    fn set_if_present(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+    ) -> Result<Option<Value>, KvError> {
+        let encoded = self.codec.encode(table, &key, &value)?;
+        let old = self.store.set_if_present(table, key.clone(), encoded)?;
+        self.decode(table, &key, old)
+    }
+
+    fn incr(&self, table: &str, key: &str, delta: i64) -> Result<i64, KvError> {
+        let value = self.update(table, key, |v| add_integer(v, delta))?;
+        i64::try_from(&value)
+    }
+
+    fn incr_float(&self, table: &str, key: &str, delta: f64) -> Result<f64, KvError> {
+        let value = self.update(table, key, |v| add_float(v, delta))?;
+        f64::try_from(&value)
+    }
+
+    fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        self.decode_pairs(table, self.store.get_all(table)?)
+    }
+
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
+        let codec = Arc::clone(&self.codec);
+        let name = table.to_owned();
+        let iter = self.store.get_iter(table)?.filter_map(move |pair| {
+            let value = pair.value.unwrap_or_default();
+            match codec.decode(&name, &pair.key, value) {
+                Ok(value) => Some(Kvpair::new(pair.key, value)),
+                // iterator 没法返回错误，转换失败的 key 会被跳过
+                Err(e) => {
+                    warn!("Skip key {} in iterator: {:?}", pair.key, e);
+                    None
+                }
+            }
+        });
+        Ok(Box::new(iter))
+    }
+
+    fn get_range(
+        &self,
+        table: &str,
+        start: &str,
+        end: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        self.decode_pairs(table, self.store.get_range(table, start, end, limit)?)
+    }
+
+    fn set_with_ttl(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+        ttl: Duration,
+    ) -> Result<Option<Value>, KvError> {
+        let encoded = self.codec.encode(table, &key, &value)?;
+        let old = self.store.set_with_ttl(table, key.clone(), encoded, ttl)?;
+        self.decode(table, &key, old)
+    }
+
+    fn expire(&self, table: &str, key: &str, ttl: Duration) -> Result<bool, KvError> {
+        self.store.expire(table, key, ttl)
+    }
+
+    fn ttl(&self, table: &str, key: &str) -> Result<Option<Duration>, KvError> {
+        self.store.ttl(table, key)
+    }
+
+    fn persist(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.store.persist(table, key)
+    }
+
+    fn purge_expired(&self) -> Result<usize, KvError> {
+        self.store.purge_expired()
+    }
+
+    fn list_tables(&self) -> Result<Vec<String>, KvError> {
+        self.store.list_tables()
+    }
+
+    fn table_info(&self, table: &str) -> Result<Option<TableStats>, KvError> {
+        let mut stats = self.store.table_info(table)?;
+        if let Some(stats) = &mut stats {
+            self.update_table_stats(table, stats)?;
+        }
+        Ok(stats)
+    }
+
+    fn drop_table(&self, table: &str) -> Result<bool, KvError> {
+        self.store.drop_table(table)
+    }
+
+    fn rename_table(&self, table: &str, to: &str) -> Result<bool, KvError> {
+        self.store.rename_table(table, to)
+    }
+
+    fn stats(&self) -> Result<StorageStats, KvError> {
+        let mut stats = self.store.stats()?;
+        if self.codec.has_table_stats() {
+            for table in self.store.list_tables()? {
+                let mut table_stats = TableStats::default();
+                self.update_table_stats(&table, &mut table_stats)?;
+                stats.uncompressed_bytes += table_stats.uncompressed_bytes;
+                stats.compressed_bytes += table_stats.compressed_bytes;
+            }
+        }
+        Ok(stats)
+    }
+
+    fn transaction<T, F>(&self, f: F) -> Result<T, KvError>
+    where
+        F: Fn(&dyn TxnStorage) -> Result<T, KvError>,
+    {
+        self.store.transaction(|txn| {
+            f(&CodecTxn {
+                txn,
+                codec: self.codec.as_ref(),
+            })
+        })
+    }
+
+    fn quota(&self, table: &str) -> Result<Option<QuotaUsage>, KvError> {
+        self.store.quota(table)
+    }
+}
+
+/// 事务中同样在写入之前转换，读出之后转换回来
+struct CodecTxn<'a, C> {
+    txn: &'a dyn TxnStorage,
+    codec: &'a C,
+}
+
+impl<C: ValueCodec> CodecTxn<'_, C> {
+    fn decode(
+        &self,
+        table: &str,
+        key: &str,
+        value: Option<Value>,
+    ) -> Result<Option<Value>, KvError> {
+        value.map(|v| self.codec.decode(table, key, v)).transpose()
+    }
+}
+
+impl<C: ValueCodec> TxnStorage for CodecTxn<'_, C> {
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.decode(table, key, self.txn.get(table, key)?)
+    }
+
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        let encoded = self.codec.encode(table, &key, &value)?;
+        let old = self.txn.set(table, key.clone(), encoded)?;
+        self.decode(table, &key, old)
+    }
+
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.txn.contains(table, key)
+    }
+
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.decode(table, key, self.txn.del(table, key)?)
+    }
+    fn ttl(&self, table: &str, key: &str) -> Result<Option<Duration>, KvError> {
+        self.txn.ttl(table, key)
+    }
+}
@@ -0,0 +1,234 @@
+use crate::{value, CompressionCodec, CompressionConfig, KvError, TableStats, Value};
+use bytes::{BufMut, Bytes, BytesMut};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use prost::Message;
+use std::io::{Read, Write};
+
+use super::{CodecStorage, ValueCodec};
+
+/// 压缩之后的 value 以 MAGIC 开头，之后是压缩算法和压缩后的 value（protobuf 编码）
+const MAGIC: &[u8] = b"KVZ1";
+const HEADER_LEN: usize = MAGIC.len() + 1;
+
+/// 按 table 的压缩策略压缩 value，并统计压缩前后的大小
+///
+/// 压缩之后没有变小的 value 按原样保存。原样保存的 value 如果恰好以 MAGIC 开头，
+/// 会用 CompressionCodec::None 包装一下，这样读出来的时候不会被误认为是压缩过的。
+/// 压缩前后的大小在统计时根据保存的 value 计算，不在写入时累计
+#[derive(Debug, Default)]
+pub struct Compressor {
+    config: CompressionConfig,
+}
+
+impl Compressor {
+    pub fn new(config: CompressionConfig) -> Self {
+        Self { config }
+    }
+
+    fn compress(codec: CompressionCodec, data: &[u8]) -> Result<Vec<u8>, KvError> {
+        match codec {
+            CompressionCodec::None => Ok(data.to_vec()),
+            CompressionCodec::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(data)?;
+                Ok(encoder.finish()?)
+            }
+            CompressionCodec::Lz4 => Ok(lz4_flex::compress_prepend_size(data)),
+            CompressionCodec::Zstd => Ok(zstd::encode_all(data, 0)?),
+        }
+    }
+
+    fn decompress(codec: u8, data: &[u8]) -> Result<Vec<u8>, KvError> {
+        match codec {
+            0 => Ok(data.to_vec()),
+            1 => {
+                let mut buf = Vec::new();
+                GzDecoder::new(data).read_to_end(&mut buf)?;
+                Ok(buf)
+            }
+            2 => lz4_flex::decompress_size_prepended(data)
+                .map_err(|e| KvError::Internal(format!("Failed to decompress value: {}", e))),
+            3 => Ok(zstd::decode_all(data)?),
+            _ => Err(KvError::Internal(format!(
+                "Unknown compression codec {}",
+                codec
+            ))),
+        }
+    }
+
+    fn wrap(codec: CompressionCodec, data: &[u8]) -> Value {
+        let mut buf = BytesMut::with_capacity(HEADER_LEN + data.len());
+        buf.put_slice(MAGIC);
+        buf.put_u8(codec_id(codec));
+        buf.put_slice(data);
+        Value::from(buf.freeze())
+    }
+
+    /// 压缩之前的大小，压缩的数据中记录了大小时不需要解压
+    fn uncompressed_len(codec: u8, data: &[u8]) -> Result<usize, KvError> {
+        match codec {
+            0 => Ok(data.len()),
+            // gzip 的最后 4 个字节是压缩之前的大小
+            1 if data.len() >= 4 => {
+                let mut size = [0u8; 4];
+                size.copy_from_slice(&data[data.len() - 4..]);
+                Ok(u32::from_le_bytes(size) as usize)
+            }
+            2 => lz4_flex::block::uncompressed_size(data)
+                .map(|(size, _)| size)
+                .map_err(|e| KvError::Internal(format!("Failed to decompress value: {}", e))),
+            3 => match zstd::zstd_safe::get_frame_content_size(data) {
+                Ok(Some(size)) => Ok(size as usize),
+                _ => Ok(Self::decompress(codec, data)?.len()),
+            },
+            _ => Ok(Self::decompress(codec, data)?.len()),
+        }
+    }
+}
+
+impl ValueCodec for Compressor {
+    fn encode(&self, table: &str, _key: &str, value: &Value) -> Result<Value, KvError> {
+        let policy = self.config.policy(table);
+        let data = value.encode_to_vec();
+        let mut encoded = None;
+        if policy.codec != CompressionCodec::None && data.len() >= policy.threshold {
+            let compressed = Self::compress(policy.codec, &data)?;
+            if compressed.len() + HEADER_LEN < data.len() {
+                encoded = Some(Self::wrap(policy.codec, &compressed));
+            }
+        }
+        Ok(match encoded {
+            Some(v) => v,
+            None if parse(value).is_some() => Self::wrap(CompressionCodec::None, &data),
+            None => value.clone(),
+        })
+    }
+
+    fn decode(&self, _table: &str, _key: &str, value: Value) -> Result<Value, KvError> {
+        match parse(&value) {
+            Some((codec, data)) => {
+                let data = Self::decompress(codec, data)?;
+                Ok(Value::decode(Bytes::from(data))?)
+            }
+            None => Ok(value),
+        }
+    }
+
+    fn has_table_stats(&self) -> bool {
+        true
+    }
+
+    fn update_table_stats(
+        &self,
+        _table: &str,
+        stored: &Value,
+        stats: &mut TableStats,
+    ) -> Result<(), KvError> {
+        stats.uncompressed_bytes += match parse(stored) {
+            Some((codec, data)) => Self::uncompressed_len(codec, data)?,
+            None => stored.encoded_len(),
+        };
+        stats.compressed_bytes += stored.encoded_len();
+        Ok(())
+    }
+}
+
+/// 按 table 的压缩策略压缩 value 的 Storage，实现了 Storage trait
+pub type CompressedStorage<S> = CodecStorage<S, Compressor>;
+
+fn codec_id(codec: CompressionCodec) -> u8 {
+    match codec {
+        CompressionCodec::None => 0,
+        CompressionCodec::Gzip => 1,
+        CompressionCodec::Lz4 => 2,
+        CompressionCodec::Zstd => 3,
+    }
+}
+
+/// 解析压缩过的 value，返回压缩算法和数据
+fn parse(value: &Value) -> Option<(u8, &[u8])> {
+    match &value.value {
+        Some(value::Value::Binary(data)) if data.len() >= HEADER_LEN && data.starts_with(MAGIC) => {
+            Some((data[MAGIC.len()], &data[HEADER_LEN..]))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CompressionPolicy, MemTable, Storage};
+
+    fn compressor(codec: CompressionCodec) -> Compressor {
+        let mut config = CompressionConfig::default();
+        let policy = CompressionPolicy {
+            codec,
+            threshold: 64,
+        };
+        config.tables.insert("t1".into(), policy);
+        Compressor::new(config)
+    }
+
+    #[test]
+    fn compressor_should_compress_large_values() {
+        for codec in [
+            CompressionCodec::Gzip,
+            CompressionCodec::Lz4,
+            CompressionCodec::Zstd,
+        ] {
+            let compressor = compressor(codec);
+            let value: Value = "hello world ".repeat(100).into();
+            let encoded = compressor.encode("t1", "k1", &value).unwrap();
+            assert_eq!(parse(&encoded).unwrap().0, codec_id(codec));
+            assert!(encoded.encoded_len() < value.encoded_len() / 4);
+            assert_eq!(compressor.decode("t1", "k1", encoded).unwrap(), value);
+
+            // 小于阈值的 value 和没有配置压缩的 table 不压缩
+            let small: Value = "hello".into();
+            assert_eq!(compressor.encode("t1", "k2", &small).unwrap(), small);
+            assert_eq!(compressor.encode("t2", "k1", &value).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn compressor_should_keep_values_like_compressed_ones() {
+        let compressor = compressor(CompressionCodec::Lz4);
+        let value = Value::from(Bytes::from_static(b"KVZ1\x02not compressed"));
+        let encoded = compressor.encode("t1", "k1", &value).unwrap();
+        assert_ne!(encoded, value);
+        assert_eq!(compressor.decode("t1", "k1", encoded).unwrap(), value);
+    }
+
+    #[test]
+    fn compressed_storage_should_report_ratio() {
+        let store = CompressedStorage::new(MemTable::new(), compressor(CompressionCodec::Gzip));
+        let value: Value = "a".repeat(1000).into();
+        store.set("t1", "k1".into(), value.clone()).unwrap();
+        store.set("t2", "k1".into(), value.clone()).unwrap();
+        assert_eq!(store.get("t1", "k1").unwrap(), Some(value.clone()));
+        let raw = store.inner().get("t1", "k1").unwrap().unwrap();
+        assert!(raw.encoded_len() < 100);
+
+        let info = store.table_info("t1").unwrap().unwrap();
+        assert_eq!(info.uncompressed_bytes, value.encoded_len());
+        assert_eq!(info.compressed_bytes, raw.encoded_len());
+        let info = store.table_info("t2").unwrap().unwrap();
+        assert_eq!(info.uncompressed_bytes, info.compressed_bytes);
+        let stats = store.stats().unwrap();
+        assert_eq!(stats.uncompressed_bytes, value.encoded_len() * 2);
+
+        // 覆盖、删除之后统计的仍然是当前保存的数据
+        for codec in [CompressionCodec::Lz4, CompressionCodec::Zstd] {
+            let store = CompressedStorage::new(MemTable::new(), compressor(codec));
+            store.set("t1", "k1".into(), value.clone()).unwrap();
+            store.set("t1", "k1".into(), value.clone()).unwrap();
+            store.set("t1", "k2".into(), value.clone()).unwrap();
+            store.del("t1", "k2").unwrap();
+            let info = store.table_info("t1").unwrap().unwrap();
+            assert_eq!(info.uncompressed_bytes, value.encoded_len());
+            store.drop_table("t1").unwrap();
+            assert_eq!(store.stats().unwrap().uncompressed_bytes, 0);
+        }
+    }
+}
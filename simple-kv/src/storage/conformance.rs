@@ -0,0 +1,542 @@
+//! Storage 的一致性测试，所有 Storage 的实现都应该通过这些测试
+//!
+//! 需要打开 `conformance` feature，然后用 [`storage_conformance_tests!`] 生成测试：
+//!
+//! ```ignore
+//! use simple_kv::{storage_conformance_tests, SledDb};
+//!
+//! // dir 是每个测试单独的临时目录，测试结束后会被删除
+//! storage_conformance_tests!(sleddb, |dir| SledDb::new(dir).unwrap());
+//! ```
+//!
+//! 也可以直接调用这个模块中的函数，每个函数需要一个空的 Storage
+
+use crate::{Chunk, KvError, Kvpair, Storage, Value};
+use bytes::Bytes;
+use prost::Message;
+use std::{sync::Arc, thread, time::Duration};
+
+#[doc(hidden)]
+pub use tempfile::tempdir;
+
+/// 为一个 Storage 的实现生成所有的一致性测试
+///
+/// 第一个参数是生成的测试模块的名字，第二个参数根据临时目录创建一个空的 Storage，
+/// 每个测试都会调用一次
+#[macro_export]
+macro_rules! storage_conformance_tests {
+    ($name:ident, |$dir:pat_param| $store:expr) => {
+        $crate::storage_conformance_tests!(
+            @tests $name, $dir, $store,
+            basic_interface, get_all, get_iter, conditional_set, incr, table_management,
+            get_range, transaction, ttl, empty_table, unicode_and_binary, large_values,
+            chunked_values, concurrent_access
+        );
+    };
+    (@tests $name:ident, $dir:pat_param, $store:expr, $($test:ident),*) => {
+        #[allow(unused_imports)]
+        mod $name {
+            use super::*;
+
+            $(
+                #[test]
+                fn $test() {
+                    let tmp = $crate::conformance::tempdir().unwrap();
+                    let $dir = tmp.path();
+                    $crate::conformance::$test($store);
+                }
+            )*
+        }
+    };
+}
+
+/// get/set/contains/del 的基本行为
+pub fn basic_interface(store: impl Storage) {
+    // 第一次 set 会创建 table，插入 key 并返回 None（之前没值）
+    let v = store.set("t1", "hello".into(), "world".into());
+    assert!(v.unwrap().is_none());
+    // 再次 set 同样的 key 会更新，并返回之前的值
+    let v1 = store.set("t1", "hello".into(), "world1".into());
+    assert_eq!(v1.unwrap(), Some("world".into()));
+
+    // get 存在的 key 会得到最新的值
+    let v = store.get("t1", "hello");
+    assert_eq!(v.unwrap(), Some("world1".into()));
+
+    // get 不存在的 key 或者 table 会得到 None
+    assert_eq!(None, store.get("t1", "hello1").unwrap());
+    assert!(store.get("t2", "hello1").unwrap().is_none());
+
+    // contains 纯在的 key 返回 true，否则 false
+    assert!(store.contains("t1", "hello").unwrap());
+    assert!(!store.contains("t1", "hello1").unwrap());
+    assert!(!store.contains("t2", "hello").unwrap());
+
+    // del 存在的 key 返回之前的值
+    let v = store.del("t1", "hello");
+    assert_eq!(v.unwrap(), Some("world1".into()));
+
+    // del 不存在的 key 或 table 返回 None
+    assert_eq!(None, store.del("t1", "hello1").unwrap());
+    assert_eq!(None, store.del("t2", "hello").unwrap());
+}
+
+/// get_all 返回 table 中所有的 kv pair
+pub fn get_all(store: impl Storage) {
+    store.set("t2", "k1".into(), "v1".into()).unwrap();
+    store.set("t2", "k2".into(), "v2".into()).unwrap();
+    let mut data = store.get_all("t2").unwrap();
+    data.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    assert_eq!(
+        data,
+        vec![
+            Kvpair::new("k1", "v1".into()),
+            Kvpair::new("k2", "v2".into())
+        ]
+    )
+}
+
+/// get_iter 遍历 table 中所有的 kv pair
+pub fn get_iter(store: impl Storage) {
+    store.set("t2", "k1".into(), "v1".into()).unwrap();
+    store.set("t2", "k2".into(), "v2".into()).unwrap();
+    let mut data: Vec<_> = store.get_iter("t2").unwrap().collect();
+    data.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    assert_eq!(
+        data,
+        vec![
+            Kvpair::new("k1", "v1".into()),
+            Kvpair::new("k2", "v2".into())
+        ]
+    )
+}
+
+/// compare_and_swap 和 set_if_present
+pub fn conditional_set(store: impl Storage) {
+    // key 不存在时，expected 为 None 才能设置成功
+    assert!(!store
+        .compare_and_swap("t7", "k1".into(), Some("v0".into()), "v1".into())
+        .unwrap());
+    assert!(store
+        .compare_and_swap("t7", "k1".into(), None, "v1".into())
+        .unwrap());
+    assert!(!store
+        .compare_and_swap("t7", "k1".into(), None, "v2".into())
+        .unwrap());
+
+    // key 存在时，expected 和当前值相同才能设置成功
+    assert!(!store
+        .compare_and_swap("t7", "k1".into(), Some("v0".into()), "v2".into())
+        .unwrap());
+    assert!(store
+        .compare_and_swap("t7", "k1".into(), Some("v1".into()), "v2".into())
+        .unwrap());
+    assert_eq!(store.get("t7", "k1").unwrap(), Some("v2".into()));
+
+    // set_if_present 只更新存在的 key
+    let v = store.set_if_present("t7", "k1".into(), "v3".into());
+    assert_eq!(v.unwrap(), Some("v2".into()));
+    assert_eq!(store.get("t7", "k1").unwrap(), Some("v3".into()));
+    let v = store.set_if_present("t7", "k2".into(), "v3".into());
+    assert_eq!(v.unwrap(), None);
+    assert!(!store.contains("t7", "k2").unwrap());
+
+    // 过期的 key 被当作不存在
+    store
+        .set_with_ttl("t7", "k3".into(), "v3".into(), Duration::ZERO)
+        .unwrap();
+    let v = store.set_if_present("t7", "k3".into(), "v4".into());
+    assert_eq!(v.unwrap(), None);
+    assert!(store
+        .compare_and_swap("t7", "k3".into(), None, "v4".into())
+        .unwrap());
+    assert_eq!(store.ttl("t7", "k3").unwrap(), None);
+    assert_eq!(store.get("t7", "k3").unwrap(), Some("v4".into()));
+}
+
+/// incr 和 incr_float
+pub fn incr(store: impl Storage) {
+    // key 不存在时当作 0
+    assert_eq!(store.incr("t8", "k1", 5).unwrap(), 5);
+    assert_eq!(store.incr("t8", "k1", -2).unwrap(), 3);
+    assert_eq!(store.get("t8", "k1").unwrap(), Some(3.into()));
+    assert_eq!(store.incr_float("t8", "k2", 1.5).unwrap(), 1.5);
+    assert_eq!(store.incr_float("t8", "k2", 0.25).unwrap(), 1.75);
+
+    // 类型不匹配返回 ConvertError，value 保持不变
+    store.set("t8", "k3".into(), "v3".into()).unwrap();
+    let err = store.incr("t8", "k3", 1).unwrap_err();
+    assert!(matches!(err, KvError::ConvertError(_, "Integer")));
+    let err = store.incr_float("t8", "k1", 1.0).unwrap_err();
+    assert!(matches!(err, KvError::ConvertError(_, "Float")));
+    assert_eq!(store.get("t8", "k3").unwrap(), Some("v3".into()));
+
+    // 溢出返回错误
+    assert!(store.incr("t8", "k1", i64::MAX).is_err());
+    assert_eq!(store.get("t8", "k1").unwrap(), Some(3.into()));
+
+    // 增加 key 的值不影响它的过期时间
+    let hour = Duration::from_secs(3600);
+    store.expire("t8", "k1", hour).unwrap();
+    assert_eq!(store.incr("t8", "k1", 1).unwrap(), 4);
+    assert!(store.ttl("t8", "k1").unwrap().is_some());
+}
+
+/// list_tables/table_info/rename_table/drop_table
+pub fn table_management(store: impl Storage) {
+    // 读取不存在的 table 不会创建它
+    assert_eq!(store.get("t9", "k1").unwrap(), None);
+    assert!(store.get_all("t9").unwrap().is_empty());
+    assert!(store.list_tables().unwrap().is_empty());
+    assert_eq!(store.table_info("t9").unwrap(), None);
+
+    store.set("t9", "k1".into(), "v1".into()).unwrap();
+    store.set("t9", "k2".into(), "v2".into()).unwrap();
+    store
+        .set_with_ttl("t9", "k3".into(), "v3".into(), Duration::ZERO)
+        .unwrap();
+    store.set("t10", "k1".into(), 1.into()).unwrap();
+    assert_eq!(store.list_tables().unwrap(), vec!["t10", "t9"]);
+
+    // 过期的 key 不计算在内
+    let info = store.table_info("t9").unwrap().unwrap();
+    assert_eq!(info.keys, 2);
+    assert!(info.bytes >= 4);
+
+    // 重命名之后旧的 table 不存在，数据和过期时间都跟着新的 table
+    store
+        .set_with_ttl("t9", "k4".into(), "v4".into(), Duration::from_secs(3600))
+        .unwrap();
+    assert!(store.rename_table("t9", "t11").unwrap());
+    assert!(!store.rename_table("t9", "t12").unwrap());
+    assert!(store.rename_table("t11", "t10").is_err());
+    assert_eq!(store.get("t9", "k1").unwrap(), None);
+    assert_eq!(store.get("t11", "k1").unwrap(), Some("v1".into()));
+    assert!(store.ttl("t11", "k4").unwrap().is_some());
+    assert_eq!(store.list_tables().unwrap(), vec!["t10", "t11"]);
+
+    // 删除 table 之后，其中的数据都不存在了
+    assert!(store.drop_table("t11").unwrap());
+    assert!(!store.drop_table("t11").unwrap());
+    assert_eq!(store.get("t11", "k1").unwrap(), None);
+    assert_eq!(store.ttl("t11", "k4").unwrap(), None);
+    assert_eq!(store.list_tables().unwrap(), vec!["t10"]);
+}
+
+/// get_range 按 key 的顺序返回范围内的 kv pair
+pub fn get_range(store: impl Storage) {
+    for k in ["k3", "k1", "k5", "k2", "k4"] {
+        store.set("t4", k.into(), k.into()).unwrap();
+    }
+    // 其它 table 的数据不应该出现在结果中
+    store.set("t40", "k1".into(), "v1".into()).unwrap();
+
+    let keys = |data: Vec<Kvpair>| data.into_iter().map(|p| p.key).collect::<Vec<_>>();
+
+    // 结果按 key 排序，并且受 limit 限制
+    let data = store.get_range("t4", "", None, 3).unwrap();
+    assert_eq!(keys(data), vec!["k1", "k2", "k3"]);
+    assert_eq!(
+        store.get_range("t4", "", None, 1).unwrap(),
+        vec![Kvpair::new("k1", "k1".into())]
+    );
+
+    // start 包含在结果中，end 不包含
+    let data = store.get_range("t4", "k2", Some("k4"), 10).unwrap();
+    assert_eq!(keys(data), vec!["k2", "k3"]);
+    let data = store.get_range("t4", "k35", None, 10).unwrap();
+    assert_eq!(keys(data), vec!["k4", "k5"]);
+
+    // 空的范围或者不存在的 table 返回空
+    assert!(store
+        .get_range("t4", "k4", Some("k2"), 10)
+        .unwrap()
+        .is_empty());
+    assert!(store.get_range("t5", "", None, 10).unwrap().is_empty());
+
+    // 删除的以及过期的 key 不会出现在结果中
+    store.del("t4", "k2").unwrap();
+    store
+        .set_with_ttl("t4", "k3".into(), "v3".into(), Duration::ZERO)
+        .unwrap();
+    let data = store.get_range("t4", "", None, 10).unwrap();
+    assert_eq!(keys(data), vec!["k1", "k4", "k5"]);
+}
+
+/// 事务要么全部生效，要么全部不生效
+pub fn transaction(store: impl Storage) {
+    store.set("t5", "k1".into(), "v1".into()).unwrap();
+    store.set("t5", "k2".into(), "v2".into()).unwrap();
+
+    // 事务成功，所有的修改都生效
+    let old = store
+        .transaction(|txn| {
+            let old = txn.set("t5", "k1".into(), "v11".into())?;
+            assert_eq!(txn.get("t5", "k1")?, Some("v11".into()));
+            txn.del("t5", "k2")?;
+            assert!(!txn.contains("t5", "k2")?);
+            txn.set("t6", "k3".into(), "v3".into())?;
+            Ok(old)
+        })
+        .unwrap();
+    assert_eq!(old, Some("v1".into()));
+    assert_eq!(store.get("t5", "k1").unwrap(), Some("v11".into()));
+    assert_eq!(store.get("t5", "k2").unwrap(), None);
+    assert_eq!(store.get("t6", "k3").unwrap(), Some("v3".into()));
+
+    // 事务失败，所有的修改都被丢弃
+    let result: Result<(), _> = store.transaction(|txn| {
+        txn.set("t5", "k1".into(), "v12".into())?;
+        txn.set("t5", "k2".into(), "v22".into())?;
+        txn.del("t6", "k3")?;
+        Err(KvError::Internal("abort".into()))
+    });
+    assert!(result.is_err());
+    assert_eq!(store.get("t5", "k1").unwrap(), Some("v11".into()));
+    assert_eq!(store.get("t5", "k2").unwrap(), None);
+    assert_eq!(store.get("t6", "k3").unwrap(), Some("v3".into()));
+}
+
+/// 过期时间相关的操作
+pub fn ttl(store: impl Storage) {
+    // 没有设置过期时间的 key，ttl 返回 None
+    store.set("t3", "k1".into(), "v1".into()).unwrap();
+    assert_eq!(None, store.ttl("t3", "k1").unwrap());
+
+    // 设置过期时间后，ttl 返回剩余的时间
+    let hour = Duration::from_secs(3600);
+    assert!(store.expire("t3", "k1", hour).unwrap());
+    let ttl = store.ttl("t3", "k1").unwrap().unwrap();
+    assert!(ttl <= hour && ttl > Duration::from_secs(3590));
+
+    // persist 会去掉过期时间
+    assert!(store.persist("t3", "k1").unwrap());
+    assert!(!store.persist("t3", "k1").unwrap());
+    assert_eq!(None, store.ttl("t3", "k1").unwrap());
+
+    // 给不存在的 key 设置过期时间返回 false
+    assert!(!store.expire("t3", "k2", hour).unwrap());
+
+    // 已经过期的 key 读不到，也不会出现在遍历结果中
+    let v = store.set_with_ttl("t3", "k2".into(), "v2".into(), Duration::ZERO);
+    assert!(v.unwrap().is_none());
+    assert_eq!(None, store.get("t3", "k2").unwrap());
+    assert!(!store.contains("t3", "k2").unwrap());
+    store
+        .set_with_ttl("t3", "k3".into(), "v3".into(), Duration::ZERO)
+        .unwrap();
+    assert_eq!(
+        store.get_all("t3").unwrap(),
+        vec![Kvpair::new("k1", "v1".into())]
+    );
+    let data: Vec<_> = store.get_iter("t3").unwrap().collect();
+    assert_eq!(data, vec![Kvpair::new("k1", "v1".into())]);
+
+    // purge_expired 删除过期的 key
+    assert_eq!(store.purge_expired().unwrap(), 1);
+    assert_eq!(store.purge_expired().unwrap(), 0);
+
+    // 再次 set 会去掉之前的过期时间
+    store
+        .set_with_ttl("t3", "k1".into(), "v1".into(), hour)
+        .unwrap();
+    store.set("t3", "k1".into(), "v2".into()).unwrap();
+    assert_eq!(None, store.ttl("t3", "k1").unwrap());
+
+    // 事务中也能读到过期时间，set 之后同样会去掉过期时间
+    store
+        .set_with_ttl("t3", "k4".into(), "v4".into(), hour)
+        .unwrap();
+    store
+        .transaction(|txn| {
+            assert!(txn.ttl("t3", "k4")?.is_some_and(|ttl| ttl <= hour));
+            assert_eq!(txn.ttl("t3", "k5")?, None);
+            txn.set("t3", "k4".into(), "v44".into())?;
+            assert_eq!(txn.ttl("t3", "k4")?, None);
+            Ok(())
+        })
+        .unwrap();
+    assert_eq!(None, store.ttl("t3", "k4").unwrap());
+}
+
+/// 不存在的 table，以及 key 都被删除了的 table，读出来都是空的
+pub fn empty_table(store: impl Storage) {
+    assert!(store.get_all("e1").unwrap().is_empty());
+    assert_eq!(store.get_iter("e1").unwrap().count(), 0);
+    assert!(store.get_range("e1", "", None, 10).unwrap().is_empty());
+    assert_eq!(store.table_info("e1").unwrap(), None);
+    assert!(!store.drop_table("e1").unwrap());
+    assert!(!store.rename_table("e1", "e2").unwrap());
+    assert_eq!(store.purge_expired().unwrap(), 0);
+
+    store.set("e1", "k1".into(), "v1".into()).unwrap();
+    store.del("e1", "k1").unwrap();
+    assert!(store.get_all("e1").unwrap().is_empty());
+    assert_eq!(store.get_iter("e1").unwrap().count(), 0);
+    assert!(store.get_range("e1", "", None, 10).unwrap().is_empty());
+    let info = store.table_info("e1").unwrap();
+    assert_eq!(info.map_or(0, |info| info.keys), 0);
+
+    // 空的 key 和空的 value 都是合法的
+    store.set("e1", "".into(), Value::default()).unwrap();
+    assert_eq!(store.get("e1", "").unwrap(), Some(Value::default()));
+    assert_eq!(
+        store.get_all("e1").unwrap(),
+        vec![Kvpair::new("", Value::default())]
+    );
+}
+
+/// unicode 的 table 名和 key，以及任意字节的 value
+pub fn unicode_and_binary(store: impl Storage) {
+    let keys = [
+        "键",
+        "ключ",
+        "🔑",
+        "a\0b",
+        "a b",
+        "a:b",
+        "a/b",
+        "\u{10ffff}",
+    ];
+    for (i, key) in keys.iter().enumerate() {
+        store.set("表", key.to_string(), (i as i64).into()).unwrap();
+    }
+    let binary: Bytes = (0..=255u8).collect::<Vec<_>>().into();
+    store
+        .set("表", "bin".into(), binary.clone().into())
+        .unwrap();
+
+    for (i, key) in keys.iter().enumerate() {
+        assert_eq!(store.get("表", key).unwrap(), Some((i as i64).into()));
+    }
+    assert_eq!(store.get("表", "bin").unwrap(), Some(binary.into()));
+    // 前缀相同的 key 互不影响
+    assert_eq!(store.get("表", "a").unwrap(), None);
+    assert_eq!(store.get("表格", "键").unwrap(), None);
+
+    // get_range 按 key 的字节顺序排序
+    let mut expected: Vec<_> = keys.iter().map(|k| k.to_string()).collect();
+    expected.push("bin".into());
+    expected.sort();
+    let data = store.get_range("表", "", None, 100).unwrap();
+    let keys: Vec<_> = data.into_iter().map(|p| p.key).collect();
+    assert_eq!(keys, expected);
+
+    assert!(store.rename_table("表", "テーブル").unwrap());
+    assert_eq!(store.get("テーブル", "🔑").unwrap(), Some(2.into()));
+    assert_eq!(store.list_tables().unwrap(), vec!["テーブル"]);
+}
+
+/// 很大的 value 和很多的 key
+pub fn large_values(store: impl Storage) {
+    let value: Bytes = (0..4 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+    store.set("l1", "big".into(), value.clone().into()).unwrap();
+    assert_eq!(store.get("l1", "big").unwrap(), Some(value.clone().into()));
+
+    let long_key = "k".repeat(64 * 1024);
+    store.set("l1", long_key.clone(), "v".into()).unwrap();
+    assert_eq!(store.get("l1", &long_key).unwrap(), Some("v".into()));
+
+    for i in 0..1000 {
+        store.set("l2", format!("k{:04}", i), i.into()).unwrap();
+    }
+    assert_eq!(store.get_all("l2").unwrap().len(), 1000);
+    assert_eq!(store.get_iter("l2").unwrap().count(), 1000);
+    let data = store.get_range("l2", "k0500", None, 10).unwrap();
+    assert_eq!(data[0], Kvpair::new("k0500", 500.into()));
+    assert_eq!(store.table_info("l2").unwrap().unwrap().keys, 1000);
+
+    // 覆盖大的 value 之后读到新的 value
+    store.set("l1", "big".into(), "small".into()).unwrap();
+    assert_eq!(store.get("l1", "big").unwrap(), Some("small".into()));
+}
+
+/// 分块上传的 value 在最后一块写入之后才可见
+pub fn chunked_values(store: impl Storage) {
+    let value: Value =
+        Bytes::from((0..100_000).map(|i| (i % 251) as u8).collect::<Vec<_>>()).into();
+    let data = value.encode_to_vec();
+    let total = data.len() as u64;
+    let chunk = |offset: usize, size: usize| Chunk {
+        offset: offset as u64,
+        total,
+        data: Bytes::copy_from_slice(&data[offset..(offset + size).min(data.len())]),
+    };
+
+    assert_eq!(
+        store.set_chunk("u1", "k1", &chunk(0, 30_000)).unwrap(),
+        None
+    );
+    assert_eq!(
+        store.set_chunk("u1", "k1", &chunk(30_000, 30_000)).unwrap(),
+        None
+    );
+    assert_eq!(store.get("u1", "k1").unwrap(), None);
+    // 块必须按顺序写入
+    assert!(store.set_chunk("u1", "k1", &chunk(90_000, 30_000)).is_err());
+    assert_eq!(
+        store.set_chunk("u1", "k1", &chunk(60_000, 30_000)).unwrap(),
+        None
+    );
+    assert_eq!(
+        store.set_chunk("u1", "k1", &chunk(90_000, 30_000)).unwrap(),
+        None
+    );
+    assert_eq!(store.get("u1", "k1").unwrap(), Some(value.clone()));
+
+    // offset 为 0 时重新开始上传，返回之前的 value
+    store.set_chunk("u1", "k1", &chunk(0, 50_000)).unwrap();
+    store.set_chunk("u1", "k1", &chunk(0, 60_000)).unwrap();
+    let old = store.set_chunk("u1", "k1", &chunk(60_000, 60_000)).unwrap();
+    assert_eq!(old, Some(value.clone()));
+    assert_eq!(store.get("u1", "k1").unwrap(), Some(value));
+}
+
+/// 多个线程同时读写，incr 和 compare_and_swap 不会丢失更新
+pub fn concurrent_access(store: impl Storage) {
+    const THREADS: i64 = 8;
+    const ROUNDS: i64 = 100;
+
+    let store = Arc::new(store);
+    let handles: Vec<_> = (0..THREADS)
+        .map(|t| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for i in 0..ROUNDS {
+                    store.incr("c1", "counter", 1).unwrap();
+                    store.set("c2", format!("t{}-{}", t, i), i.into()).unwrap();
+                    // 读-改-写，失败时重试
+                    loop {
+                        let old = store.get("c1", "cas").unwrap();
+                        let n = old.as_ref().map_or(0, |v| i64::try_from(v).unwrap());
+                        if store
+                            .compare_and_swap("c1", "cas".into(), old, (n + 1).into())
+                            .unwrap()
+                        {
+                            break;
+                        }
+                    }
+                    store
+                        .transaction(|txn| {
+                            let n = match txn.get("c1", "txn")? {
+                                Some(v) => i64::try_from(&v)?,
+                                None => 0,
+                            };
+                            txn.set("c1", "txn".into(), (n + 1).into())?;
+                            Ok(())
+                        })
+                        .unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    let total = THREADS * ROUNDS;
+    assert_eq!(store.get("c1", "counter").unwrap(), Some(total.into()));
+    assert_eq!(store.get("c1", "cas").unwrap(), Some(total.into()));
+    assert_eq!(store.get("c1", "txn").unwrap(), Some(total.into()));
+    assert_eq!(store.get_all("c2").unwrap().len(), total as usize);
+}
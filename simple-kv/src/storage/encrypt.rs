@@ -0,0 +1,271 @@
+use crate::{value, KvError, Storage, Value};
+use bytes::{Buf, BufMut, Bytes};
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    ChaCha20Poly1305, Nonce,
+};
+use prost::Message;
+use std::{
+    collections::BTreeMap,
+    fs::{self, OpenOptions},
+    io::Write,
+    path::Path,
+};
+
+use super::{CodecStorage, ValueCodec};
+
+/// 加密之后的 value 以 MAGIC 开头，之后是密钥的 id、nonce 和密文
+const MAGIC: &[u8] = b"KVE1";
+const NONCE_LEN: usize = 12;
+const HEADER_LEN: usize = MAGIC.len() + 4 + NONCE_LEN;
+
+/// 加密用的一组密钥，加密时使用 id 最大的密钥，解密时根据 value 中记录的 id 选择密钥
+///
+/// 密钥文件中每行是一个密钥：`<id> <base64 编码的 32 字节密钥>`，以 # 开头的行是注释。
+/// 轮换密钥时往文件中加入一个 id 更大的密钥，用旧密钥加密的数据仍然可以读取，
+/// 之后可以用 kv-encrypt reencrypt 把所有数据用新的密钥重新加密，再从文件中删除旧的密钥
+#[derive(Clone)]
+pub struct Keyring {
+    keys: BTreeMap<u32, ChaCha20Poly1305>,
+}
+
+impl Keyring {
+    pub fn new() -> Self {
+        Self {
+            keys: BTreeMap::new(),
+        }
+    }
+
+    /// 从密钥文件中加载所有的密钥
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, KvError> {
+        let mut keyring = Self::new();
+        for line in fs::read_to_string(path)?.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = || KvError::EncryptionError(format!("invalid key line: {}", line));
+            let (id, key) = line.split_once(' ').ok_or_else(invalid)?;
+            let id = id.parse().map_err(|_| invalid())?;
+            let key = base64::decode(key.trim()).map_err(|_| invalid())?;
+            keyring.add(id, &key)?;
+        }
+        match keyring.keys.is_empty() {
+            true => Err(KvError::EncryptionError("no key in key file".into())),
+            false => Ok(keyring),
+        }
+    }
+
+    /// 生成一个新的密钥，以比文件中已有的 id 都大的 id 追加到密钥文件中，返回新密钥的 id
+    /// 文件不存在时会创建它
+    pub fn rotate(path: impl AsRef<Path>) -> Result<u32, KvError> {
+        let path = path.as_ref();
+        let id = match path.exists() {
+            true => Self::load(path)?.current_id() + 1,
+            false => 1,
+        };
+        let key = ChaCha20Poly1305::generate_key(&mut OsRng);
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        writeln!(file, "{} {}", id, base64::encode(key))?;
+        file.sync_all()?;
+        Ok(id)
+    }
+
+    /// 加入一个 32 字节的密钥
+    pub fn add(&mut self, id: u32, key: &[u8]) -> Result<(), KvError> {
+        let cipher = ChaCha20Poly1305::new_from_slice(key)
+            .map_err(|_| KvError::EncryptionError(format!("key {} is not 32 bytes", id)))?;
+        self.keys.insert(id, cipher);
+        Ok(())
+    }
+
+    /// 加密时使用的密钥的 id
+    pub fn current_id(&self) -> u32 {
+        self.keys.keys().next_back().copied().unwrap_or_default()
+    }
+
+    /// 加密后的 value 使用的密钥的 id，value 没有加密时返回 None
+    pub fn key_id(value: &Value) -> Option<u32> {
+        parse(value).map(|(id, _)| id)
+    }
+
+    /// 用当前的密钥加密 value，key 作为附加数据，这样密文不能被挪到别的 key 下使用
+    pub fn encrypt(&self, key: &str, value: &Value) -> Result<Value, KvError> {
+        let (id, cipher) = self
+            .keys
+            .iter()
+            .next_back()
+            .ok_or_else(|| KvError::EncryptionError("no key to encrypt".into()))?;
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let payload = Payload {
+            msg: &value.encode_to_vec(),
+            aad: key.as_bytes(),
+        };
+        let ciphertext = cipher
+            .encrypt(&nonce, payload)
+            .map_err(|_| KvError::EncryptionError(format!("failed to encrypt key {}", key)))?;
+
+        let mut data = Vec::with_capacity(HEADER_LEN + ciphertext.len());
+        data.put_slice(MAGIC);
+        data.put_u32(*id);
+        data.put_slice(&nonce);
+        data.put_slice(&ciphertext);
+        Ok(Bytes::from(data).into())
+    }
+
+    /// 解密 value，value 没有加密、密钥不存在或者数据被篡改时返回错误
+    pub fn decrypt(&self, key: &str, value: &Value) -> Result<Value, KvError> {
+        let (id, data) = parse(value)
+            .ok_or_else(|| KvError::EncryptionError(format!("key {} is not encrypted", key)))?;
+        let cipher = self.keys.get(&id).ok_or_else(|| {
+            KvError::EncryptionError(format!("key {} is encrypted by unknown key {}", key, id))
+        })?;
+        let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+        let payload = Payload {
+            msg: ciphertext,
+            aad: key.as_bytes(),
+        };
+        let plaintext = cipher
+            .decrypt(Nonce::from_slice(nonce), payload)
+            .map_err(|_| KvError::EncryptionError(format!("failed to decrypt key {}", key)))?;
+        Ok(Value::decode(&plaintext[..])?)
+    }
+}
+
+/// 从加密后的 value 中取出密钥的 id，以及之后的 nonce 和密文
+fn parse(value: &Value) -> Option<(u32, &[u8])> {
+    match &value.value {
+        Some(value::Value::Binary(data)) if data.len() >= HEADER_LEN && data.starts_with(MAGIC) => {
+            let mut data = &data[MAGIC.len()..];
+            Some((data.get_u32(), data))
+        }
+        _ => None,
+    }
+}
+
+impl Default for Keyring {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ValueCodec for Keyring {
+    fn encode(&self, _table: &str, key: &str, value: &Value) -> Result<Value, KvError> {
+        self.encrypt(key, value)
+    }
+
+    fn decode(&self, _table: &str, key: &str, value: Value) -> Result<Value, KvError> {
+        self.decrypt(key, &value)
+    }
+}
+
+/// 在 value 写入 Storage 之前加密，读出之后解密，实现了 Storage trait
+///
+/// 只加密 value，table 和 key 保持明文，这样 key 的顺序和范围扫描都不受影响
+pub type EncryptedStorage<S> = CodecStorage<S, Keyring>;
+
+impl<S: Storage> CodecStorage<S, Keyring> {
+    /// 把所有不是用当前密钥加密的 value（包括没有加密的 value）用当前密钥重新加密，
+    /// 返回重新加密的 value 个数。它会保留 key 的过期时间，但不是原子的，需要先停止服务器
+    pub fn reencrypt(&self) -> Result<usize, KvError> {
+        let keyring = self.codec();
+        let current = keyring.current_id();
+        let mut count = 0;
+        for table in self.store.list_tables()? {
+            for pair in self.store.get_all(&table)? {
+                let raw = pair.value.unwrap_or_default();
+                let value = match Keyring::key_id(&raw) {
+                    Some(id) if id == current => continue,
+                    Some(_) => keyring.decrypt(&pair.key, &raw)?,
+                    None => raw,
+                };
+                let value = keyring.encrypt(&pair.key, &value)?;
+                match self.store.ttl(&table, &pair.key)? {
+                    Some(ttl) => self.store.set_with_ttl(&table, pair.key, value, ttl)?,
+                    None => self.store.set(&table, pair.key, value)?,
+                };
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MemTable, SledDb};
+    use std::time::Duration;
+    use tempfile::tempdir;
+
+    #[test]
+    fn keyring_should_encrypt_and_decrypt() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("keys");
+        assert_eq!(Keyring::rotate(&path).unwrap(), 1);
+        let old = Keyring::load(&path).unwrap();
+        let encrypted = old.encrypt("k1", &"hello".into()).unwrap();
+        assert_eq!(Keyring::key_id(&encrypted), Some(1));
+        // 相同的 value 每次加密的结果都不一样
+        assert_ne!(old.encrypt("k1", &"hello".into()).unwrap(), encrypted);
+        assert_eq!(old.decrypt("k1", &encrypted).unwrap(), "hello".into());
+
+        // 轮换之后用新的密钥加密，用旧密钥加密的数据仍然可以解密
+        assert_eq!(Keyring::rotate(&path).unwrap(), 2);
+        let new = Keyring::load(&path).unwrap();
+        assert_eq!(new.current_id(), 2);
+        assert_eq!(new.decrypt("k1", &encrypted).unwrap(), "hello".into());
+        let encrypted = new.encrypt("k1", &"hello".into()).unwrap();
+        assert!(matches!(
+            old.decrypt("k1", &encrypted),
+            Err(KvError::EncryptionError(_))
+        ));
+
+        // 密文不能挪到别的 key 下使用
+        assert!(new.decrypt("k2", &encrypted).is_err());
+        assert!(new.decrypt("k1", &"hello".into()).is_err());
+    }
+
+    #[test]
+    fn encrypted_storage_should_not_store_plaintext() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("keys");
+        Keyring::rotate(&path).unwrap();
+        let store = EncryptedStorage::new(
+            SledDb::new(dir.path()).unwrap(),
+            Keyring::load(&path).unwrap(),
+        );
+        store.set("t1", "k1".into(), "secret".into()).unwrap();
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("secret".into()));
+
+        let raw = store.inner().get("t1", "k1").unwrap().unwrap();
+        assert_eq!(Keyring::key_id(&raw), Some(1));
+        let data: Bytes = raw.try_into().unwrap();
+        assert!(!data.windows(6).any(|w| w == b"secret"));
+    }
+
+    #[test]
+    fn reencrypt_should_use_current_key() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("keys");
+        Keyring::rotate(&path).unwrap();
+        let inner = MemTable::new();
+        // 没有加密的数据也会被加密
+        inner.set("t1", "plain".into(), 1.into()).unwrap();
+        let store = EncryptedStorage::new(inner, Keyring::load(&path).unwrap());
+        store
+            .set_with_ttl("t1", "k1".into(), "v1".into(), Duration::from_secs(3600))
+            .unwrap();
+
+        Keyring::rotate(&path).unwrap();
+        let store = EncryptedStorage::new(store.store, Keyring::load(&path).unwrap());
+        assert_eq!(store.reencrypt().unwrap(), 2);
+        assert_eq!(store.reencrypt().unwrap(), 0);
+
+        for key in ["plain", "k1"] {
+            let raw = store.inner().get("t1", key).unwrap().unwrap();
+            assert_eq!(Keyring::key_id(&raw), Some(2));
+        }
+        assert_eq!(store.get("t1", "plain").unwrap(), Some(1.into()));
+        assert!(store.ttl("t1", "k1").unwrap().is_some());
+    }
+}
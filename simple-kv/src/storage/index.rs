@@ -0,0 +1,585 @@
+use crate::{
+    value, IndexConfig, KvError, Kvpair, QuotaUsage, Storage, StorageStats, TableStats, TxnStorage,
+    Value,
+};
+use prost::Message;
+use serde_json::Value as Json;
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet, HashMap},
+    sync::{Mutex, RwLock},
+    time::Duration,
+};
+
+use super::peek;
+
+/// 给任意的 Storage 加上二级索引，实现了 Storage trait
+///
+/// 索引属于某个 table，可以索引标量类型的 value，也可以索引字符串 value 中 JSON path 指向的字段。
+/// 每次修改数据时都会同步更新索引，find 通过索引找到索引值等于某个 value 的所有 kv pair
+///
+/// 索引只保存在内存中，创建索引时会扫描 table 中已有的数据
+pub struct IndexedStorage<S> {
+    store: S,
+    // table -> table 上的所有索引；写操作持有读锁，修改有索引的 table 时再持有这个 table 的锁，
+    // 这样索引和数据总是一致的，没有索引的 table 之间互不影响
+    indexes: RwLock<HashMap<String, Mutex<TableIndexes>>>,
+}
+
+#[derive(Debug, Default)]
+struct TableIndexes {
+    indexes: Vec<Index>,
+    // 设置过过期时间的 key，清理过期的 key 之后只需要检查它们
+    expiring: BTreeSet<String>,
+}
+
+impl TableIndexes {
+    /// 根据 key 现在的 value 更新所有索引
+    fn update(&mut self, key: &str, value: Option<&Value>) {
+        for index in self.indexes.iter_mut() {
+            index.update(key, value);
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Index {
+    name: String,
+    // 转换成 JSON pointer 之后的 path，为 None 时索引整个 value
+    pointer: Option<String>,
+    // 编码之后的索引值 -> 索引值等于它的 key
+    entries: HashMap<Vec<u8>, BTreeSet<String>>,
+    // key -> key 的索引值，更新索引时不需要读取修改之前的 value
+    values: HashMap<String, Vec<u8>>,
+}
+
+impl Index {
+    fn new(config: &IndexConfig) -> Self {
+        // user.name 这样的 path 转换成 JSON pointer /user/name
+        let pointer = config
+            .path
+            .as_ref()
+            .filter(|p| !p.is_empty())
+            .map(|p| p.split('.').map(|s| format!("/{}", s)).collect());
+        Self {
+            name: config.name.clone(),
+            pointer,
+            entries: HashMap::new(),
+            values: HashMap::new(),
+        }
+    }
+
+    /// value 的索引值，集合类型、不是 JSON 或者找不到字段的 value 不会被索引
+    fn extract(&self, value: &Value) -> Option<Vec<u8>> {
+        let pointer = match &self.pointer {
+            Some(pointer) => pointer,
+            None => return encode_scalar(value),
+        };
+        match &value.value {
+            Some(value::Value::String(s)) => {
+                let json: Json = serde_json::from_str(s).ok()?;
+                encode_scalar(&from_json(json.pointer(pointer)?)?)
+            }
+            _ => None,
+        }
+    }
+
+    /// 把 key 的索引值改成 value 的索引值，value 为 None 时从索引中去掉 key
+    fn update(&mut self, key: &str, value: Option<&Value>) {
+        if let Some(old) = self.values.remove(key) {
+            if let Some(keys) = self.entries.get_mut(&old) {
+                keys.remove(key);
+                if keys.is_empty() {
+                    self.entries.remove(&old);
+                }
+            }
+        }
+        if let Some(new) = value.and_then(|v| self.extract(v)) {
+            self.entries
+                .entry(new.clone())
+                .or_default()
+                .insert(key.to_owned());
+            self.values.insert(key.to_owned(), new);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.values.clear();
+    }
+}
+
+/// 把标量类型的 value 编码成索引值，相等的 value 编码之后也相等
+fn encode_scalar(value: &Value) -> Option<Vec<u8>> {
+    match value.value {
+        Some(
+            value::Value::String(_)
+            | value::Value::Binary(_)
+            | value::Value::Integer(_)
+            | value::Value::Float(_)
+            | value::Value::Bool(_),
+        ) => Some(value.encode_to_vec()),
+        _ => None,
+    }
+}
+
+/// JSON 中的数字可以表示成整数时转换成 Integer，否则转换成 Float
+fn from_json(json: &Json) -> Option<Value> {
+    match json {
+        Json::String(s) => Some(s.as_str().into()),
+        Json::Bool(b) => Some((*b).into()),
+        Json::Number(n) => match n.as_i64() {
+            Some(i) => Some(i.into()),
+            None => n.as_f64().map(Into::into),
+        },
+        _ => None,
+    }
+}
+
+impl<S: Storage> IndexedStorage<S> {
+    pub fn new(store: S) -> Self {
+        Self {
+            store,
+            indexes: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// 获取内部的 Storage
+    pub fn inner(&self) -> &S {
+        &self.store
+    }
+
+    /// 在 table 上创建索引，并把 table 中已有的数据加入索引
+    pub fn create_index(&self, config: &IndexConfig) -> Result<(), KvError> {
+        let mut indexes = self.indexes.write().unwrap();
+        let table = indexes.entry(config.table.clone()).or_default();
+        let table = table.get_mut().unwrap();
+        if table.indexes.iter().any(|index| index.name == config.name) {
+            return Err(KvError::PreconditionFailed(format!(
+                "index {} of table {} already exists",
+                config.name, config.table
+            )));
+        }
+        let mut index = Index::new(config);
+        for pair in self.store.get_all(&config.table)? {
+            index.update(&pair.key, pair.value.as_ref());
+            if self.store.ttl(&config.table, &pair.key)?.is_some() {
+                table.expiring.insert(pair.key);
+            }
+        }
+        table.indexes.push(index);
+        Ok(())
+    }
+
+    /// 修改 keys 的操作：执行 f 之后，根据 key 修改之后的 value 更新索引；
+    /// expiring 为 true 时 f 给 key 设置了过期时间
+    fn write<T>(
+        &self,
+        table: &str,
+        keys: &[&str],
+        expiring: bool,
+        f: impl FnOnce() -> Result<T, KvError>,
+    ) -> Result<T, KvError> {
+        // 持有读锁，执行 f 的时候不会在这个 table 上创建索引
+        let indexes = self.indexes.read().unwrap();
+        let mut table_indexes = match indexes.get(table) {
+            Some(v) => v.lock().unwrap(),
+            // 没有索引的 table 不需要额外的读取
+            None => return f(),
+        };
+        let result = f()?;
+        for key in keys {
+            let value = peek(&self.store, table, key)?;
+            table_indexes.update(key, value.as_ref());
+            if expiring && value.is_some() {
+                table_indexes.expiring.insert(key.to_string());
+            }
+        }
+        Ok(result)
+    }
+}
+
+impl<S: Storage> Storage for IndexedStorage<S> {
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.store.get(table, key)
+    }
+
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        let keys = [key.as_str()];
+        self.write(table, &keys, false, || {
+            self.store.set(table, key.clone(), value)
+        })
+    }
+
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.store.contains(table, key)
+    }
+
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.write(table, &[key], false, || self.store.del(table, key))
+    }
+
+    fn compare_and_swap(
+        &self,
+        table: &str,
+        key: String,
+        expected: Option<Value>,
+        value: Value,
+    ) -> Result<bool, KvError> {
+        let keys = [key.as_str()];
+        self.write(table, &keys, false, || {
+            self.store
+                .compare_and_swap(table, key.clone(), expected, value)
+        })
+    }
+
+    fn set_if_present(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+    ) -> Result<Option<Value>, KvError> {
+        let keys = [key.as_str()];
+        self.write(table, &keys, false, || {
+            self.store.set_if_present(table, key.clone(), value)
+        })
+    }
+
+    fn incr(&self, table: &str, key: &str, delta: i64) -> Result<i64, KvError> {
+        self.write(table, &[key], false, || self.store.incr(table, key, delta))
+    }
+
+    fn incr_float(&self, table: &str, key: &str, delta: f64) -> Result<f64, KvError> {
+        self.write(table, &[key], false, || {
+            self.store.incr_float(table, key, delta)
+        })
+    }
+
+    fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        self.store.get_all(table)
+    }
+
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
+        self.store.get_iter(table)
+    }
+
+    fn get_range(
+        &self,
+        table: &str,
+        start: &str,
+        end: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        self.store.get_range(table, start, end, limit)
+    }
+
+    fn set_with_ttl(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+        ttl: Duration,
+    ) -> Result<Option<Value>, KvError> {
+        let keys = [key.as_str()];
+        self.write(table, &keys, true, || {
+            self.store.set_with_ttl(table, key.clone(), value, ttl)
+        })
+    }
+
+    fn expire(&self, table: &str, key: &str, ttl: Duration) -> Result<bool, KvError> {
+        self.write(table, &[key], true, || self.store.expire(table, key, ttl))
+    }
+
+    fn ttl(&self, table: &str, key: &str) -> Result<Option<Duration>, KvError> {
+        self.store.ttl(table, key)
+    }
+
+    fn persist(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.store.persist(table, key)
+    }
+
+    fn purge_expired(&self) -> Result<usize, KvError> {
+        let purged = self.store.purge_expired()?;
+        // 删除了过期的 key 之后，只把设置过过期时间、现在已经不存在的 key 从索引中去掉
+        if purged > 0 {
+            let indexes = self.indexes.read().unwrap();
+            for (table, table_indexes) in indexes.iter() {
+                let mut table_indexes = table_indexes.lock().unwrap();
+                let keys: Vec<_> = table_indexes.expiring.iter().cloned().collect();
+                for key in keys {
+                    if !self.store.contains(table, &key)? {
+                        table_indexes.update(&key, None);
+                        table_indexes.expiring.remove(&key);
+                    } else if self.store.ttl(table, &key)?.is_none() {
+                        table_indexes.expiring.remove(&key);
+                    }
+                }
+            }
+        }
+        Ok(purged)
+    }
+
+    fn list_tables(&self) -> Result<Vec<String>, KvError> {
+        self.store.list_tables()
+    }
+
+    fn table_info(&self, table: &str) -> Result<Option<TableStats>, KvError> {
+        self.store.table_info(table)
+    }
+
+    fn drop_table(&self, table: &str) -> Result<bool, KvError> {
+        let indexes = self.indexes.read().unwrap();
+        let mut table_indexes = match indexes.get(table) {
+            Some(v) => v.lock().unwrap(),
+            None => return self.store.drop_table(table),
+        };
+        let dropped = self.store.drop_table(table)?;
+        table_indexes.indexes.iter_mut().for_each(Index::clear);
+        table_indexes.expiring.clear();
+        Ok(dropped)
+    }
+
+    fn rename_table(&self, table: &str, to: &str) -> Result<bool, KvError> {
+        // 索引属于 table 的名字，改名之后两个 table 的索引都需要重新生成
+        let mut indexes = self.indexes.write().unwrap();
+        let renamed = self.store.rename_table(table, to)?;
+        if let Some(table_indexes) = indexes.get_mut(table) {
+            let table_indexes = table_indexes.get_mut().unwrap();
+            table_indexes.indexes.iter_mut().for_each(Index::clear);
+            table_indexes.expiring.clear();
+        }
+        if let Some(table_indexes) = indexes.get_mut(to) {
+            let table_indexes = table_indexes.get_mut().unwrap();
+            table_indexes.indexes.iter_mut().for_each(Index::clear);
+            table_indexes.expiring.clear();
+            for pair in self.store.get_all(to)? {
+                table_indexes.update(&pair.key, pair.value.as_ref());
+                if self.store.ttl(to, &pair.key)?.is_some() {
+                    table_indexes.expiring.insert(pair.key);
+                }
+            }
+        }
+        Ok(renamed)
+    }
+
+    fn stats(&self) -> Result<StorageStats, KvError> {
+        self.store.stats()
+    }
+
+    fn transaction<T, F>(&self, f: F) -> Result<T, KvError>
+    where
+        F: Fn(&dyn TxnStorage) -> Result<T, KvError>,
+    {
+        // 事务可能修改任意有索引的 table，按名字的顺序持有它们的锁，没有索引的 table 不受影响
+        let indexes = self.indexes.read().unwrap();
+        let mut locked: BTreeMap<_, _> = indexes
+            .iter()
+            .map(|(table, v)| (table.as_str(), v.lock().unwrap()))
+            .collect();
+        let touched = RefCell::new(BTreeSet::new());
+        let result = self.store.transaction(|txn| {
+            // f 可能被执行多次，只记录最后一次执行时修改的 key
+            touched.borrow_mut().clear();
+            f(&IndexedTxn {
+                txn,
+                touched: &touched,
+            })
+        })?;
+        for (table, key) in touched.into_inner() {
+            if let Some(table_indexes) = locked.get_mut(table.as_str()) {
+                let value = peek(&self.store, &table, &key)?;
+                table_indexes.update(&key, value.as_ref());
+            }
+        }
+        Ok(result)
+    }
+
+    fn find(&self, table: &str, index: &str, value: &Value) -> Result<Vec<Kvpair>, KvError> {
+        let indexes = self.indexes.read().unwrap();
+        let table_indexes = indexes.get(table).map(|v| v.lock().unwrap());
+        let index = table_indexes
+            .as_ref()
+            .and_then(|v| v.indexes.iter().find(|i| i.name == index))
+            .ok_or_else(|| KvError::NotFound(format!("index {} of table {}", index, table)))?;
+        let keys = match encode_scalar(value) {
+            Some(v) => index.entries.get(&v),
+            None => {
+                return Err(KvError::InvalidCommand(format!(
+                    "Cannot find by non-scalar value {:?}",
+                    value
+                )))
+            }
+        };
+
+        // 已经过期但还没有被清理的 key 读不到，不会出现在结果中
+        let mut pairs = Vec::new();
+        for key in keys.into_iter().flatten() {
+            if let Some(v) = peek(&self.store, table, key)? {
+                pairs.push(Kvpair::new(key, v));
+            }
+        }
+        Ok(pairs)
+    }
+
+    fn quota(&self, table: &str) -> Result<Option<QuotaUsage>, KvError> {
+        self.store.quota(table)
+    }
+
+    fn version(&self) -> Result<u64, KvError> {
+        self.store.version()
+    }
+
+    fn get_at(&self, table: &str, key: &str, version: u64) -> Result<Option<Value>, KvError> {
+        self.store.get_at(table, key, version)
+    }
+
+    fn get_all_at(&self, table: &str, version: u64) -> Result<Vec<Kvpair>, KvError> {
+        self.store.get_all_at(table, version)
+    }
+
+    fn snapshot(&self) -> Result<u64, KvError> {
+        self.store.snapshot()
+    }
+
+    fn release_snapshot(&self, version: u64) -> Result<bool, KvError> {
+        self.store.release_snapshot(version)
+    }
+
+    fn purge_versions(&self) -> Result<usize, KvError> {
+        self.store.purge_versions()
+    }
+}
+
+/// 事务中记下修改过的 key，事务提交之后更新它们的索引
+struct IndexedTxn<'a> {
+    txn: &'a dyn TxnStorage,
+    touched: &'a RefCell<BTreeSet<(String, String)>>,
+}
+
+impl IndexedTxn<'_> {
+    fn touch(&self, table: &str, key: &str) -> Result<(), KvError> {
+        let id = (table.to_owned(), key.to_owned());
+        self.touched.borrow_mut().insert(id);
+        Ok(())
+    }
+}
+
+impl TxnStorage for IndexedTxn<'_> {
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.txn.get(table, key)
+    }
+
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        self.touch(table, &key)?;
+        self.txn.set(table, key, value)
+    }
+
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.txn.contains(table, key)
+    }
+
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.touch(table, key)?;
+        self.txn.del(table, key)
+    }
+    fn ttl(&self, table: &str, key: &str) -> Result<Option<Duration>, KvError> {
+        self.txn.ttl(table, key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MemTable;
+
+    fn index(table: &str, name: &str, path: Option<&str>) -> IndexConfig {
+        IndexConfig {
+            table: table.into(),
+            name: name.into(),
+            path: path.map(Into::into),
+        }
+    }
+
+    #[test]
+    fn index_should_be_maintained_on_writes() {
+        let store = IndexedStorage::new(MemTable::new());
+        store.set("t1", "k1".into(), "red".into()).unwrap();
+        store.create_index(&index("t1", "color", None)).unwrap();
+        assert!(store.create_index(&index("t1", "color", None)).is_err());
+
+        store.set("t1", "k2".into(), "red".into()).unwrap();
+        store.set("t1", "k3".into(), "blue".into()).unwrap();
+        let pairs = store.find("t1", "color", &"red".into()).unwrap();
+        let keys: Vec<_> = pairs.iter().map(|p| p.key.as_str()).collect();
+        assert_eq!(keys, vec!["k1", "k2"]);
+
+        // 修改和删除之后索引随之更新
+        store.set("t1", "k1".into(), "blue".into()).unwrap();
+        store.del("t1", "k2").unwrap();
+        assert!(store.find("t1", "color", &"red".into()).unwrap().is_empty());
+        assert_eq!(store.find("t1", "color", &"blue".into()).unwrap().len(), 2);
+
+        // 整数和字符串是不同的索引值
+        store.set("t1", "k4".into(), 1.into()).unwrap();
+        assert!(store.find("t1", "color", &"1".into()).unwrap().is_empty());
+        assert_eq!(store.find("t1", "color", &1.into()).unwrap().len(), 1);
+
+        let result = store.find("t1", "size", &1.into());
+        assert!(matches!(result, Err(KvError::NotFound(_))));
+    }
+
+    #[test]
+    fn json_path_index_should_work() {
+        let store = IndexedStorage::new(MemTable::new());
+        store
+            .create_index(&index("users", "city", Some("address.city")))
+            .unwrap();
+        let user = |city: &str| Value::from(format!(r#"{{"address":{{"city":"{}"}}}}"#, city));
+        store.set("users", "u1".into(), user("paris")).unwrap();
+        store.set("users", "u2".into(), user("rome")).unwrap();
+        // 不是 JSON 或者没有这个字段的 value 不会被索引
+        store.set("users", "u3".into(), "paris".into()).unwrap();
+
+        let pairs = store.find("users", "city", &"paris".into()).unwrap();
+        assert_eq!(pairs, vec![Kvpair::new("u1", user("paris"))]);
+
+        store
+            .transaction(|txn| {
+                txn.set("users", "u2".into(), user("paris"))?;
+                txn.del("users", "u1")?;
+                Ok(())
+            })
+            .unwrap();
+        let pairs = store.find("users", "city", &"paris".into()).unwrap();
+        assert_eq!(pairs, vec![Kvpair::new("u2", user("paris"))]);
+
+        // table 改名之后索引属于新的 table
+        store.rename_table("users", "old_users").unwrap();
+        assert!(store
+            .find("users", "city", &"paris".into())
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn expired_keys_should_be_removed_from_index() {
+        let store = IndexedStorage::new(MemTable::new());
+        store.create_index(&index("t1", "value", None)).unwrap();
+        store
+            .set_with_ttl("t1", "k1".into(), "v".into(), Duration::ZERO)
+            .unwrap();
+        store
+            .set_with_ttl("t1", "k2".into(), "v".into(), Duration::from_secs(60))
+            .unwrap();
+        store.set("t1", "k3".into(), "v".into()).unwrap();
+        assert_eq!(store.find("t1", "value", &"v".into()).unwrap().len(), 2);
+        assert_eq!(store.purge_expired().unwrap(), 1);
+        // 只有过期的 key 从索引中去掉
+        let indexes = store.indexes.read().unwrap();
+        let table_indexes = indexes["t1"].lock().unwrap();
+        let keys: Vec<_> = table_indexes.indexes[0].values.keys().cloned().collect();
+        assert_eq!(keys.len(), 2);
+        assert!(!keys.contains(&"k1".to_string()));
+        assert_eq!(table_indexes.expiring, BTreeSet::from(["k2".to_string()]));
+    }
+}
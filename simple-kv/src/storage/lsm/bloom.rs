@@ -0,0 +1,82 @@
+/// 每个 key 占用的 bit 数，误判率大约是 1%
+const BITS_PER_KEY: usize = 10;
+
+/// 布隆过滤器，用来快速判断一个 key 一定不在 SSTable 中
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct Bloom {
+    bits: Vec<u8>,
+    hashes: u32,
+}
+
+impl Bloom {
+    /// 根据所有 key 的 hash 值创建布隆过滤器
+    pub fn new(keys: &[u64]) -> Self {
+        let nbits = (keys.len() * BITS_PER_KEY).max(64);
+        // 最优的 hash 函数个数是 BITS_PER_KEY * ln2
+        let hashes = (BITS_PER_KEY as u32 * 69 / 100).clamp(1, 30);
+        let mut bloom = Self {
+            bits: vec![0; nbits.div_ceil(8)],
+            hashes,
+        };
+        for h in keys {
+            for bit in bloom.bits_of(*h) {
+                bloom.bits[bit / 8] |= 1 << (bit % 8);
+            }
+        }
+        bloom
+    }
+
+    /// 返回 false 时 key 一定不存在，返回 true 时 key 可能存在
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        self.bits_of(hash(key))
+            .all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = self.bits.clone();
+        data.push(self.hashes as u8);
+        data
+    }
+
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        let (hashes, bits) = data.split_last()?;
+        (!bits.is_empty()).then(|| Self {
+            bits: bits.to_vec(),
+            hashes: *hashes as u32,
+        })
+    }
+
+    /// 用两个 hash 值模拟多个 hash 函数（double hashing）
+    fn bits_of(&self, h: u64) -> impl Iterator<Item = usize> {
+        let nbits = self.bits.len() * 8;
+        let (h1, h2) = (h as u32, (h >> 32) as u32);
+        (0..self.hashes).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) as usize % nbits)
+    }
+}
+
+/// FNV-1a hash，布隆过滤器会写入文件，所以不能用每次启动都不一样的 hash 函数
+pub(super) fn hash(key: &[u8]) -> u64 {
+    key.iter().fold(0xcbf2_9ce4_8422_2325, |h, b| {
+        (h ^ *b as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bloom_should_work() {
+        let keys: Vec<_> = (0..1000).map(|i| format!("key{}", i)).collect();
+        let hashes: Vec<_> = keys.iter().map(|k| hash(k.as_bytes())).collect();
+        let bloom = Bloom::decode(&Bloom::new(&hashes).encode()).unwrap();
+
+        // 存在的 key 一定返回 true
+        assert!(keys.iter().all(|k| bloom.may_contain(k.as_bytes())));
+        // 不存在的 key 大部分返回 false
+        let hits = (0..1000)
+            .filter(|i| bloom.may_contain(format!("other{}", i).as_bytes()))
+            .count();
+        assert!(hits < 50);
+    }
+}
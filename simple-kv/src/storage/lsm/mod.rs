@@ -0,0 +1,1086 @@
+mod bloom;
+mod sstable;
+
+use crate::{
+    FsyncPolicy, KvError, Kvpair, Storage, StorageStats, TableStats, TxnStorage, Value, WalEntry,
+    WalRecord,
+};
+use bytes::{BufMut, BytesMut};
+use prost::Message;
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fs::{self, File, OpenOptions},
+    io::Write,
+    iter::Peekable,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard, RwLock},
+    time::Duration,
+};
+use tracing::{info, warn};
+
+use self::sstable::{SsTable, SsTableBuilder};
+use super::{
+    add_float, add_integer, deadline_from, now_millis, remaining,
+    wal::{encode_record, is_legacy, read_records, LOG_MAGIC},
+};
+
+/// 预写日志的文件名
+const LOG_FILE: &str = "wal.log";
+/// 记录每一层有哪些 SSTable 的文件名
+const MANIFEST_FILE: &str = "MANIFEST";
+/// 写 MANIFEST 时先写入这个文件，写完再 rename 成 MANIFEST_FILE
+const MANIFEST_TMP_FILE: &str = "MANIFEST.tmp";
+/// 第 0 层的 SSTable 达到这个个数之后合并到第 1 层
+const L0_COMPACTION_TRIGGER: usize = 4;
+/// 每一层的大小是上一层的多少倍
+const LEVEL_MULTIPLIER: u64 = 10;
+
+/// 一个 key 的某个版本，value 为 None 表示 key 已经被删除（tombstone）
+#[derive(Debug, Clone, PartialEq)]
+struct Entry {
+    value: Option<Value>,
+    // 过期的时间点（毫秒），0 表示没有过期时间
+    deadline: u64,
+}
+
+impl Entry {
+    fn new(value: Value, deadline: u64) -> Self {
+        Self {
+            value: Some(value),
+            deadline,
+        }
+    }
+
+    fn tombstone() -> Self {
+        Self {
+            value: None,
+            deadline: 0,
+        }
+    }
+
+    fn is_expired(&self, now: u64) -> bool {
+        self.deadline > 0 && self.deadline <= now
+    }
+
+    /// 没有被删除也没有过期
+    fn is_live(&self, now: u64) -> bool {
+        self.value.is_some() && !self.is_expired(now)
+    }
+
+    fn size(&self) -> usize {
+        8 + self.value.as_ref().map_or(0, |v| v.encoded_len())
+    }
+}
+
+/// 基于 LSM-tree 的存储，实现了 Storage trait
+///
+/// 写操作先追加到预写日志，再写入内存中的 memtable；memtable 超过 memtable_size 之后
+/// 写成第 0 层的 SSTable 并清空日志。第 0 层的 SSTable 之间 key 的范围可能重叠，
+/// 个数达到 L0_COMPACTION_TRIGGER 之后和第 1 层合并；第 1 层及以下每层的 SSTable
+/// 互不重叠，大小超过上限之后挑一个 SSTable 和下一层合并（leveled compaction）。
+///
+/// 所有的 table 共用一个 key 空间，内部的 key 是 table 名字的长度、table 名字和 key
+/// 拼接起来的，这样同一个 table 的 key 是连续的。写操作持有日志锁，读操作不需要
+///
+/// 打开之后写入的有过期时间的 key 记录在内存中，purge_expired 只检查它们；
+/// 之前写入 SSTable 的过期 key 读不到，在 compaction 时被删除。
+/// drop_table 和 rename_table 需要持有日志锁遍历整个 table，list_tables 需要遍历每个 table 的开头
+#[derive(Debug)]
+pub struct LsmDb {
+    dir: PathBuf,
+    memtable_size: usize,
+    state: RwLock<State>,
+    writer: Mutex<Writer>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    memtable: BTreeMap<Vec<u8>, Entry>,
+    // memtable 大约占用的字节数
+    memtable_bytes: usize,
+    // 第 0 层从新到旧排列，其它层按 key 的顺序排列
+    levels: Vec<Vec<Arc<SsTable>>>,
+    // 打开之后写入的有过期时间的 key，按过期时间排列
+    deadlines: BTreeSet<(u64, Vec<u8>)>,
+    // deadlines 中每个 key 的过期时间
+    deadline_of: HashMap<Vec<u8>, u64>,
+}
+
+impl State {
+    /// 把 entry 写入 memtable，同时更新 key 的过期时间
+    fn insert(&mut self, key: Vec<u8>, entry: Entry) {
+        self.memtable_bytes += key.len() + entry.size();
+        if let Some(old) = self.deadline_of.remove(&key) {
+            self.deadlines.remove(&(old, key.clone()));
+        }
+        if entry.deadline > 0 {
+            self.deadlines.insert((entry.deadline, key.clone()));
+            self.deadline_of.insert(key.clone(), entry.deadline);
+        }
+        self.memtable.insert(key, entry);
+    }
+}
+
+#[derive(Debug)]
+struct Writer {
+    log: File,
+    // 日志当前的大小
+    log_size: u64,
+    fsync_policy: FsyncPolicy,
+    next_id: u64,
+}
+
+impl LsmDb {
+    /// 打开 dir 中的数据，目录不存在时会创建
+    pub fn open(
+        dir: impl AsRef<Path>,
+        fsync_policy: FsyncPolicy,
+        memtable_size: usize,
+    ) -> Result<Self, KvError> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let mut state = State::default();
+        let mut next_id = 1;
+        let manifest = fs::read_to_string(dir.join(MANIFEST_FILE)).unwrap_or_default();
+        for line in manifest.lines() {
+            let (level, id) = parse_manifest_line(line)
+                .ok_or_else(|| KvError::Internal(format!("Invalid manifest line: {:?}", line)))?;
+            let table = SsTable::open(sstable_path(&dir, id), id)?;
+            if state.levels.len() <= level {
+                state.levels.resize_with(level + 1, Vec::new);
+            }
+            state.levels[level].push(Arc::new(table));
+            next_id = next_id.max(id + 1);
+        }
+        for level in state.levels.iter_mut().skip(1) {
+            level.sort_by(|a, b| a.first_key.cmp(&b.first_key));
+        }
+        if state.levels.is_empty() {
+            state.levels.push(Vec::new());
+        }
+
+        // 删除没有写入 MANIFEST 的文件（比如 compaction 到一半时崩溃了）
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            let id = match parse_sstable_id(&path) {
+                Some(id) => id,
+                None => continue,
+            };
+            next_id = next_id.max(id + 1);
+            if !state.levels.iter().flatten().any(|t| t.id == id) {
+                warn!("Remove unused SSTable {}", path.display());
+                fs::remove_file(&path)?;
+            }
+        }
+
+        let path = dir.join(LOG_FILE);
+        let data = fs::read(&path).unwrap_or_default();
+        let size = read_records(&data, |record| {
+            for entry in record.entries {
+                let key = internal_key(&entry.table, &entry.key);
+                let entry = Entry {
+                    value: entry.value,
+                    deadline: entry.deadline,
+                };
+                state.insert(key, entry);
+            }
+        })?;
+        let log = OpenOptions::new().create(true).append(true).open(&path)?;
+        if size < data.len() {
+            // 最后一条记录没有写完整（比如写到一半时崩溃了），直接丢弃
+            warn!("Discard {} bytes of incomplete log", data.len() - size);
+            log.set_len(size as u64)?;
+        }
+        info!(
+            "Opened LSM storage with {} SSTables and {} keys in memtable",
+            state.levels.iter().flatten().count(),
+            state.memtable.len()
+        );
+
+        let db = Self {
+            dir,
+            memtable_size,
+            state: RwLock::new(state),
+            writer: Mutex::new(Writer {
+                log,
+                log_size: size as u64,
+                fsync_policy,
+                next_id,
+            }),
+        };
+        if size > 0 && is_legacy(&data) {
+            // 旧格式的日志没有校验和，把其中的数据落盘，之后的日志都使用新的格式
+            let mut writer = db.writer.lock().unwrap();
+            db.flush(&mut writer)?;
+        }
+        Ok(db)
+    }
+
+    /// 查找 key 最新的版本，可能是 tombstone 或者已经过期
+    fn lookup(&self, key: &[u8]) -> Result<Option<Entry>, KvError> {
+        let state = self.state.read().unwrap();
+        if let Some(entry) = state.memtable.get(key) {
+            return Ok(Some(entry.clone()));
+        }
+        for table in &state.levels[0] {
+            if let Some(entry) = table.get(key)? {
+                return Ok(Some(entry));
+            }
+        }
+        for level in &state.levels[1..] {
+            let i = level.partition_point(|t| t.last_key.as_slice() < key);
+            match level.get(i) {
+                Some(table) if table.first_key.as_slice() <= key => {
+                    if let Some(entry) = table.get(key)? {
+                        return Ok(Some(entry));
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(None)
+    }
+
+    /// 查找 key 当前的值，被删除或者已经过期时返回 None
+    fn live(&self, key: &[u8]) -> Result<Option<Entry>, KvError> {
+        Ok(self.lookup(key)?.filter(|e| e.is_live(now_millis())))
+    }
+
+    /// 和 live 一样，但 key 已经过期时会写入 tombstone 删除它
+    fn live_or_purge(&self, key: &[u8]) -> Result<Option<Entry>, KvError> {
+        match self.lookup(key)? {
+            Some(entry) if entry.is_live(now_millis()) => Ok(Some(entry)),
+            Some(entry) if entry.value.is_some() => {
+                let mut writer = self.writer.lock().unwrap();
+                // 拿到锁之前 key 可能已经被修改了
+                if let Some(entry) = self.lookup(key)? {
+                    if entry.value.is_some() && entry.is_expired(now_millis()) {
+                        self.write(&mut writer, vec![(key.to_vec(), Entry::tombstone())])?;
+                    }
+                }
+                Ok(None)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// 把 entries 作为一条记录写入日志，然后写入 memtable，memtable 太大时落盘
+    fn write(
+        &self,
+        writer: &mut MutexGuard<Writer>,
+        entries: Vec<(Vec<u8>, Entry)>,
+    ) -> Result<(), KvError> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+        let record = WalRecord {
+            entries: entries
+                .iter()
+                .map(|(key, entry)| {
+                    let (table, key) = split_key(key);
+                    WalEntry {
+                        table,
+                        key,
+                        value: entry.value.clone(),
+                        deadline: entry.deadline,
+                    }
+                })
+                .collect(),
+            ..Default::default()
+        };
+        let mut buf = BytesMut::new();
+        if writer.log_size == 0 {
+            buf.put_slice(LOG_MAGIC);
+        }
+        encode_record(&record, &mut buf)?;
+        writer.log.write_all(&buf)?;
+        if writer.fsync_policy == FsyncPolicy::Always {
+            writer.log.sync_data()?;
+        }
+        writer.log_size += buf.len() as u64;
+
+        let full = {
+            let mut state = self.state.write().unwrap();
+            for (key, entry) in entries {
+                state.insert(key, entry);
+            }
+            state.memtable_bytes >= self.memtable_size
+        };
+        if full {
+            // 日志已经写成功了，落盘失败不影响这次修改，下次写入时会再尝试
+            if let Err(e) = self.flush(writer).and_then(|_| self.compact(writer)) {
+                warn!("Failed to flush memtable: {:?}", e);
+            }
+        }
+        Ok(())
+    }
+
+    /// 把 memtable 写成第 0 层的 SSTable，然后清空日志
+    fn flush(&self, writer: &mut MutexGuard<Writer>) -> Result<(), KvError> {
+        let id = writer.next_id;
+        writer.next_id += 1;
+        let table = {
+            // 只有持有日志锁才能修改 memtable，所以这里只需要读锁
+            let state = self.state.read().unwrap();
+            let mut builder = SsTableBuilder::new(sstable_path(&self.dir, id))?;
+            let now = now_millis();
+            let tombstone = Entry::tombstone();
+            for (key, entry) in state.memtable.iter() {
+                // 已经过期的 key 只需要写入 tombstone
+                match entry.is_expired(now) {
+                    true => builder.add(key, &tombstone)?,
+                    false => builder.add(key, entry)?,
+                }
+            }
+            builder.finish(id)?
+        };
+
+        {
+            let mut state = self.state.write().unwrap();
+            state.levels[0].insert(0, Arc::new(table));
+            state.memtable.clear();
+            state.memtable_bytes = 0;
+        }
+        self.save_manifest()?;
+        writer.log.set_len(0)?;
+        writer.log.sync_all()?;
+        writer.log_size = 0;
+        Ok(())
+    }
+
+    /// 不断合并需要合并的层，直到每一层都不超过上限
+    fn compact(&self, writer: &mut MutexGuard<Writer>) -> Result<(), KvError> {
+        while let Some((level, inputs)) = self.pick_compaction() {
+            self.compact_level(writer, level, inputs)?;
+        }
+        Ok(())
+    }
+
+    /// 找出需要合并到下一层的 SSTable
+    fn pick_compaction(&self) -> Option<(usize, Vec<Arc<SsTable>>)> {
+        let state = self.state.read().unwrap();
+        if state.levels[0].len() >= L0_COMPACTION_TRIGGER {
+            return Some((0, state.levels[0].clone()));
+        }
+        let mut max_bytes = self.memtable_size as u64 * LEVEL_MULTIPLIER;
+        for (i, level) in state.levels.iter().enumerate().skip(1) {
+            if level.iter().map(|t| t.size).sum::<u64>() > max_bytes {
+                return Some((i, vec![level[0].clone()]));
+            }
+            max_bytes = max_bytes.saturating_mul(LEVEL_MULTIPLIER);
+        }
+        None
+    }
+
+    /// 把 level 层的 inputs 和下一层中与之重叠的 SSTable 合并，写入下一层
+    fn compact_level(
+        &self,
+        writer: &mut MutexGuard<Writer>,
+        level: usize,
+        inputs: Vec<Arc<SsTable>>,
+    ) -> Result<(), KvError> {
+        let (overlaps, bottom) = {
+            let state = self.state.read().unwrap();
+            let first = inputs.iter().map(|t| &t.first_key).min().unwrap();
+            let last = inputs.iter().map(|t| &t.last_key).max().unwrap();
+            let overlaps: Vec<_> = state
+                .levels
+                .get(level + 1)
+                .map(|tables| {
+                    tables
+                        .iter()
+                        .filter(|t| &t.last_key >= first && &t.first_key <= last)
+                        .cloned()
+                        .collect()
+                })
+                .unwrap_or_default();
+            let bottom = state.levels.iter().skip(level + 2).all(|l| l.is_empty());
+            (overlaps, bottom)
+        };
+
+        // inputs 比下一层的数据新，第 0 层的 inputs 本身也是从新到旧排列的
+        let mut sources: Vec<Source> = inputs
+            .iter()
+            .map(|t| Box::new(t.clone().iter(&[])) as Source)
+            .collect();
+        sources.push(Box::new(
+            overlaps.clone().into_iter().flat_map(|t| t.iter(&[])),
+        ));
+
+        let now = now_millis();
+        let target_size = self.memtable_size as u64;
+        let mut outputs = Vec::new();
+        let mut builder: Option<SsTableBuilder> = None;
+        for item in MergeIter::new(sources, None) {
+            let (key, mut entry) = item?;
+            // 最底层没有更旧的数据了，可以直接丢掉 tombstone 和过期的 key；
+            // 其它层过期的 key 换成 tombstone，这样不会读到下一层中更旧的版本
+            if bottom && !entry.is_live(now) {
+                continue;
+            }
+            if entry.is_expired(now) {
+                entry = Entry::tombstone();
+            }
+            let b = match builder.as_mut() {
+                Some(b) => b,
+                None => {
+                    let path = sstable_path(&self.dir, writer.next_id);
+                    builder.insert(SsTableBuilder::new(path)?)
+                }
+            };
+            b.add(&key, &entry)?;
+            if b.size() >= target_size {
+                outputs.push(builder.take().unwrap().finish(writer.next_id)?);
+                writer.next_id += 1;
+            }
+        }
+        if let Some(b) = builder {
+            if !b.is_empty() {
+                outputs.push(b.finish(writer.next_id)?);
+            }
+            writer.next_id += 1;
+        }
+
+        let removed: Vec<_> = inputs.iter().chain(overlaps.iter()).cloned().collect();
+        {
+            let mut state = self.state.write().unwrap();
+            if state.levels.len() <= level + 1 {
+                state.levels.resize_with(level + 2, Vec::new);
+            }
+            for tables in &mut state.levels[level..=level + 1] {
+                tables.retain(|t| !removed.iter().any(|r| r.id == t.id));
+            }
+            let next = &mut state.levels[level + 1];
+            next.extend(outputs.into_iter().map(Arc::new));
+            next.sort_by(|a, b| a.first_key.cmp(&b.first_key));
+        }
+        self.save_manifest()?;
+
+        // 正在遍历这些文件的 iterator 持有打开的文件，删除之后仍然可以读取
+        for table in removed {
+            fs::remove_file(&table.path)?;
+        }
+        info!("Compacted level {} into level {}", level, level + 1);
+        Ok(())
+    }
+
+    /// 把每一层的 SSTable 写入 MANIFEST
+    fn save_manifest(&self) -> Result<(), KvError> {
+        let manifest: String = {
+            let state = self.state.read().unwrap();
+            state
+                .levels
+                .iter()
+                .enumerate()
+                .flat_map(|(i, level)| level.iter().map(move |t| format!("{} {}\n", i, t.id)))
+                .collect()
+        };
+        let tmp = self.dir.join(MANIFEST_TMP_FILE);
+        let mut file = File::create(&tmp)?;
+        file.write_all(manifest.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp, self.dir.join(MANIFEST_FILE))?;
+        File::open(&self.dir)?.sync_all()?;
+        Ok(())
+    }
+
+    /// 按 key 的顺序遍历 [start, end) 范围内每个 key 最新的版本，包括 tombstone
+    fn scan(&self, start: &[u8], end: Option<Vec<u8>>) -> MergeIter {
+        let state = self.state.read().unwrap();
+        let memtable: Vec<_> = state
+            .memtable
+            .range(start.to_vec()..)
+            .take_while(|(k, _)| end.as_ref().is_none_or(|end| *k < end))
+            .map(|(k, e)| Ok((k.clone(), e.clone())))
+            .collect();
+
+        let mut sources: Vec<Source> = vec![Box::new(memtable.into_iter())];
+        for table in &state.levels[0] {
+            sources.push(Box::new(table.clone().iter(start)));
+        }
+        for level in &state.levels[1..] {
+            let i = level.partition_point(|t| t.last_key.as_slice() < start);
+            let start = start.to_vec();
+            let tables = level[i..].to_vec();
+            sources.push(Box::new(
+                tables.into_iter().flat_map(move |t| t.iter(&start)),
+            ));
+        }
+        MergeIter::new(sources, end)
+    }
+
+    /// 遍历 table 中 [start, end) 范围内没有被删除的 key，包括已经过期的 key
+    fn scan_table(
+        &self,
+        table: &str,
+        start: &str,
+        end: Option<&str>,
+    ) -> impl Iterator<Item = Result<(Vec<u8>, Entry), KvError>> {
+        let end = match end {
+            Some(end) => Some(internal_key(table, end)),
+            None => prefix_end(&internal_key(table, "")),
+        };
+        self.scan(&internal_key(table, start), end)
+            .filter(|item| !matches!(item, Ok((_, e)) if e.value.is_none()))
+    }
+
+    /// 持有日志锁修改 key 的值，f 根据 key 当前的值返回新的值和返回给调用者的结果
+    fn update<T, F>(&self, table: &str, key: &str, f: F) -> Result<T, KvError>
+    where
+        F: FnOnce(Option<Entry>) -> Result<(Option<Entry>, T), KvError>,
+    {
+        let mut writer = self.writer.lock().unwrap();
+        let key = internal_key(table, key);
+        let (entry, result) = f(self.live(&key)?)?;
+        if let Some(entry) = entry {
+            self.write(&mut writer, vec![(key, entry)])?;
+        }
+        Ok(result)
+    }
+}
+
+impl Storage for LsmDb {
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        let entry = self.live_or_purge(&internal_key(table, key))?;
+        Ok(entry.and_then(|e| e.value))
+    }
+
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        self.update(table, &key, |old| {
+            Ok((Some(Entry::new(value, 0)), old.and_then(|e| e.value)))
+        })
+    }
+
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        Ok(self.live_or_purge(&internal_key(table, key))?.is_some())
+    }
+
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.update(table, key, |old| match old {
+            Some(old) => Ok((Some(Entry::tombstone()), old.value)),
+            None => Ok((None, None)),
+        })
+    }
+
+    fn compare_and_swap(
+        &self,
+        table: &str,
+        key: String,
+        expected: Option<Value>,
+        value: Value,
+    ) -> Result<bool, KvError> {
+        self.update(table, &key, |old| {
+            match old.and_then(|e| e.value) == expected {
+                true => Ok((Some(Entry::new(value, 0)), true)),
+                false => Ok((None, false)),
+            }
+        })
+    }
+
+    fn set_if_present(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+    ) -> Result<Option<Value>, KvError> {
+        self.update(table, &key, |old| match old {
+            Some(old) => Ok((Some(Entry::new(value, 0)), old.value)),
+            None => Ok((None, None)),
+        })
+    }
+
+    fn incr(&self, table: &str, key: &str, delta: i64) -> Result<i64, KvError> {
+        let value = self.update(table, key, |old| {
+            let deadline = old.as_ref().map_or(0, |e| e.deadline);
+            let value = add_integer(old.as_ref().and_then(|e| e.value.as_ref()), delta)?;
+            Ok((Some(Entry::new(value.clone(), deadline)), value))
+        })?;
+        i64::try_from(&value)
+    }
+
+    fn incr_float(&self, table: &str, key: &str, delta: f64) -> Result<f64, KvError> {
+        let value = self.update(table, key, |old| {
+            let deadline = old.as_ref().map_or(0, |e| e.deadline);
+            let value = add_float(old.as_ref().and_then(|e| e.value.as_ref()), delta)?;
+            Ok((Some(Entry::new(value.clone(), deadline)), value))
+        })?;
+        f64::try_from(&value)
+    }
+
+    fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        self.get_range(table, "", None, usize::MAX)
+    }
+
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
+        let now = now_millis();
+        let iter = self
+            .scan_table(table, "", None)
+            .map_while(|item| match item {
+                Ok(v) => Some(v),
+                Err(e) => {
+                    warn!("Failed to iterate table: {:?}", e);
+                    None
+                }
+            })
+            .filter(move |(_, e)| e.is_live(now))
+            .map(|(k, e)| Kvpair::new(split_key(&k).1, e.value.unwrap_or_default()));
+        Ok(Box::new(iter))
+    }
+
+    fn get_range(
+        &self,
+        table: &str,
+        start: &str,
+        end: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        let now = now_millis();
+        let mut pairs = Vec::new();
+        for item in self.scan_table(table, start, end) {
+            if pairs.len() >= limit {
+                break;
+            }
+            let (key, entry) = item?;
+            if entry.is_live(now) {
+                pairs.push(Kvpair::new(
+                    split_key(&key).1,
+                    entry.value.unwrap_or_default(),
+                ));
+            }
+        }
+        Ok(pairs)
+    }
+
+    fn set_with_ttl(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+        ttl: Duration,
+    ) -> Result<Option<Value>, KvError> {
+        self.update(table, &key, |old| {
+            let entry = Entry::new(value, deadline_from(ttl));
+            Ok((Some(entry), old.and_then(|e| e.value)))
+        })
+    }
+
+    fn expire(&self, table: &str, key: &str, ttl: Duration) -> Result<bool, KvError> {
+        self.update(table, key, |old| match old {
+            Some(old) => Ok((
+                Some(Entry {
+                    deadline: deadline_from(ttl),
+                    ..old
+                }),
+                true,
+            )),
+            None => Ok((None, false)),
+        })
+    }
+
+    fn ttl(&self, table: &str, key: &str) -> Result<Option<Duration>, KvError> {
+        let entry = self.live(&internal_key(table, key))?;
+        Ok(entry.and_then(|e| (e.deadline > 0).then(|| remaining(e.deadline)).flatten()))
+    }
+
+    fn persist(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.update(table, key, |old| match old {
+            Some(old) if old.deadline > 0 => Ok((Some(Entry { deadline: 0, ..old }), true)),
+            _ => Ok((None, false)),
+        })
+    }
+
+    fn purge_expired(&self) -> Result<usize, KvError> {
+        let mut writer = self.writer.lock().unwrap();
+        let now = now_millis();
+        // 只检查记录下来的、已经到了过期时间的 key，不需要遍历整个数据库
+        let candidates: Vec<_> = {
+            let mut state = self.state.write().unwrap();
+            let rest = state.deadlines.split_off(&(now + 1, Vec::new()));
+            let expired = std::mem::replace(&mut state.deadlines, rest);
+            for (_, key) in expired.iter() {
+                state.deadline_of.remove(key);
+            }
+            expired.into_iter().map(|(_, key)| key).collect()
+        };
+        // 落盘和 compaction 时可能已经把过期的 key 换成了 tombstone，它们也算在删除的个数中；
+        // 之后被修改或者删除的 key 已经不在 deadlines 中了
+        let mut count = 0;
+        let mut expired = Vec::new();
+        for key in candidates {
+            match self.lookup(&key)? {
+                Some(entry) if entry.value.is_some() && entry.is_expired(now) => {
+                    expired.push((key, Entry::tombstone()));
+                    count += 1;
+                }
+                Some(entry) if entry.value.is_none() => count += 1,
+                _ => {}
+            }
+        }
+        self.write(&mut writer, expired)?;
+        Ok(count)
+    }
+
+    fn list_tables(&self) -> Result<Vec<String>, KvError> {
+        // 找到一个 table 之后直接跳到下一个 table 的开头，不需要遍历 table 中所有的 key
+        let mut tables = Vec::new();
+        let mut start = Vec::new();
+        loop {
+            let mut next = None;
+            for item in self.scan(&start, None) {
+                let (key, entry) = item?;
+                if entry.value.is_some() {
+                    next = Some(split_key(&key).0);
+                    break;
+                }
+            }
+            let table = match next {
+                Some(table) => table,
+                None => break,
+            };
+            let end = prefix_end(&internal_key(&table, ""));
+            tables.push(table);
+            match end {
+                Some(end) => start = end,
+                None => break,
+            }
+        }
+        tables.sort();
+        Ok(tables)
+    }
+
+    fn table_info(&self, table: &str) -> Result<Option<TableStats>, KvError> {
+        let now = now_millis();
+        let mut exists = false;
+        let mut stats = TableStats::default();
+        for item in self.scan_table(table, "", None) {
+            let (key, entry) = item?;
+            exists = true;
+            if let (true, Some(value)) = (entry.is_live(now), &entry.value) {
+                stats.keys += 1;
+                stats.bytes += split_key(&key).1.len() + value.encoded_len();
+            }
+        }
+        Ok(exists.then_some(stats))
+    }
+
+    fn drop_table(&self, table: &str) -> Result<bool, KvError> {
+        let mut writer = self.writer.lock().unwrap();
+        let entries = self
+            .scan_table(table, "", None)
+            .map(|item| item.map(|(key, _)| (key, Entry::tombstone())))
+            .collect::<Result<Vec<_>, _>>()?;
+        let exists = !entries.is_empty();
+        self.write(&mut writer, entries)?;
+        Ok(exists)
+    }
+
+    fn rename_table(&self, table: &str, to: &str) -> Result<bool, KvError> {
+        let mut writer = self.writer.lock().unwrap();
+        let data = self
+            .scan_table(table, "", None)
+            .collect::<Result<Vec<_>, _>>()?;
+        if data.is_empty() {
+            return Ok(false);
+        }
+        if self.scan_table(to, "", None).next().is_some() {
+            return Err(KvError::PreconditionFailed(format!(
+                "table {} already exists",
+                to
+            )));
+        }
+
+        // 在同一条记录中删除旧的 key 并写入新的 key，保证重命名是原子的
+        let mut entries = Vec::with_capacity(data.len() * 2);
+        for (key, entry) in data {
+            let (_, k) = split_key(&key);
+            entries.push((key, Entry::tombstone()));
+            entries.push((internal_key(to, &k), entry));
+        }
+        self.write(&mut writer, entries)?;
+        Ok(true)
+    }
+
+    fn stats(&self) -> Result<StorageStats, KvError> {
+        let state = self.state.read().unwrap();
+        let sstables: u64 = state.levels.iter().flatten().map(|t| t.size).sum();
+        Ok(StorageStats {
+            used_bytes: state.memtable_bytes + sstables as usize,
+            ..Default::default()
+        })
+    }
+
+    fn transaction<T, F>(&self, f: F) -> Result<T, KvError>
+    where
+        F: Fn(&dyn TxnStorage) -> Result<T, KvError>,
+    {
+        // 持有日志锁，事务执行期间其它的写操作都需要等待
+        let mut writer = self.writer.lock().unwrap();
+        let txn = LsmTxn {
+            store: self,
+            writes: RefCell::default(),
+        };
+        let result = f(&txn)?;
+        // 事务中的修改只保存在 writes 中，成功之后作为一条记录写入
+        let entries = txn.writes.into_inner().into_iter().collect();
+        self.write(&mut writer, entries)?;
+        Ok(result)
+    }
+}
+
+/// 事务中的修改先保存在 writes 中，事务成功之后才写入 LsmDb
+struct LsmTxn<'a> {
+    store: &'a LsmDb,
+    writes: RefCell<BTreeMap<Vec<u8>, Entry>>,
+}
+
+impl LsmTxn<'_> {
+    fn value(&self, key: &[u8]) -> Result<Option<Value>, KvError> {
+        if let Some(entry) = self.writes.borrow().get(key) {
+            return Ok(entry.value.clone());
+        }
+        Ok(self.store.live(key)?.and_then(|e| e.value))
+    }
+}
+
+impl TxnStorage for LsmTxn<'_> {
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.value(&internal_key(table, key))
+    }
+
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        let key = internal_key(table, &key);
+        let old = self.value(&key)?;
+        self.writes.borrow_mut().insert(key, Entry::new(value, 0));
+        Ok(old)
+    }
+
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        Ok(self.get(table, key)?.is_some())
+    }
+
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        let key = internal_key(table, key);
+        let old = self.value(&key)?;
+        if old.is_some() {
+            self.writes.borrow_mut().insert(key, Entry::tombstone());
+        }
+        Ok(old)
+    }
+
+    fn ttl(&self, table: &str, key: &str) -> Result<Option<Duration>, KvError> {
+        let key = internal_key(table, key);
+        // 事务中写入的 key 都没有过期时间
+        let deadline = match self.writes.borrow().get(&key) {
+            Some(entry) => entry.deadline,
+            None => self.store.live(&key)?.map_or(0, |e| e.deadline),
+        };
+        Ok((deadline > 0).then(|| remaining(deadline)).flatten())
+    }
+}
+
+type Source = Box<dyn Iterator<Item = Result<(Vec<u8>, Entry), KvError>> + Send>;
+
+/// 合并多个有序的数据源，同一个 key 只返回最新的版本（sources 中越靠前的越新）
+struct MergeIter {
+    sources: Vec<Peekable<Source>>,
+    end: Option<Vec<u8>>,
+}
+
+impl MergeIter {
+    fn new(sources: Vec<Source>, end: Option<Vec<u8>>) -> Self {
+        Self {
+            sources: sources.into_iter().map(|s| s.peekable()).collect(),
+            end,
+        }
+    }
+}
+
+impl Iterator for MergeIter {
+    type Item = Result<(Vec<u8>, Entry), KvError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // 找到 key 最小的数据源，key 相同时选择最新的
+        let mut min: Option<(usize, &[u8])> = None;
+        for (i, source) in self.sources.iter_mut().enumerate() {
+            match source.peek() {
+                Some(Ok((key, _))) if min.is_none_or(|(_, k)| key.as_slice() < k) => {
+                    min = Some((i, key));
+                }
+                Some(Ok(_)) => {}
+                Some(Err(_)) => return source.next(),
+                None => {}
+            }
+        }
+        let (i, key) = min?;
+        if self.end.as_deref().is_some_and(|end| key >= end) {
+            return None;
+        }
+        let key = key.to_vec();
+
+        // 其它数据源中同一个 key 的旧版本直接跳过
+        for source in self.sources.iter_mut().skip(i + 1) {
+            if matches!(source.peek(), Some(Ok((k, _))) if *k == key) {
+                source.next();
+            }
+        }
+        self.sources[i].next()
+    }
+}
+
+/// 内部的 key：table 名字的长度（4 字节）、table 名字、key
+fn internal_key(table: &str, key: &str) -> Vec<u8> {
+    let mut data = Vec::with_capacity(4 + table.len() + key.len());
+    data.extend_from_slice(&(table.len() as u32).to_be_bytes());
+    data.extend_from_slice(table.as_bytes());
+    data.extend_from_slice(key.as_bytes());
+    data
+}
+
+/// 把内部的 key 拆分成 table 和 key
+fn split_key(data: &[u8]) -> (String, String) {
+    let len = u32::from_be_bytes(data[..4].try_into().unwrap()) as usize;
+    let (table, key) = data[4..].split_at(len);
+    (
+        String::from_utf8_lossy(table).into(),
+        String::from_utf8_lossy(key).into(),
+    )
+}
+
+/// 比所有以 prefix 开头的 key 都大的最小的 key
+fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+    None
+}
+
+fn sstable_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{:06}.sst", id))
+}
+
+fn parse_sstable_id(path: &Path) -> Option<u64> {
+    match path.extension() {
+        Some(ext) if ext == "sst" => path.file_stem()?.to_str()?.parse().ok(),
+        _ => None,
+    }
+}
+
+fn parse_manifest_line(line: &str) -> Option<(usize, u64)> {
+    let (level, id) = line.split_once(' ')?;
+    Some((level.parse().ok()?, id.parse().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn lsm_should_recover_after_reopen() {
+        let dir = tempdir().unwrap();
+        {
+            let store = LsmDb::open(&dir, FsyncPolicy::Never, 256).unwrap();
+            for i in 0..100 {
+                store.set("t1", format!("k{:03}", i), i.into()).unwrap();
+            }
+            store.del("t1", "k010").unwrap();
+            store.set("t1", "k011".into(), "v11".into()).unwrap();
+            // 部分数据已经写入 SSTable，部分还在日志中
+            let state = store.state.read().unwrap();
+            assert!(state.levels.iter().flatten().count() > 0);
+            assert!(!state.memtable.is_empty());
+        }
+
+        let store = LsmDb::open(&dir, FsyncPolicy::Never, 256).unwrap();
+        assert_eq!(store.get_all("t1").unwrap().len(), 99);
+        assert_eq!(store.get("t1", "k010").unwrap(), None);
+        assert_eq!(store.get("t1", "k011").unwrap(), Some("v11".into()));
+        assert_eq!(store.get("t1", "k099").unwrap(), Some(99.into()));
+    }
+
+    #[test]
+    fn lsm_compaction_should_keep_latest_version() {
+        let dir = tempdir().unwrap();
+        let store = LsmDb::open(&dir, FsyncPolicy::Never, 256).unwrap();
+        for round in 0..10 {
+            for i in 0..50 {
+                store
+                    .set("t1", format!("k{:02}", i), (round * 100 + i).into())
+                    .unwrap();
+            }
+        }
+        for i in 0..25 {
+            store.del("t1", &format!("k{:02}", i)).unwrap();
+        }
+        {
+            let state = store.state.read().unwrap();
+            assert!(state.levels[0].len() < L0_COMPACTION_TRIGGER);
+            assert!(state.levels.len() > 1);
+            // 第 1 层及以下的 SSTable 互不重叠
+            for level in &state.levels[1..] {
+                for pair in level.windows(2) {
+                    assert!(pair[0].last_key < pair[1].first_key);
+                }
+            }
+        }
+
+        let pairs = store.get_all("t1").unwrap();
+        assert_eq!(pairs.len(), 25);
+        assert_eq!(pairs[0], Kvpair::new("k25", 925.into()));
+        assert_eq!(store.get("t1", "k49").unwrap(), Some(949.into()));
+        assert_eq!(store.get("t1", "k00").unwrap(), None);
+
+        // 磁盘上只有 MANIFEST 中记录的文件
+        let files = fs::read_dir(&dir)
+            .unwrap()
+            .filter(|e| parse_sstable_id(&e.as_ref().unwrap().path()).is_some())
+            .count();
+        assert_eq!(
+            files,
+            store.state.read().unwrap().levels.iter().flatten().count()
+        );
+    }
+
+    #[test]
+    fn lsm_should_drop_expired_keys_without_full_scan() {
+        let dir = tempdir().unwrap();
+        let store = LsmDb::open(&dir, FsyncPolicy::Never, 256).unwrap();
+        for i in 0..50 {
+            let ttl = Duration::from_millis(if i % 2 == 0 { 0 } else { 3_600_000 });
+            store
+                .set_with_ttl("t1", format!("k{:02}", i), i.into(), ttl)
+                .unwrap();
+        }
+        store.set("t2", "k1".into(), "v1".into()).unwrap();
+        assert_eq!(store.list_tables().unwrap(), vec!["t1", "t2"]);
+
+        // 只有记录下来、已经过期的 key 会被检查
+        assert_eq!(store.purge_expired().unwrap(), 25);
+        assert_eq!(store.state.read().unwrap().deadlines.len(), 25);
+        assert_eq!(store.purge_expired().unwrap(), 0);
+        assert_eq!(store.get_all("t1").unwrap().len(), 25);
+
+        // 落盘和 compaction 时过期的 key 被换成 tombstone 或者直接丢掉
+        let now = now_millis();
+        let state = store.state.read().unwrap();
+        for table in state.levels.iter().flatten() {
+            for item in table.clone().iter(&[]) {
+                let (_, entry) = item.unwrap();
+                assert!(!(entry.value.is_some() && entry.is_expired(now)));
+            }
+        }
+    }
+
+    #[test]
+    fn prefix_end_should_work() {
+        assert_eq!(prefix_end(b"ab"), Some(b"ac".to_vec()));
+        assert_eq!(prefix_end(b"a\xff"), Some(b"b".to_vec()));
+        assert_eq!(prefix_end(b"\xff\xff"), None);
+    }
+}
@@ -0,0 +1,364 @@
+use super::{
+    bloom::{self, Bloom},
+    Entry,
+};
+use crate::{KvError, Value};
+use bytes::{Buf, BufMut, BytesMut};
+use prost::Message;
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+/// data block 的大小，超过之后开始写下一个 block
+const BLOCK_SIZE: usize = 4096;
+/// footer 依次是 index 和 bloom 的 offset、长度，以及 MAGIC
+const FOOTER_LEN: usize = 8 * 4 + 4;
+const MAGIC: u32 = 0x4c53_4d31;
+
+/// 不可变的有序文件，由 memtable 落盘或者 compaction 生成
+///
+/// 文件的格式为：data block ... | index | bloom | footer
+/// 每个 data block 中是依次排列的 entry，index 记录了每个 block 的最后一个 key 和位置，
+/// 打开文件时 index 和 bloom 会加载到内存中
+#[derive(Debug)]
+pub(super) struct SsTable {
+    pub id: u64,
+    pub path: PathBuf,
+    pub first_key: Vec<u8>,
+    pub last_key: Vec<u8>,
+    pub size: u64,
+    file: Mutex<File>,
+    index: Vec<BlockHandle>,
+    bloom: Bloom,
+}
+
+#[derive(Debug)]
+struct BlockHandle {
+    last_key: Vec<u8>,
+    offset: u64,
+    len: u32,
+}
+
+/// 按 key 的顺序写入 entry，生成 SSTable
+pub(super) struct SsTableBuilder {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    block: BytesMut,
+    index: Vec<BlockHandle>,
+    hashes: Vec<u64>,
+    first_key: Option<Vec<u8>>,
+    last_key: Vec<u8>,
+    offset: u64,
+}
+
+impl SsTableBuilder {
+    pub fn new(path: impl Into<PathBuf>) -> Result<Self, KvError> {
+        let path = path.into();
+        let writer = BufWriter::new(File::create(&path)?);
+        Ok(Self {
+            path,
+            writer,
+            block: BytesMut::with_capacity(BLOCK_SIZE),
+            index: Vec::new(),
+            hashes: Vec::new(),
+            first_key: None,
+            last_key: Vec::new(),
+            offset: 0,
+        })
+    }
+
+    /// 写入一个 entry，key 必须比之前写入的都大
+    pub fn add(&mut self, key: &[u8], entry: &Entry) -> Result<(), KvError> {
+        encode_entry(&mut self.block, key, entry)?;
+        self.hashes.push(bloom::hash(key));
+        self.first_key.get_or_insert_with(|| key.to_vec());
+        self.last_key = key.to_vec();
+        if self.block.len() >= BLOCK_SIZE {
+            self.flush_block()?;
+        }
+        Ok(())
+    }
+
+    /// 目前写入的字节数
+    pub fn size(&self) -> u64 {
+        self.offset + self.block.len() as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.first_key.is_none()
+    }
+
+    /// 写入 index、bloom 和 footer，然后打开生成的 SSTable
+    pub fn finish(mut self, id: u64) -> Result<SsTable, KvError> {
+        self.flush_block()?;
+
+        let mut buf = BytesMut::new();
+        let first_key = self.first_key.unwrap_or_default();
+        buf.put_u32(first_key.len() as _);
+        buf.put_slice(&first_key);
+        for handle in &self.index {
+            buf.put_u32(handle.last_key.len() as _);
+            buf.put_slice(&handle.last_key);
+            buf.put_u64(handle.offset);
+            buf.put_u32(handle.len);
+        }
+        let index_offset = self.offset;
+        let index_len = buf.len() as u64;
+        let bloom = Bloom::new(&self.hashes).encode();
+        buf.put_slice(&bloom);
+        buf.put_u64(index_offset);
+        buf.put_u64(index_len);
+        buf.put_u64(index_offset + index_len);
+        buf.put_u64(bloom.len() as _);
+        buf.put_u32(MAGIC);
+        self.writer.write_all(&buf)?;
+
+        let file = self.writer.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        SsTable::open(self.path, id)
+    }
+
+    fn flush_block(&mut self) -> Result<(), KvError> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+        self.writer.write_all(&self.block)?;
+        self.index.push(BlockHandle {
+            last_key: self.last_key.clone(),
+            offset: self.offset,
+            len: self.block.len() as _,
+        });
+        self.offset += self.block.len() as u64;
+        self.block.clear();
+        Ok(())
+    }
+}
+
+impl SsTable {
+    pub fn open(path: impl Into<PathBuf>, id: u64) -> Result<Self, KvError> {
+        let path = path.into();
+        let mut file = File::open(&path)?;
+        let size = file.metadata()?.len();
+        if size < FOOTER_LEN as u64 {
+            return Err(corrupted(&path));
+        }
+
+        let footer = read_at(&mut file, size - FOOTER_LEN as u64, FOOTER_LEN)?;
+        let mut footer = &footer[..];
+        let (index_offset, index_len) = (footer.get_u64(), footer.get_u64());
+        let (bloom_offset, bloom_len) = (footer.get_u64(), footer.get_u64());
+        if footer.get_u32() != MAGIC || bloom_offset + bloom_len + FOOTER_LEN as u64 != size {
+            return Err(corrupted(&path));
+        }
+
+        let data = read_at(&mut file, index_offset, index_len as usize)?;
+        let (first_key, index) = decode_index(&data).ok_or_else(|| corrupted(&path))?;
+        let data = read_at(&mut file, bloom_offset, bloom_len as usize)?;
+        let bloom = Bloom::decode(&data).ok_or_else(|| corrupted(&path))?;
+        let last_key = index.last().map(|h| h.last_key.clone()).unwrap_or_default();
+
+        Ok(Self {
+            id,
+            path,
+            first_key,
+            last_key,
+            size,
+            file: Mutex::new(file),
+            index,
+            bloom,
+        })
+    }
+
+    /// 查找 key，返回 None 表示这个文件中没有 key（tombstone 会返回 Some）
+    pub fn get(&self, key: &[u8]) -> Result<Option<Entry>, KvError> {
+        if !self.bloom.may_contain(key) {
+            return Ok(None);
+        }
+        let i = self.index.partition_point(|h| h.last_key.as_slice() < key);
+        if i == self.index.len() {
+            return Ok(None);
+        }
+        let entry = self
+            .read_block(i)?
+            .into_iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, e)| e);
+        Ok(entry)
+    }
+
+    /// 从 start 开始按顺序遍历文件中的 entry
+    pub fn iter(self: Arc<Self>, start: &[u8]) -> SsTableIter {
+        let block = self
+            .index
+            .partition_point(|h| h.last_key.as_slice() < start);
+        SsTableIter {
+            table: self,
+            block,
+            entries: VecDeque::new(),
+            start: start.to_vec(),
+        }
+    }
+
+    fn read_block(&self, i: usize) -> Result<Vec<(Vec<u8>, Entry)>, KvError> {
+        let handle = &self.index[i];
+        let data = {
+            let mut file = self.file.lock().unwrap();
+            read_at(&mut file, handle.offset, handle.len as usize)?
+        };
+        let mut buf = &data[..];
+        let mut entries = Vec::new();
+        while buf.has_remaining() {
+            entries.push(decode_entry(&mut buf).ok_or_else(|| corrupted(&self.path))?);
+        }
+        Ok(entries)
+    }
+}
+
+/// 按顺序遍历 SSTable 中的 entry，每次读取一个 block
+pub(super) struct SsTableIter {
+    table: Arc<SsTable>,
+    block: usize,
+    entries: VecDeque<(Vec<u8>, Entry)>,
+    start: Vec<u8>,
+}
+
+impl Iterator for SsTableIter {
+    type Item = Result<(Vec<u8>, Entry), KvError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.entries.is_empty() {
+            if self.block >= self.table.index.len() {
+                return None;
+            }
+            match self.table.read_block(self.block) {
+                Ok(entries) => {
+                    let start = &self.start;
+                    self.entries = entries.into_iter().filter(|(k, _)| k >= start).collect();
+                    self.block += 1;
+                }
+                Err(e) => {
+                    // 出错之后不再继续遍历
+                    self.block = self.table.index.len();
+                    return Some(Err(e));
+                }
+            }
+        }
+        self.entries.pop_front().map(Ok)
+    }
+}
+
+/// entry 的格式为：key 长度 | key | 过期时间 | 是否有 value | value 长度 | value
+fn encode_entry(buf: &mut BytesMut, key: &[u8], entry: &Entry) -> Result<(), KvError> {
+    buf.put_u32(key.len() as _);
+    buf.put_slice(key);
+    buf.put_u64(entry.deadline);
+    match &entry.value {
+        Some(value) => {
+            buf.put_u8(1);
+            buf.put_u32(value.encoded_len() as _);
+            value.encode(buf)?;
+        }
+        None => buf.put_u8(0),
+    }
+    Ok(())
+}
+
+fn decode_entry(buf: &mut &[u8]) -> Option<(Vec<u8>, Entry)> {
+    let key = get_bytes(buf)?;
+    if buf.remaining() < 9 {
+        return None;
+    }
+    let deadline = buf.get_u64();
+    let value = match buf.get_u8() {
+        0 => None,
+        _ => Some(Value::decode(&get_bytes(buf)?[..]).ok()?),
+    };
+    Some((key, Entry { value, deadline }))
+}
+
+fn decode_index(mut buf: &[u8]) -> Option<(Vec<u8>, Vec<BlockHandle>)> {
+    let first_key = get_bytes(&mut buf)?;
+    let mut index = Vec::new();
+    while buf.has_remaining() {
+        let last_key = get_bytes(&mut buf)?;
+        if buf.remaining() < 12 {
+            return None;
+        }
+        index.push(BlockHandle {
+            last_key,
+            offset: buf.get_u64(),
+            len: buf.get_u32(),
+        });
+    }
+    Some((first_key, index))
+}
+
+/// 读取一个 u32 长度以及之后的数据
+fn get_bytes(buf: &mut &[u8]) -> Option<Vec<u8>> {
+    if buf.remaining() < 4 {
+        return None;
+    }
+    let len = buf.get_u32() as usize;
+    if buf.remaining() < len {
+        return None;
+    }
+    let data = buf[..len].to_vec();
+    buf.advance(len);
+    Some(data)
+}
+
+fn read_at(file: &mut File, offset: u64, len: usize) -> Result<Vec<u8>, KvError> {
+    let mut data = vec![0; len];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut data)?;
+    Ok(data)
+}
+
+fn corrupted(path: &Path) -> KvError {
+    KvError::Internal(format!("SSTable {} is corrupted", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn sstable_should_work() {
+        let dir = tempdir().unwrap();
+        let mut builder = SsTableBuilder::new(dir.path().join("1.sst")).unwrap();
+        let keys: Vec<_> = (0..1000).map(|i| format!("key{:04}", i)).collect();
+        for (i, key) in keys.iter().enumerate() {
+            let entry = match i % 10 {
+                0 => Entry::tombstone(),
+                _ => Entry::new(key.as_str().into(), i as u64),
+            };
+            builder.add(key.as_bytes(), &entry).unwrap();
+        }
+        let table = Arc::new(builder.finish(1).unwrap());
+        assert!(table.index.len() > 1);
+        assert_eq!(table.first_key, b"key0000");
+        assert_eq!(table.last_key, b"key0999");
+
+        // 重新打开之后可以读取每个 key
+        let table = Arc::new(SsTable::open(&table.path, 1).unwrap());
+        let entry = table.get(b"key0123").unwrap().unwrap();
+        assert_eq!(entry, Entry::new("key0123".into(), 123));
+        assert_eq!(table.get(b"key0120").unwrap(), Some(Entry::tombstone()));
+        assert_eq!(table.get(b"key1000").unwrap(), None);
+
+        // 从某个 key 开始遍历
+        let data: Vec<_> = table
+            .clone()
+            .iter(b"key0990")
+            .map(|v| v.unwrap().0)
+            .collect();
+        assert_eq!(data.len(), 10);
+        assert_eq!(data[0], b"key0990");
+        assert_eq!(table.iter(b"").count(), 1000);
+    }
+}
@@ -1,10 +1,77 @@
-use crate::{KvError, Kvpair, Storage, StorageIter, Value};
-use dashmap::{mapref::one::Ref, DashMap};
+use crate::{
+    EvictionPolicy, KvError, Kvpair, Storage, StorageIter, StorageStats, TableStats, TxnStorage,
+    Value,
+};
+use dashmap::{
+    mapref::{entry::Entry, one::Ref},
+    DashMap,
+};
+use prost::Message;
+use std::{
+    cell::RefCell,
+    collections::{hash_map::RandomState, BTreeMap, BTreeSet},
+    convert::TryFrom,
+    hash::BuildHasher,
+    ops::Bound,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Mutex, RwLock,
+    },
+    time::Duration,
+};
+
+use super::{add_float, add_integer, deadline_from, now_millis, remaining};
+
+/// 除了 key 和 value 本身，每个 kv pair 在 MemTable 中额外占用的内存（估计值）
+const ENTRY_OVERHEAD: usize = 64;
 
 /// 使用 DashMap 构建的 MemTable，实现了 Storage trait
-#[derive(Clone, Debug, Default)]
+#[derive(Debug, Default)]
 pub struct MemTable {
     tables: DashMap<String, DashMap<String, Value>>,
+    // 每个 table 中设置了过期时间的 key，值为过期的时间点（毫秒）
+    expires: DashMap<String, DashMap<String, u64>>,
+    // 每个 table 中有序的 key，用于按范围扫描
+    keys: DashMap<String, RwLock<BTreeSet<String>>>,
+    // 事务持有写锁，其它操作持有读锁，这样事务中的修改要么全部可见，要么都不可见
+    txn: RwLock<()>,
+    // 所有 kv pair 大约占用的内存
+    used: AtomicUsize,
+    // 设置了内存上限时，用于淘汰 key
+    eviction: Option<Eviction>,
+}
+
+/// 超过内存上限时淘汰 key 所需要的信息
+#[derive(Debug, Default)]
+struct Eviction {
+    max_bytes: usize,
+    policy: EvictionPolicy,
+    // 每个 table 中 key 的访问记录
+    access: DashMap<String, DashMap<String, Access>>,
+    // 按淘汰的先后顺序排列的 key，访问或者修改 key 时更新，淘汰时从前往后取
+    order: Mutex<BTreeMap<Rank, (String, String)>>,
+    // 逻辑时钟，每访问一次 key 加一
+    clock: AtomicU64,
+    // LFU 的基准值，等于最近一次淘汰的 key 的 rank，
+    // 新的访问在此基础上计算 rank，这样很久以前频繁访问的 key 也能被淘汰
+    age: AtomicU64,
+    // Random 策略用来给 key 随机排序
+    random: RandomState,
+    // 被淘汰的 key 的个数
+    evictions: AtomicU64,
+    // 同一时间只有一个线程在淘汰 key
+    running: Mutex<()>,
+}
+
+/// key 在淘汰顺序中的位置，越小越先被淘汰；第二项是逻辑时钟，保证不会重复
+type Rank = (u64, u64);
+
+/// key 最近一次被访问的时间（逻辑时钟）、访问次数以及在淘汰顺序中的位置
+#[derive(Debug, Clone, Copy, Default)]
+struct Access {
+    last: u64,
+    hits: u64,
+    rank: Option<Rank>,
 }
 
 impl MemTable {
@@ -13,7 +80,19 @@ impl MemTable {
         Self::default()
     }
 
-    /// 如果名为 name 的 hash table 不存在，则创建，否则返回
+    /// 创建一个最多使用 max_bytes 内存的 MemTable，超过之后按 policy 淘汰 key
+    pub fn with_limit(max_bytes: usize, policy: EvictionPolicy) -> Self {
+        Self {
+            eviction: Some(Eviction {
+                max_bytes,
+                policy,
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    /// 如果名为 name 的 hash table 不存在，则创建，否则返回；只有写操作才会创建 table
     fn get_or_create_table(&self, name: &str) -> Ref<String, DashMap<String, Value>> {
         match self.tables.get(name) {
             Some(table) => table,
@@ -23,43 +102,837 @@ impl MemTable {
             }
         }
     }
+
+    /// 往 table 中插入一个 kv pair，同时维护有序的 key
+    fn insert(&self, name: &str, key: String, value: Value) -> Option<Value> {
+        self.insert_if(name, key, value, |_| true)
+            .unwrap_or_default()
+    }
+
+    /// 持有 key 的 entry 锁时用 cond 检查 key 当前的 value，满足条件才插入 kv pair，
+    /// 返回 Some(旧的 value)；不满足条件时返回 None，也不会创建不存在的 table
+    fn insert_if<F>(&self, name: &str, key: String, value: Value, cond: F) -> Option<Option<Value>>
+    where
+        F: Fn(Option<&Value>) -> bool,
+    {
+        let table = match self.tables.get(name) {
+            Some(table) => table,
+            None if !cond(None) => return None,
+            None => self.get_or_create_table(name),
+        };
+        let size = entry_size(&key, &value);
+        // 在持有 entry 锁的时候更新 keys，保证两者一致
+        let result = match table.entry(key.clone()) {
+            Entry::Occupied(mut entry) => match cond(Some(entry.get())) {
+                true => {
+                    let old = entry.insert(value);
+                    self.used.fetch_add(size, Ordering::Relaxed);
+                    self.used
+                        .fetch_sub(entry_size(&key, &old), Ordering::Relaxed);
+                    Some(Some(old))
+                }
+                false => None,
+            },
+            Entry::Vacant(entry) => match cond(None) {
+                true => {
+                    self.add_to_index(name, entry.key().clone());
+                    entry.insert(value);
+                    self.used.fetch_add(size, Ordering::Relaxed);
+                    Some(None)
+                }
+                false => None,
+            },
+        };
+        drop(table);
+        if result.is_some() {
+            self.touch(name, &key);
+        }
+        result
+    }
+
+    /// 持有 key 的 entry 锁时用 f 根据 key 当前的 value 计算出新的 value 并写入，
+    /// 返回新的 value；f 出错时不做任何修改，也不会创建不存在的 table
+    fn update<F>(&self, name: &str, key: &str, f: F) -> Result<Value, KvError>
+    where
+        F: Fn(Option<&Value>) -> Result<Value, KvError>,
+    {
+        self.remove_if_expired(name, key);
+        let table = match self.tables.get(name) {
+            Some(table) => table,
+            None => {
+                f(None)?;
+                self.get_or_create_table(name)
+            }
+        };
+        let result = match table.entry(key.into()) {
+            Entry::Occupied(mut entry) => {
+                let value = f(Some(entry.get()))?;
+                let old = entry.insert(value.clone());
+                self.used
+                    .fetch_add(entry_size(key, &value), Ordering::Relaxed);
+                self.used
+                    .fetch_sub(entry_size(key, &old), Ordering::Relaxed);
+                value
+            }
+            Entry::Vacant(entry) => {
+                let value = f(None)?;
+                self.add_to_index(name, entry.key().clone());
+                entry.insert(value.clone());
+                self.used
+                    .fetch_add(entry_size(key, &value), Ordering::Relaxed);
+                value
+            }
+        };
+        drop(table);
+        self.touch(name, key);
+        Ok(result)
+    }
+
+    /// 把 key 加入 table 的有序 key 中
+    fn add_to_index(&self, name: &str, key: String) {
+        let index = self.keys.entry(name.into()).or_default();
+        index.write().unwrap().insert(key);
+    }
+
+    /// 从 table 中删除一个 key，同时维护有序的 key
+    fn remove(&self, name: &str, key: &str) -> Option<Value> {
+        let table = self.tables.get(name)?;
+        let result = match table.entry(key.into()) {
+            Entry::Occupied(entry) => {
+                if let Some(index) = self.keys.get(name) {
+                    index.write().unwrap().remove(key);
+                }
+                let value = entry.remove();
+                self.used
+                    .fetch_sub(entry_size(key, &value), Ordering::Relaxed);
+                Some(value)
+            }
+            Entry::Vacant(_) => None,
+        };
+        drop(table);
+        if let Some(eviction) = &self.eviction {
+            let removed = eviction.access.get(name).and_then(|a| a.remove(key));
+            if let Some((
+                _,
+                Access {
+                    rank: Some(rank), ..
+                },
+            )) = removed
+            {
+                eviction.order.lock().unwrap().remove(&rank);
+            }
+        }
+        result
+    }
+
+    /// 获取 key 的 value，已经过期的 key 会被删除
+    fn get_value(&self, table: &str, key: &str) -> Option<Value> {
+        if self.remove_if_expired(table, key) {
+            return None;
+        }
+        let value = self.tables.get(table)?.get(key).map(|v| v.value().clone());
+        if value.is_some() {
+            self.touch(table, key);
+        }
+        value
+    }
+
+    /// 设置 key 的 value，同时去掉它的过期时间
+    fn set_value(&self, table: &str, key: String, value: Value) -> Option<Value> {
+        self.remove_if_expired(table, &key);
+        self.clear_deadline(table, &key);
+        self.insert(table, key, value)
+    }
+
+    /// 查看 key 是否存在，已经过期的 key 会被删除
+    fn contains_key(&self, table: &str, key: &str) -> bool {
+        if self.remove_if_expired(table, key) {
+            return false;
+        }
+        self.tables.get(table).is_some_and(|t| t.contains_key(key))
+    }
+
+    /// 删除 key 以及它的过期时间
+    fn del_value(&self, table: &str, key: &str) -> Option<Value> {
+        if self.remove_if_expired(table, key) {
+            return None;
+        }
+        self.clear_deadline(table, key);
+        self.remove(table, key)
+    }
+
+    /// 获取 key 的过期时间点
+    fn deadline(&self, table: &str, key: &str) -> Option<u64> {
+        self.expires
+            .get(table)
+            .and_then(|t| t.get(key).map(|v| *v.value()))
+    }
+
+    /// 如果 key 已经过期，把它从 table 中删除，返回是否删除
+    fn remove_if_expired(&self, table: &str, key: &str) -> bool {
+        let removed = match self.expires.get(table) {
+            Some(t) => t
+                .remove_if(key, |_, deadline| *deadline <= now_millis())
+                .is_some(),
+            None => false,
+        };
+        if removed {
+            self.remove(table, key);
+        }
+        removed
+    }
+
+    /// 去掉 key 的过期时间，返回之前是否有过期时间
+    fn clear_deadline(&self, table: &str, key: &str) -> bool {
+        let cleared = match self.expires.get(table) {
+            Some(t) => t.remove(key).is_some(),
+            None => false,
+        };
+        if cleared && self.is_policy(EvictionPolicy::VolatileTtl) {
+            self.update_access(table, key, |_, _| None);
+        }
+        cleared
+    }
+
+    /// 设置 key 的过期时间点
+    fn set_deadline(&self, table: &str, key: String, deadline: u64) {
+        if self.is_policy(EvictionPolicy::VolatileTtl) {
+            self.update_access(table, &key, |_, now| Some((deadline, now)));
+        }
+        self.expires
+            .entry(table.into())
+            .or_default()
+            .insert(key, deadline);
+    }
+
+    /// 是否设置了内存上限并且使用 policy 淘汰 key
+    fn is_policy(&self, policy: EvictionPolicy) -> bool {
+        matches!(&self.eviction, Some(eviction) if eviction.policy == policy)
+    }
+
+    /// 记录对 key 的一次访问
+    fn touch(&self, table: &str, key: &str) {
+        let eviction = match &self.eviction {
+            Some(eviction) => eviction,
+            None => return,
+        };
+        self.update_access(table, key, |a, now| {
+            a.last = now;
+            a.hits = a.hits.saturating_add(1);
+            match eviction.policy {
+                EvictionPolicy::Lru => Some((now, 0)),
+                EvictionPolicy::Lfu => {
+                    let age = eviction.age.load(Ordering::Relaxed);
+                    Some((age.saturating_add(a.hits), now))
+                }
+                EvictionPolicy::Random => a
+                    .rank
+                    .or_else(|| Some((eviction.random.hash_one((table, key)), now))),
+                // VolatileTtl 的顺序只和过期时间有关
+                EvictionPolicy::VolatileTtl => a.rank,
+            }
+        });
+    }
+
+    /// 用 f 更新 key 的访问记录，f 返回 key 新的 rank，None 表示不参与淘汰；
+    /// 持有访问记录的锁时同时更新淘汰顺序，保证两者一致
+    fn update_access<F>(&self, table: &str, key: &str, f: F)
+    where
+        F: FnOnce(&mut Access, u64) -> Option<Rank>,
+    {
+        let eviction = match &self.eviction {
+            Some(eviction) => eviction,
+            None => return,
+        };
+        let now = eviction.clock.fetch_add(1, Ordering::Relaxed);
+        let access = match eviction.access.get(table) {
+            Some(access) => access,
+            None => eviction.access.entry(table.into()).or_default().downgrade(),
+        };
+        let mut a = match access.get_mut(key) {
+            Some(a) => a,
+            None => access.entry(key.into()).or_default(),
+        };
+        let old = a.rank;
+        a.rank = f(&mut a, now);
+        if a.rank != old {
+            let mut order = eviction.order.lock().unwrap();
+            if let Some(old) = old {
+                order.remove(&old);
+            }
+            if let Some(rank) = a.rank {
+                order.insert(rank, (table.into(), key.into()));
+            }
+        }
+    }
+
+    /// 写入之前检查内存，超过上限并且无法淘汰任何 key 时返回错误
+    fn reserve(&self) -> Result<(), KvError> {
+        let max_bytes = match &self.eviction {
+            Some(eviction) => eviction.max_bytes,
+            None => return Ok(()),
+        };
+        if self.used.load(Ordering::Relaxed) > max_bytes {
+            self.evict();
+        }
+        let used = self.used.load(Ordering::Relaxed);
+        match used > max_bytes {
+            true => Err(KvError::OutOfMemory(format!(
+                "used {} bytes, max {} bytes",
+                used, max_bytes
+            ))),
+            false => Ok(()),
+        }
+    }
+
+    /// 占用的内存超过上限时，按淘汰策略删除 key，直到低于上限的 90%，返回淘汰的个数
+    /// 每次多淘汰一些，避免之后的每次写入都要淘汰
+    fn evict(&self) -> usize {
+        let eviction = match &self.eviction {
+            Some(eviction) => eviction,
+            None => return 0,
+        };
+        if self.used.load(Ordering::Relaxed) <= eviction.max_bytes {
+            return 0;
+        }
+        let _running = eviction.running.lock().unwrap();
+        // 等待锁的时候，其它线程可能已经淘汰过了
+        if self.used.load(Ordering::Relaxed) <= eviction.max_bytes {
+            return 0;
+        }
+
+        let target = eviction.max_bytes / 10 * 9;
+        let mut count = 0;
+        while self.used.load(Ordering::Relaxed) > target {
+            // 不能在持有 order 锁的时候删除 key，remove 也需要这个锁
+            let candidate = eviction.order.lock().unwrap().pop_first();
+            let (rank, (table, key)) = match candidate {
+                Some(candidate) => candidate,
+                None => break,
+            };
+            if eviction.policy == EvictionPolicy::Lfu {
+                eviction.age.fetch_max(rank.0, Ordering::Relaxed);
+            }
+            self.clear_deadline(&table, &key);
+            if self.remove(&table, &key).is_some() {
+                count += 1;
+            }
+        }
+
+        eviction
+            .evictions
+            .fetch_add(count as u64, Ordering::Relaxed);
+        count
+    }
+
+    /// 名为 name 的 table 是否存在
+    pub(super) fn has_table(&self, name: &str) -> bool {
+        self.tables.contains_key(name)
+    }
+
+    /// 获取 key 当前的 value 和过期时间点，不检查是否过期
+    pub(super) fn state(&self, table: &str, key: &str) -> (Option<Value>, Option<u64>) {
+        let value = self
+            .tables
+            .get(table)
+            .and_then(|t| t.get(key).map(|v| v.value().clone()));
+        (value, self.deadline(table, key))
+    }
+
+    /// 返回所有 table 中没有过期的 key 的 (table, key, value, 过期时间点)
+    pub(super) fn dump(&self) -> Vec<(String, String, Value, Option<u64>)> {
+        let names: Vec<String> = self.tables.iter().map(|t| t.key().clone()).collect();
+        names
+            .iter()
+            .flat_map(|name| self.dump_table(name))
+            .collect()
+    }
+
+    /// 返回 table 中没有过期的 key 的 (table, key, value, 过期时间点)
+    pub(super) fn dump_table(&self, name: &str) -> Vec<(String, String, Value, Option<u64>)> {
+        let now = now_millis();
+        let expires = self.expires.get(name);
+        let table = match self.tables.get(name) {
+            Some(table) => table,
+            None => return Vec::new(),
+        };
+        let result = table
+            .iter()
+            .map(|v| {
+                let deadline = expires.as_ref().and_then(|e| e.get(v.key()).map(|d| *d));
+                (name.into(), v.key().clone(), v.value().clone(), deadline)
+            })
+            .filter(|(_, _, _, deadline)| !matches!(deadline, Some(d) if *d <= now))
+            .collect();
+        result
+    }
+
+    /// 直接把 key 恢复成给定的 value 和过期时间点，value 为 None 时删除 key
+    pub(super) fn restore(
+        &self,
+        table: &str,
+        key: String,
+        value: Option<Value>,
+        deadline: Option<u64>,
+    ) {
+        match value {
+            Some(value) => {
+                match deadline {
+                    Some(deadline) => self.set_deadline(table, key.clone(), deadline),
+                    None => {
+                        self.clear_deadline(table, &key);
+                    }
+                }
+                self.insert(table, key, value);
+            }
+            None => {
+                self.clear_deadline(table, &key);
+                self.remove(table, &key);
+            }
+        }
+    }
+}
+
+impl Clone for MemTable {
+    fn clone(&self) -> Self {
+        Self {
+            tables: self.tables.clone(),
+            expires: self.expires.clone(),
+            keys: self
+                .keys
+                .iter()
+                .map(|v| (v.key().clone(), RwLock::new(v.read().unwrap().clone())))
+                .collect(),
+            txn: RwLock::default(),
+            used: AtomicUsize::new(self.used.load(Ordering::Relaxed)),
+            eviction: self.eviction.clone(),
+        }
+    }
+}
+
+impl Clone for Eviction {
+    fn clone(&self) -> Self {
+        Self {
+            max_bytes: self.max_bytes,
+            policy: self.policy,
+            access: self.access.clone(),
+            order: Mutex::new(self.order.lock().unwrap().clone()),
+            clock: AtomicU64::new(self.clock.load(Ordering::Relaxed)),
+            age: AtomicU64::new(self.age.load(Ordering::Relaxed)),
+            random: self.random.clone(),
+            evictions: AtomicU64::new(self.evictions.load(Ordering::Relaxed)),
+            running: Mutex::default(),
+        }
+    }
 }
 
 impl Storage for MemTable {
     fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
-        let table = self.get_or_create_table(table);
-        Ok(table.get(key).map(|v| v.value().clone()))
+        let _guard = self.txn.read().unwrap();
+        Ok(self.get_value(table, key))
     }
 
     fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
-        let table = self.get_or_create_table(table);
-        Ok(table.insert(key, value))
+        let _guard = self.txn.read().unwrap();
+        self.reserve()?;
+        let old = self.set_value(table, key, value);
+        self.evict();
+        Ok(old)
     }
 
     fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
-        let table = self.get_or_create_table(table);
-        Ok(table.contains_key(key))
+        let _guard = self.txn.read().unwrap();
+        Ok(self.contains_key(table, key))
     }
 
     fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
-        let table = self.get_or_create_table(table);
-        Ok(table.remove(key).map(|(_k, v)| v))
+        let _guard = self.txn.read().unwrap();
+        Ok(self.del_value(table, key))
+    }
+
+    fn compare_and_swap(
+        &self,
+        table: &str,
+        key: String,
+        expected: Option<Value>,
+        value: Value,
+    ) -> Result<bool, KvError> {
+        let _guard = self.txn.read().unwrap();
+        self.reserve()?;
+        self.remove_if_expired(table, &key);
+        let swapped = self
+            .insert_if(table, key.clone(), value, |v| v == expected.as_ref())
+            .is_some();
+        if swapped {
+            self.clear_deadline(table, &key);
+            self.evict();
+        }
+        Ok(swapped)
+    }
+
+    fn set_if_present(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+    ) -> Result<Option<Value>, KvError> {
+        let _guard = self.txn.read().unwrap();
+        self.reserve()?;
+        self.remove_if_expired(table, &key);
+        let old = self
+            .insert_if(table, key.clone(), value, |v| v.is_some())
+            .flatten();
+        if old.is_some() {
+            self.clear_deadline(table, &key);
+            self.evict();
+        }
+        Ok(old)
+    }
+
+    fn incr(&self, table: &str, key: &str, delta: i64) -> Result<i64, KvError> {
+        let _guard = self.txn.read().unwrap();
+        self.reserve()?;
+        let value = self.update(table, key, |v| add_integer(v, delta))?;
+        self.evict();
+        i64::try_from(&value)
+    }
+
+    fn incr_float(&self, table: &str, key: &str, delta: f64) -> Result<f64, KvError> {
+        let _guard = self.txn.read().unwrap();
+        self.reserve()?;
+        let value = self.update(table, key, |v| add_float(v, delta))?;
+        self.evict();
+        f64::try_from(&value)
     }
 
     fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
-        let table = self.get_or_create_table(table);
+        let _guard = self.txn.read().unwrap();
+        let now = now_millis();
+        let expires = self.expires.get(table);
+        let table = match self.tables.get(table) {
+            Some(table) => table,
+            None => return Ok(Vec::new()),
+        };
         Ok(table
             .iter()
+            .filter(|v| !is_expired(expires.as_deref(), v.key(), now))
             .map(|v| Kvpair::new(v.key(), v.value().clone()))
             .collect())
     }
 
     fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
-        // 使用 clone() 来获取 table 的 snapshot
-        let table = self.get_or_create_table(table).clone();
-        let iter = StorageIter::new(table.into_iter());
+        let _guard = self.txn.read().unwrap();
+        let now = now_millis();
+        let expires = self.expires.get(table).map(|t| t.clone());
+        // 使用 clone() 来获取 table 的 snapshot，table 不存在时返回空的 table
+        let table = self
+            .tables
+            .get(table)
+            .map(|t| t.clone())
+            .unwrap_or_default();
+        let data = table
+            .into_iter()
+            .filter(move |(k, _)| !is_expired(expires.as_ref(), k, now));
+        let iter = StorageIter::new(data);
         Ok(Box::new(iter))
     }
+
+    fn get_range(
+        &self,
+        table: &str,
+        start: &str,
+        end: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        if matches!(end, Some(end) if end <= start) {
+            return Ok(Vec::new());
+        }
+
+        let _guard = self.txn.read().unwrap();
+        let now = now_millis();
+        let expires = self.expires.get(table);
+        // 先从有序的 key 中取出范围内的 key，释放锁之后再读取 value
+        let keys: Vec<String> = match self.keys.get(table) {
+            Some(index) => {
+                let end = end.map_or(Bound::Unbounded, Bound::Excluded);
+                index
+                    .read()
+                    .unwrap()
+                    .range::<str, _>((Bound::Included(start), end))
+                    .filter(|k| !is_expired(expires.as_deref(), k, now))
+                    .take(limit)
+                    .cloned()
+                    .collect()
+            }
+            None => return Ok(Vec::new()),
+        };
+
+        let table = match self.tables.get(table) {
+            Some(table) => table,
+            None => return Ok(Vec::new()),
+        };
+        Ok(keys
+            .into_iter()
+            .filter_map(|k| table.get(&k).map(|v| Kvpair::new(k, v.value().clone())))
+            .collect())
+    }
+
+    fn set_with_ttl(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+        ttl: Duration,
+    ) -> Result<Option<Value>, KvError> {
+        let _guard = self.txn.read().unwrap();
+        self.reserve()?;
+        self.remove_if_expired(table, &key);
+        self.set_deadline(table, key.clone(), deadline_from(ttl));
+        let old = self.insert(table, key, value);
+        self.evict();
+        Ok(old)
+    }
+
+    fn expire(&self, table: &str, key: &str, ttl: Duration) -> Result<bool, KvError> {
+        let _guard = self.txn.read().unwrap();
+        if !self.contains_key(table, key) {
+            return Ok(false);
+        }
+        self.set_deadline(table, key.into(), deadline_from(ttl));
+        Ok(true)
+    }
+
+    fn ttl(&self, table: &str, key: &str) -> Result<Option<Duration>, KvError> {
+        let _guard = self.txn.read().unwrap();
+        if self.remove_if_expired(table, key) {
+            return Ok(None);
+        }
+        Ok(self.deadline(table, key).and_then(remaining))
+    }
+
+    fn persist(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        let _guard = self.txn.read().unwrap();
+        if self.remove_if_expired(table, key) {
+            return Ok(false);
+        }
+        Ok(self.clear_deadline(table, key))
+    }
+
+    fn purge_expired(&self) -> Result<usize, KvError> {
+        let _guard = self.txn.read().unwrap();
+        let now = now_millis();
+        // 先找出所有过期的 key，避免在遍历 DashMap 的时候修改它
+        let candidates: Vec<(String, String)> = self
+            .expires
+            .iter()
+            .flat_map(|t| {
+                let name = t.key().clone();
+                t.value()
+                    .iter()
+                    .filter(|v| *v.value() <= now)
+                    .map(|v| (name.clone(), v.key().clone()))
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+
+        Ok(candidates
+            .iter()
+            .filter(|(table, key)| self.remove_if_expired(table, key))
+            .count())
+    }
+
+    fn list_tables(&self) -> Result<Vec<String>, KvError> {
+        let _guard = self.txn.read().unwrap();
+        let mut tables: Vec<String> = self.tables.iter().map(|t| t.key().clone()).collect();
+        tables.sort();
+        Ok(tables)
+    }
+
+    fn table_info(&self, table: &str) -> Result<Option<TableStats>, KvError> {
+        let _guard = self.txn.read().unwrap();
+        let now = now_millis();
+        let expires = self.expires.get(table);
+        let table = match self.tables.get(table) {
+            Some(table) => table,
+            None => return Ok(None),
+        };
+        let stats = table
+            .iter()
+            .filter(|v| !is_expired(expires.as_deref(), v.key(), now))
+            .fold(TableStats::default(), |stats, v| TableStats {
+                keys: stats.keys + 1,
+                bytes: stats.bytes + v.key().len() + v.value().encoded_len(),
+                ..stats
+            });
+        Ok(Some(stats))
+    }
+
+    fn drop_table(&self, table: &str) -> Result<bool, KvError> {
+        // 持有写锁，避免其它操作看到只删除了一部分的 table
+        let _guard = self.txn.write().unwrap();
+        self.expires.remove(table);
+        self.keys.remove(table);
+        if let Some(eviction) = &self.eviction {
+            if let Some((_, access)) = eviction.access.remove(table) {
+                let mut order = eviction.order.lock().unwrap();
+                for rank in access.iter().filter_map(|a| a.rank) {
+                    order.remove(&rank);
+                }
+            }
+        }
+        let data = match self.tables.remove(table) {
+            Some((_, data)) => data,
+            None => return Ok(false),
+        };
+        let size: usize = data.iter().map(|v| entry_size(v.key(), v.value())).sum();
+        self.used.fetch_sub(size, Ordering::Relaxed);
+        Ok(true)
+    }
+
+    fn rename_table(&self, table: &str, to: &str) -> Result<bool, KvError> {
+        let _guard = self.txn.write().unwrap();
+        if !self.tables.contains_key(table) {
+            return Ok(false);
+        }
+        if self.tables.contains_key(to) {
+            return Err(KvError::PreconditionFailed(format!(
+                "table {} already exists",
+                to
+            )));
+        }
+        let data = match self.tables.remove(table) {
+            Some((_, data)) => data,
+            None => return Ok(false),
+        };
+        self.tables.insert(to.into(), data);
+        if let Some((_, expires)) = self.expires.remove(table) {
+            self.expires.insert(to.into(), expires);
+        }
+        if let Some((_, keys)) = self.keys.remove(table) {
+            self.keys.insert(to.into(), keys);
+        }
+        if let Some(eviction) = &self.eviction {
+            if let Some((_, access)) = eviction.access.remove(table) {
+                let mut order = eviction.order.lock().unwrap();
+                for a in access.iter() {
+                    if let Some(rank) = a.rank {
+                        order.insert(rank, (to.into(), a.key().clone()));
+                    }
+                }
+                drop(order);
+                eviction.access.insert(to.into(), access);
+            }
+        }
+        Ok(true)
+    }
+
+    fn stats(&self) -> Result<StorageStats, KvError> {
+        Ok(StorageStats {
+            used_bytes: self.used.load(Ordering::Relaxed),
+            max_bytes: self.eviction.as_ref().map_or(0, |e| e.max_bytes),
+            evictions: self
+                .eviction
+                .as_ref()
+                .map_or(0, |e| e.evictions.load(Ordering::Relaxed)),
+            ..Default::default()
+        })
+    }
+
+    fn transaction<T, F>(&self, f: F) -> Result<T, KvError>
+    where
+        F: Fn(&dyn TxnStorage) -> Result<T, KvError>,
+    {
+        // 持有写锁，事务执行期间其它的操作都需要等待
+        let _guard = self.txn.write().unwrap();
+        self.reserve()?;
+        let txn = MemTxn {
+            store: self,
+            undo: RefCell::default(),
+        };
+        let result = f(&txn);
+        match result.is_err() {
+            true => txn.rollback(),
+            false => {
+                self.evict();
+            }
+        }
+        result
+    }
+}
+
+/// MemTable 的事务：直接修改 MemTable，同时记录修改前的数据，失败时用来回滚
+struct MemTxn<'a> {
+    store: &'a MemTable,
+    undo: RefCell<Vec<Undo>>,
+}
+
+/// 事务中某个 key 修改前的 value 和过期时间
+struct Undo {
+    table: String,
+    key: String,
+    value: Option<Value>,
+    deadline: Option<u64>,
+}
+
+impl MemTxn<'_> {
+    /// 修改 key 之前，记录它当前的数据
+    fn record(&self, table: &str, key: &str) {
+        let value = self.store.get_value(table, key);
+        let deadline = self.store.deadline(table, key);
+        self.undo.borrow_mut().push(Undo {
+            table: table.into(),
+            key: key.into(),
+            value,
+            deadline,
+        });
+    }
+
+    /// 按相反的顺序恢复事务中修改过的数据
+    fn rollback(self) {
+        let store = self.store;
+        for undo in self.undo.into_inner().into_iter().rev() {
+            store.restore(&undo.table, undo.key, undo.value, undo.deadline);
+        }
+    }
+}
+
+impl TxnStorage for MemTxn<'_> {
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        Ok(self.store.get_value(table, key))
+    }
+
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        self.record(table, &key);
+        Ok(self.store.set_value(table, key, value))
+    }
+
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        Ok(self.store.contains_key(table, key))
+    }
+
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.record(table, key);
+        Ok(self.store.del_value(table, key))
+    }
+
+    fn ttl(&self, table: &str, key: &str) -> Result<Option<Duration>, KvError> {
+        if self.store.remove_if_expired(table, key) {
+            return Ok(None);
+        }
+        Ok(self.store.deadline(table, key).and_then(remaining))
+    }
+}
+
+/// kv pair 大约占用的内存
+fn entry_size(key: &str, value: &Value) -> usize {
+    key.len() + value.encoded_len() + ENTRY_OVERHEAD
+}
+
+/// 根据 table 的过期时间表判断 key 是否过期
+fn is_expired(expires: Option<&DashMap<String, u64>>, key: &str, now: u64) -> bool {
+    expires
+        .and_then(|t| t.get(key).map(|v| *v.value() <= now))
+        .unwrap_or(false)
 }
 
 impl From<(String, Value)> for Kvpair {
@@ -79,4 +952,147 @@ mod tests {
         store.get_or_create_table("t1");
         assert!(store.tables.contains_key("t1"));
     }
+
+    #[test]
+    fn lru_should_evict_least_recently_used_keys() {
+        let store = MemTable::with_limit(entry_size("k1", &"v1".into()) * 4, EvictionPolicy::Lru);
+        for key in ["k1", "k2", "k3", "k4"] {
+            store.set("t1", key.into(), "v1".into()).unwrap();
+        }
+        store.get("t1", "k1").unwrap();
+        store.get("t1", "k2").unwrap();
+
+        // 超过上限之后淘汰到上限的 90% 以下，也就是淘汰两个最久没有访问的 key
+        store.set("t1", "k5".into(), "v1".into()).unwrap();
+        assert!(!store.contains("t1", "k3").unwrap());
+        assert!(!store.contains("t1", "k4").unwrap());
+        for key in ["k1", "k2", "k5"] {
+            assert!(store.contains("t1", key).unwrap());
+        }
+        let stats = store.stats().unwrap();
+        assert_eq!(stats.evictions, 2);
+        assert!(stats.used_bytes <= stats.max_bytes);
+    }
+
+    #[test]
+    fn lfu_should_evict_least_frequently_used_keys() {
+        let store = MemTable::with_limit(entry_size("k1", &"v1".into()) * 4, EvictionPolicy::Lfu);
+        for key in ["k1", "k2", "k3", "k4"] {
+            store.set("t1", key.into(), "v1".into()).unwrap();
+        }
+        for _ in 0..3 {
+            store.get("t1", "k1").unwrap();
+            store.get("t1", "k4").unwrap();
+        }
+        store.get("t1", "k3").unwrap();
+
+        store.set("t1", "k5".into(), "v1".into()).unwrap();
+        assert!(!store.contains("t1", "k2").unwrap());
+        assert!(store.contains("t1", "k1").unwrap());
+        assert!(store.contains("t1", "k4").unwrap());
+        assert_eq!(store.stats().unwrap().evictions, 2);
+    }
+
+    #[test]
+    fn lfu_should_evict_keys_that_were_hot_long_ago() {
+        let store = MemTable::with_limit(entry_size("k1", &"v1".into()) * 4, EvictionPolicy::Lfu);
+        store.set("t0", "k1".into(), "v1".into()).unwrap();
+        for _ in 0..5 {
+            store.get("t0", "k1").unwrap();
+        }
+
+        // 之后的每次淘汰都会提高基准值，k1 最终也会被淘汰
+        for i in 0..50 {
+            store.set("t1", format!("k{}", i), "v1".into()).unwrap();
+        }
+        assert!(!store.contains("t0", "k1").unwrap());
+    }
+
+    #[test]
+    fn eviction_should_follow_renamed_tables() {
+        let store = MemTable::with_limit(entry_size("k1", &"v1".into()) * 4, EvictionPolicy::Lru);
+        for key in ["k1", "k2", "k3", "k4"] {
+            store.set("t1", key.into(), "v1".into()).unwrap();
+        }
+        store.rename_table("t1", "t2").unwrap();
+
+        store.set("t3", "k5".into(), "v1".into()).unwrap();
+        assert_eq!(store.stats().unwrap().evictions, 2);
+        assert!(!store.contains("t2", "k1").unwrap());
+        assert!(!store.contains("t2", "k2").unwrap());
+        assert!(store.contains("t2", "k3").unwrap());
+        assert!(store.contains("t3", "k5").unwrap());
+    }
+
+    #[test]
+    fn random_eviction_should_keep_memory_under_limit() {
+        let max_bytes = entry_size("k1", &"v1".into()) * 10;
+        let store = MemTable::with_limit(max_bytes, EvictionPolicy::Random);
+        for i in 0..100 {
+            store.set("t1", format!("k{}", i), "v1".into()).unwrap();
+            assert!(store.stats().unwrap().used_bytes <= max_bytes);
+        }
+        assert!(store.stats().unwrap().evictions >= 90);
+    }
+
+    #[test]
+    fn volatile_ttl_should_only_evict_keys_with_ttl() {
+        let store = MemTable::with_limit(
+            entry_size("k1", &"v1".into()) * 4,
+            EvictionPolicy::VolatileTtl,
+        );
+        let hour = Duration::from_secs(3600);
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        store
+            .set_with_ttl("t1", "k2".into(), "v1".into(), hour * 2)
+            .unwrap();
+        store
+            .set_with_ttl("t1", "k3".into(), "v1".into(), hour)
+            .unwrap();
+        store.set("t1", "k4".into(), "v1".into()).unwrap();
+
+        // 最先过期的 k3 先被淘汰
+        store.set("t1", "k5".into(), "v1".into()).unwrap();
+        assert!(!store.contains("t1", "k3").unwrap());
+        assert!(!store.contains("t1", "k2").unwrap());
+        for key in ["k1", "k4", "k5"] {
+            assert!(store.contains("t1", key).unwrap());
+        }
+
+        // 没有可以淘汰的 key 时，超过上限之后的写入失败
+        store.set("t1", "k6".into(), "v1".into()).unwrap();
+        store.set("t1", "k7".into(), "v1".into()).unwrap();
+        let result = store.set("t1", "k8".into(), "v1".into());
+        assert!(matches!(result, Err(KvError::OutOfMemory(_))));
+
+        // 删除之后又可以写入
+        assert!(store.del("t1", "k1").unwrap().is_some());
+        store.set("t1", "k8".into(), "v1".into()).unwrap();
+    }
+
+    #[test]
+    fn failed_conditional_writes_should_not_create_tables() {
+        let store = MemTable::new();
+        assert!(!store
+            .compare_and_swap("t1", "k1".into(), Some("v0".into()), "v1".into())
+            .unwrap());
+        assert!(store
+            .set_if_present("t1", "k1".into(), "v1".into())
+            .unwrap()
+            .is_none());
+        store.set("t2", "k1".into(), "v1".into()).unwrap();
+        assert!(store.incr("t3", "k1", 1).is_ok());
+        assert_eq!(store.list_tables().unwrap(), vec!["t2", "t3"]);
+    }
+
+    #[test]
+    fn purge_expired_should_remove_deadline() {
+        let store = MemTable::new();
+        store
+            .set_with_ttl("t1", "k1".into(), "v1".into(), Duration::ZERO)
+            .unwrap();
+        assert_eq!(store.purge_expired().unwrap(), 1);
+        assert!(store.deadline("t1", "k1").is_none());
+        assert!(!store.get_or_create_table("t1").contains_key("k1"));
+    }
 }
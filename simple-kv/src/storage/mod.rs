@@ -1,10 +1,43 @@
+mod backup;
+mod blocking;
+mod bulk;
+mod chunk;
+mod codec;
+mod compress;
+#[cfg(any(test, feature = "conformance"))]
+pub mod conformance;
+mod encrypt;
+mod index;
+mod lsm;
 mod memory;
+mod mvcc;
+mod quota;
 mod sleddb;
+mod wal;
 
+pub use backup::{backup, backup_to_file, restore, restore_from_file};
+pub(crate) use blocking::with_written_version;
+pub use blocking::BlockingStorage;
+pub use bulk::{export_table, import_table, DataFormat};
+pub use codec::{CodecStorage, ValueCodec};
+pub use compress::{CompressedStorage, Compressor};
+pub use encrypt::{EncryptedStorage, Keyring};
+pub use index::IndexedStorage;
+pub use lsm::LsmDb;
 pub use memory::MemTable;
+pub use mvcc::MvccStorage;
+pub use quota::QuotaStorage;
 pub use sleddb::SledDb;
+pub use wal::WalMemTable;
 
-use crate::{KvError, Kvpair, Value};
+use std::{
+    convert::TryFrom,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use async_trait::async_trait;
+
+use crate::{Chunk, KvError, Kvpair, Value};
 
 /// 对存储的抽象，我们不关心数据存在哪儿，但需要定义外界如何和存储打交道
 pub trait Storage: Send + Sync + 'static {
@@ -16,10 +49,323 @@ pub trait Storage: Send + Sync + 'static {
     fn contains(&self, table: &str, key: &str) -> Result<bool, KvError>;
     /// 从 HashTable 中删除一个 key
     fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError>;
+    /// 如果 key 当前的 value 等于 expected（None 表示 key 不存在），就把它设置成 value，
+    /// 返回是否设置成功
+    fn compare_and_swap(
+        &self,
+        table: &str,
+        key: String,
+        expected: Option<Value>,
+        value: Value,
+    ) -> Result<bool, KvError>;
+    /// 如果 key 存在，就把它设置成 value 并返回旧的 value；key 不存在则什么都不做，返回 None
+    fn set_if_present(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+    ) -> Result<Option<Value>, KvError>;
+    /// 把 key 的整数 value 加上 delta，返回新的 value；key 不存在时当作 0，不是整数时返回错误
+    fn incr(&self, table: &str, key: &str, delta: i64) -> Result<i64, KvError>;
+    /// 把 key 的浮点数 value 加上 delta，返回新的 value；key 不存在时当作 0，不是浮点数时返回错误
+    fn incr_float(&self, table: &str, key: &str, delta: f64) -> Result<f64, KvError>;
     /// 遍历 HashTable，返回所有 kv pair（这个接口不好）
     fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError>;
     /// 遍历 HashTable，返回 kv pair 的 Iterator
     fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError>;
+    /// 按 key 的顺序返回 HashTable 中 [start, end) 范围内的 kv pair，最多返回 limit 个，
+    /// end 为 None 表示一直到 HashTable 的末尾
+    fn get_range(
+        &self,
+        table: &str,
+        start: &str,
+        end: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<Kvpair>, KvError>;
+    /// 从一个 HashTable 里设置一个 key 的 value，并在 ttl 之后过期，返回旧的 value
+    fn set_with_ttl(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+        ttl: Duration,
+    ) -> Result<Option<Value>, KvError>;
+    /// 给 HashTable 中的 key 设置存活时间，key 不存在时返回 false
+    fn expire(&self, table: &str, key: &str, ttl: Duration) -> Result<bool, KvError>;
+    /// 获取 key 剩余的存活时间，key 不存在或者没有过期时间时返回 None
+    fn ttl(&self, table: &str, key: &str) -> Result<Option<Duration>, KvError>;
+    /// 去掉 key 的过期时间，key 之前有过期时间时返回 true
+    fn persist(&self, table: &str, key: &str) -> Result<bool, KvError>;
+    /// 删除所有已经过期的 key，返回删除的个数
+    fn purge_expired(&self) -> Result<usize, KvError>;
+    /// 返回所有 table 的名字，按名字排序
+    fn list_tables(&self) -> Result<Vec<String>, KvError>;
+    /// 返回 table 的统计信息，table 不存在时返回 None
+    fn table_info(&self, table: &str) -> Result<Option<TableStats>, KvError>;
+    /// 删除 table 以及其中所有的 key，返回 table 之前是否存在
+    fn drop_table(&self, table: &str) -> Result<bool, KvError>;
+    /// 把 table 重命名为 to，table 不存在时返回 false，to 已经存在时返回错误
+    fn rename_table(&self, table: &str, to: &str) -> Result<bool, KvError>;
+    /// 返回存储的统计信息
+    fn stats(&self) -> Result<StorageStats, KvError>;
+    /// 在一个事务中执行 f，f 返回 Ok 时事务中的修改全部生效，返回 Err 时全部丢弃
+    /// 注意 f 有可能被执行多次（比如 sled 遇到冲突时会重试），所以 f 不应该有副作用
+    fn transaction<T, F>(&self, f: F) -> Result<T, KvError>
+    where
+        F: Fn(&dyn TxnStorage) -> Result<T, KvError>;
+    /// 返回当前的版本号，每次修改数据版本号都会增加，不支持多版本的存储总是返回 0
+    fn version(&self) -> Result<u64, KvError> {
+        Ok(0)
+    }
+    /// 读取 key 在 version 时的 value，不支持多版本的存储只能读取当前的版本
+    fn get_at(&self, table: &str, key: &str, version: u64) -> Result<Option<Value>, KvError> {
+        match version == self.version()? {
+            true => self.get(table, key),
+            false => Err(KvError::VersionNotAvailable(version)),
+        }
+    }
+    /// 读取 table 在 version 时的所有 kv pair
+    fn get_all_at(&self, table: &str, version: u64) -> Result<Vec<Kvpair>, KvError> {
+        match version == self.version()? {
+            true => self.get_all(table),
+            false => Err(KvError::VersionNotAvailable(version)),
+        }
+    }
+    /// 创建一个快照，返回它的版本号，快照的版本在释放之前不会被清理
+    fn snapshot(&self) -> Result<u64, KvError> {
+        Err(KvError::InvalidCommand(
+            "Storage does not support snapshots".into(),
+        ))
+    }
+    /// 释放快照，快照不存在时返回 false
+    fn release_snapshot(&self, _version: u64) -> Result<bool, KvError> {
+        Ok(false)
+    }
+    /// 清理超过保留时间的旧版本，返回清理的版本个数
+    fn purge_versions(&self) -> Result<usize, KvError> {
+        Ok(0)
+    }
+    /// 通过 table 上的索引找到索引值等于 value 的所有 kv pair，按 key 的顺序返回
+    fn find(&self, table: &str, index: &str, _value: &Value) -> Result<Vec<Kvpair>, KvError> {
+        Err(KvError::NotFound(format!(
+            "index {} of table {}",
+            index, table
+        )))
+    }
+    /// 返回 table 的配额和当前的用量，table 没有配额时返回 None
+    fn quota(&self, _table: &str) -> Result<Option<QuotaUsage>, KvError> {
+        Ok(None)
+    }
+    /// 写入分块上传的 value 的一块，块按 offset 依次写入，offset 为 0 时重新开始上传。
+    /// 之前的块暂存在存储中，最后一块写入之后把整个 value 写入 table 并返回之前的 value，
+    /// 其它时候返回 None
+    fn set_chunk(&self, table: &str, key: &str, chunk: &Chunk) -> Result<Option<Value>, KvError> {
+        chunk::set_chunk(self, table, key, chunk)
+    }
+}
+
+/// 异步的存储接口，Service 通过它访问存储，这样慢的存储不会阻塞 tokio 的 worker 线程
+/// 同步的 Storage 可以用 BlockingStorage 包装成 AsyncStorage
+#[async_trait]
+pub trait AsyncStorage: Send + Sync + 'static {
+    /// 从一个 HashTable 里获取一个 key 的 value
+    async fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError>;
+    /// 从一个 HashTable 里设置一个 key 的 value，返回旧的 value
+    async fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError>;
+    /// 查看 HashTable 中是否有 key
+    async fn contains(&self, table: &str, key: &str) -> Result<bool, KvError>;
+    /// 从 HashTable 中删除一个 key
+    async fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError>;
+    /// 如果 key 当前的 value 等于 expected（None 表示 key 不存在），就把它设置成 value，
+    /// 返回是否设置成功
+    async fn compare_and_swap(
+        &self,
+        table: &str,
+        key: String,
+        expected: Option<Value>,
+        value: Value,
+    ) -> Result<bool, KvError>;
+    /// 如果 key 存在，就把它设置成 value 并返回旧的 value；key 不存在则什么都不做，返回 None
+    async fn set_if_present(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+    ) -> Result<Option<Value>, KvError>;
+    /// 把 key 的整数 value 加上 delta，返回新的 value
+    async fn incr(&self, table: &str, key: &str, delta: i64) -> Result<i64, KvError>;
+    /// 把 key 的浮点数 value 加上 delta，返回新的 value
+    async fn incr_float(&self, table: &str, key: &str, delta: f64) -> Result<f64, KvError>;
+    /// 遍历 HashTable，返回所有 kv pair
+    async fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError>;
+    /// 按 key 的顺序返回 HashTable 中 [start, end) 范围内的 kv pair，最多返回 limit 个
+    async fn get_range(
+        &self,
+        table: &str,
+        start: &str,
+        end: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<Kvpair>, KvError>;
+    /// 从一个 HashTable 里设置一个 key 的 value，并在 ttl 之后过期，返回旧的 value
+    async fn set_with_ttl(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+        ttl: Duration,
+    ) -> Result<Option<Value>, KvError>;
+    /// 给 HashTable 中的 key 设置存活时间，key 不存在时返回 false
+    async fn expire(&self, table: &str, key: &str, ttl: Duration) -> Result<bool, KvError>;
+    /// 获取 key 剩余的存活时间，key 不存在或者没有过期时间时返回 None
+    async fn ttl(&self, table: &str, key: &str) -> Result<Option<Duration>, KvError>;
+    /// 去掉 key 的过期时间，key 之前有过期时间时返回 true
+    async fn persist(&self, table: &str, key: &str) -> Result<bool, KvError>;
+    /// 删除所有已经过期的 key，返回删除的个数
+    async fn purge_expired(&self) -> Result<usize, KvError>;
+    /// 返回所有 table 的名字，按名字排序
+    async fn list_tables(&self) -> Result<Vec<String>, KvError>;
+    /// 返回 table 的统计信息，table 不存在时返回 None
+    async fn table_info(&self, table: &str) -> Result<Option<TableStats>, KvError>;
+    /// 删除 table 以及其中所有的 key，返回 table 之前是否存在
+    async fn drop_table(&self, table: &str) -> Result<bool, KvError>;
+    /// 把 table 重命名为 to，table 不存在时返回 false，to 已经存在时返回错误
+    async fn rename_table(&self, table: &str, to: &str) -> Result<bool, KvError>;
+    /// 返回存储的统计信息
+    async fn stats(&self) -> Result<StorageStats, KvError>;
+    /// 把所有 table 备份到 path 文件中，返回备份的 kv pair 个数
+    async fn backup(&self, path: &str) -> Result<usize, KvError>;
+    /// 从 path 文件中恢复数据，返回恢复的 kv pair 个数
+    async fn restore(&self, path: &str) -> Result<usize, KvError>;
+    /// 在一个事务中执行 f，语义和 Storage::transaction 一样
+    async fn transaction<T, F>(&self, f: F) -> Result<T, KvError>
+    where
+        F: Fn(&dyn TxnStorage) -> Result<T, KvError> + Send + 'static,
+        T: Send + 'static;
+    /// 返回当前的版本号
+    async fn version(&self) -> Result<u64, KvError>;
+    /// 读取 key 在 version 时的 value
+    async fn get_at(&self, table: &str, key: &str, version: u64) -> Result<Option<Value>, KvError>;
+    /// 读取 table 在 version 时的所有 kv pair
+    async fn get_all_at(&self, table: &str, version: u64) -> Result<Vec<Kvpair>, KvError>;
+    /// 创建一个快照，返回它的版本号
+    async fn snapshot(&self) -> Result<u64, KvError>;
+    /// 释放快照，快照不存在时返回 false
+    async fn release_snapshot(&self, version: u64) -> Result<bool, KvError>;
+    /// 清理超过保留时间的旧版本，返回清理的版本个数
+    async fn purge_versions(&self) -> Result<usize, KvError>;
+    /// 通过 table 上的索引找到索引值等于 value 的所有 kv pair
+    async fn find(&self, table: &str, index: &str, value: Value) -> Result<Vec<Kvpair>, KvError>;
+    /// 返回 table 的配额和当前的用量，table 没有配额时返回 None
+    async fn quota(&self, table: &str) -> Result<Option<QuotaUsage>, KvError>;
+    /// 写入分块上传的 value 的一块
+    async fn set_chunk(
+        &self,
+        table: &str,
+        key: &str,
+        chunk: Chunk,
+    ) -> Result<Option<Value>, KvError>;
+}
+
+/// table 的统计信息
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TableStats {
+    /// 没有过期的 key 的个数
+    pub keys: usize,
+    /// key 和 value 大约占用的字节数
+    pub bytes: usize,
+    /// 写入时压缩之前的 value 字节数，只有开启了压缩才会统计
+    pub uncompressed_bytes: usize,
+    /// 写入时压缩之后实际保存的 value 字节数
+    pub compressed_bytes: usize,
+}
+
+/// table 的配额和当前的用量，配额为 0 表示没有限制
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QuotaUsage {
+    pub max_keys: usize,
+    pub max_bytes: usize,
+    pub max_value_size: usize,
+    pub keys: usize,
+    pub bytes: usize,
+}
+
+/// 存储的统计信息
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StorageStats {
+    /// 数据大约占用的字节数
+    pub used_bytes: usize,
+    /// 数据最多可以占用的字节数，0 表示没有限制
+    pub max_bytes: usize,
+    /// 因为超过上限而被淘汰的 key 的个数
+    pub evictions: u64,
+    /// 写入时压缩之前的 value 字节数，只有开启了压缩才会统计
+    pub uncompressed_bytes: usize,
+    /// 写入时压缩之后实际保存的 value 字节数
+    pub compressed_bytes: usize,
+}
+
+/// 事务中可以对存储进行的操作
+pub trait TxnStorage {
+    /// 从一个 HashTable 里获取一个 key 的 value
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError>;
+    /// 从一个 HashTable 里设置一个 key 的 value，返回旧的 value
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError>;
+    /// 查看 HashTable 中是否有 key
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError>;
+    /// 从 HashTable 中删除一个 key
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError>;
+    /// 获取 key 剩余的存活时间，没有设置过期时间或者 key 不存在时返回 None
+    fn ttl(&self, table: &str, key: &str) -> Result<Option<Duration>, KvError>;
+}
+
+/// 给整数 value 加上 delta，value 不存在时当作 0
+fn add_integer(value: Option<&Value>, delta: i64) -> Result<Value, KvError> {
+    let current = value.map(i64::try_from).transpose()?.unwrap_or_default();
+    match current.checked_add(delta) {
+        Some(v) => Ok(v.into()),
+        None => Err(KvError::InvalidCommand(format!(
+            "Increment {} by {} would overflow",
+            current, delta
+        ))),
+    }
+}
+
+/// 给浮点数 value 加上 delta，value 不存在时当作 0
+fn add_float(value: Option<&Value>, delta: f64) -> Result<Value, KvError> {
+    let current = value.map(f64::try_from).transpose()?.unwrap_or_default();
+    match current + delta {
+        v if v.is_finite() => Ok(v.into()),
+        _ => Err(KvError::InvalidCommand(format!(
+            "Increment {} by {} would produce NaN or Infinity",
+            current, delta
+        ))),
+    }
+}
+
+/// 当前时间距 UNIX_EPOCH 的毫秒数，过期时间都用这个来表示
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// 根据 ttl 计算出过期的时间点
+fn deadline_from(ttl: Duration) -> u64 {
+    now_millis().saturating_add(ttl.as_millis() as u64)
+}
+
+/// 读取 key 当前的 value，但不会像 get 那样顺便删除过期的 key，
+/// 包装其它 Storage 的实现用它读取修改前后的 value，这样 purge_expired 的结果不受影响
+fn peek(store: &impl Storage, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+    let pair = store.get_range(table, key, None, 1)?.into_iter().next();
+    Ok(pair.filter(|p| p.key == key).and_then(|p| p.value))
+}
+
+/// 计算过期时间点之前还剩下多少时间，已经过期则返回 None
+fn remaining(deadline: u64) -> Option<Duration> {
+    let now = now_millis();
+    (deadline > now).then(|| Duration::from_millis(deadline - now))
 }
 
 /// 提供 Storage iterator，这样 trait 的实现者只需要
@@ -49,104 +395,65 @@ where
 
 #[cfg(test)]
 mod tests {
-    use tempfile::tempdir;
-
     use super::*;
+    use crate::{
+        storage_conformance_tests, CompressionCodec, CompressionConfig, CompressionPolicy,
+        EvictionPolicy, FsyncPolicy, IndexConfig, QuotaConfig,
+    };
 
-    #[test]
-    fn memtable_basic_interface_should_work() {
-        let store = MemTable::new();
-        test_basi_interface(store);
-    }
-
-    #[test]
-    fn memtable_get_all_should_work() {
-        let store = MemTable::new();
-        test_get_all(store);
-    }
-
-    #[test]
-    fn memtable_iter_should_work() {
-        let store = MemTable::new();
-        test_get_iter(store);
-    }
-
-    #[test]
-    fn sleddb_basic_interface_should_work() {
-        let dir = tempdir().unwrap();
-        let store = SledDb::new(dir);
-        test_basi_interface(store);
-    }
-
-    #[test]
-    fn sleddb_get_all_should_work() {
-        let dir = tempdir().unwrap();
-        let store = SledDb::new(dir);
-        test_get_all(store);
-    }
-
-    #[test]
-    fn sleddb_iter_should_work() {
-        let dir = tempdir().unwrap();
-        let store = SledDb::new(dir);
-        test_get_iter(store);
-    }
-
-    fn test_basi_interface(store: impl Storage) {
-        // 第一次 set 会创建 table，插入 key 并返回 None（之前没值）
-        let v = store.set("t1", "hello".into(), "world".into());
-        assert!(v.unwrap().is_none());
-        // 再次 set 同样的 key 会更新，并返回之前的值
-        let v1 = store.set("t1", "hello".into(), "world1".into());
-        assert_eq!(v1.unwrap(), Some("world".into()));
-
-        // get 存在的 key 会得到最新的值
-        let v = store.get("t1", "hello");
-        assert_eq!(v.unwrap(), Some("world1".into()));
-
-        // get 不存在的 key 或者 table 会得到 None
-        assert_eq!(None, store.get("t1", "hello1").unwrap());
-        assert!(store.get("t2", "hello1").unwrap().is_none());
-
-        // contains 纯在的 key 返回 true，否则 false
-        assert!(store.contains("t1", "hello").unwrap());
-        assert!(!store.contains("t1", "hello1").unwrap());
-        assert!(!store.contains("t2", "hello").unwrap());
-
-        // del 存在的 key 返回之前的值
-        let v = store.del("t1", "hello");
-        assert_eq!(v.unwrap(), Some("world1".into()));
-
-        // del 不存在的 key 或 table 返回 None
-        assert_eq!(None, store.del("t1", "hello1").unwrap());
-        assert_eq!(None, store.del("t2", "hello").unwrap());
-    }
-
-    fn test_get_all(store: impl Storage) {
-        store.set("t2", "k1".into(), "v1".into()).unwrap();
-        store.set("t2", "k2".into(), "v2".into()).unwrap();
-        let mut data = store.get_all("t2").unwrap();
-        data.sort_by(|a, b| a.partial_cmp(b).unwrap());
-        assert_eq!(
-            data,
-            vec![
-                Kvpair::new("k1", "v1".into()),
-                Kvpair::new("k2", "v2".into())
-            ]
-        )
-    }
-
-    fn test_get_iter(store: impl Storage) {
-        store.set("t2", "k1".into(), "v1".into()).unwrap();
-        store.set("t2", "k2".into(), "v2".into()).unwrap();
-        let mut data: Vec<_> = store.get_iter("t2").unwrap().collect();
-        data.sort_by(|a, b| a.partial_cmp(b).unwrap());
-        assert_eq!(
-            data,
-            vec![
-                Kvpair::new("k1", "v1".into()),
-                Kvpair::new("k2", "v2".into())
-            ]
-        )
-    }
+    storage_conformance_tests!(memtable, |_| MemTable::new());
+    storage_conformance_tests!(bounded_memtable, |_| MemTable::with_limit(
+        64 * 1024 * 1024,
+        EvictionPolicy::Lru
+    ));
+    storage_conformance_tests!(sleddb, |dir| SledDb::new(dir).unwrap());
+    storage_conformance_tests!(wal_memtable, |dir| WalMemTable::open(
+        dir,
+        FsyncPolicy::Never,
+        1024
+    )
+    .unwrap());
+    storage_conformance_tests!(lsm, |dir| LsmDb::open(dir, FsyncPolicy::Never, 4096)
+        .unwrap());
+    storage_conformance_tests!(encrypted, |_| {
+        let mut keyring = Keyring::new();
+        keyring.add(1, &[7; 32]).unwrap();
+        EncryptedStorage::new(MemTable::new(), keyring)
+    });
+    storage_conformance_tests!(compressed, |_| {
+        let policy = CompressionPolicy {
+            codec: CompressionCodec::Lz4,
+            threshold: 0,
+        };
+        let config = CompressionConfig {
+            default: policy,
+            ..Default::default()
+        };
+        CompressedStorage::new(MemTable::new(), Compressor::new(config))
+    });
+    storage_conformance_tests!(indexed, |_| {
+        let store = IndexedStorage::new(MemTable::new());
+        let index = |table: &str| IndexConfig {
+            table: table.into(),
+            name: "value".into(),
+            path: None,
+        };
+        for table in ["t1", "t2", "t3"] {
+            store.create_index(&index(table)).unwrap();
+        }
+        store
+    });
+    storage_conformance_tests!(quota, |_| {
+        let quota = QuotaConfig {
+            table: "t1".into(),
+            max_keys: 1_000_000,
+            max_bytes: 1 << 30,
+            max_value_size: 1 << 20,
+        };
+        QuotaStorage::new(MemTable::new(), &[quota])
+    });
+    storage_conformance_tests!(mvcc, |_| MvccStorage::new(
+        MemTable::new(),
+        Duration::from_secs(60)
+    ));
 }
@@ -0,0 +1,807 @@
+use crate::{KvError, Kvpair, QuotaUsage, Storage, StorageStats, TableStats, TxnStorage, Value};
+use prost::Message;
+use std::{
+    cell::{Cell, RefCell},
+    collections::{BTreeMap, HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, RwLock, RwLockReadGuard,
+    },
+    time::Duration,
+};
+
+use super::{deadline_from, now_millis, peek};
+
+/// 给任意的 Storage 加上多版本（MVCC），实现了 Storage trait
+///
+/// 每次修改数据都会得到一个新的、单调递增的版本号，被修改的 key 修改前后的 value
+/// 都会保存在内存中，这样可以读取某个版本时的数据。读取最新的数据直接访问内部的存储，
+/// 按版本读取时从保存的历史中查找，没有历史的 key 说明之后没有被修改过，直接读取当前的值
+///
+/// 超过 retention 的旧版本会在 purge_versions 时被清理，快照引用的版本会一直保留；
+/// 历史占用的内存超过 max_bytes 时，不论是否超过保留时间、是否被快照引用，都会提前清理最旧的版本。
+/// 修改一个 table 只需要持有这个 table 的锁，不需要多版本时可以用 disabled 创建，直接访问内部的存储
+pub struct MvccStorage<S> {
+    store: S,
+    // 为 false 时不记录历史，所有操作都直接交给内部的存储
+    enabled: bool,
+    retention: Duration,
+    // 历史占用的内存上限（字节），0 表示没有限制
+    max_bytes: u64,
+    version: AtomicU64,
+    // table -> table 中 key 的历史。修改一个 table 时持有 map 的读锁和这个 table 的写锁，
+    // 按版本读取时持有 table 的读锁，这样不会看到只写了一半的修改；
+    // 事务和 drop/rename table 可能修改多个 table，持有 map 的写锁
+    tables: RwLock<HashMap<String, RwLock<TableHistory>>>,
+    versions: Mutex<Versions>,
+}
+
+/// key -> 按版本号从小到大排列的 value
+type TableHistory = BTreeMap<String, VecDeque<Version>>;
+
+#[derive(Debug, Default)]
+struct Versions {
+    // 每个版本产生的时间（毫秒）以及这个版本加入历史的字节数
+    times: VecDeque<(u64, u64, u64)>,
+    // 快照引用的版本，以及引用的次数
+    snapshots: BTreeMap<u64, usize>,
+    // 比它小的版本已经被清理了
+    oldest: u64,
+    // 历史中所有 value 占用的字节数
+    bytes: u64,
+}
+
+/// key 在某个版本时的状态，value 为 None 表示 key 不存在
+#[derive(Debug, Clone, PartialEq)]
+struct Version {
+    version: u64,
+    value: Option<Value>,
+    // 过期的时间点（毫秒），0 表示没有过期时间
+    deadline: u64,
+}
+
+impl Version {
+    /// 在历史中占用的字节数
+    fn size(&self) -> u64 {
+        let value = self.value.as_ref().map_or(0, |v| v.encoded_len());
+        (std::mem::size_of::<Self>() + value) as u64
+    }
+
+    fn live_value(&self) -> Option<Value> {
+        match self.deadline > 0 && self.deadline <= now_millis() {
+            true => None,
+            false => self.value.clone(),
+        }
+    }
+}
+
+/// 被修改的 key：table、key 以及修改之前的 value 和过期时间
+type Change = (String, String, Option<Value>, u64);
+
+thread_local! {
+    // 当前线程上的写操作产生的最大的版本号，BlockingStorage 用它把版本号带回给 Service
+    static WRITTEN_VERSION: Cell<u64> = const { Cell::new(0) };
+}
+
+/// 取出当前线程上的写操作产生的最大的版本号并清零，没有产生新的版本时返回 0
+pub(super) fn take_written_version() -> u64 {
+    WRITTEN_VERSION.with(|v| v.replace(0))
+}
+
+impl<S: Storage> MvccStorage<S> {
+    pub fn new(store: S, retention: Duration) -> Self {
+        Self {
+            store,
+            enabled: true,
+            retention,
+            max_bytes: 0,
+            version: AtomicU64::new(0),
+            tables: RwLock::new(HashMap::new()),
+            versions: Mutex::new(Versions::default()),
+        }
+    }
+
+    /// 不记录历史，写操作不需要持有锁，版本号和快照的行为和内部的存储一样
+    pub fn disabled(store: S) -> Self {
+        Self {
+            enabled: false,
+            ..Self::new(store, Duration::ZERO)
+        }
+    }
+
+    /// 设置历史占用的内存上限（字节），0 表示没有限制
+    pub fn with_max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    /// 获取内部的 Storage
+    pub fn inner(&self) -> &S {
+        &self.store
+    }
+
+    /// key 当前的 value 和过期时间
+    fn current(&self, table: &str, key: &str) -> Result<(Option<Value>, u64), KvError> {
+        let value = peek(&self.store, table, key)?;
+        let deadline = match &value {
+            Some(_) => self.store.ttl(table, key)?.map_or(0, deadline_from),
+            None => 0,
+        };
+        Ok((value, deadline))
+    }
+
+    /// 修改 table 中 keys 的操作：先记下它们修改前的 value，执行 f，再记下修改后的 value
+    fn write<T>(
+        &self,
+        table: &str,
+        keys: &[&str],
+        f: impl FnOnce() -> Result<T, KvError>,
+    ) -> Result<T, KvError> {
+        if !self.enabled {
+            return f();
+        }
+        let result = {
+            let tables = self.table_history(table);
+            let mut history = tables[table].write().unwrap();
+            let mut changes = Vec::with_capacity(keys.len());
+            for key in keys {
+                let (value, deadline) = self.current(table, key)?;
+                changes.push((table.to_owned(), key.to_string(), value, deadline));
+            }
+            let result = f()?;
+            self.record(changes, &mut HashMap::from([(table, &mut *history)]))?;
+            result
+        };
+        self.enforce_max_bytes();
+        Ok(result)
+    }
+
+    /// 修改多个 table 的操作，持有 map 的写锁，f 执行修改并返回被修改的 key 修改前的状态
+    fn write_tables<T>(
+        &self,
+        f: impl FnOnce() -> Result<(T, Vec<Change>), KvError>,
+    ) -> Result<T, KvError> {
+        let result = {
+            let mut tables = self.tables.write().unwrap();
+            let (result, changes) = f()?;
+            for (table, ..) in &changes {
+                if !tables.contains_key(table) {
+                    tables.insert(table.clone(), Default::default());
+                }
+            }
+            let mut history = tables
+                .iter_mut()
+                .map(|(table, v)| (table.as_str(), v.get_mut().unwrap()))
+                .collect();
+            self.record(changes, &mut history)?;
+            result
+        };
+        self.enforce_max_bytes();
+        Ok(result)
+    }
+
+    /// 持有 map 的读锁，并确保 table 的历史存在
+    fn table_history(
+        &self,
+        table: &str,
+    ) -> RwLockReadGuard<'_, HashMap<String, RwLock<TableHistory>>> {
+        loop {
+            let tables = self.tables.read().unwrap();
+            if tables.contains_key(table) {
+                return tables;
+            }
+            drop(tables);
+            // 清理历史时可能删除空的 table，重新拿到读锁之后需要再检查一次
+            self.tables
+                .write()
+                .unwrap()
+                .entry(table.to_owned())
+                .or_default();
+        }
+    }
+
+    /// 给这次修改分配一个新的版本号，把 key 修改后的 value 加入对应 table 的历史
+    fn record(
+        &self,
+        changes: Vec<Change>,
+        history: &mut HashMap<&str, &mut TableHistory>,
+    ) -> Result<(), KvError> {
+        let mut changed = Vec::with_capacity(changes.len());
+        for (table, key, old, old_deadline) in changes {
+            let (value, deadline) = self.current(&table, &key)?;
+            if value != old || deadline != old_deadline {
+                changed.push((table, key, old, old_deadline, value, deadline));
+            }
+        }
+        // 没有修改任何数据的操作（比如删除不存在的 key）不会产生新的版本
+        if changed.is_empty() {
+            return Ok(());
+        }
+
+        let mut versions = self.versions.lock().unwrap();
+        let version = self.version.load(Ordering::Acquire) + 1;
+        let mut bytes = 0;
+        for (table, key, old, old_deadline, value, deadline) in changed {
+            let table_history = history.get_mut(table.as_str()).unwrap();
+            let key_history = table_history.entry(key).or_default();
+            // 第一次修改时把之前的 value 作为最早的版本
+            if key_history.is_empty() {
+                let old = Version {
+                    version: 0,
+                    value: old,
+                    deadline: old_deadline,
+                };
+                bytes += old.size();
+                key_history.push_back(old);
+            }
+            let new = Version {
+                version,
+                value,
+                deadline,
+            };
+            bytes += new.size();
+            key_history.push_back(new);
+        }
+        versions.times.push_back((version, now_millis(), bytes));
+        versions.bytes += bytes;
+        self.version.store(version, Ordering::Release);
+        WRITTEN_VERSION.with(|v| v.set(v.get().max(version)));
+        Ok(())
+    }
+
+    /// 历史超过 max_bytes 时清理最旧的版本，直到只用一半的内存
+    fn enforce_max_bytes(&self) {
+        if self.max_bytes == 0 {
+            return;
+        }
+        let oldest = {
+            let versions = self.versions.lock().unwrap();
+            if versions.bytes <= self.max_bytes {
+                return;
+            }
+            let mut excess = versions.bytes - self.max_bytes / 2;
+            let mut oldest = versions.oldest;
+            for &(version, _, bytes) in versions.times.iter() {
+                oldest = version;
+                if bytes >= excess {
+                    break;
+                }
+                excess -= bytes;
+            }
+            oldest
+        };
+        self.purge_to(oldest);
+    }
+
+    /// 清理 oldest 之前的版本，返回清理的版本个数
+    fn purge_to(&self, oldest: u64) -> usize {
+        {
+            let mut versions = self.versions.lock().unwrap();
+            if oldest <= versions.oldest {
+                return 0;
+            }
+            versions.oldest = oldest;
+            while matches!(versions.times.front(), Some(&(v, _, _)) if v <= oldest) {
+                versions.times.pop_front();
+            }
+        }
+
+        // 每个 key 只需要保留 oldest 时的状态以及之后的版本，
+        // 如果 oldest 之后没有被修改过，当前的 value 就是它在 oldest 时的 value
+        let mut purged = 0;
+        let mut freed = 0;
+        let mut tables = self.tables.write().unwrap();
+        for keys in tables.values_mut() {
+            keys.get_mut().unwrap().retain(|_, versions| {
+                let i = versions.partition_point(|v| v.version <= oldest);
+                let n = match i == versions.len() {
+                    true => versions.len(),
+                    false => i.saturating_sub(1),
+                };
+                for v in versions.drain(..n) {
+                    freed += v.size();
+                }
+                purged += n;
+                !versions.is_empty()
+            });
+        }
+        tables.retain(|_, keys| !keys.get_mut().unwrap().is_empty());
+        let mut versions = self.versions.lock().unwrap();
+        versions.bytes = versions.bytes.saturating_sub(freed);
+        purged
+    }
+
+    /// table 中和 from 的 key 相同的 key 修改前的 value，drop/rename table 时用
+    fn changes(&self, from: &str, table: &str) -> Result<Vec<Change>, KvError> {
+        let mut changes = Vec::new();
+        for pair in self.store.get_all(from)? {
+            let (value, deadline) = self.current(table, &pair.key)?;
+            changes.push((table.to_owned(), pair.key, value, deadline));
+        }
+        Ok(changes)
+    }
+
+    fn check_version(&self, version: u64) -> Result<(), KvError> {
+        let oldest = self.versions.lock().unwrap().oldest;
+        match version < oldest || version > self.version.load(Ordering::Acquire) {
+            true => Err(KvError::VersionNotAvailable(version)),
+            false => Ok(()),
+        }
+    }
+}
+
+/// 找到 version 时 key 的状态
+fn find(versions: &VecDeque<Version>, version: u64) -> Option<&Version> {
+    let i = versions.partition_point(|v| v.version <= version);
+    i.checked_sub(1).map(|i| &versions[i])
+}
+
+impl<S: Storage> Storage for MvccStorage<S> {
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.store.get(table, key)
+    }
+
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        self.write(table, &[&key.clone()], || self.store.set(table, key, value))
+    }
+
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.store.contains(table, key)
+    }
+
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.write(table, &[key], || self.store.del(table, key))
+    }
+
+    fn compare_and_swap(
+        &self,
+        table: &str,
+        key: String,
+        expected: Option<Value>,
+        value: Value,
+    ) -> Result<bool, KvError> {
+        self.write(table, &[&key.clone()], || {
+            self.store.compare_and_swap(table, key, expected, value)
+        })
+    }
+
+    fn set_if_present(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+    ) -> Result<Option<Value>, KvError> {
+        self.write(table, &[&key.clone()], || {
+            self.store.set_if_present(table, key, value)
+        })
+    }
+
+    fn incr(&self, table: &str, key: &str, delta: i64) -> Result<i64, KvError> {
+        self.write(table, &[key], || self.store.incr(table, key, delta))
+    }
+
+    fn incr_float(&self, table: &str, key: &str, delta: f64) -> Result<f64, KvError> {
+        self.write(table, &[key], || self.store.incr_float(table, key, delta))
+    }
+
+    fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        self.store.get_all(table)
+    }
+
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
+        self.store.get_iter(table)
+    }
+
+    fn get_range(
+        &self,
+        table: &str,
+        start: &str,
+        end: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        self.store.get_range(table, start, end, limit)
+    }
+
+    fn set_with_ttl(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+        ttl: Duration,
+    ) -> Result<Option<Value>, KvError> {
+        self.write(table, &[&key.clone()], || {
+            self.store.set_with_ttl(table, key, value, ttl)
+        })
+    }
+
+    fn expire(&self, table: &str, key: &str, ttl: Duration) -> Result<bool, KvError> {
+        self.write(table, &[key], || self.store.expire(table, key, ttl))
+    }
+
+    fn ttl(&self, table: &str, key: &str) -> Result<Option<Duration>, KvError> {
+        self.store.ttl(table, key)
+    }
+
+    fn persist(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.write(table, &[key], || self.store.persist(table, key))
+    }
+
+    fn purge_expired(&self) -> Result<usize, KvError> {
+        // 过期的 key 在每个版本中都读不到，删除它们不算修改数据
+        self.store.purge_expired()
+    }
+
+    fn list_tables(&self) -> Result<Vec<String>, KvError> {
+        self.store.list_tables()
+    }
+
+    fn table_info(&self, table: &str) -> Result<Option<TableStats>, KvError> {
+        self.store.table_info(table)
+    }
+
+    fn drop_table(&self, table: &str) -> Result<bool, KvError> {
+        if !self.enabled {
+            return self.store.drop_table(table);
+        }
+        self.write_tables(|| {
+            let changes = self.changes(table, table)?;
+            Ok((self.store.drop_table(table)?, changes))
+        })
+    }
+
+    fn rename_table(&self, table: &str, to: &str) -> Result<bool, KvError> {
+        if !self.enabled {
+            return self.store.rename_table(table, to);
+        }
+        self.write_tables(|| {
+            let mut changes = self.changes(table, table)?;
+            changes.extend(self.changes(table, to)?);
+            Ok((self.store.rename_table(table, to)?, changes))
+        })
+    }
+
+    fn stats(&self) -> Result<StorageStats, KvError> {
+        self.store.stats()
+    }
+
+    fn transaction<T, F>(&self, f: F) -> Result<T, KvError>
+    where
+        F: Fn(&dyn TxnStorage) -> Result<T, KvError>,
+    {
+        if !self.enabled {
+            return self.store.transaction(f);
+        }
+        self.write_tables(|| {
+            let touched = RefCell::new(BTreeMap::new());
+            let result = self.store.transaction(|txn| {
+                // f 可能被执行多次，只记录最后一次执行时修改的 key
+                touched.borrow_mut().clear();
+                f(&MvccTxn {
+                    txn,
+                    touched: &touched,
+                })
+            })?;
+            let changes = touched
+                .into_inner()
+                .into_iter()
+                .map(|((table, key), (old, deadline))| (table, key, old, deadline))
+                .collect();
+            Ok((result, changes))
+        })
+    }
+
+    fn find(&self, table: &str, index: &str, value: &Value) -> Result<Vec<Kvpair>, KvError> {
+        self.store.find(table, index, value)
+    }
+
+    fn quota(&self, table: &str) -> Result<Option<QuotaUsage>, KvError> {
+        self.store.quota(table)
+    }
+
+    fn version(&self) -> Result<u64, KvError> {
+        if !self.enabled {
+            return self.store.version();
+        }
+        Ok(self.version.load(Ordering::Acquire))
+    }
+
+    fn get_at(&self, table: &str, key: &str, version: u64) -> Result<Option<Value>, KvError> {
+        if !self.enabled {
+            return self.store.get_at(table, key, version);
+        }
+        let tables = self.tables.read().unwrap();
+        let history = tables.get(table).map(|t| t.read().unwrap());
+        self.check_version(version)?;
+        let versions = history.as_ref().and_then(|t| t.get(key));
+        match versions.and_then(|v| find(v, version)) {
+            Some(v) => Ok(v.live_value()),
+            None => self.store.get(table, key),
+        }
+    }
+
+    fn get_all_at(&self, table: &str, version: u64) -> Result<Vec<Kvpair>, KvError> {
+        if !self.enabled {
+            return self.store.get_all_at(table, version);
+        }
+        let tables = self.tables.read().unwrap();
+        let history = tables.get(table).map(|t| t.read().unwrap());
+        self.check_version(version)?;
+        let mut data: BTreeMap<_, _> = self
+            .store
+            .get_all(table)?
+            .into_iter()
+            .map(|pair| (pair.key, pair.value.unwrap_or_default()))
+            .collect();
+        // 用历史中 version 时的 value 覆盖当前的 value
+        for (key, versions) in history.iter().flat_map(|t| t.iter()) {
+            match find(versions, version).and_then(Version::live_value) {
+                Some(value) => data.insert(key.clone(), value),
+                None => data.remove(key),
+            };
+        }
+        Ok(data.into_iter().map(|(k, v)| Kvpair::new(k, v)).collect())
+    }
+
+    fn snapshot(&self) -> Result<u64, KvError> {
+        if !self.enabled {
+            return self.store.snapshot();
+        }
+        let mut versions = self.versions.lock().unwrap();
+        let version = self.version.load(Ordering::Acquire);
+        *versions.snapshots.entry(version).or_default() += 1;
+        Ok(version)
+    }
+
+    fn release_snapshot(&self, version: u64) -> Result<bool, KvError> {
+        if !self.enabled {
+            return self.store.release_snapshot(version);
+        }
+        let mut versions = self.versions.lock().unwrap();
+        match versions.snapshots.get_mut(&version) {
+            Some(1) => {
+                versions.snapshots.remove(&version);
+                Ok(true)
+            }
+            Some(count) => {
+                *count -= 1;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn purge_versions(&self) -> Result<usize, KvError> {
+        if !self.enabled {
+            return self.store.purge_versions();
+        }
+        let oldest = {
+            let versions = self.versions.lock().unwrap();
+            let cutoff = now_millis().saturating_sub(self.retention.as_millis() as u64);
+            // 超过保留时间的最新的版本，快照引用的版本不能被清理
+            let pinned = versions
+                .snapshots
+                .keys()
+                .next()
+                .copied()
+                .unwrap_or(u64::MAX);
+            versions
+                .times
+                .iter()
+                .take_while(|&&(version, time, _)| time <= cutoff && version <= pinned)
+                .last()
+                .map_or(versions.oldest, |&(version, ..)| version)
+        };
+        Ok(self.purge_to(oldest))
+    }
+}
+
+/// 事务中修改 key 之前先记下它修改前的 value 和过期时间
+struct MvccTxn<'a> {
+    txn: &'a dyn TxnStorage,
+    touched: &'a RefCell<Touched>,
+}
+
+/// 事务中修改过的 key 修改前的 value 和过期时间
+type Touched = BTreeMap<(String, String), (Option<Value>, u64)>;
+
+impl MvccTxn<'_> {
+    fn touch(&self, table: &str, key: &str) -> Result<(), KvError> {
+        let id = (table.to_owned(), key.to_owned());
+        if !self.touched.borrow().contains_key(&id) {
+            let old = self.txn.get(table, key)?;
+            let deadline = match &old {
+                Some(_) => self.txn.ttl(table, key)?.map_or(0, deadline_from),
+                None => 0,
+            };
+            self.touched.borrow_mut().insert(id, (old, deadline));
+        }
+        Ok(())
+    }
+}
+
+impl TxnStorage for MvccTxn<'_> {
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.txn.get(table, key)
+    }
+
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        self.touch(table, &key)?;
+        self.txn.set(table, key, value)
+    }
+
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.txn.contains(table, key)
+    }
+
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.touch(table, key)?;
+        self.txn.del(table, key)
+    }
+
+    fn ttl(&self, table: &str, key: &str) -> Result<Option<Duration>, KvError> {
+        self.txn.ttl(table, key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MemTable;
+
+    fn new_store(retention: Duration) -> MvccStorage<MemTable> {
+        MvccStorage::new(MemTable::new(), retention)
+    }
+
+    #[test]
+    fn mvcc_should_read_old_versions() {
+        let store = new_store(Duration::from_secs(60));
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        let v1 = store.version().unwrap();
+        store.set("t1", "k2".into(), "v2".into()).unwrap();
+        store.set("t1", "k1".into(), "v11".into()).unwrap();
+        store.del("t1", "k2").unwrap();
+        let v2 = store.version().unwrap();
+        assert_eq!(v2, v1 + 3);
+
+        // 没有修改数据的操作不会产生新的版本
+        store.del("t1", "k3").unwrap();
+        assert_eq!(store.version().unwrap(), v2);
+
+        assert_eq!(store.get_at("t1", "k1", v1).unwrap(), Some("v1".into()));
+        assert_eq!(store.get_at("t1", "k2", v1).unwrap(), None);
+        assert_eq!(store.get_at("t1", "k2", v1 + 1).unwrap(), Some("v2".into()));
+        assert_eq!(store.get_at("t1", "k1", v2).unwrap(), Some("v11".into()));
+        assert_eq!(
+            store.get_all_at("t1", v1 + 1).unwrap(),
+            vec![
+                Kvpair::new("k1", "v1".into()),
+                Kvpair::new("k2", "v2".into())
+            ]
+        );
+        assert_eq!(
+            store.get_all_at("t1", v2).unwrap(),
+            vec![Kvpair::new("k1", "v11".into())]
+        );
+
+        // 还不存在的版本不能读取
+        let result = store.get_at("t1", "k1", v2 + 1);
+        assert!(matches!(result, Err(KvError::VersionNotAvailable(_))));
+    }
+
+    #[test]
+    fn mvcc_should_record_table_and_transaction_changes() {
+        let store = new_store(Duration::from_secs(60));
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        store.set("t1", "k2".into(), "v2".into()).unwrap();
+        let before = store.version().unwrap();
+
+        store
+            .transaction(|txn| {
+                txn.set("t1", "k1".into(), "v11".into())?;
+                txn.del("t1", "k2")?;
+                Ok(())
+            })
+            .unwrap();
+        let after_txn = store.version().unwrap();
+        // 一个事务只产生一个版本
+        assert_eq!(after_txn, before + 1);
+
+        assert!(store.rename_table("t1", "t2").unwrap());
+        assert_eq!(
+            store.get_at("t1", "k1", after_txn).unwrap(),
+            Some("v11".into())
+        );
+        assert_eq!(store.get_at("t2", "k1", after_txn).unwrap(), None);
+        assert_eq!(store.get_all_at("t1", before).unwrap().len(), 2);
+
+        assert!(store.drop_table("t2").unwrap());
+        let v = store.version().unwrap();
+        assert_eq!(store.get_all_at("t2", v - 1).unwrap().len(), 1);
+        assert!(store.get_all_at("t2", v).unwrap().is_empty());
+    }
+
+    #[test]
+    fn purge_versions_should_keep_snapshots() {
+        let store = new_store(Duration::ZERO);
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        let snapshot = store.snapshot().unwrap();
+        store.set("t1", "k1".into(), "v2".into()).unwrap();
+        store.set("t1", "k1".into(), "v3".into()).unwrap();
+
+        // 快照之后的版本都超过了保留时间，但是快照的版本需要保留
+        store.purge_versions().unwrap();
+        assert_eq!(
+            store.get_at("t1", "k1", snapshot).unwrap(),
+            Some("v1".into())
+        );
+
+        assert!(store.release_snapshot(snapshot).unwrap());
+        assert!(!store.release_snapshot(snapshot).unwrap());
+        assert!(store.purge_versions().unwrap() > 0);
+        let result = store.get_at("t1", "k1", snapshot);
+        assert!(matches!(result, Err(KvError::VersionNotAvailable(_))));
+
+        // 最新的版本仍然可以读取，之后没有修改过的 key 不再需要保存历史
+        let version = store.version().unwrap();
+        assert_eq!(
+            store.get_at("t1", "k1", version).unwrap(),
+            Some("v3".into())
+        );
+        assert!(store.tables.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn history_should_not_exceed_max_bytes() {
+        let store = new_store(Duration::from_secs(60)).with_max_bytes(4096);
+        store.set("t1", "k1".into(), "v0".into()).unwrap();
+        let snapshot = store.snapshot().unwrap();
+        for i in 0..100 {
+            let value = format!("{:0>100}", i);
+            store.set("t1", "k1".into(), value.into()).unwrap();
+        }
+        assert!(store.versions.lock().unwrap().bytes <= 4096);
+
+        // 超过上限时快照引用的版本也会被清理
+        let result = store.get_at("t1", "k1", snapshot);
+        assert!(matches!(result, Err(KvError::VersionNotAvailable(_))));
+        let version = store.version().unwrap();
+        let value = store.get_at("t1", "k1", version - 1).unwrap();
+        assert_eq!(value, Some(format!("{:0>100}", 98).into()));
+    }
+
+    #[test]
+    fn transaction_should_keep_old_ttl_in_history() {
+        let store = new_store(Duration::from_secs(60));
+        let hour = Duration::from_secs(3600);
+        // 直接写入内部的存储，k1 没有历史，事务中第一次修改时才记下它原来的状态
+        store
+            .inner()
+            .set_with_ttl("t1", "k1".into(), "v1".into(), hour)
+            .unwrap();
+        let v1 = store.version().unwrap();
+        store
+            .transaction(|txn| txn.set("t1", "k1".into(), "v2".into()))
+            .unwrap();
+
+        // 事务之前的版本保留了 key 原来的过期时间
+        let tables = store.tables.read().unwrap();
+        let history = tables["t1"].read().unwrap();
+        let old = find(&history["k1"], v1).unwrap();
+        assert_eq!(old.value, Some("v1".into()));
+        assert!(old.deadline > now_millis() + hour.as_millis() as u64 - 1000);
+    }
+
+    #[test]
+    fn disabled_mvcc_should_not_keep_history() {
+        let store = MvccStorage::disabled(MemTable::new());
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        store
+            .transaction(|txn| txn.set("t1", "k2".into(), "v2".into()))
+            .unwrap();
+        assert_eq!(store.get("t1", "k2").unwrap(), Some("v2".into()));
+
+        assert_eq!(store.version().unwrap(), 0);
+        assert!(store.tables.read().unwrap().is_empty());
+        assert!(matches!(store.snapshot(), Err(KvError::InvalidCommand(_))));
+    }
+}
@@ -0,0 +1,493 @@
+use crate::{
+    KvError, Kvpair, QuotaConfig, QuotaUsage, Storage, StorageStats, TableStats, TxnStorage, Value,
+};
+use dashmap::{mapref::entry::Entry, DashMap};
+use prost::Message;
+use std::{cell::RefCell, collections::HashMap, time::Duration};
+
+use super::{add_float, add_integer};
+
+/// 给 table 加上 key 个数、占用字节数和单个 value 大小的配额，实现了 Storage trait
+///
+/// 用量按照内部 Storage 读出的 value 计算，放在压缩、加密之上时限制的就是客户端写入的大小。
+/// 用量第一次用到时遍历 table 统计，之后随着写入更新。过期的 key 不会从用量中减掉，
+/// 所以用量可能偏大，写入超出配额时会重新统计一次再决定是否拒绝。
+/// 已经超出配额的 table（比如调小了配额）仍然可以删除 key 或者把 value 改小
+pub struct QuotaStorage<S> {
+    store: S,
+    quotas: HashMap<String, QuotaConfig>,
+    // table -> 用量；写入时持有 table 的 entry，这样检查配额和写入之间不会被别的写入插进来
+    usage: DashMap<String, Usage>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Usage {
+    keys: usize,
+    bytes: usize,
+}
+
+impl Usage {
+    /// key 的 value 从 old 大小变成 new 大小之后的用量，None 表示 key 不存在
+    fn apply(self, key: &str, old: Option<usize>, new: Option<usize>) -> Self {
+        let size = |v: Option<usize>| v.map_or(0, |v| key.len() + v);
+        Self {
+            keys: (self.keys + new.is_some() as usize).saturating_sub(old.is_some() as usize),
+            bytes: (self.bytes + size(new)).saturating_sub(size(old)),
+        }
+    }
+}
+
+impl<S: Storage> QuotaStorage<S> {
+    pub fn new(store: S, quotas: &[QuotaConfig]) -> Self {
+        Self {
+            store,
+            quotas: quotas
+                .iter()
+                .map(|q| (q.table.clone(), q.clone()))
+                .collect(),
+            usage: DashMap::new(),
+        }
+    }
+
+    /// 获取内部的 Storage
+    pub fn inner(&self) -> &S {
+        &self.store
+    }
+
+    /// 遍历 table 统计用量，和写入时更新用量的方式保持一致，而不是用 table_info 中
+    /// 内部 Storage 实际存储（可能已经压缩、加密）的大小
+    fn load(&self, table: &str) -> Result<Usage, KvError> {
+        let usage = self
+            .store
+            .get_iter(table)?
+            .fold(Usage::default(), |usage, pair| {
+                let size = pair.value.unwrap_or_default().encoded_len();
+                usage.apply(&pair.key, None, Some(size))
+            });
+        Ok(usage)
+    }
+
+    fn cached(&self, table: &str) -> Result<Usage, KvError> {
+        if let Some(usage) = self.usage.get(table) {
+            return Ok(*usage);
+        }
+        let usage = self.load(table)?;
+        self.usage.insert(table.to_owned(), usage);
+        Ok(usage)
+    }
+
+    /// 检查写入 key 之后是否超出配额，size 根据旧的 value 算出新 value 的大小，
+    /// f 执行写入并返回结果以及是否真的写入了
+    fn write<T>(
+        &self,
+        table: &str,
+        key: &str,
+        size: impl FnOnce(Option<&Value>) -> Result<usize, KvError>,
+        f: impl FnOnce() -> Result<(T, bool), KvError>,
+    ) -> Result<T, KvError> {
+        let quota = match self.quotas.get(table) {
+            Some(quota) => quota,
+            None => return Ok(f()?.0),
+        };
+        let mut usage = match self.usage.entry(table.to_owned()) {
+            Entry::Occupied(e) => e.into_ref(),
+            Entry::Vacant(e) => e.insert(self.load(table)?),
+        };
+        let old = self.store.get(table, key)?;
+        let new = size(old.as_ref())?;
+        check_value_size(quota, new)?;
+        let old = old.map(|v| v.encoded_len());
+        let mut next = usage.apply(key, old, Some(new));
+        if check(quota, &usage, &next).is_err() {
+            // 用量可能因为过期的 key 偏大，重新统计一次再检查
+            *usage = self.load(table)?;
+            next = usage.apply(key, old, Some(new));
+            check(quota, &usage, &next)?;
+        }
+        let (result, written) = f()?;
+        if written {
+            *usage = next;
+        }
+        Ok(result)
+    }
+
+    fn remove(
+        &self,
+        table: &str,
+        key: &str,
+        f: impl FnOnce() -> Result<Option<Value>, KvError>,
+    ) -> Result<Option<Value>, KvError> {
+        let mut usage = match self.usage.get_mut(table) {
+            Some(usage) => usage,
+            None => return f(),
+        };
+        let old = f()?;
+        let size = old.as_ref().map(|v| v.encoded_len());
+        *usage = usage.apply(key, size, None);
+        Ok(old)
+    }
+}
+
+impl<S: Storage> Storage for QuotaStorage<S> {
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.store.get(table, key)
+    }
+
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        let size = value.encoded_len();
+        self.write(
+            table,
+            &key.clone(),
+            |_| Ok(size),
+            || Ok((self.store.set(table, key, value)?, true)),
+        )
+    }
+
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.store.contains(table, key)
+    }
+
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.remove(table, key, || self.store.del(table, key))
+    }
+
+    fn compare_and_swap(
+        &self,
+        table: &str,
+        key: String,
+        expected: Option<Value>,
+        value: Value,
+    ) -> Result<bool, KvError> {
+        let size = value.encoded_len();
+        self.write(
+            table,
+            &key.clone(),
+            |_| Ok(size),
+            || {
+                let swapped = self.store.compare_and_swap(table, key, expected, value)?;
+                Ok((swapped, swapped))
+            },
+        )
+    }
+
+    fn set_if_present(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+    ) -> Result<Option<Value>, KvError> {
+        let size = value.encoded_len();
+        self.write(
+            table,
+            &key.clone(),
+            |_| Ok(size),
+            || {
+                let old = self.store.set_if_present(table, key, value)?;
+                let written = old.is_some();
+                Ok((old, written))
+            },
+        )
+    }
+
+    fn incr(&self, table: &str, key: &str, delta: i64) -> Result<i64, KvError> {
+        let size = |v: Option<&Value>| Ok(add_integer(v, delta)?.encoded_len());
+        self.write(table, key, size, || {
+            Ok((self.store.incr(table, key, delta)?, true))
+        })
+    }
+
+    fn incr_float(&self, table: &str, key: &str, delta: f64) -> Result<f64, KvError> {
+        let size = |v: Option<&Value>| Ok(add_float(v, delta)?.encoded_len());
+        self.write(table, key, size, || {
+            Ok((self.store.incr_float(table, key, delta)?, true))
+        })
+    }
+
+    fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        self.store.get_all(table)
+    }
+
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
+        self.store.get_iter(table)
+    }
+
+    fn get_range(
+        &self,
+        table: &str,
+        start: &str,
+        end: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        self.store.get_range(table, start, end, limit)
+    }
+
+    fn set_with_ttl(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+        ttl: Duration,
+    ) -> Result<Option<Value>, KvError> {
+        let size = value.encoded_len();
+        self.write(
+            table,
+            &key.clone(),
+            |_| Ok(size),
+            || Ok((self.store.set_with_ttl(table, key, value, ttl)?, true)),
+        )
+    }
+
+    fn expire(&self, table: &str, key: &str, ttl: Duration) -> Result<bool, KvError> {
+        self.store.expire(table, key, ttl)
+    }
+
+    fn ttl(&self, table: &str, key: &str) -> Result<Option<Duration>, KvError> {
+        self.store.ttl(table, key)
+    }
+
+    fn persist(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.store.persist(table, key)
+    }
+
+    fn purge_expired(&self) -> Result<usize, KvError> {
+        let purged = self.store.purge_expired()?;
+        if purged > 0 {
+            self.usage.clear();
+        }
+        Ok(purged)
+    }
+
+    fn list_tables(&self) -> Result<Vec<String>, KvError> {
+        self.store.list_tables()
+    }
+
+    fn table_info(&self, table: &str) -> Result<Option<TableStats>, KvError> {
+        self.store.table_info(table)
+    }
+
+    fn drop_table(&self, table: &str) -> Result<bool, KvError> {
+        let dropped = self.store.drop_table(table)?;
+        self.usage.remove(table);
+        Ok(dropped)
+    }
+
+    fn rename_table(&self, table: &str, to: &str) -> Result<bool, KvError> {
+        let renamed = self.store.rename_table(table, to)?;
+        self.usage.remove(table);
+        self.usage.remove(to);
+        Ok(renamed)
+    }
+
+    fn stats(&self) -> Result<StorageStats, KvError> {
+        self.store.stats()
+    }
+
+    fn transaction<T, F>(&self, f: F) -> Result<T, KvError>
+    where
+        F: Fn(&dyn TxnStorage) -> Result<T, KvError>,
+    {
+        // 事务中不能统计 table 的用量，所以先加载所有有配额的 table 的用量
+        let mut base = HashMap::new();
+        for table in self.quotas.keys() {
+            base.insert(table.clone(), self.cached(table)?);
+        }
+        let usage = RefCell::new(HashMap::new());
+        let result = self.store.transaction(|txn| {
+            // f 可能被执行多次，每次都从事务开始时的用量算起
+            *usage.borrow_mut() = base.clone();
+            f(&QuotaTxn {
+                txn,
+                quotas: &self.quotas,
+                usage: &usage,
+            })
+        })?;
+        // 事务执行期间别的写入也可能修改了用量，下次用到时重新统计
+        for (table, usage) in usage.into_inner() {
+            if base.get(&table) != Some(&usage) {
+                self.usage.remove(&table);
+            }
+        }
+        Ok(result)
+    }
+
+    fn quota(&self, table: &str) -> Result<Option<QuotaUsage>, KvError> {
+        let quota = match self.quotas.get(table) {
+            Some(quota) => quota,
+            None => return Ok(None),
+        };
+        let usage = self.cached(table)?;
+        Ok(Some(QuotaUsage {
+            max_keys: quota.max_keys,
+            max_bytes: quota.max_bytes,
+            max_value_size: quota.max_value_size,
+            keys: usage.keys,
+            bytes: usage.bytes,
+        }))
+    }
+}
+
+/// 事务中同样检查配额，用量在事务开始时的用量上累计
+struct QuotaTxn<'a> {
+    txn: &'a dyn TxnStorage,
+    quotas: &'a HashMap<String, QuotaConfig>,
+    usage: &'a RefCell<HashMap<String, Usage>>,
+}
+
+impl TxnStorage for QuotaTxn<'_> {
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.txn.get(table, key)
+    }
+
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        if let Some(quota) = self.quotas.get(table) {
+            let new = value.encoded_len();
+            check_value_size(quota, new)?;
+            let old = self.txn.get(table, &key)?.map(|v| v.encoded_len());
+            let mut usage = self.usage.borrow_mut();
+            let current = usage.get(table).copied().unwrap_or_default();
+            let next = current.apply(&key, old, Some(new));
+            check(quota, &current, &next)?;
+            usage.insert(table.to_owned(), next);
+        }
+        self.txn.set(table, key, value)
+    }
+
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.txn.contains(table, key)
+    }
+
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        let old = self.txn.del(table, key)?;
+        if let Some(usage) = self.usage.borrow_mut().get_mut(table) {
+            *usage = usage.apply(key, old.as_ref().map(|v| v.encoded_len()), None);
+        }
+        Ok(old)
+    }
+    fn ttl(&self, table: &str, key: &str) -> Result<Option<Duration>, KvError> {
+        self.txn.ttl(table, key)
+    }
+}
+
+fn check_value_size(quota: &QuotaConfig, size: usize) -> Result<(), KvError> {
+    if quota.max_value_size > 0 && size > quota.max_value_size {
+        return Err(KvError::QuotaExceeded(format!(
+            "value of {} bytes is larger than {} bytes allowed in table {}",
+            size, quota.max_value_size, quota.table
+        )));
+    }
+    Ok(())
+}
+
+/// 用量从 current 变成 next 时是否超出配额，用量没有增加时不算超出
+fn check(quota: &QuotaConfig, current: &Usage, next: &Usage) -> Result<(), KvError> {
+    if quota.max_keys > 0 && next.keys > quota.max_keys && next.keys > current.keys {
+        return Err(KvError::QuotaExceeded(format!(
+            "table {} can have at most {} keys",
+            quota.table, quota.max_keys
+        )));
+    }
+    if quota.max_bytes > 0 && next.bytes > quota.max_bytes && next.bytes > current.bytes {
+        return Err(KvError::QuotaExceeded(format!(
+            "table {} can use at most {} bytes",
+            quota.table, quota.max_bytes
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        CompressedStorage, CompressionCodec, CompressionConfig, CompressionPolicy, Compressor,
+        MemTable,
+    };
+
+    fn quota(max_keys: usize, max_bytes: usize, max_value_size: usize) -> QuotaConfig {
+        QuotaConfig {
+            table: "t1".into(),
+            max_keys,
+            max_bytes,
+            max_value_size,
+        }
+    }
+
+    #[test]
+    fn quota_storage_should_limit_keys_and_value_size() {
+        let store = QuotaStorage::new(MemTable::new(), &[quota(2, 0, 16)]);
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        store.incr("t1", "k2", 1).unwrap();
+        // 覆盖已有的 key 不增加 key 的个数
+        store.set("t1", "k1".into(), "v2".into()).unwrap();
+        let result = store.set("t1", "k3".into(), "v3".into());
+        assert!(matches!(result, Err(KvError::QuotaExceeded(_))));
+        let result = store.set("t1", "k1".into(), "v".repeat(20).into());
+        assert!(matches!(result, Err(KvError::QuotaExceeded(_))));
+        // 没有配额的 table 不受限制
+        store.set("t2", "k3".into(), "v".repeat(20).into()).unwrap();
+
+        store.del("t1", "k2").unwrap();
+        store.set("t1", "k3".into(), "v3".into()).unwrap();
+        let usage = store.quota("t1").unwrap().unwrap();
+        assert_eq!((usage.keys, usage.max_keys), (2, 2));
+        assert_eq!(usage.bytes, store.table_info("t1").unwrap().unwrap().bytes);
+        assert_eq!(store.quota("t2").unwrap(), None);
+    }
+
+    #[test]
+    fn quota_storage_should_limit_bytes_and_recount_expired_keys() {
+        let store = QuotaStorage::new(MemTable::new(), &[quota(0, 64, 0)]);
+        let value: Value = "v".repeat(40).into();
+        store
+            .set_with_ttl("t1", "k1".into(), value.clone(), Duration::ZERO)
+            .unwrap();
+        // k1 已经过期，重新统计之后可以写入
+        store.set("t1", "k2".into(), value.clone()).unwrap();
+        let result = store.set("t1", "k3".into(), value.clone());
+        assert!(matches!(result, Err(KvError::QuotaExceeded(_))));
+        // 超出配额时仍然可以把 value 改小
+        store.set("t1", "k2".into(), "v".into()).unwrap();
+        store.set("t1", "k3".into(), value).unwrap();
+    }
+
+    #[test]
+    fn quota_storage_should_limit_uncompressed_values() {
+        let compression = CompressionConfig {
+            default: CompressionPolicy {
+                codec: CompressionCodec::Zstd,
+                threshold: 0,
+            },
+            ..Default::default()
+        };
+        let store = CompressedStorage::new(MemTable::new(), Compressor::new(compression));
+        let store = QuotaStorage::new(store, &[quota(0, 256, 64)]);
+        // 压缩之后很小，但是写入的 value 超出了配额
+        let result = store.set("t1", "k1".into(), "v".repeat(1000).into());
+        assert!(matches!(result, Err(KvError::QuotaExceeded(_))));
+
+        store.set("t1", "k1".into(), "v".repeat(60).into()).unwrap();
+        let usage = store.quota("t1").unwrap().unwrap();
+        assert!(usage.bytes > 60);
+        assert!(store.inner().table_info("t1").unwrap().unwrap().bytes < 60);
+    }
+
+    #[test]
+    fn quota_storage_should_check_transactions() {
+        let store = QuotaStorage::new(MemTable::new(), &[quota(1, 0, 0)]);
+        let result = store.transaction(|txn| {
+            txn.set("t1", "k1".into(), 1.into())?;
+            txn.set("t1", "k2".into(), 2.into())
+        });
+        assert!(matches!(result, Err(KvError::QuotaExceeded(_))));
+        assert_eq!(store.get("t1", "k1").unwrap(), None);
+
+        store
+            .transaction(|txn| {
+                txn.set("t1", "k1".into(), 1.into())?;
+                txn.del("t1", "k1")?;
+                txn.set("t1", "k2".into(), 2.into())
+            })
+            .unwrap();
+        assert_eq!(store.quota("t1").unwrap().unwrap().keys, 1);
+    }
+}
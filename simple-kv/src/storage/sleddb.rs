@@ -1,25 +1,208 @@
-use sled::{Db, IVec};
-use std::{convert::TryInto, path::Path, str};
+use dashmap::DashMap;
+use sled::{
+    transaction::{
+        ConflictableTransactionError, TransactionError, TransactionalTree,
+        UnabortableTransactionError,
+    },
+    Db, IVec, Transactional, Tree,
+};
+use std::{
+    cell::RefCell,
+    convert::{TryFrom, TryInto},
+    ops::Bound,
+    path::Path,
+    sync::{RwLock, RwLockReadGuard},
+    time::Duration,
+};
+use tracing::{error, info, warn};
 
-use crate::{KvError, Kvpair, Storage, StorageIter, Value};
+use super::{add_float, add_integer, deadline_from, now_millis, remaining};
+use crate::{KvError, Kvpair, Storage, StorageIter, StorageStats, TableStats, TxnStorage, Value};
+
+/// 存放过期时间的 tree，key 由 table 名和 key 编码而成（见 ttl_key），value 是过期的时间点（毫秒）
+const TTL_TREE: &str = "__expires__";
+/// 旧版本存放过期时间的 tree，key 是 "table:key"
+const LEGACY_TTL_TREE: &str = "__ttl__";
+/// 每个 table 对应一个以此为前缀的 tree，和 sled 自己以及我们内部使用的 tree 区分开
+const TABLE_TREE_PREFIX: &str = "table:";
 
 #[derive(Debug)]
-pub struct SledDb(Db);
+pub struct SledDb {
+    db: Db,
+    ttl: Tree,
+    // 所有已经存在的 table，读操作只在这里查找，不会创建新的 tree
+    tables: DashMap<String, Tree>,
+    // 写操作持有读锁，drop/rename table 持有写锁，这样搬运数据期间不会有写入丢失
+    writing: RwLock<()>,
+}
 
 impl SledDb {
-    pub fn new(path: impl AsRef<Path>) -> Self {
-        Self(sled::open(path).unwrap())
+    /// 打开 path 下的 sled db，必要时从旧版本的格式迁移数据
+    pub fn new(path: impl AsRef<Path>) -> Result<Self, KvError> {
+        let db = sled::open(path)?;
+        let ttl = db.open_tree(TTL_TREE)?;
+        let store = Self {
+            db,
+            ttl,
+            tables: DashMap::new(),
+            writing: RwLock::new(()),
+        };
+        let count = store.migrate()?;
+        if count > 0 {
+            info!("Migrated {} keys from legacy layout", count);
+        }
+        store.load_tables()?;
+        Ok(store)
+    }
+
+    // 在 sleddb 里，每个 table 都是一个独立的 tree，key 原样存储，
+    // 所以 key 中可以包含任何字符
+    fn open_table(&self, table: &str) -> Result<Tree, KvError> {
+        if let Some(tree) = self.tables.get(table) {
+            return Ok(tree.clone());
+        }
+        let tree = self.db.open_tree(table_tree_name(table.as_bytes()))?;
+        Ok(self.tables.entry(table.into()).or_insert(tree).clone())
+    }
+
+    /// 获取已经存在的 table，不存在时返回 None
+    fn get_table(&self, table: &str) -> Option<Tree> {
+        self.tables.get(table).map(|t| t.clone())
+    }
+
+    /// 写操作开始之前调用，持有返回的锁期间不会有 table 被删除或者重命名；
+    /// 只在 Storage 的方法入口处调用一次，不能嵌套
+    fn start_write(&self) -> RwLockReadGuard<'_, ()> {
+        self.writing.read().unwrap()
+    }
+
+    /// 从 db 中加载所有的 table
+    fn load_tables(&self) -> Result<(), KvError> {
+        let prefix = TABLE_TREE_PREFIX.as_bytes();
+        for name in self.db.tree_names() {
+            if let Some(table) = name.strip_prefix(prefix) {
+                let tree = self.db.open_tree(&name)?;
+                self.tables.insert(ivec_to_key(table)?, tree);
+            }
+        }
+        Ok(())
+    }
+
+    /// 删除 table 中所有 key 的过期时间
+    fn remove_deadlines(&self, table: &str) -> Result<(), KvError> {
+        let prefix = ttl_key(table.as_bytes(), &[]);
+        for item in self.ttl.scan_prefix(prefix).keys() {
+            self.ttl.remove(item?)?;
+        }
+        Ok(())
+    }
+
+    /// 旧版本把所有的数据以 "table:key" 为 key 存在缺省的 tree 中，
+    /// 这里把它们逐个搬到 table 对应的 tree 中，返回迁移的 key 的个数。
+    /// 每个 key 的迁移都在一个事务中完成，中途退出的话下次启动会继续迁移
+    fn migrate(&self) -> Result<usize, KvError> {
+        let has_legacy_ttl = self
+            .db
+            .tree_names()
+            .iter()
+            .any(|name| name == LEGACY_TTL_TREE.as_bytes());
+        if self.db.is_empty() && !has_legacy_ttl {
+            return Ok(0);
+        }
+
+        let legacy_ttl = self.db.open_tree(LEGACY_TTL_TREE)?;
+        let mut count = 0;
+        for item in self.db.iter() {
+            let (name, value) = item?;
+            // 旧版本无法区分 table 名和 key 中的 ':'，这里以第一个 ':' 为准
+            let pos = match name.iter().position(|b| *b == b':') {
+                Some(pos) => pos,
+                None => {
+                    warn!("Skip legacy key without table: {:?}", name);
+                    continue;
+                }
+            };
+            let (table, key) = (&name[..pos], &name[pos + 1..]);
+            // 迁移之后 table 名和 key 都要能转换成 String，否则就无法再访问到
+            ivec_to_key(table)?;
+            ivec_to_key(key)?;
+            let tree = self.db.open_tree(table_tree_name(table))?;
+
+            let result = (&*self.db, &tree, &self.ttl, &legacy_ttl).transaction(
+                |(db, tree, ttl, legacy_ttl)| {
+                    tree.insert(key, value.clone())?;
+                    if let Some(deadline) = legacy_ttl.remove(&name)? {
+                        ttl.insert(ttl_key(table, key), deadline)?;
+                    }
+                    db.remove(&name)?;
+                    Ok(())
+                },
+            );
+            result.map_err(into_kv_error)?;
+            count += 1;
+        }
+
+        self.db.drop_tree(LEGACY_TTL_TREE)?;
+        Ok(count)
     }
 
-    // 在 sleddb 里，因为它可以 scan_prefix，我们用 prefix
-    // 来模拟一个 table。当然，还可以用其它方案。
-    fn get_full_key(table: &str, key: &str) -> String {
-        format!("{}:{}", table, key)
+    /// 如果 key 已经过期，把它从 table 中删除，返回是否删除
+    fn remove_if_expired(&self, tree: &Tree, table: &str, key: &str) -> Result<bool, KvError> {
+        let name = ttl_key(table.as_bytes(), key.as_bytes());
+        // 大部分 key 没有过期时间，先不开启事务检查一下
+        match self.ttl.get(&name)? {
+            Some(deadline) if ivec_to_deadline(&deadline) <= now_millis() => {}
+            _ => return Ok(false),
+        }
+
+        // 在同一个事务中再检查一次并删除，避免删掉并发的 set 刚写入的 value
+        let result = (tree, &self.ttl).transaction(|(tree, ttl)| {
+            match ttl.get(&name)? {
+                Some(deadline) if ivec_to_deadline(&deadline) <= now_millis() => {}
+                _ => return Ok(false),
+            }
+            ttl.remove(name.as_slice())?;
+            tree.remove(key)?;
+            Ok::<_, ConflictableTransactionError<KvError>>(true)
+        });
+        result.map_err(into_kv_error)
     }
 
-    // 遍历 table 的 key 时，我们直接把 prefix: 当成 table
-    fn get_table_prefix(table: &str) -> String {
-        format!("{}:", table)
+    /// 使用 sled 的 update_and_fetch 原子地用 f 计算出 key 新的 value 并写入，
+    /// 返回新的 value；f 出错时不做任何修改
+    fn update<F>(&self, table: &str, key: &str, f: F) -> Result<Value, KvError>
+    where
+        F: Fn(Option<&Value>) -> Result<Value, KvError>,
+    {
+        let _writing = self.start_write();
+        let tree = self.open_table(table)?;
+        self.remove_if_expired(&tree, table, key)?;
+
+        // update_and_fetch 的闭包无法返回错误，所以先把错误记下来
+        let mut error = None;
+        let result = tree.update_and_fetch(key, |old| {
+            let result = old
+                .map(Value::try_from)
+                .transpose()
+                .and_then(|v| f(v.as_ref()))
+                .and_then(Vec::<u8>::try_from);
+            match result {
+                Ok(data) => {
+                    error = None;
+                    Some(data)
+                }
+                Err(e) => {
+                    error = Some(e);
+                    old.map(|v| v.to_vec())
+                }
+            }
+        })?;
+
+        match (error, result) {
+            (Some(e), _) => Err(e),
+            (None, Some(v)) => v.as_ref().try_into(),
+            (None, None) => Err(KvError::Internal("Failed to update value".into())),
+        }
     }
 }
 
@@ -31,61 +214,653 @@ fn flip<T, E>(x: Option<Result<T, E>>) -> Result<Option<T>, E> {
 
 impl Storage for SledDb {
     fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
-        let name = SledDb::get_full_key(table, key);
-        let result = self.0.get(name.as_bytes())?.map(|v| v.as_ref().try_into());
+        let tree = match self.get_table(table) {
+            Some(tree) => tree,
+            None => return Ok(None),
+        };
+        if self.remove_if_expired(&tree, table, key)? {
+            return Ok(None);
+        }
+        let result = tree.get(key)?.map(|v| v.as_ref().try_into());
         flip(result)
     }
 
     fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
-        let name = SledDb::get_full_key(table, &key);
+        let _writing = self.start_write();
+        let tree = self.open_table(table)?;
         let data: Vec<u8> = value.try_into()?;
 
-        let result = self.0.insert(name, data)?.map(|v| v.as_ref().try_into());
+        self.remove_if_expired(&tree, table, &key)?;
+        self.ttl.remove(ttl_key(table.as_bytes(), key.as_bytes()))?;
+        let result = tree.insert(key, data)?.map(|v| v.as_ref().try_into());
         flip(result)
     }
 
     fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
-        let name = SledDb::get_full_key(table, key);
+        let tree = match self.get_table(table) {
+            Some(tree) => tree,
+            None => return Ok(false),
+        };
+        if self.remove_if_expired(&tree, table, key)? {
+            return Ok(false);
+        }
 
-        Ok(self.0.contains_key(name)?)
+        Ok(tree.contains_key(key)?)
     }
 
     fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
-        let name = SledDb::get_full_key(table, key);
+        let _writing = self.start_write();
+        let tree = match self.get_table(table) {
+            Some(tree) => tree,
+            None => return Ok(None),
+        };
+        if self.remove_if_expired(&tree, table, key)? {
+            return Ok(None);
+        }
 
-        let result = self.0.remove(name)?.map(|v| v.as_ref().try_into());
+        self.ttl.remove(ttl_key(table.as_bytes(), key.as_bytes()))?;
+        let result = tree.remove(key)?.map(|v| v.as_ref().try_into());
         flip(result)
     }
 
+    fn compare_and_swap(
+        &self,
+        table: &str,
+        key: String,
+        expected: Option<Value>,
+        value: Value,
+    ) -> Result<bool, KvError> {
+        let _writing = self.start_write();
+        let tree = self.open_table(table)?;
+        let expected: Option<Vec<u8>> = expected.map(|v| v.try_into()).transpose()?;
+        let data: Vec<u8> = value.try_into()?;
+
+        self.remove_if_expired(&tree, table, &key)?;
+        let swapped = tree
+            .compare_and_swap(key.as_bytes(), expected, Some(data))?
+            .is_ok();
+        if swapped {
+            self.ttl.remove(ttl_key(table.as_bytes(), key.as_bytes()))?;
+        }
+        Ok(swapped)
+    }
+
+    fn set_if_present(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+    ) -> Result<Option<Value>, KvError> {
+        let _writing = self.start_write();
+        // key 不存在时不写入，也就不需要创建 table
+        let tree = match self.get_table(table) {
+            Some(tree) => tree,
+            None => return Ok(None),
+        };
+        let data: Vec<u8> = value.try_into()?;
+
+        self.remove_if_expired(&tree, table, &key)?;
+        // 如果在读取和写入之间 key 被修改了，就重新读取再试
+        loop {
+            let old = match tree.get(key.as_bytes())? {
+                Some(v) => v,
+                None => return Ok(None),
+            };
+            let result =
+                tree.compare_and_swap(key.as_bytes(), Some(&old), Some(data.as_slice()))?;
+            if result.is_ok() {
+                self.ttl.remove(ttl_key(table.as_bytes(), key.as_bytes()))?;
+                return Ok(Some(old.as_ref().try_into()?));
+            }
+        }
+    }
+
+    fn incr(&self, table: &str, key: &str, delta: i64) -> Result<i64, KvError> {
+        let value = self.update(table, key, |v| add_integer(v, delta))?;
+        i64::try_from(&value)
+    }
+
+    fn incr_float(&self, table: &str, key: &str, delta: f64) -> Result<f64, KvError> {
+        let value = self.update(table, key, |v| add_float(v, delta))?;
+        f64::try_from(&value)
+    }
+
     fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
-        let prefix = SledDb::get_table_prefix(table);
-        let result = self.0.scan_prefix(prefix).map(|v| v.into()).collect();
+        let tree = match self.get_table(table) {
+            Some(tree) => tree,
+            None => return Ok(Vec::new()),
+        };
+        let now = now_millis();
+        let result = tree
+            .iter()
+            .filter(|v| !is_expired(&self.ttl, table, v, now))
+            .map(ivec_to_kvpair)
+            .collect::<Result<_, _>>()?;
 
         Ok(result)
     }
 
     fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
-        let prefix = SledDb::get_table_prefix(table);
-        let iter = StorageIter::new(self.0.scan_prefix(prefix));
+        let tree = match self.get_table(table) {
+            Some(tree) => tree,
+            None => return Ok(Box::new(std::iter::empty())),
+        };
+        let now = now_millis();
+        let ttl = self.ttl.clone();
+        let table = table.to_owned();
+        let data = tree
+            .iter()
+            .filter(move |v| !is_expired(&ttl, &table, v, now))
+            // iterator 无法返回错误，遇到无法转换的数据时记录下来并结束遍历
+            .map_while(|v| match ivec_to_kvpair(v) {
+                Ok(pair) => Some(pair),
+                Err(e) => {
+                    error!("Failed to read sled table: {:?}", e);
+                    None
+                }
+            });
+        let iter = StorageIter::new(data);
         Ok(Box::new(iter))
     }
+
+    fn get_range(
+        &self,
+        table: &str,
+        start: &str,
+        end: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        if matches!(end, Some(end) if end <= start) {
+            return Ok(Vec::new());
+        }
+
+        let tree = match self.get_table(table) {
+            Some(tree) => tree,
+            None => return Ok(Vec::new()),
+        };
+        let start = Bound::Included(start.as_bytes());
+        let end = end.map_or(Bound::Unbounded, |end| Bound::Excluded(end.as_bytes()));
+        let now = now_millis();
+        let result = tree
+            .range::<&[u8], _>((start, end))
+            .filter(|v| !is_expired(&self.ttl, table, v, now))
+            .take(limit)
+            .map(ivec_to_kvpair)
+            .collect::<Result<_, _>>()?;
+
+        Ok(result)
+    }
+
+    fn set_with_ttl(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+        ttl: Duration,
+    ) -> Result<Option<Value>, KvError> {
+        let _writing = self.start_write();
+        let tree = self.open_table(table)?;
+        let data: Vec<u8> = value.try_into()?;
+
+        self.remove_if_expired(&tree, table, &key)?;
+        self.ttl.insert(
+            ttl_key(table.as_bytes(), key.as_bytes()),
+            &deadline_from(ttl).to_be_bytes()[..],
+        )?;
+        let result = tree.insert(key, data)?.map(|v| v.as_ref().try_into());
+        flip(result)
+    }
+
+    fn expire(&self, table: &str, key: &str, ttl: Duration) -> Result<bool, KvError> {
+        let _writing = self.start_write();
+        if !self.contains(table, key)? {
+            return Ok(false);
+        }
+        self.ttl.insert(
+            ttl_key(table.as_bytes(), key.as_bytes()),
+            &deadline_from(ttl).to_be_bytes()[..],
+        )?;
+        Ok(true)
+    }
+
+    fn ttl(&self, table: &str, key: &str) -> Result<Option<Duration>, KvError> {
+        let tree = match self.get_table(table) {
+            Some(tree) => tree,
+            None => return Ok(None),
+        };
+        if self.remove_if_expired(&tree, table, key)? {
+            return Ok(None);
+        }
+        Ok(self
+            .ttl
+            .get(ttl_key(table.as_bytes(), key.as_bytes()))?
+            .and_then(|v| remaining(ivec_to_deadline(&v))))
+    }
+
+    fn persist(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        let _writing = self.start_write();
+        let tree = match self.get_table(table) {
+            Some(tree) => tree,
+            None => return Ok(false),
+        };
+        if self.remove_if_expired(&tree, table, key)? {
+            return Ok(false);
+        }
+        Ok(self
+            .ttl
+            .remove(ttl_key(table.as_bytes(), key.as_bytes()))?
+            .is_some())
+    }
+
+    fn purge_expired(&self) -> Result<usize, KvError> {
+        let _writing = self.start_write();
+        let now = now_millis();
+        let mut count = 0;
+        for item in self.ttl.iter() {
+            let (k, v) = item?;
+            if ivec_to_deadline(&v) > now {
+                continue;
+            }
+            let (table, key) = match split_ttl_key(&k) {
+                Some(v) => v,
+                None => continue,
+            };
+            // 如果过期时间在此期间被更新过，就不删除
+            if self
+                .ttl
+                .compare_and_swap(&k, Some(&v), None as Option<&[u8]>)?
+                .is_ok()
+            {
+                if let Some(tree) = self.get_table(&ivec_to_key(table)?) {
+                    tree.remove(key)?;
+                }
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    fn list_tables(&self) -> Result<Vec<String>, KvError> {
+        let mut tables: Vec<String> = self.tables.iter().map(|t| t.key().clone()).collect();
+        tables.sort();
+        Ok(tables)
+    }
+
+    fn table_info(&self, table: &str) -> Result<Option<TableStats>, KvError> {
+        let tree = match self.get_table(table) {
+            Some(tree) => tree,
+            None => return Ok(None),
+        };
+        let now = now_millis();
+        let mut stats = TableStats::default();
+        for item in tree.iter() {
+            if is_expired(&self.ttl, table, &item, now) {
+                continue;
+            }
+            let (k, v) = item?;
+            stats.keys += 1;
+            stats.bytes += k.len() + v.len();
+        }
+        Ok(Some(stats))
+    }
+
+    fn drop_table(&self, table: &str) -> Result<bool, KvError> {
+        let _writing = self.writing.write().unwrap();
+        if self.tables.remove(table).is_none() {
+            return Ok(false);
+        }
+        self.remove_deadlines(table)?;
+        Ok(self.db.drop_tree(table_tree_name(table.as_bytes()))?)
+    }
+
+    fn rename_table(&self, table: &str, to: &str) -> Result<bool, KvError> {
+        // 搬运数据期间所有的写操作都需要等待，重命名很少发生，这样的代价可以接受
+        let _writing = self.writing.write().unwrap();
+        let from = match self.get_table(table) {
+            Some(tree) => tree,
+            None => return Ok(false),
+        };
+        if self.tables.contains_key(to) {
+            return Err(KvError::PreconditionFailed(format!(
+                "table {} already exists",
+                to
+            )));
+        }
+
+        // sled 不支持重命名 tree，只能把数据和过期时间都搬到新的 tree 中
+        let tree = self.db.open_tree(table_tree_name(to.as_bytes()))?;
+        let prefix = ttl_key(table.as_bytes(), &[]);
+        let data = from.iter().collect::<Result<Vec<_>, _>>()?;
+        let deadlines = self
+            .ttl
+            .scan_prefix(&prefix)
+            .collect::<Result<Vec<_>, _>>()?;
+        let result = (&from, &tree, &self.ttl).transaction(|(from, tree, ttl)| {
+            for (k, v) in data.iter() {
+                tree.insert(k, v)?;
+                from.remove(k)?;
+            }
+            for (k, v) in deadlines.iter() {
+                ttl.insert(ttl_key(to.as_bytes(), &k[prefix.len()..]), v)?;
+                ttl.remove(k)?;
+            }
+            Ok(())
+        });
+        result.map_err(into_kv_error)?;
+
+        self.tables.insert(to.into(), tree);
+        self.tables.remove(table);
+        self.db.drop_tree(table_tree_name(table.as_bytes()))?;
+        Ok(true)
+    }
+
+    fn stats(&self) -> Result<StorageStats, KvError> {
+        // sled 自己管理缓存，这里只报告磁盘占用，不做淘汰
+        Ok(StorageStats {
+            used_bytes: self.db.size_on_disk()? as usize,
+            ..Default::default()
+        })
+    }
+
+    fn transaction<T, F>(&self, f: F) -> Result<T, KvError>
+    where
+        F: Fn(&dyn TxnStorage) -> Result<T, KvError>,
+    {
+        let _writing = self.start_write();
+        // sled 的事务需要事先知道会用到哪些 tree，但我们不知道 f 会访问哪些 table，
+        // 所以每遇到一个新的 table，就放弃这次事务，把 table 加进来之后重新执行
+        let mut tables: Vec<String> = Vec::new();
+        let mut trees = vec![self.ttl.clone()];
+        loop {
+            let missing = RefCell::new(None);
+            let result = trees[..].transaction(|views| {
+                let txn = SledTxn {
+                    tables: &tables,
+                    trees: &views[1..],
+                    ttl: &views[0],
+                    error: RefCell::default(),
+                    missing: RefCell::default(),
+                };
+                let result = f(&txn);
+                if let Some(table) = txn.missing.into_inner() {
+                    let err = KvError::Internal(format!("Table {} is not opened", table));
+                    *missing.borrow_mut() = Some(table);
+                    return Err(ConflictableTransactionError::Abort(err));
+                }
+                // sled 自身的错误（比如冲突）要交还给 sled 处理，冲突时 sled 会重试
+                if let Some(e) = txn.error.into_inner() {
+                    return Err(e.into());
+                }
+                result.map_err(ConflictableTransactionError::Abort)
+            });
+
+            match (result, missing.into_inner()) {
+                (Err(TransactionError::Abort(_)), Some(table)) => {
+                    trees.push(self.open_table(&table)?);
+                    tables.push(table);
+                }
+                (result, _) => return result.map_err(into_kv_error),
+            }
+        }
+    }
+}
+
+/// SledDb 的事务，使用 sled 的事务 API，同时操作 table 的数据和过期时间
+struct SledTxn<'a> {
+    // 事务中可以访问的 table，和 trees 一一对应
+    tables: &'a [String],
+    trees: &'a [TransactionalTree],
+    ttl: &'a TransactionalTree,
+    // 事务执行过程中 sled 返回的错误
+    error: RefCell<Option<UnabortableTransactionError>>,
+    // 事务访问了但是还没有加入事务的 table
+    missing: RefCell<Option<String>>,
 }
 
-impl From<Result<(IVec, IVec), sled::Error>> for Kvpair {
-    fn from(v: Result<(IVec, IVec), sled::Error>) -> Self {
-        match v {
-            Ok((k, v)) => match v.as_ref().try_into() {
-                Ok(v) => Kvpair::new(ivec_to_key(k.as_ref()), v),
-                Err(_) => Kvpair::default(),
-            },
-            _ => Kvpair::default(),
+impl SledTxn<'_> {
+    /// 记录 sled 返回的错误，然后把它转换成 KvError
+    fn check<T>(&self, result: Result<T, UnabortableTransactionError>) -> Result<T, KvError> {
+        result.map_err(|e| {
+            let err = KvError::Internal(format!("Transaction failed: {:?}", e));
+            self.error.borrow_mut().get_or_insert(e);
+            err
+        })
+    }
+
+    /// 获取 table 对应的 tree，table 还没有加入事务时记录下来，事务会加上它重新执行
+    fn open_table(&self, table: &str) -> Result<&TransactionalTree, KvError> {
+        match self.tables.iter().position(|t| t == table) {
+            Some(i) => Ok(&self.trees[i]),
+            None => {
+                self.missing
+                    .borrow_mut()
+                    .get_or_insert_with(|| table.into());
+                Err(KvError::Internal(format!("Table {} is not opened", table)))
+            }
+        }
+    }
+
+    /// 如果 key 已经过期，在事务中把它删除，返回是否删除
+    fn remove_if_expired(
+        &self,
+        tree: &TransactionalTree,
+        table: &str,
+        key: &str,
+    ) -> Result<bool, KvError> {
+        let name = ttl_key(table.as_bytes(), key.as_bytes());
+        match self.check(self.ttl.get(&name))? {
+            Some(v) if ivec_to_deadline(&v) <= now_millis() => {
+                self.check(self.ttl.remove(name))?;
+                self.check(tree.remove(key))?;
+                Ok(true)
+            }
+            _ => Ok(false),
         }
     }
 }
 
-fn ivec_to_key(ivec: &[u8]) -> &str {
-    let s = str::from_utf8(ivec).unwrap();
-    let mut iter = s.split(':');
-    iter.next();
-    iter.next().unwrap()
+impl TxnStorage for SledTxn<'_> {
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        let tree = self.open_table(table)?;
+        if self.remove_if_expired(tree, table, key)? {
+            return Ok(None);
+        }
+        let result = self.check(tree.get(key))?.map(|v| v.as_ref().try_into());
+        flip(result)
+    }
+
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        let tree = self.open_table(table)?;
+        let data: Vec<u8> = value.try_into()?;
+
+        self.remove_if_expired(tree, table, &key)?;
+        self.check(self.ttl.remove(ttl_key(table.as_bytes(), key.as_bytes())))?;
+        let result = self
+            .check(tree.insert(key.as_bytes(), data))?
+            .map(|v| v.as_ref().try_into());
+        flip(result)
+    }
+
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        let tree = self.open_table(table)?;
+        if self.remove_if_expired(tree, table, key)? {
+            return Ok(false);
+        }
+        Ok(self.check(tree.get(key))?.is_some())
+    }
+
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        let tree = self.open_table(table)?;
+        if self.remove_if_expired(tree, table, key)? {
+            return Ok(None);
+        }
+        self.check(self.ttl.remove(ttl_key(table.as_bytes(), key.as_bytes())))?;
+        let result = self.check(tree.remove(key))?.map(|v| v.as_ref().try_into());
+        flip(result)
+    }
+
+    fn ttl(&self, table: &str, key: &str) -> Result<Option<Duration>, KvError> {
+        let tree = self.open_table(table)?;
+        if self.remove_if_expired(tree, table, key)? {
+            return Ok(None);
+        }
+        let deadline = self.check(self.ttl.get(ttl_key(table.as_bytes(), key.as_bytes())))?;
+        Ok(deadline.and_then(|v| remaining(ivec_to_deadline(&v))))
+    }
+}
+
+fn ivec_to_kvpair(v: Result<(IVec, IVec), sled::Error>) -> Result<Kvpair, KvError> {
+    let (k, v) = v?;
+    Ok(Kvpair::new(ivec_to_key(&k)?, v.as_ref().try_into()?))
+}
+
+/// 把 sled 中的 key 转换成 String，不是合法 UTF-8 时返回错误，而不是悄悄替换掉
+fn ivec_to_key(ivec: &[u8]) -> Result<String, KvError> {
+    String::from_utf8(ivec.to_vec())
+        .map_err(|_| KvError::ConvertError(format!("{:?}", ivec), "UTF-8 string"))
+}
+
+fn ivec_to_deadline(ivec: &[u8]) -> u64 {
+    ivec.try_into().map(u64::from_be_bytes).unwrap_or_default()
+}
+
+/// table 对应的 tree 的名字
+fn table_tree_name(table: &[u8]) -> Vec<u8> {
+    [TABLE_TREE_PREFIX.as_bytes(), table].concat()
+}
+
+/// 过期时间 tree 中的 key：4 字节的 table 名长度 + table 名 + key，
+/// table 名和 key 中包含任何字节都不会产生歧义
+fn ttl_key(table: &[u8], key: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(4 + table.len() + key.len());
+    buf.extend_from_slice(&(table.len() as u32).to_be_bytes());
+    buf.extend_from_slice(table);
+    buf.extend_from_slice(key);
+    buf
+}
+
+/// 从过期时间 tree 的 key 中拆出 table 名和 key
+fn split_ttl_key(name: &[u8]) -> Option<(&[u8], &[u8])> {
+    let len = u32::from_be_bytes(name.get(..4)?.try_into().ok()?) as usize;
+    let rest = &name[4..];
+    (rest.len() >= len).then(|| rest.split_at(len))
+}
+
+fn into_kv_error(e: TransactionError<KvError>) -> KvError {
+    match e {
+        TransactionError::Abort(e) => e,
+        TransactionError::Storage(e) => e.into(),
+    }
+}
+
+/// 遍历 table 时判断某一项是否已经过期
+fn is_expired(ttl: &Tree, table: &str, item: &Result<(IVec, IVec), sled::Error>, now: u64) -> bool {
+    match item {
+        Ok((k, _)) => {
+            let name = ttl_key(table.as_bytes(), k);
+            matches!(ttl.get(name), Ok(Some(v)) if ivec_to_deadline(&v) <= now)
+        }
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn keys_with_colon_should_not_be_mixed_up() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(&dir).unwrap();
+        store.set("t1", "a:b".into(), "v1".into()).unwrap();
+        store.set("t1:a", "b".into(), "v2".into()).unwrap();
+        store.set("t1", "\0:\u{fffd}".into(), "v3".into()).unwrap();
+
+        assert_eq!(store.get("t1", "a:b").unwrap(), Some("v1".into()));
+        assert_eq!(store.get("t1:a", "b").unwrap(), Some("v2".into()));
+        let mut keys: Vec<_> = store
+            .get_all("t1")
+            .unwrap()
+            .into_iter()
+            .map(|p| p.key)
+            .collect();
+        keys.sort();
+        assert_eq!(keys, vec!["\0:\u{fffd}", "a:b"]);
+    }
+
+    #[test]
+    fn legacy_layout_should_be_migrated() {
+        let dir = tempdir().unwrap();
+        let db = sled::open(&dir).unwrap();
+        let legacy_ttl = db.open_tree(LEGACY_TTL_TREE).unwrap();
+        let data: Vec<u8> = Value::from("v1").try_into().unwrap();
+        db.insert("t1:k1", data.clone()).unwrap();
+        db.insert("t1:k2:x", data.clone()).unwrap();
+        db.insert("t2:k3", data).unwrap();
+        let deadline = deadline_from(Duration::from_secs(3600));
+        legacy_ttl
+            .insert("t2:k3", &deadline.to_be_bytes()[..])
+            .unwrap();
+        db.flush().unwrap();
+        drop(legacy_ttl);
+        drop(db);
+
+        let store = SledDb::new(&dir).unwrap();
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v1".into()));
+        assert_eq!(store.get("t1", "k2:x").unwrap(), Some("v1".into()));
+        assert_eq!(store.get("t2", "k3").unwrap(), Some("v1".into()));
+        assert!(store.ttl("t2", "k3").unwrap().is_some());
+        assert!(store.db.is_empty());
+        assert!(!store
+            .db
+            .tree_names()
+            .iter()
+            .any(|name| name == LEGACY_TTL_TREE.as_bytes()));
+    }
+
+    #[test]
+    fn rename_table_should_not_lose_concurrent_writes() {
+        let dir = tempdir().unwrap();
+        let store = std::sync::Arc::new(SledDb::new(&dir).unwrap());
+        store.set("t1", "k0".into(), "v".into()).unwrap();
+
+        let writer = {
+            let store = store.clone();
+            std::thread::spawn(move || {
+                for i in 1..500 {
+                    store.set("t1", format!("k{}", i), "v".into()).unwrap();
+                }
+            })
+        };
+        assert!(store.rename_table("t1", "t2").unwrap());
+        writer.join().unwrap();
+
+        // 每个 key 要么被搬到了 t2，要么是在重命名之后写入了新的 t1
+        for i in 0..500 {
+            let key = format!("k{}", i);
+            assert!(store.contains("t1", &key).unwrap() || store.contains("t2", &key).unwrap());
+        }
+    }
+
+    #[test]
+    fn invalid_utf8_keys_should_be_reported() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(&dir).unwrap();
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        let data: Vec<u8> = Value::from("v2").try_into().unwrap();
+        store
+            .get_table("t1")
+            .unwrap()
+            .insert(b"\xffk2", data)
+            .unwrap();
+
+        assert!(matches!(
+            store.get_all("t1"),
+            Err(KvError::ConvertError(_, _))
+        ));
+        assert!(store.get_range("t1", "", None, 10).is_err());
+        let keys: Vec<_> = store.get_iter("t1").unwrap().map(|p| p.key).collect();
+        assert_eq!(keys, vec!["k1"]);
+    }
 }
@@ -0,0 +1,704 @@
+use crate::{
+    decode_header, FrameCoder, FsyncPolicy, KvError, Kvpair, Storage, StorageStats, TableStats,
+    TxnStorage, Value, WalEntry, WalRecord, LEN_LEN,
+};
+use bytes::{BufMut, BytesMut};
+use std::{
+    cell::RefCell,
+    collections::BTreeSet,
+    convert::TryInto,
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard},
+    time::Duration,
+};
+use tracing::{info, warn};
+
+use super::MemTable;
+
+/// 预写日志的文件名
+const LOG_FILE: &str = "wal.log";
+/// 快照的文件名
+const SNAPSHOT_FILE: &str = "snapshot";
+/// 生成快照时先写入这个文件，写完再 rename 成 SNAPSHOT_FILE
+const SNAPSHOT_TMP_FILE: &str = "snapshot.tmp";
+/// 快照中每个 WalRecord 最多包含的 key 的个数
+const SNAPSHOT_BATCH: usize = 1024;
+/// 日志和快照文件开头的标记，之后的每条记录都带有 CRC32 校验和；
+/// 没有这个标记的是旧格式的文件，记录没有校验和
+pub(super) const LOG_MAGIC: &[u8] = b"KVWAL\0\0\x01";
+/// 记录末尾校验和的长度
+const CRC_LEN: usize = 4;
+
+/// 带预写日志（WAL）和快照的 MemTable，实现了 Storage trait
+///
+/// 写操作持有日志锁，先把被修改的 key 修改之后的完整状态追加到日志中，再修改 MemTable，
+/// 所以日志的顺序和修改的顺序一致，重放多少次结果都一样；读操作不需要日志锁。
+/// 启动时先加载快照再重放日志，日志超过 compact_threshold 之后会生成新的快照并清空日志
+#[derive(Debug)]
+pub struct WalMemTable {
+    store: MemTable,
+    wal: Mutex<Wal>,
+}
+
+#[derive(Debug)]
+struct Wal {
+    dir: PathBuf,
+    file: File,
+    // 日志当前的大小
+    size: u64,
+    fsync_policy: FsyncPolicy,
+    compact_threshold: u64,
+}
+
+impl WalMemTable {
+    /// 打开 dir 下的快照和日志，恢复出 MemTable
+    pub fn open(
+        dir: impl AsRef<Path>,
+        fsync_policy: FsyncPolicy,
+        compact_threshold: u64,
+    ) -> Result<Self, KvError> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let store = MemTable::new();
+
+        let snapshot = dir.join(SNAPSHOT_FILE);
+        if snapshot.exists() {
+            let data = fs::read(&snapshot)?;
+            if replay(&data, &store)? != data.len() {
+                return Err(KvError::Internal(format!(
+                    "Snapshot {} is corrupted",
+                    snapshot.display()
+                )));
+            }
+        }
+
+        let path = dir.join(LOG_FILE);
+        let data = fs::read(&path).unwrap_or_default();
+        let size = replay(&data, &store)?;
+        info!("Replayed {} bytes of write-ahead log", size);
+
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        if size < data.len() {
+            // 最后一条记录没有写完整（比如写到一半时崩溃了），直接丢弃
+            warn!("Discard {} bytes of incomplete log", data.len() - size);
+            file.set_len(size as u64)?;
+        }
+
+        let mut wal = Wal {
+            dir,
+            file,
+            size: size as u64,
+            fsync_policy,
+            compact_threshold,
+        };
+        if wal.size > 0 {
+            wal.compact(&store)?;
+        }
+
+        Ok(Self {
+            store,
+            wal: Mutex::new(wal),
+        })
+    }
+
+    /// 持有日志锁，先在 key 当前状态的副本上执行 f，把 key 修改之后的状态写入日志，
+    /// 写成功之后再应用到 MemTable 上，日志写失败时 MemTable 保持不变
+    fn write<T, F>(&self, table: &str, key: &str, f: F) -> Result<T, KvError>
+    where
+        F: FnOnce(&MemTable) -> Result<T, KvError>,
+    {
+        let mut wal = self.wal.lock().unwrap();
+        let (value, deadline) = self.store.state(table, key);
+        let scratch = MemTable::new();
+        scratch.restore(table, key.into(), value, deadline);
+        let result = f(&scratch)?;
+
+        let (value, deadline) = scratch.state(table, key);
+        let record = WalRecord {
+            entries: vec![WalEntry {
+                table: table.into(),
+                key: key.into(),
+                value: value.clone(),
+                deadline: deadline.unwrap_or_default(),
+            }],
+            ..Default::default()
+        };
+        wal.append(&record)?;
+        self.store.restore(table, key.into(), value, deadline);
+        self.maybe_compact(&mut wal);
+        Ok(result)
+    }
+
+    /// 把一组 key 当前的状态作为一条记录写入日志
+    fn log<'a>(
+        &self,
+        wal: &mut MutexGuard<Wal>,
+        keys: impl IntoIterator<Item = (&'a str, &'a str)>,
+    ) -> Result<(), KvError> {
+        let entries: Vec<WalEntry> = keys
+            .into_iter()
+            .map(|(table, key)| {
+                let (value, deadline) = self.store.state(table, key);
+                WalEntry {
+                    table: table.into(),
+                    key: key.into(),
+                    value,
+                    deadline: deadline.unwrap_or_default(),
+                }
+            })
+            .collect();
+        if entries.is_empty() {
+            return Ok(());
+        }
+
+        wal.append(&WalRecord {
+            entries,
+            ..Default::default()
+        })
+    }
+
+    /// 日志太大时生成快照，需要在修改已经应用到 MemTable 之后调用
+    fn maybe_compact(&self, wal: &mut MutexGuard<Wal>) {
+        if wal.size >= wal.compact_threshold {
+            // 日志已经写成功了，生成快照失败不影响这次修改，下次写入时会再尝试
+            if let Err(e) = wal.compact(&self.store) {
+                warn!("Failed to compact write-ahead log: {:?}", e);
+            }
+        }
+    }
+}
+
+impl Wal {
+    /// 追加一条记录，并根据 fsync_policy 决定是否立即落盘
+    fn append(&mut self, record: &WalRecord) -> Result<(), KvError> {
+        let mut buf = BytesMut::new();
+        if self.size == 0 {
+            buf.put_slice(LOG_MAGIC);
+        }
+        encode_record(record, &mut buf)?;
+        self.file.write_all(&buf)?;
+        if self.fsync_policy == FsyncPolicy::Always {
+            self.file.sync_data()?;
+        }
+        self.size += buf.len() as u64;
+        Ok(())
+    }
+
+    /// 把 MemTable 写成新的快照，然后清空日志
+    fn compact(&mut self, store: &MemTable) -> Result<(), KvError> {
+        let entries: Vec<WalEntry> = store.dump().into_iter().map(into_entry).collect();
+
+        let tmp = self.dir.join(SNAPSHOT_TMP_FILE);
+        let mut file = File::create(&tmp)?;
+        let mut buf = BytesMut::from(LOG_MAGIC);
+        for chunk in entries.chunks(SNAPSHOT_BATCH) {
+            let record = WalRecord {
+                entries: chunk.to_vec(),
+                ..Default::default()
+            };
+            encode_record(&record, &mut buf)?;
+            file.write_all(&buf)?;
+            buf.clear();
+        }
+        file.sync_all()?;
+        fs::rename(&tmp, self.dir.join(SNAPSHOT_FILE))?;
+        // 保证新的快照落盘之后再清空日志
+        File::open(&self.dir)?.sync_all()?;
+
+        self.file.set_len(0)?;
+        self.file.sync_all()?;
+        self.size = 0;
+        info!("Compacted write-ahead log into {} keys", entries.len());
+        Ok(())
+    }
+}
+
+/// 把 data 中的记录依次应用到 MemTable 上，返回完整的记录占用的字节数
+fn replay(data: &[u8], store: &MemTable) -> Result<usize, KvError> {
+    read_records(data, |record| apply(store, record))
+}
+
+/// 把记录追加到 buf 中：先是记录编码成的帧（帧头包含长度），然后是帧的 CRC32
+pub(super) fn encode_record(record: &WalRecord, buf: &mut BytesMut) -> Result<(), KvError> {
+    let start = buf.len();
+    record.encode_frame(buf)?;
+    let crc = crc32fast::hash(&buf[start..]);
+    buf.put_u32(crc);
+    Ok(())
+}
+
+/// data 是否是没有校验和的旧格式的日志
+pub(super) fn is_legacy(data: &[u8]) -> bool {
+    !LOG_MAGIC.starts_with(data) && !data.starts_with(LOG_MAGIC)
+}
+
+/// 依次解码 data 中的记录并交给 f 处理，返回完整的记录占用的字节数。
+/// 只有最后一条记录不完整或者损坏时才认为是写到一半时崩溃了，丢弃它；
+/// 其它位置的记录损坏时返回错误
+pub(super) fn read_records(data: &[u8], mut f: impl FnMut(WalRecord)) -> Result<usize, KvError> {
+    if LOG_MAGIC.starts_with(data) {
+        // 写第一条记录时崩溃了，连文件开头的标记都没有写完整
+        return Ok(0);
+    }
+    let (mut offset, crc_len) = match data.starts_with(LOG_MAGIC) {
+        true => (LOG_MAGIC.len(), CRC_LEN),
+        false => (0, 0),
+    };
+    while data.len() - offset >= LEN_LEN {
+        let header = u32::from_be_bytes(data[offset..offset + LEN_LEN].try_into().unwrap());
+        let (len, _) = decode_header(header as usize);
+        let end = offset + LEN_LEN + len + crc_len;
+        if end > data.len() {
+            break;
+        }
+
+        let frame = &data[offset..end - crc_len];
+        let checksum = &data[end - crc_len..end];
+        let result = match crc_len > 0 && crc32fast::hash(frame).to_be_bytes() != checksum {
+            true => Err(KvError::Internal("checksum mismatch".into())),
+            false => WalRecord::decode_frame(&mut BytesMut::from(frame)),
+        };
+        match result {
+            Ok(record) => f(record),
+            Err(e) if end == data.len() => {
+                warn!(
+                    "Failed to decode the last log record at {}: {:?}",
+                    offset, e
+                );
+                break;
+            }
+            Err(e) => {
+                return Err(KvError::Internal(format!(
+                    "Log record at {} is corrupted: {:?}",
+                    offset, e
+                )))
+            }
+        }
+        offset = end;
+    }
+    Ok(offset)
+}
+
+/// 把 MemTable 导出的数据转换成日志中的记录
+fn into_entry((table, key, value, deadline): (String, String, Value, Option<u64>)) -> WalEntry {
+    WalEntry {
+        table,
+        key,
+        value: Some(value),
+        deadline: deadline.unwrap_or_default(),
+    }
+}
+
+/// 把一条记录中 table 和 key 的状态恢复到 MemTable 中
+fn apply(store: &MemTable, record: WalRecord) {
+    for table in record.dropped_tables {
+        // MemTable 的 drop_table 不会失败
+        let _ = store.drop_table(&table);
+    }
+    for entry in record.entries {
+        let deadline = (entry.deadline > 0).then_some(entry.deadline);
+        store.restore(&entry.table, entry.key, entry.value, deadline);
+    }
+}
+
+impl Storage for WalMemTable {
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.store.get(table, key)
+    }
+
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        self.write(table, &key, |store| store.set(table, key.clone(), value))
+    }
+
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.store.contains(table, key)
+    }
+
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.write(table, key, |store| store.del(table, key))
+    }
+
+    fn compare_and_swap(
+        &self,
+        table: &str,
+        key: String,
+        expected: Option<Value>,
+        value: Value,
+    ) -> Result<bool, KvError> {
+        self.write(table, &key, |store| {
+            store.compare_and_swap(table, key.clone(), expected, value)
+        })
+    }
+
+    fn set_if_present(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+    ) -> Result<Option<Value>, KvError> {
+        self.write(table, &key, |store| {
+            store.set_if_present(table, key.clone(), value)
+        })
+    }
+
+    fn incr(&self, table: &str, key: &str, delta: i64) -> Result<i64, KvError> {
+        self.write(table, key, |store| store.incr(table, key, delta))
+    }
+
+    fn incr_float(&self, table: &str, key: &str, delta: f64) -> Result<f64, KvError> {
+        self.write(table, key, |store| store.incr_float(table, key, delta))
+    }
+
+    fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        self.store.get_all(table)
+    }
+
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
+        self.store.get_iter(table)
+    }
+
+    fn get_range(
+        &self,
+        table: &str,
+        start: &str,
+        end: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        self.store.get_range(table, start, end, limit)
+    }
+
+    fn set_with_ttl(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+        ttl: Duration,
+    ) -> Result<Option<Value>, KvError> {
+        self.write(table, &key, |store| {
+            store.set_with_ttl(table, key.clone(), value, ttl)
+        })
+    }
+
+    fn expire(&self, table: &str, key: &str, ttl: Duration) -> Result<bool, KvError> {
+        self.write(table, key, |store| store.expire(table, key, ttl))
+    }
+
+    fn ttl(&self, table: &str, key: &str) -> Result<Option<Duration>, KvError> {
+        self.store.ttl(table, key)
+    }
+
+    fn persist(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.write(table, key, |store| store.persist(table, key))
+    }
+
+    fn purge_expired(&self) -> Result<usize, KvError> {
+        // 日志中记录了过期时间点，重放时过期的 key 依然是过期的，所以不需要写日志
+        self.store.purge_expired()
+    }
+
+    fn list_tables(&self) -> Result<Vec<String>, KvError> {
+        self.store.list_tables()
+    }
+
+    fn table_info(&self, table: &str) -> Result<Option<TableStats>, KvError> {
+        self.store.table_info(table)
+    }
+
+    fn drop_table(&self, table: &str) -> Result<bool, KvError> {
+        let mut wal = self.wal.lock().unwrap();
+        if !self.store.has_table(table) {
+            return Ok(false);
+        }
+        let record = WalRecord {
+            dropped_tables: vec![table.into()],
+            ..Default::default()
+        };
+        wal.append(&record)?;
+        self.store.drop_table(table)?;
+        self.maybe_compact(&mut wal);
+        Ok(true)
+    }
+
+    fn rename_table(&self, table: &str, to: &str) -> Result<bool, KvError> {
+        let mut wal = self.wal.lock().unwrap();
+        if !self.store.has_table(table) {
+            return Ok(false);
+        }
+        if self.store.has_table(to) {
+            return Err(KvError::PreconditionFailed(format!(
+                "table {} already exists",
+                to
+            )));
+        }
+        // 记录为删除旧的 table，再把所有的 key 写入新的 table
+        let entries = self
+            .store
+            .dump_table(table)
+            .into_iter()
+            .map(|(_, key, value, deadline)| into_entry((to.into(), key, value, deadline)))
+            .collect();
+        let record = WalRecord {
+            entries,
+            dropped_tables: vec![table.into()],
+        };
+        wal.append(&record)?;
+        self.store.rename_table(table, to)?;
+        self.maybe_compact(&mut wal);
+        Ok(true)
+    }
+
+    fn stats(&self) -> Result<StorageStats, KvError> {
+        self.store.stats()
+    }
+
+    fn transaction<T, F>(&self, f: F) -> Result<T, KvError>
+    where
+        F: Fn(&dyn TxnStorage) -> Result<T, KvError>,
+    {
+        let wal = RefCell::new(self.wal.lock().unwrap());
+        let keys: RefCell<BTreeSet<(String, String)>> = RefCell::default();
+        let result = self.store.transaction(|txn| {
+            keys.borrow_mut().clear();
+            let result = f(&WalTxn { txn, keys: &keys })?;
+            // 事务修改的所有 key 写在同一条记录里，重放时要么全部生效，要么都不生效；
+            // 写日志失败时返回错误，MemTable 会回滚事务
+            let keys = keys.borrow();
+            self.log(
+                &mut wal.borrow_mut(),
+                keys.iter()
+                    .map(|(t, k): &(String, String)| (t.as_str(), k.as_str())),
+            )?;
+            Ok(result)
+        })?;
+
+        self.maybe_compact(&mut wal.borrow_mut());
+        Ok(result)
+    }
+}
+
+/// WalMemTable 的事务：记录事务中修改过的 key，事务提交后写入日志
+struct WalTxn<'a> {
+    txn: &'a dyn TxnStorage,
+    keys: &'a RefCell<BTreeSet<(String, String)>>,
+}
+
+impl WalTxn<'_> {
+    fn record(&self, table: &str, key: &str) {
+        self.keys.borrow_mut().insert((table.into(), key.into()));
+    }
+}
+
+impl TxnStorage for WalTxn<'_> {
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.txn.get(table, key)
+    }
+
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        self.record(table, &key);
+        self.txn.set(table, key, value)
+    }
+
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.txn.contains(table, key)
+    }
+
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.record(table, key);
+        self.txn.del(table, key)
+    }
+    fn ttl(&self, table: &str, key: &str) -> Result<Option<Duration>, KvError> {
+        self.txn.ttl(table, key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    const NO_COMPACT: u64 = u64::MAX;
+
+    #[test]
+    fn wal_memtable_should_recover_after_reopen() {
+        let dir = tempdir().unwrap();
+        let store = WalMemTable::open(&dir, FsyncPolicy::Always, NO_COMPACT).unwrap();
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        store.set("t1", "k2".into(), "v2".into()).unwrap();
+        store.del("t1", "k2").unwrap();
+        store.incr("t1", "counter", 3).unwrap();
+        store
+            .set_with_ttl("t1", "k3".into(), "v3".into(), Duration::from_secs(3600))
+            .unwrap();
+        store
+            .set_with_ttl("t1", "k4".into(), "v4".into(), Duration::ZERO)
+            .unwrap();
+        store
+            .transaction(|txn| {
+                txn.set("t2", "k1".into(), 1.into())?;
+                txn.set("t2", "k2".into(), 2.into())
+            })
+            .unwrap();
+        drop(store);
+
+        let store = WalMemTable::open(&dir, FsyncPolicy::Always, NO_COMPACT).unwrap();
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v1".into()));
+        assert_eq!(store.get("t1", "k2").unwrap(), None);
+        assert_eq!(store.get("t1", "counter").unwrap(), Some(3.into()));
+        assert_eq!(store.get("t1", "k3").unwrap(), Some("v3".into()));
+        assert!(store.ttl("t1", "k3").unwrap().is_some());
+        assert_eq!(store.get("t1", "k4").unwrap(), None);
+        assert_eq!(store.get("t2", "k2").unwrap(), Some(2.into()));
+    }
+
+    #[test]
+    fn wal_memtable_should_compact_log() {
+        let dir = tempdir().unwrap();
+        let store = WalMemTable::open(&dir, FsyncPolicy::Never, 1).unwrap();
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        store.set("t1", "k2".into(), "v2".into()).unwrap();
+
+        // 每次写入之后都生成了快照，日志被清空
+        assert_eq!(fs::metadata(dir.path().join(LOG_FILE)).unwrap().len(), 0);
+        assert!(dir.path().join(SNAPSHOT_FILE).exists());
+        drop(store);
+
+        let store = WalMemTable::open(&dir, FsyncPolicy::Never, NO_COMPACT).unwrap();
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v1".into()));
+        assert_eq!(store.get("t1", "k2").unwrap(), Some("v2".into()));
+    }
+
+    #[test]
+    fn wal_memtable_should_recover_dropped_and_renamed_tables() {
+        let dir = tempdir().unwrap();
+        let store = WalMemTable::open(&dir, FsyncPolicy::Always, NO_COMPACT).unwrap();
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        store.set("t2", "k2".into(), "v2".into()).unwrap();
+        store
+            .set_with_ttl("t2", "k3".into(), "v3".into(), Duration::from_secs(3600))
+            .unwrap();
+        store.drop_table("t1").unwrap();
+        store.rename_table("t2", "t3").unwrap();
+        drop(store);
+
+        let store = WalMemTable::open(&dir, FsyncPolicy::Always, NO_COMPACT).unwrap();
+        assert_eq!(store.list_tables().unwrap(), vec!["t3"]);
+        assert_eq!(store.get("t3", "k2").unwrap(), Some("v2".into()));
+        assert!(store.ttl("t3", "k3").unwrap().is_some());
+    }
+
+    #[test]
+    fn wal_memtable_should_discard_incomplete_record() {
+        let dir = tempdir().unwrap();
+        let store = WalMemTable::open(&dir, FsyncPolicy::Always, NO_COMPACT).unwrap();
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        drop(store);
+
+        // 模拟写到一半时崩溃：日志末尾只有一部分记录
+        let mut buf = BytesMut::new();
+        encode_record(&record("k2", "v2"), &mut buf).unwrap();
+        append_log(&dir, &buf[..buf.len() - 1]);
+
+        let store = WalMemTable::open(&dir, FsyncPolicy::Always, NO_COMPACT).unwrap();
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v1".into()));
+        assert_eq!(store.get("t1", "k2").unwrap(), None);
+    }
+
+    #[test]
+    fn wal_memtable_should_discard_corrupted_last_record() {
+        let dir = tempdir().unwrap();
+        let store = WalMemTable::open(&dir, FsyncPolicy::Always, NO_COMPACT).unwrap();
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        store.set("t1", "k2".into(), "v2".into()).unwrap();
+        drop(store);
+
+        corrupt_log(&dir, |data| *data.last_mut().unwrap() ^= 0xff);
+
+        let store = WalMemTable::open(&dir, FsyncPolicy::Always, NO_COMPACT).unwrap();
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v1".into()));
+        assert_eq!(store.get("t1", "k2").unwrap(), None);
+    }
+
+    #[test]
+    fn wal_memtable_should_refuse_to_open_corrupted_log() {
+        let dir = tempdir().unwrap();
+        let store = WalMemTable::open(&dir, FsyncPolicy::Always, NO_COMPACT).unwrap();
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        store.set("t1", "k2".into(), "v2".into()).unwrap();
+        drop(store);
+
+        // 第一条记录中间的一个字节被改掉了
+        corrupt_log(&dir, |data| data[LOG_MAGIC.len() + LEN_LEN + 2] ^= 0xff);
+
+        assert!(WalMemTable::open(&dir, FsyncPolicy::Always, NO_COMPACT).is_err());
+    }
+
+    #[test]
+    fn wal_memtable_should_replay_legacy_log() {
+        let dir = tempdir().unwrap();
+        // 旧格式的日志没有文件开头的标记，记录也没有校验和
+        let mut buf = BytesMut::new();
+        record("k1", "v1").encode_frame(&mut buf).unwrap();
+        record("k2", "v2").encode_frame(&mut buf).unwrap();
+        append_log(&dir, &buf);
+
+        let store = WalMemTable::open(&dir, FsyncPolicy::Always, NO_COMPACT).unwrap();
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v1".into()));
+        assert_eq!(store.get("t1", "k2").unwrap(), Some("v2".into()));
+        store.set("t1", "k3".into(), "v3".into()).unwrap();
+        drop(store);
+
+        let data = fs::read(dir.path().join(LOG_FILE)).unwrap();
+        assert!(data.starts_with(LOG_MAGIC));
+        let store = WalMemTable::open(&dir, FsyncPolicy::Always, NO_COMPACT).unwrap();
+        assert_eq!(store.get("t1", "k3").unwrap(), Some("v3".into()));
+    }
+
+    #[test]
+    fn wal_memtable_should_not_apply_changes_when_log_fails() {
+        let dir = tempdir().unwrap();
+        let store = WalMemTable::open(&dir, FsyncPolicy::Always, NO_COMPACT).unwrap();
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+
+        // 换成只读的文件，模拟写日志失败
+        store.wal.lock().unwrap().file = File::open(dir.path().join(LOG_FILE)).unwrap();
+        assert!(store.set("t1", "k1".into(), "v2".into()).is_err());
+        assert!(store.del("t1", "k1").is_err());
+        assert!(store.drop_table("t1").is_err());
+        assert!(store
+            .transaction(|txn| txn.set("t1", "k2".into(), "v2".into()))
+            .is_err());
+
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v1".into()));
+        assert_eq!(store.get("t1", "k2").unwrap(), None);
+    }
+
+    fn record(key: &str, value: &str) -> WalRecord {
+        WalRecord {
+            entries: vec![WalEntry {
+                table: "t1".into(),
+                key: key.into(),
+                value: Some(value.into()),
+                deadline: 0,
+            }],
+            ..Default::default()
+        }
+    }
+
+    fn append_log(dir: &tempfile::TempDir, data: &[u8]) {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.path().join(LOG_FILE))
+            .unwrap();
+        file.write_all(data).unwrap();
+    }
+
+    fn corrupt_log(dir: &tempfile::TempDir, f: impl FnOnce(&mut Vec<u8>)) {
+        let path = dir.path().join(LOG_FILE);
+        let mut data = fs::read(&path).unwrap();
+        f(&mut data);
+        fs::write(&path, data).unwrap();
+    }
+}
@@ -1,7 +1,8 @@
 use anyhow::Result;
 use simple_kv::{
-    ClientConfig, ClientTlsConfig, GeneralConfig, LogConfig, NetworkType, RotationConfig,
-    ServerConfig, ServerTlsConfig, StorageConfig,
+    ClientConfig, ClientTlsConfig, CompressionConfig, FrameCompression, GeneralConfig, LogConfig,
+    MvccConfig, NetworkType, ReaperConfig, RotationConfig, ServerConfig, ServerTlsConfig,
+    StorageConfig,
 };
 use std::fs;
 
@@ -13,6 +14,8 @@ fn main() -> Result<()> {
     let general_config = GeneralConfig {
         addr: "127.0.0.1:9527".into(),
         network: NetworkType::Tcp,
+        compression: FrameCompression::default(),
+        max_frame_size: 16 * 1024 * 1024,
     };
     let server_config = ServerConfig {
         storage: StorageConfig::SledDb("/tmp/kv_server".into()),
@@ -29,6 +32,14 @@ fn main() -> Result<()> {
             path: "/tmp/kv-log".into(),
             rotation: RotationConfig::Daily,
         },
+        reaper: ReaperConfig::default(),
+        mvcc: MvccConfig::default(),
+        indexes: Vec::new(),
+        quotas: Vec::new(),
+        encryption: None,
+        compression: CompressionConfig::default(),
+        backup_dir: "/tmp/kv-backup".into(),
+        max_value_size: 256 * 1024 * 1024,
     };
 
     fs::write(
@@ -0,0 +1,113 @@
+use anyhow::{bail, Result};
+use simple_kv::{
+    backup_to_file, export_table, import_table, restore_from_file, CompressedStorage, Compressor,
+    DataFormat, EncryptedStorage, Keyring, LsmDb, ServerConfig, SledDb, Storage, StorageConfig,
+    ValueCodec, WalMemTable,
+};
+use std::{
+    env,
+    fs::File,
+    io::{BufReader, BufWriter},
+    path::Path,
+};
+
+const USAGE: &str = "Usage:
+    kv-dump export <server config> <backup file>
+    kv-dump import <server config> <backup file>
+    kv-dump export-table <server config> <table> <file.jsonl|file.csv>
+    kv-dump import-table <server config> <table> <file.jsonl|file.csv>
+
+export/import files are encrypted when the server enables encryption,
+export-table files are always plaintext.";
+
+/// 离线导出/导入服务器的数据，使用前需要先停止服务器
+/// 服务器运行时可以使用 Backup/Restore 命令在线备份
+fn main() -> Result<()> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (cmd, config, args) = match &args[..] {
+        [cmd, config, args @ ..] => (cmd.as_str(), config, args),
+        _ => bail!(USAGE),
+    };
+    let config = ServerConfig::load(config)?;
+
+    match &config.storage {
+        StorageConfig::SledDb(path) => run(SledDb::new(path)?, &config, cmd, args),
+        StorageConfig::MemTableWal {
+            dir,
+            fsync_policy,
+            compact_threshold,
+        } => run(
+            WalMemTable::open(dir, *fsync_policy, *compact_threshold)?,
+            &config,
+            cmd,
+            args,
+        ),
+        StorageConfig::Lsm {
+            dir,
+            fsync_policy,
+            memtable_size,
+        } => run(
+            LsmDb::open(dir, *fsync_policy, *memtable_size)?,
+            &config,
+            cmd,
+            args,
+        ),
+        StorageConfig::MemTable | StorageConfig::BoundedMemTable { .. } => {
+            bail!("MemTable has no data on disk, use the Backup command instead")
+        }
+    }
+}
+
+/// 读写的是解密、解压之后的数据，导入时按服务器的配置压缩、加密。
+/// 开启了加密时 export/import 的文件和 Backup 命令一样用密钥加密，
+/// export-table 导出的 JSONL/CSV 文件则总是明文
+fn run(store: impl Storage, config: &ServerConfig, cmd: &str, args: &[String]) -> Result<()> {
+    let compressor = Compressor::new(config.compression.clone());
+    match &config.encryption {
+        Some(encryption) => {
+            let keyring = Keyring::load(&encryption.key_file)?;
+            let store = CompressedStorage::new(EncryptedStorage::new(store, keyring), compressor);
+            execute(&store, Some(store.inner().codec()), cmd, args)
+        }
+        None => execute(&CompressedStorage::new(store, compressor), None, cmd, args),
+    }
+}
+
+fn execute(
+    store: &impl Storage,
+    codec: Option<&dyn ValueCodec>,
+    cmd: &str,
+    args: &[String],
+) -> Result<()> {
+    match (cmd, args) {
+        ("export", [file]) => {
+            let count = backup_to_file(store, file, codec)?;
+            println!("Exported {} pairs to {}", count, file);
+        }
+        ("import", [file]) => {
+            let count = restore_from_file(store, file, codec)?;
+            println!("Imported {} pairs from {}", count, file);
+        }
+        ("export-table", [table, file]) => {
+            let writer = BufWriter::new(File::create(file)?);
+            let count = export_table(store, table, format_of(file)?, writer)?;
+            println!("Exported {} pairs from table {} to {}", count, table, file);
+        }
+        ("import-table", [table, file]) => {
+            let reader = BufReader::new(File::open(file)?);
+            let count = import_table(store, table, format_of(file)?, reader)?;
+            println!("Imported {} pairs from {} to table {}", count, file, table);
+        }
+        _ => bail!(USAGE),
+    }
+    Ok(())
+}
+
+/// 根据文件的扩展名决定文件格式
+fn format_of(file: &str) -> Result<DataFormat> {
+    let ext = Path::new(file)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or_default();
+    Ok(ext.parse()?)
+}
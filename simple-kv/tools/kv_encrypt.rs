@@ -0,0 +1,56 @@
+use anyhow::{bail, Result};
+use simple_kv::{
+    EncryptedStorage, Keyring, LsmDb, ServerConfig, SledDb, Storage, StorageConfig, WalMemTable,
+};
+use std::env;
+
+const USAGE: &str = "Usage:
+    kv-encrypt gen-key <key file>
+    kv-encrypt reencrypt <server config>";
+
+/// 管理加密存储的密钥
+/// gen-key 往密钥文件中加入一个新的密钥，之后写入的数据都用它加密
+/// reencrypt 把已有的数据用最新的密钥重新加密（没有加密的数据也会被加密），使用前需要先停止服务器
+fn main() -> Result<()> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    match &args[..] {
+        [cmd, file] if cmd == "gen-key" => {
+            let id = Keyring::rotate(file)?;
+            println!("Added key {} to {}", id, file);
+        }
+        [cmd, config] if cmd == "reencrypt" => {
+            let config = ServerConfig::load(config)?;
+            let key_file = match &config.encryption {
+                Some(encryption) => &encryption.key_file,
+                None => bail!("Encryption is not configured in {}", args[1]),
+            };
+            let keyring = Keyring::load(key_file)?;
+            let count = match &config.storage {
+                StorageConfig::SledDb(path) => reencrypt(SledDb::new(path)?, keyring)?,
+                StorageConfig::MemTableWal {
+                    dir,
+                    fsync_policy,
+                    compact_threshold,
+                } => reencrypt(
+                    WalMemTable::open(dir, *fsync_policy, *compact_threshold)?,
+                    keyring,
+                )?,
+                StorageConfig::Lsm {
+                    dir,
+                    fsync_policy,
+                    memtable_size,
+                } => reencrypt(LsmDb::open(dir, *fsync_policy, *memtable_size)?, keyring)?,
+                StorageConfig::MemTable | StorageConfig::BoundedMemTable { .. } => {
+                    bail!("MemTable has no data on disk")
+                }
+            };
+            println!("Re-encrypted {} values", count);
+        }
+        _ => bail!(USAGE),
+    }
+    Ok(())
+}
+
+fn reencrypt(store: impl Storage, keyring: Keyring) -> Result<usize> {
+    Ok(EncryptedStorage::new(store, keyring).reencrypt()?)
+}